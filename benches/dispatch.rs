@@ -0,0 +1,69 @@
+#![feature(test)]
+
+extern crate test;
+use test::Bencher;
+use futures::executor::block_on;
+use libenigma::atom;
+use libenigma::loader::Loader;
+use libenigma::module::Module;
+use libenigma::process::{self, RcProcess};
+use libenigma::servo_arc::Arc;
+use libenigma::value::Term;
+use libenigma::vm::Machine;
+
+fn load(vm: &Machine, bytes: &[u8], specialize: bool) -> Box<Module> {
+    let loader = Loader::new();
+    let loader = if specialize { loader } else { loader.generic() };
+    let mut module = Box::new(loader.load_file(bytes).unwrap());
+    module.link_imports(&mut vm.exports.write());
+    module
+}
+
+/// Runs fib:fib(N) to completion, across as many slices as it takes.
+fn fib(vm: &Machine, process: &mut RcProcess, module: &Module, n: i32) -> Term {
+    let context = process.context_mut();
+    context.ip.ptr = module.funs[&(atom::from_str("fib"), 1)];
+    context.cp = None;
+    context.x[0] = Term::int(n);
+
+    loop {
+        vm.begin_slice(process.context_mut());
+        let state = block_on(vm.run(process)).unwrap();
+        vm.end_slice(process.context_mut());
+        if let process::State::Done = state {
+            break;
+        }
+    }
+    process.context_mut().x[0]
+}
+
+fn bench_fib(b: &mut Bencher, specialize: bool) {
+    let vm: Arc<Machine> = Machine::new();
+    let bytes = std::fs::read("examples/fib.beam").unwrap();
+    let module = load(&vm, &bytes, specialize);
+    let mut process = process::allocate(&vm, 0, 0, &*module).unwrap();
+
+    b.iter(|| assert_eq!(fib(&vm, &mut process, &module, 15), Term::int(610)))
+}
+
+#[bench]
+fn dispatch_fib_generic(b: &mut Bencher) {
+    bench_fib(b, false)
+}
+
+#[bench]
+fn dispatch_fib_specialized(b: &mut Bencher) {
+    bench_fib(b, true)
+}
+
+#[bench]
+fn load_fib_generic(b: &mut Bencher) {
+    let bytes = std::fs::read("examples/fib.beam").unwrap();
+    b.iter(|| Loader::new().generic().load_file(&bytes).unwrap())
+}
+
+#[bench]
+fn load_fib_specialized(b: &mut Bencher) {
+    let bytes = std::fs::read("examples/fib.beam").unwrap();
+    b.iter(|| Loader::new().load_file(&bytes).unwrap())
+}
@@ -2,6 +2,7 @@
 use hashbrown::HashMap;
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
+use std::mem;
 use std::u16;
 
 /// Maximum character length of an atom.
@@ -109,6 +110,22 @@ impl AtomTable {
         }
         Some(&index_r[index as usize] as *const Atom)
     }
+
+    /// Memory used by the table, in bytes: (allocated, used). The names are stored twice, once
+    /// as index keys and once in the atoms themselves.
+    pub fn memory(&self) -> (usize, usize) {
+        let index = self.index.read();
+        let index_r = self.index_r.read();
+        let entry = mem::size_of::<String>() + mem::size_of::<u32>();
+
+        let names: usize = index_r.iter().map(|atom| atom.name.capacity()).sum();
+        let allocated =
+            index.capacity() * entry + index_r.capacity() * mem::size_of::<Atom>() + 2 * names;
+        let used = index.len() * entry
+            + index_r.len() * mem::size_of::<Atom>()
+            + 2 * index_r.iter().map(|atom| atom.len as usize).sum::<usize>();
+        (allocated, used)
+    }
 }
 
 pub static ATOMS: Lazy<AtomTable> = sync_lazy! {
@@ -421,6 +438,97 @@ pub static ATOMS: Lazy<AtomTable> = sync_lazy! {
     atoms.register_atom("bof");
     atoms.register_atom("cur");
 
+    atoms.register_atom("call");
+    atoms.register_atom("send");
+    atoms.register_atom("receive");
+    atoms.register_atom("return_from");
+    atoms.register_atom("set_on_spawn");
+    atoms.register_atom("tracer");
+    atoms.register_atom("flags");
+    atoms.register_atom("traced");
+    atoms.register_atom("match_spec");
+    atoms.register_atom("global");
+    atoms.register_atom("existing");
+
+    atoms.register_atom("not_purged");
+    atoms.register_atom("check_process_code");
+    atoms.register_atom("async");
+    atoms.register_atom("allow_gc");
+    atoms.register_atom("prepare");
+    atoms.register_atom("prepare_on_load");
+    atoms.register_atom("abort");
+    atoms.register_atom("complete");
+
+    atoms.register_atom("garbage_collect");
+    atoms.register_atom("major");
+    atoms.register_atom("minor");
+
+    atoms.register_atom("error_logger");
+    atoms.register_atom("log");
+    atoms.register_atom("emulator");
+    atoms.register_atom("gl");
+    atoms.register_atom("time");
+    atoms.register_atom("tag");
+
+    atoms.register_atom("minor_gcs");
+
+    atoms.register_atom("total");
+    atoms.register_atom("processes");
+    atoms.register_atom("processes_used");
+    atoms.register_atom("system");
+    atoms.register_atom("atom");
+    atoms.register_atom("atom_used");
+    atoms.register_atom("code");
+    atoms.register_atom("ets");
+
+    atoms.register_atom("runtime");
+    atoms.register_atom("wall_clock");
+    atoms.register_atom("exact_reductions");
+    atoms.register_atom("context_switches");
+    atoms.register_atom("io");
+    atoms.register_atom("input");
+    atoms.register_atom("output");
+    atoms.register_atom("run_queue");
+    atoms.register_atom("run_queue_lengths");
+    atoms.register_atom("active_tasks");
+    atoms.register_atom("scheduler_wall_time");
+
+    atoms.register_atom("monitors");
+
+    atoms.register_atom("process_count");
+
+    atoms.register_atom("timeout");
+    atoms.register_atom("cancel_timer");
+    atoms.register_atom("read_timer");
+
+    atoms.register_atom("noconnection");
+    atoms.register_atom("clock_service");
+
+    atoms.register_atom("alias");
+    atoms.register_atom("explicit_unalias");
+    atoms.register_atom("reply");
+    atoms.register_atom("demonitor");
+    atoms.register_atom("reply_demonitor");
+
+    atoms.register_atom("yes");
+    atoms.register_atom("no");
+    atoms.register_atom("reply_tag");
+    atoms.register_atom("spawn_reply");
+    atoms.register_atom("error_only");
+    atoms.register_atom("success_only");
+    atoms.register_atom("abandoned");
+
+    atoms.register_atom("heap_block_size");
+    atoms.register_atom("old_heap_block_size");
+    atoms.register_atom("mbuf_size");
+    atoms.register_atom("recent_size");
+    atoms.register_atom("old_heap_size");
+    atoms.register_atom("bin_vheap_size");
+    atoms.register_atom("bin_vheap_block_size");
+    atoms.register_atom("bin_old_vheap_size");
+    atoms.register_atom("bin_old_vheap_block_size");
+    atoms.register_atom("nanosecond");
+
     atoms
 };
 
@@ -729,6 +837,97 @@ pub const OTP_RELEASE: u32 = 256;
 pub const BOF: u32 = 257;
 pub const CUR: u32 = 258;
 
+pub const CALL: u32 = 259;
+pub const SEND: u32 = 260;
+pub const RECEIVE: u32 = 261;
+pub const RETURN_FROM: u32 = 262;
+pub const SET_ON_SPAWN: u32 = 263;
+pub const TRACER: u32 = 264;
+pub const FLAGS: u32 = 265;
+pub const TRACED: u32 = 266;
+pub const MATCH_SPEC: u32 = 267;
+pub const GLOBAL: u32 = 268;
+pub const EXISTING: u32 = 269;
+
+pub const NOT_PURGED: u32 = 270;
+pub const CHECK_PROCESS_CODE: u32 = 271;
+pub const ASYNC: u32 = 272;
+pub const ALLOW_GC: u32 = 273;
+pub const PREPARE: u32 = 274;
+pub const PREPARE_ON_LOAD: u32 = 275;
+pub const ABORT: u32 = 276;
+pub const COMPLETE: u32 = 277;
+
+pub const GARBAGE_COLLECT: u32 = 278;
+pub const MAJOR: u32 = 279;
+pub const MINOR: u32 = 280;
+
+pub const ERROR_LOGGER: u32 = 281;
+pub const LOG: u32 = 282;
+pub const EMULATOR: u32 = 283;
+pub const GL: u32 = 284;
+pub const TIME: u32 = 285;
+pub const TAG: u32 = 286;
+
+pub const MINOR_GCS: u32 = 287;
+
+pub const TOTAL: u32 = 288;
+pub const PROCESSES: u32 = 289;
+pub const PROCESSES_USED: u32 = 290;
+pub const SYSTEM: u32 = 291;
+pub const ATOM: u32 = 292;
+pub const ATOM_USED: u32 = 293;
+pub const CODE: u32 = 294;
+pub const ETS: u32 = 295;
+
+pub const RUNTIME: u32 = 296;
+pub const WALL_CLOCK: u32 = 297;
+pub const EXACT_REDUCTIONS: u32 = 298;
+pub const CONTEXT_SWITCHES: u32 = 299;
+pub const IO: u32 = 300;
+pub const INPUT: u32 = 301;
+pub const OUTPUT: u32 = 302;
+pub const RUN_QUEUE: u32 = 303;
+pub const RUN_QUEUE_LENGTHS: u32 = 304;
+pub const ACTIVE_TASKS: u32 = 305;
+pub const SCHEDULER_WALL_TIME: u32 = 306;
+
+pub const MONITORS: u32 = 307;
+
+pub const PROCESS_COUNT: u32 = 308;
+
+pub const TIMEOUT: u32 = 309;
+pub const CANCEL_TIMER: u32 = 310;
+pub const READ_TIMER: u32 = 311;
+
+pub const NOCONNECTION: u32 = 312;
+pub const CLOCK_SERVICE: u32 = 313;
+
+pub const ALIAS: u32 = 314;
+pub const EXPLICIT_UNALIAS: u32 = 315;
+pub const REPLY: u32 = 316;
+pub const DEMONITOR: u32 = 317;
+pub const REPLY_DEMONITOR: u32 = 318;
+
+pub const YES: u32 = 319;
+pub const NO: u32 = 320;
+pub const REPLY_TAG: u32 = 321;
+pub const SPAWN_REPLY: u32 = 322;
+pub const ERROR_ONLY: u32 = 323;
+pub const SUCCESS_ONLY: u32 = 324;
+pub const ABANDONED: u32 = 325;
+
+pub const HEAP_BLOCK_SIZE: u32 = 326;
+pub const OLD_HEAP_BLOCK_SIZE: u32 = 327;
+pub const MBUF_SIZE: u32 = 328;
+pub const RECENT_SIZE: u32 = 329;
+pub const OLD_HEAP_SIZE: u32 = 330;
+pub const BIN_VHEAP_SIZE: u32 = 331;
+pub const BIN_VHEAP_BLOCK_SIZE: u32 = 332;
+pub const BIN_OLD_VHEAP_SIZE: u32 = 333;
+pub const BIN_OLD_VHEAP_BLOCK_SIZE: u32 = 334;
+pub const NANOSECOND: u32 = 335;
+
 pub fn from_str(val: &str) -> u32 {
     ATOMS.from_str(val)
 }
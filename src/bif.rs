@@ -9,10 +9,14 @@ use crate::persistent_term;
 use crate::port;
 use crate::process::{self, RcProcess};
 use crate::regex;
+use crate::signal_queue;
+use crate::trace;
 use crate::value::{self, Cons, Term, TryFrom, TryInto, Tuple, Variant};
 use crate::vm;
 use hashbrown::HashMap;
 use once_cell::sync::Lazy;
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
 use std::pin::Pin;
 
 pub mod arith;
@@ -44,13 +48,26 @@ macro_rules! trap {
 
 // maybe use https://github.com/sfackler/rust-phf
 
+/// Maps the optional `; dirty_cpu` or `; dirty_io` suffix of a table entry to a Dirty.
+macro_rules! dirty {
+    () => {
+        None
+    };
+    (dirty_cpu) => {
+        Some(Dirty::Cpu)
+    };
+    (dirty_io) => {
+        Some(Dirty::Io)
+    };
+}
+
 macro_rules! bif_map {
-    ($($module:expr => {$($fun:expr, $arity:expr => $rust_fn:path,)*},)*) => {
+    ($($module:expr => {$($fun:expr, $arity:expr => $rust_fn:path $(; $dirty:ident)?,)*},)*) => {
         {
             let mut table: BifTable = HashMap::new();
             $(
                 let module = atom::from_str($module);
-                $(table.insert(module::MFA(module, atom::from_str($fun), $arity), $rust_fn);)*
+                $(table.insert(module::MFA(module, atom::from_str($fun), $arity), ($rust_fn as Fn, dirty!($($dirty)?)));)*
             )*
             table
         }
@@ -59,7 +76,17 @@ macro_rules! bif_map {
 
 pub type Result = std::result::Result<Term, Exception>;
 pub type Fn = fn(&vm::Machine, &RcProcess, &[Term]) -> Result;
-type BifTable = HashMap<module::MFA, Fn>;
+type BifTable = HashMap<module::MFA, (Fn, Option<Dirty>)>;
+
+/// Dirty BIFs can block for a long time, so instead of running on the process schedulers they
+/// get moved to one of the dirty scheduler pools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dirty {
+    /// CPU bound work.
+    Cpu,
+    /// Blocking I/O.
+    Io,
+}
 
 pub static BIFS: Lazy<BifTable> = sync_lazy! {
     bif_map![
@@ -78,15 +105,22 @@ pub static BIFS: Lazy<BifTable> = sync_lazy! {
             "-", 2 => arith::sub_2,
             "*", 2 => arith::mult_2,
             "div", 2 => arith::intdiv_2,
-            "rem", 2 => arith::mod_2,// TODO: confirm if this is ok
+            "rem", 2 => arith::rem_2,
             "spawn", 3 => bif_erlang_spawn_3,
             "spawn_link", 3 => bif_erlang_spawn_link_3,
             "spawn_opt", 1 => bif_erlang_spawn_opt_1,
+            "spawn_request", 5 => bif_erlang_spawn_request_5,
+            "spawn_request_abandon", 1 => bif_erlang_spawn_request_abandon_1,
+            "hibernate", 3 => bif_erlang_hibernate_3,
             "link", 1 => bif_erlang_link_1,
             "unlink", 1 => bif_erlang_unlink_1,
             "monitor", 2 => bif_erlang_monitor_2,
+            "monitor", 3 => bif_erlang_monitor_3,
             "demonitor", 1 => bif_erlang_demonitor_1,
             "demonitor", 2 => bif_erlang_demonitor_2,
+            "alias", 0 => bif_erlang_alias_0,
+            "alias", 1 => bif_erlang_alias_1,
+            "unalias", 1 => bif_erlang_unalias_1,
             "self", 0 => bif_erlang_self_0,
             "send", 2 => bif_erlang_send_2,
             "send", 3 => bif_erlang_send_2,// TODO: send/3 acts as send/2 until distributed nodes work
@@ -134,6 +168,9 @@ pub static BIFS: Lazy<BifTable> = sync_lazy! {
             "loaded", 0 => bif_erlang_loaded_0,
             "module_loaded", 1 => bif_erlang_module_loaded_1,
             "process_flag", 2 => bif_erlang_process_flag_2,
+            "process_info", 1 => info::process_info_1,
+            "processes", 0 => info::processes_0,
+            "registered", 0 => info::registered_0,
             "process_info", 2 => info::process_info_2,
             "group_leader", 0 => info::group_leader_0,
             "make_tuple", 2 => erlang::make_tuple_2,
@@ -163,6 +200,10 @@ pub static BIFS: Lazy<BifTable> = sync_lazy! {
             "fun_info", 2 => info::fun_info_2,
             "system_info", 1 => info::system_info_1,
             "system_flag", 2 => info::system_flag_2,
+            "statistics", 1 => info::statistics_1,
+            "memory", 0 => info::memory_0,
+            "memory", 1 => info::memory_1,
+            "bump_reductions", 1 => info::bump_reductions_1,
             "get_module_info", 1 => load::get_module_info_1,
             "get_module_info", 2 => load::get_module_info_2,
             "make_fun", 3 => erlang::make_fun_3,
@@ -202,8 +243,25 @@ pub static BIFS: Lazy<BifTable> = sync_lazy! {
             "has_prepared_code_on_load", 1 => load::has_prepared_code_on_load_1,
             "finish_loading", 1 => load::finish_loading_1,
             "pre_loaded", 0 => load::pre_loaded_0,
+            "check_old_code", 1 => load::check_old_code_1,
+            "check_process_code", 2 => load::check_process_code_2,
+            "check_process_code", 3 => load::check_process_code_3,
+            "delete_module", 1 => load::delete_module_1,
+            "purge_module", 1 => load::purge_module_1,
 
             "send_after", 3 => timer::send_after_3,
+            "send_after", 4 => timer::send_after_4,
+            "start_timer", 3 => timer::start_timer_3,
+            "start_timer", 4 => timer::start_timer_4,
+            "cancel_timer", 1 => timer::cancel_timer_1,
+            "cancel_timer", 2 => timer::cancel_timer_2,
+            "read_timer", 1 => timer::read_timer_1,
+            "read_timer", 2 => timer::read_timer_2,
+
+            // tracing
+            "trace", 3 => trace::bif::trace_3,
+            "trace_pattern", 3 => trace::bif::trace_pattern_3,
+            "trace_info", 2 => trace::bif::trace_info_2,
 
             // pdict
             "get", 0 => pdict::get_0,
@@ -305,12 +363,17 @@ pub static BIFS: Lazy<BifTable> = sync_lazy! {
         "erts_internal" => {
             "group_leader", 2 => info::group_leader_2,
             "garbage_collect", 1 => garbage_collect_1,
-            "scheduler_wall_time", 1 => scheduler_wall_time_1,
+            "request_system_task", 3 => request_system_task_3,
+            "scheduler_wall_time", 1 => info::scheduler_wall_time_1,
             "open_port", 2 => open_port_2,
             "port_control", 3 => port_control_3,
             "spawn_system_process", 3 => bif_erlang_spawn_3, // TODO: aliased to normal spawn for now
             "map_next", 3 => erts_internal_map_next_3,
         },
+        "erts_code_purger" => {
+            "purge", 1 => load::purger_purge_1,
+            "soft_purge", 1 => load::purger_soft_purge_1,
+        },
         "unicode" => {
             "characters_to_binary", 2 => erlang::unicode_characters_to_binary_2,
             "characters_to_list", 2 => erlang::unicode_characters_to_list_2,
@@ -349,16 +412,16 @@ pub static BIFS: Lazy<BifTable> = sync_lazy! {
     ]
 };
 
-type NifTable = HashMap<u32, Vec<(u32, u32, Fn)>>;
+type NifTable = HashMap<u32, Vec<(u32, u32, Fn, Option<Dirty>)>>;
 
 macro_rules! nif_map {
-    ($($module:expr => {$($fun:expr, $arity:expr => $rust_fn:path,)*},)*) => {
+    ($($module:expr => {$($fun:expr, $arity:expr => $rust_fn:path $(; $dirty:ident)?,)*},)*) => {
         {
             let mut table: NifTable = HashMap::new();
             $(
                 let module = atom::from_str($module);
                 table.insert(module, vec![
-                    $((atom::from_str($fun), $arity, $rust_fn),)*
+                    $((atom::from_str($fun), $arity, $rust_fn as Fn, dirty!($($dirty)?)),)*
                 ]);
             )*
             table
@@ -369,43 +432,43 @@ macro_rules! nif_map {
 pub static NIFS: Lazy<NifTable> = sync_lazy! {
     nif_map![
         "beam_lib" => {
-            "compress", 1 => prim_file::compress_1,
-            "uncompress", 1 => prim_file::uncompress_1,
+            "compress", 1 => prim_file::compress_1; dirty_cpu,
+            "uncompress", 1 => prim_file::uncompress_1; dirty_cpu,
         },
         "prim_file" => {
-            "open_nif", 2 => prim_file::open_nif_2,
-            "close_nif", 1 => prim_file::close_nif_1,
-            "read_nif", 2 => prim_file::read_nif_2,
-            "write_nif", 2 => prim_file::write_nif_2,
-            "pread_nif", 3 => prim_file::pread_nif_3,
-            "pwrite_nif", 3 => prim_file::pwrite_nif_3,
-            "seek_nif", 3 => prim_file::seek_nif_3,
-            "sync_nif", 2 => prim_file::sync_nif_2,
-            "truncate_nif", 1 => prim_file::truncate_nif_1,
-            "allocate_nif", 3 => prim_file::allocate_nif_3,
-            "advise_nif", 4 => prim_file::advise_nif_4,
+            "open_nif", 2 => prim_file::open_nif_2; dirty_io,
+            "close_nif", 1 => prim_file::close_nif_1; dirty_io,
+            "read_nif", 2 => prim_file::read_nif_2; dirty_io,
+            "write_nif", 2 => prim_file::write_nif_2; dirty_io,
+            "pread_nif", 3 => prim_file::pread_nif_3; dirty_io,
+            "pwrite_nif", 3 => prim_file::pwrite_nif_3; dirty_io,
+            "seek_nif", 3 => prim_file::seek_nif_3; dirty_io,
+            "sync_nif", 2 => prim_file::sync_nif_2; dirty_io,
+            "truncate_nif", 1 => prim_file::truncate_nif_1; dirty_io,
+            "allocate_nif", 3 => prim_file::allocate_nif_3; dirty_io,
+            "advise_nif", 4 => prim_file::advise_nif_4; dirty_io,
 
             // filesystem ops
-            "make_hard_link_nif", 2 => prim_file::make_hard_link_nif_2,
-            "make_soft_link_nif", 2 => prim_file::make_soft_link_nif_2,
-            "rename_nif", 2 => prim_file::rename_nif_2,
-            "read_info_nif", 2 => prim_file::read_info_nif_2,
-            "set_permissions_nif", 2 => prim_file::set_permissions_nif_2,
-            "set_owner_nif", 3 => prim_file::set_owner_nif_3,
-            "set_time_nif", 4 => prim_file::set_time_nif_4,
-            "read_link_nif", 1 => prim_file::read_link_nif_1,
-            "list_dir_nif", 1 => prim_file::list_dir_nif_1,
-            "make_dir_nif", 1 => prim_file::make_dir_nif_1,
-            "del_file_nif", 1 => prim_file::del_file_nif_1,
-            "del_dir_nif", 1 => prim_file::del_dir_nif_1,
-            "get_device_cwd_nif", 1 => prim_file::get_device_cwd_nif_1,
-            "get_cwd_nif", 0 => prim_file::get_cwd_nif_0,
-            "set_cwd_nif", 1 => prim_file::set_cwd_nif_1,
+            "make_hard_link_nif", 2 => prim_file::make_hard_link_nif_2; dirty_io,
+            "make_soft_link_nif", 2 => prim_file::make_soft_link_nif_2; dirty_io,
+            "rename_nif", 2 => prim_file::rename_nif_2; dirty_io,
+            "read_info_nif", 2 => prim_file::read_info_nif_2; dirty_io,
+            "set_permissions_nif", 2 => prim_file::set_permissions_nif_2; dirty_io,
+            "set_owner_nif", 3 => prim_file::set_owner_nif_3; dirty_io,
+            "set_time_nif", 4 => prim_file::set_time_nif_4; dirty_io,
+            "read_link_nif", 1 => prim_file::read_link_nif_1; dirty_io,
+            "list_dir_nif", 1 => prim_file::list_dir_nif_1; dirty_io,
+            "make_dir_nif", 1 => prim_file::make_dir_nif_1; dirty_io,
+            "del_file_nif", 1 => prim_file::del_file_nif_1; dirty_io,
+            "del_dir_nif", 1 => prim_file::del_dir_nif_1; dirty_io,
+            "get_device_cwd_nif", 1 => prim_file::get_device_cwd_nif_1; dirty_io,
+            "get_cwd_nif", 0 => prim_file::get_cwd_nif_0; dirty_io,
+            "set_cwd_nif", 1 => prim_file::set_cwd_nif_1; dirty_io,
 
             // These operations are equivalent to chained calls of other operations,
             // but have been moved down to avoid excessive rescheduling.
-            "ipread_s32bu_p32bu_nif", 3 => prim_file::ipread_s32bu_p32bu_nif_3,
-            "read_file_nif", 1 => prim_file::read_file_nif_1,
+            "ipread_s32bu_p32bu_nif", 3 => prim_file::ipread_s32bu_p32bu_nif_3; dirty_io,
+            "read_file_nif", 1 => prim_file::read_file_nif_1; dirty_io,
 
             // internal nifs
             "get_handle_nif", 1 => prim_file::get_handle_nif_1,
@@ -434,11 +497,16 @@ pub fn is_bif(mfa: &module::MFA) -> bool {
     BIFS.contains_key(mfa)
 }
 
-#[inline]
-pub fn apply(vm: &vm::Machine, process: &RcProcess, mfa: &module::MFA, args: &[Term]) -> Result {
+pub async fn apply(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    mfa: &module::MFA,
+    args: &[Term],
+) -> Result {
     // println!("bif_apply {}", mfa);
     match BIFS.get(mfa) {
-        Some(fun) => fun(vm, process, args),
+        Some((fun, Some(dirty))) => vm.call_dirty(*dirty, process, *fun, args.to_vec()).await,
+        Some((fun, None)) => fun(vm, process, args),
         None => unimplemented!("BIF {} not implemented", mfa),
     }
 }
@@ -462,10 +530,15 @@ fn bif_erlang_spawn_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> R
     };
     let arglist = args[2];
 
-    let registry = vm.modules.lock();
-    let module = registry.lookup(module).unwrap();
     // TODO: avoid the clone here since we copy later
-    process::spawn(vm, process, module, func, arglist, process::SpawnFlag::NONE)
+    process::spawn(
+        vm,
+        process,
+        module,
+        func,
+        arglist,
+        process::SpawnOpts::new(process::SpawnFlag::NONE),
+    )
 }
 
 fn bif_erlang_spawn_link_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
@@ -486,14 +559,19 @@ fn bif_erlang_spawn_link_3(vm: &vm::Machine, process: &RcProcess, args: &[Term])
     };
     let arglist = args[2];
 
-    let registry = vm.modules.lock();
-    let module = registry.lookup(module).unwrap();
     // TODO: avoid the clone here since we copy later
-    process::spawn(vm, process, module, func, arglist, process::SpawnFlag::LINK)
+    process::spawn(
+        vm,
+        process,
+        module,
+        func,
+        arglist,
+        process::SpawnOpts::new(process::SpawnFlag::LINK),
+    )
 }
 
 fn bif_erlang_spawn_opt_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
-    use process::SpawnFlag;
+    use process::{SpawnFlag, SpawnOpts};
 
     // arg 0 is a 4 value tuple
     let tup: &Tuple = match Tuple::try_from(&args[0]) {
@@ -519,32 +597,208 @@ fn bif_erlang_spawn_opt_1(vm: &vm::Machine, process: &RcProcess, args: &[Term])
 
     let opts = Cons::try_from(&tup[3])?;
 
-    let flag = opts.iter().fold(SpawnFlag::NONE, |acc, val| {
-        match val.into_variant() {
-            Variant::Atom(atom::LINK) => acc | SpawnFlag::LINK,
-            Variant::Atom(atom::MONITOR) => acc | SpawnFlag::MONITOR,
-            _ => {
-                if let Ok(tup) = Tuple::try_from(&val) {
-                    if tup.len() != 2 {
-                        unimplemented!("error!");
-                        // return Err(Exception::new(Reason::EXC_BADARG));
+    let mut spawn_opts = SpawnOpts::new(SpawnFlag::NONE);
+
+    for val in opts.iter() {
+        spawn_opt(&mut spawn_opts, *val)?;
+    }
+
+    // TODO: avoid the clone here since we copy later
+    process::spawn(vm, process, module, func, arglist, spawn_opts)
+}
+
+/// Parses a single spawn_opt option into spawn_opts.
+fn spawn_opt(spawn_opts: &mut process::SpawnOpts, val: Term) -> std::result::Result<(), Exception> {
+    use process::{SpawnFlag, StateFlag};
+
+    match val.into_variant() {
+        Variant::Atom(atom::LINK) => spawn_opts.flags |= SpawnFlag::LINK,
+        Variant::Atom(atom::MONITOR) => spawn_opts.flags |= SpawnFlag::MONITOR,
+        _ => {
+            let tup = match Tuple::try_from(&val) {
+                Ok(tup) if tup.len() == 2 => tup,
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            };
+            match tup[0].into_variant() {
+                Variant::Atom(atom::PRIORITY) => {
+                    spawn_opts.priority = match StateFlag::from_priority(tup[1]) {
+                        Some(priority) => priority,
+                        None => return Err(Exception::new(Reason::EXC_BADARG)),
+                    }
+                }
+                Variant::Atom(atom::MIN_HEAP_SIZE) => {
+                    spawn_opts.gc.min_heap_size = non_neg_int_opt(tup[1])?
+                }
+                Variant::Atom(atom::MIN_BIN_VHEAP_SIZE) => {
+                    spawn_opts.gc.min_bin_vheap_size = non_neg_int_opt(tup[1])?
+                }
+                Variant::Atom(atom::FULLSWEEP_AFTER) => {
+                    spawn_opts.gc.fullsweep_after = non_neg_int_opt(tup[1])?
+                }
+                Variant::Atom(atom::MAX_HEAP_SIZE) => {
+                    spawn_opts.gc.max_heap_size = max_heap_size_opt(tup[1])?
+                }
+                Variant::Atom(atom::MESSAGE_QUEUE_DATA) => match tup[1].into_variant() {
+                    Variant::Atom(atom::OFF_HEAP) => {
+                        spawn_opts.flags.remove(SpawnFlag::ON_HEAP_MSGQ);
+                        spawn_opts.flags.insert(SpawnFlag::OFF_HEAP_MSGQ);
+                    }
+                    Variant::Atom(atom::ON_HEAP) => {
+                        spawn_opts.flags.remove(SpawnFlag::OFF_HEAP_MSGQ);
+                        spawn_opts.flags.insert(SpawnFlag::ON_HEAP_MSGQ);
                     }
-                    match tup[0].into_variant() {
-                        Variant::Atom(atom::MESSAGE_QUEUE_DATA) => acc, // TODO: implement
-                        opt => unimplemented!("Unimplemented spawn_opt for {}", opt),
+                    _ => return Err(Exception::new(Reason::EXC_BADARG)),
+                },
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// spawn_request(Node, Module, Function, Args, Options) spawns a process asynchronously. The
+/// result arrives as a {spawn_reply, ReqId, ok, Pid} or {spawn_reply, ReqId, error, Reason}
+/// message, ReqId is also the monitor reference if the monitor option is given.
+fn bif_erlang_spawn_request_5(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    use process::{SpawnFlag, SpawnOpts, SpawnReplyMode};
+
+    let (module, func) = match (args[1].into_variant(), args[2].into_variant()) {
+        (Variant::Atom(module), Variant::Atom(func)) => (module, func),
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+    if !args[0].is_atom() || !args[3].is_list() {
+        return Err(Exception::new(Reason::EXC_BADARG));
+    }
+
+    let mut spawn_opts = SpawnOpts::new(SpawnFlag::NONE);
+    let mut tag = atom!(SPAWN_REPLY);
+    let mut reply = SpawnReplyMode::Yes;
+
+    if !args[4].is_nil() {
+        for val in Cons::try_from(&args[4])?.iter() {
+            let tup = Tuple::try_from(val).ok().filter(|tup| tup.len == 2);
+            match tup.map(|tup| (tup[0].into_variant(), tup[1])) {
+                Some((Variant::Atom(atom::REPLY_TAG), value)) => tag = value,
+                Some((Variant::Atom(atom::REPLY), value)) => {
+                    reply = match value.into_variant() {
+                        Variant::Atom(atom::YES) => SpawnReplyMode::Yes,
+                        Variant::Atom(atom::NO) => SpawnReplyMode::No,
+                        Variant::Atom(atom::ERROR_ONLY) => SpawnReplyMode::ErrorOnly,
+                        Variant::Atom(atom::SUCCESS_ONLY) => SpawnReplyMode::SuccessOnly,
+                        _ => return Err(Exception::new(Reason::EXC_BADARG)),
                     }
-                } else {
-                    unimplemented!("Unimplemented spawn_opt for badarg");
-                    // return Err(Exception::new(Reason::EXC_BADARG));
                 }
+                _ => spawn_opt(&mut spawn_opts, *val)?,
             }
         }
-    });
+    }
 
-    let registry = vm.modules.lock();
-    let module = registry.lookup(module).unwrap();
-    // TODO: avoid the clone here since we copy later
-    process::spawn(vm, process, module, func, arglist, flag)
+    let reference = vm.next_ref();
+    let result = if args[0] != atom!(NO_NODE_NO_HOST) {
+        // we're not distributed, other nodes are never connected
+        Err(atom::NOCONNECTION)
+    } else {
+        spawn_opts.monitor_ref = Some(reference);
+        // running out of pids is the only way spawning fails
+        process::spawn_process(vm, process, module, func, args[3], spawn_opts)
+            .map(|(pid, _)| pid)
+            .map_err(|_| atom::SYSTEM_LIMIT)
+    };
+
+    let link = result.is_ok() && spawn_opts.flags.contains(SpawnFlag::LINK);
+    let request = process::SpawnRequest::new(tag, reply, result.ok(), link);
+    let local_data = process.local_data_mut();
+    local_data.spawn_requests.insert(reference, request);
+    // Queued ahead of any signal the new process might have sent us already, so that the reply
+    // arrives before a DOWN or EXIT.
+    let signal = process::Signal::SpawnReply { reference, result };
+    local_data.signal_queue.send_internal(signal);
+
+    Ok(Term::reference(&process.context_mut().heap, reference))
+}
+
+/// spawn_request_abandon(ReqId) drops an outstanding spawn request: no reply gets delivered, the
+/// monitor is removed and a linked process gets unlinked and sent an abandoned exit signal.
+fn bif_erlang_spawn_request_abandon_1(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    args: &[Term],
+) -> Result {
+    if args[0].get_boxed_header() != Ok(value::BOXED_REF) {
+        return Err(Exception::new(Reason::EXC_BADARG));
+    }
+    let reference = args[0].get_boxed_value::<process::Ref>().unwrap();
+    let request = match process.local_data_mut().spawn_requests.remove(reference) {
+        Some(request) => request,
+        None => return Ok(atom!(FALSE)),
+    };
+
+    if let (Some(pid), true) = (request.pid, request.link) {
+        process.local_data_mut().links.remove(&pid);
+        process::send_signal(vm, pid, process::Signal::Unlink { from: process.pid });
+        let reason = Exception::with_value(Reason::EXC_EXIT, atom!(ABANDONED));
+        let signal = process::Signal::exit(process.pid, &reason, process::ExitKind::Exit);
+        process::send_signal(vm, pid, signal);
+    }
+    demonitor(vm, process, args[0])?;
+    Ok(atom!(TRUE))
+}
+
+/// hibernate(Module, Function, Args) throws away the call stack, shrinks the heap down to what
+/// Args, the mailbox and the dictionary need, and parks the process until a message arrives. It
+/// then continues with apply(Module, Function, Args), the call never returns.
+fn bif_erlang_hibernate_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    use crate::exports_table::Export;
+    use process::Flag;
+    let (module, func) = match (args[0].into_variant(), args[1].into_variant()) {
+        (Variant::Atom(module), Variant::Atom(func)) => (module, func),
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+    let mut argv = Vec::new();
+    if !args[2].is_nil() {
+        argv.extend(Cons::try_from(&args[2])?.iter().copied());
+    }
+    if argv.len() >= process::MAX_REG {
+        return Err(Exception::new(Reason::EXC_BADARG));
+    }
+    let mfa = module::MFA(module, func, argv.len() as u32);
+    let ptr = match vm.exports.read().lookup(&mfa) {
+        Some(Export::Fun(ptr)) => ptr,
+        _ => return Err(Exception::new(Reason::EXC_UNDEF)),
+    };
+
+    let context = process.context_mut();
+    context.stack.clear();
+    context.stack.shrink_to_fit();
+    context.cp = None;
+    context.catches = 0;
+    context.exc = None;
+    context.return_trace.clear();
+    context.x[..argv.len()].copy_from_slice(&argv);
+    // with the stack gone, a major collection leaves only the arguments and the process state
+    process.garbage_collect(vm, argv.len(), true)?;
+
+    // a message that's already queued wakes us right away
+    if !process.local_data().mailbox.has_messages() {
+        process.local_data_mut().flags.insert(Flag::HIBERNATE);
+    }
+    context.ip = ptr;
+    Err(Exception::new(Reason::TRAP))
+}
+
+/// Parses the value of a garbage collection option, a non-negative integer.
+fn non_neg_int_opt(value: Term) -> std::result::Result<usize, Exception> {
+    match value.into_number() {
+        Ok(value::Num::Integer(i)) if i >= 0 => Ok(i as usize),
+        Ok(value::Num::Bignum(i)) => i
+            .to_usize()
+            .ok_or_else(|| Exception::new(Reason::EXC_BADARG)),
+        _ => Err(Exception::new(Reason::EXC_BADARG)),
+    }
+}
+
+fn max_heap_size_opt(value: Term) -> std::result::Result<process::MaxHeapSize, Exception> {
+    process::MaxHeapSize::from_term(value).ok_or_else(|| Exception::new(Reason::EXC_BADARG))
 }
 
 fn bif_erlang_link_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
@@ -560,7 +814,7 @@ fn bif_erlang_link_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Re
                 // scope the process_table lock
                 if !vm.process_table.lock().contains_key(pid) {
                     // if pid doesn't exist fail with noproc
-                    if process
+                    if !process
                         .local_data()
                         .flags
                         .contains(process::Flag::TRAP_EXIT)
@@ -569,13 +823,11 @@ fn bif_erlang_link_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Re
                     } else {
                         // if trapping exits, fail with exit signal that has reason noproc instead
                         let heap = &process.context_mut().heap;
-                        let from = Term::pid(process.pid);
+                        let from = Term::pid(pid);
 
-                        process::send_message(
-                            vm,
+                        process.send_message(
                             process.pid,
-                            from,
-                            tup3!(heap, atom!(EXIT), from, atom!(NOPROC)),
+                            tup3!(heap, atom!(EXIT_U), from, atom!(NOPROC)),
                         );
                         return Ok(atom!(TRUE));
                     }
@@ -612,6 +864,32 @@ fn bif_erlang_unlink_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
     }
 }
 
+/// Delivers a DOWN message right away, for monitors on things that don't exist.
+fn monitor_down_now(
+    process: &RcProcess,
+    reference: Term,
+    kind: Term,
+    object: Term,
+    reason: Term,
+) -> Result {
+    let heap = &process.context_mut().heap;
+    let msg = tup!(heap, atom!(DOWN_U), reference, kind, object, reason);
+    process.send_message(process.pid, msg);
+    Ok(reference)
+}
+
+/// Parses RegName or {RegName, Node}, returning the name and the node.
+fn registered_name(term: Term) -> std::result::Result<(u32, Term), Exception> {
+    if let Variant::Atom(name) = term.into_variant() {
+        return Ok((name, atom!(NO_NODE_NO_HOST)));
+    }
+    let tup = Tuple::try_from(&term)?;
+    if tup.len != 2 || !tup[0].is_atom() || !tup[1].is_atom() {
+        return Err(Exception::new(Reason::EXC_BADARG));
+    }
+    Ok((tup[0].to_u32(), tup[1]))
+}
+
 fn bif_erlang_monitor_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
     // arg[0] = pid/port
     let heap = &process.context_mut().heap;
@@ -621,29 +899,42 @@ fn bif_erlang_monitor_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
 
     match args[0].into_variant() {
         Variant::Atom(atom::PROCESS) => {
-            let pid = match args[1].into_variant() {
+            let (pid, monitor) = match args[1].into_variant() {
                 Variant::Pid(pid) => {
                     if pid == process.pid {
                         return Ok(ref_term);
                     }
-                    pid
+                    (pid, process::Monitor::Process(pid))
                 }
-                Variant::Atom(name) => {
-                    if let Some(process) = vm.process_registry.lock().whereis(name) {
-                        process.pid
-                    } else {
-                        println!("registered name {} not found!", args[1]);
-                        return Err(Exception::new(Reason::EXC_BADARG));
+                Variant::Atom(_) | Variant::Pointer(_) => {
+                    let (name, node) = registered_name(args[1])?;
+                    let object = tup2!(heap, Term::atom(name), node);
+                    if node != atom!(NO_NODE_NO_HOST) {
+                        // we're not distributed, other nodes are never connected
+                        let reason = atom!(NOCONNECTION);
+                        return monitor_down_now(process, ref_term, atom!(PROCESS), object, reason);
+                    }
+                    let pid = vm.process_registry.lock().whereis(name).map(|p| p.pid);
+                    match pid {
+                        Some(pid) if pid == process.pid => return Ok(ref_term),
+                        Some(pid) => (pid, process::Monitor::Name(pid, name)),
+                        None => {
+                            let reason = atom!(NOPROC);
+                            return monitor_down_now(
+                                process,
+                                ref_term,
+                                atom!(PROCESS),
+                                object,
+                                reason,
+                            );
+                        }
                     }
                 }
-                // TODO: {atom name, node}
-                Variant::Pointer(_) => unimplemented!("monitor for {}", args[1]),
-                Variant::Port(_) => unimplemented!("monitor for {}", args[1]),
                 _ => return Err(Exception::new(Reason::EXC_BADARG)),
             };
 
             // add the pid to our monitor tree
-            process.local_data_mut().monitors.insert(reference, pid);
+            process.local_data_mut().monitors.insert(reference, monitor);
 
             // send MONITOR signal to the other process return true
             let sent = process::send_signal(
@@ -659,23 +950,84 @@ fn bif_erlang_monitor_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
                 process::send_signal(
                     vm,
                     process.pid,
-                    process::Signal::MonitorDown {
-                        from: process.pid,
+                    process::Signal::monitor_down(
+                        process.pid,
                         // TODO: could be just reason: term
-                        reason: Exception::with_value(Reason::EXC_ERROR, atom!(NOPROC)),
+                        &Exception::with_value(Reason::EXC_ERROR, atom!(NOPROC)),
                         reference,
-                    },
+                    ),
                 );
             }
 
             Ok(ref_term)
         }
-        Variant::Atom(atom::PORT) => unimplemented!(),
-        Variant::Atom(atom::TIME_OFFSET) => unimplemented!(),
+        Variant::Atom(atom::PORT) => {
+            let id = match args[1].into_variant() {
+                Variant::Port(id) => id,
+                Variant::Atom(_) => {
+                    // ports can't be registered yet, so a name never resolves
+                    let object = tup2!(heap, args[1], atom!(NO_NODE_NO_HOST));
+                    let reason = atom!(NOPROC);
+                    return monitor_down_now(process, ref_term, atom!(PORT), object, reason);
+                }
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            };
+
+            match vm.port_table.read().lookup(id) {
+                Some(mut port) => port.monitor(process.pid, reference),
+                None => {
+                    let reason = atom!(NOPROC);
+                    return monitor_down_now(process, ref_term, atom!(PORT), args[1], reason);
+                }
+            }
+            let monitor = process::Monitor::Port(id);
+            process.local_data_mut().monitors.insert(reference, monitor);
+            Ok(ref_term)
+        }
+        Variant::Atom(atom::TIME_OFFSET) => {
+            if args[1] != atom!(CLOCK_SERVICE) {
+                return Err(Exception::new(Reason::EXC_BADARG));
+            }
+            let monitor = process::Monitor::TimeOffset;
+            process.local_data_mut().monitors.insert(reference, monitor);
+            Ok(ref_term)
+        }
         _ => Err(Exception::new(Reason::EXC_BADARG)),
     }
 }
 
+/// monitor(Type, Item, Opts), the only option is {alias, Mode}: the monitor reference doubles as
+/// an alias, see alias/1.
+fn bif_erlang_monitor_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    let mut alias = None;
+    if !args[2].is_nil() {
+        for opt in Cons::try_from(&args[2])?.iter() {
+            let tup = Tuple::try_from(opt)?;
+            if tup.len != 2 || tup[0] != atom!(ALIAS) {
+                return Err(Exception::new(Reason::EXC_BADARG));
+            }
+            alias = match tup[1].into_variant() {
+                Variant::Atom(atom::EXPLICIT_UNALIAS) => Some(process::Alias::Explicit),
+                Variant::Atom(atom::DEMONITOR) => Some(process::Alias::Demonitor),
+                Variant::Atom(atom::REPLY_DEMONITOR) => Some(process::Alias::ReplyDemonitor),
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            };
+        }
+    }
+
+    let ref_term = bif_erlang_monitor_2(vm, process, &args[..2])?;
+
+    if let Some(kind) = alias {
+        let reference = *ref_term.get_boxed_value::<process::Ref>().unwrap();
+        // a monitor that already triggered takes its alias with it
+        let active = process.local_data().monitors.contains_key(&reference);
+        if active || kind == process::Alias::Explicit {
+            process.alias(vm, reference, kind);
+        }
+    }
+    Ok(ref_term)
+}
+
 fn demonitor(
     vm: &vm::Machine,
     process: &RcProcess,
@@ -684,17 +1036,11 @@ fn demonitor(
     // TODO: inefficient, we do get_boxed_ twice
     if reference.get_boxed_header() == Ok(value::BOXED_REF) {
         let reference = reference.get_boxed_value().unwrap();
-        // remove the pid from our monitor tree
-        if let Some(pid) = process.local_data_mut().monitors.remove(&reference) {
-            // send DEMONITOR signal to the other process return true
-            process::send_signal(
-                vm,
-                pid,
-                process::Signal::Demonitor {
-                    from: process.pid,
-                    reference: *reference,
-                },
-            );
+        // remove the monitor from our monitor tree
+        if let Some(monitor) = process.local_data_mut().monitors.remove(&reference) {
+            // tell the other side to stop monitoring
+            monitor.cancel(vm, process.pid, *reference);
+            process.monitor_removed(vm, *reference);
             return Ok(true);
         }
         return Ok(false);
@@ -724,32 +1070,55 @@ fn bif_erlang_demonitor_2(vm: &vm::Machine, process: &RcProcess, args: &[Term])
         }
     }
 
-    let mut res = atom!(TRUE);
+    let removed = demonitor(vm, process, args[0])?;
+
+    if flush {
+        // With the monitor gone, a DOWN signal that's still queued gets dropped when it's
+        // processed. Only a message that was already delivered needs to go.
+        let reference = args[0];
+        process
+            .local_data_mut()
+            .mailbox
+            .remove_first(|message| match Tuple::try_from(&message) {
+                Ok(tup) => tup.len == 5 && tup[0] == atom!(DOWN_U) && tup[1] == reference,
+                Err(_) => false,
+            });
+    }
 
-    if demonitor(vm, process, args[0])? {
-        // ok
-        // TODO: if multi and flush, also trap
-    } else {
-        if info {
-            res = atom!(FALSE);
-        }
+    if info {
+        return Ok(Term::boolean(removed));
+    }
+    Ok(atom!(TRUE))
+}
 
-        if flush {
-            use crate::exports_table::Export;
-            // TODO: precompute this lookup
-            let multi = atom!(FALSE); // TODO: get this to work later
-            if let Some(Export::Fun(ptr)) = vm.exports.read().lookup(&module::MFA(
-                atom::ERTS_INTERNAL,
-                atom::FLUSH_MONITOR_MESSAGES,
-                3,
-            )) {
-                trap!(process.context_mut(), ptr, args[0], multi, res);
-            } else {
-                unreachable!()
-            }
+/// alias(Opts) creates an alias for the calling process. Opts are explicit_unalias (the default),
+/// or reply: the alias gets deactivated after the first message that arrives through it.
+fn bif_erlang_alias_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    let mut kind = process::Alias::Explicit;
+    if !args[0].is_nil() {
+        for opt in Cons::try_from(&args[0])?.iter() {
+            kind = match opt.into_variant() {
+                Variant::Atom(atom::EXPLICIT_UNALIAS) => process::Alias::Explicit,
+                Variant::Atom(atom::REPLY) => process::Alias::Reply,
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            };
         }
     }
-    Ok(res)
+    let reference = vm.next_ref();
+    process.alias(vm, reference, kind);
+    Ok(Term::reference(&process.context_mut().heap, reference))
+}
+
+fn bif_erlang_alias_0(vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> Result {
+    bif_erlang_alias_1(vm, process, &[Term::nil()])
+}
+
+fn bif_erlang_unalias_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    if args[0].get_boxed_header() == Ok(value::BOXED_REF) {
+        let reference = args[0].get_boxed_value::<process::Ref>().unwrap();
+        return Ok(Term::boolean(process.unalias(vm, *reference)));
+    }
+    Err(Exception::new(Reason::EXC_BADARG))
 }
 
 fn bif_erlang_self_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> Result {
@@ -761,6 +1130,10 @@ fn bif_erlang_send_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Re
     let pid = args[0];
     let msg = args[1];
 
+    if process.local_data().trace.contains(trace::Flag::SEND) {
+        trace::send(vm, process, pid, msg);
+    }
+
     match pid.into_variant() {
         Variant::Port(id) => port::send_message(vm, process.pid, id, msg),
         _ => process::send_message(vm, process.pid, pid, msg),
@@ -895,16 +1268,17 @@ fn bif_erlang_exit_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Re
 
     match args[0].into_variant() {
         Variant::Pid(pid) => {
-            process::send_signal(
-                vm,
-                pid,
-                // TODO: deep copy reason
-                process::Signal::Exit {
-                    from: process.pid,
-                    reason: Exception::with_value(Reason::EXC_EXIT, args[1]),
-                    kind: process::ExitKind::Exit,
-                },
+            let signal = process::Signal::exit(
+                process.pid,
+                &Exception::with_value(Reason::EXC_EXIT, args[1]),
+                process::ExitKind::Exit,
             );
+            if pid == process.pid {
+                // terminate ourselves before exit/2 returns
+                process.handle_exit_signal(signal)?;
+            } else {
+                process::send_signal(vm, pid, signal);
+            }
             Ok(atom!(TRUE))
         }
         // TODO: port
@@ -1039,7 +1413,7 @@ pub fn bif_erlang_apply_3(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term
 }
 
 /// this sets some process info- trapping exits or the error handler
-pub fn bif_erlang_process_flag_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+pub fn bif_erlang_process_flag_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
     match args[0].into_variant() {
         Variant::Atom(atom::TRAP_EXIT) => {
             let local_data = process.local_data_mut();
@@ -1054,26 +1428,69 @@ pub fn bif_erlang_process_flag_2(_vm: &vm::Machine, process: &RcProcess, args: &
         }
         Variant::Atom(atom::PRIORITY) => {
             use process::StateFlag;
-            let flag = match args[1].into_variant() {
-                // TODO atom to_bool, then pass that in as 2 arg
-                Variant::Atom(atom::MAX) => StateFlag::PRQ_MAX,
-                Variant::Atom(atom::HIGH) => StateFlag::PRQ_HIGH,
-                Variant::Atom(atom::MEDIUM) => StateFlag::PRQ_MEDIUM,
-                Variant::Atom(atom::LOW) => StateFlag::PRQ_LOW,
-                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            let flag = match StateFlag::from_priority(args[1]) {
+                Some(flag) => flag,
+                None => return Err(Exception::new(Reason::EXC_BADARG)),
             };
             let local_data = process.local_data_mut();
 
-            let old_value = match local_data.state & StateFlag::PRQ_MASK {
-                StateFlag::PRQ_MAX => atom!(MAX),
-                _ => atom!(UNDEFINED),
+            let old_flag = local_data.priority();
+            local_data.state = (local_data.state & !StateFlag::PRQ_MASK) | flag;
+
+            // we're running, so move ourselves over to the new run queue
+            vm.dequeue(old_flag);
+            vm.enqueue(flag);
+            Ok(old_flag.to_priority())
+        }
+        Variant::Atom(atom::MESSAGE_QUEUE_DATA) => {
+            let local_data = process.local_data_mut();
+            let old_value = if local_data.flags.contains(process::Flag::OFF_HEAP_MSGQ) {
+                atom!(OFF_HEAP)
+            } else {
+                atom!(ON_HEAP)
             };
-            local_data.state = flag;
+            match args[1].into_variant() {
+                Variant::Atom(atom::OFF_HEAP) => {
+                    local_data.flags.insert(process::Flag::OFF_HEAP_MSGQ)
+                }
+                Variant::Atom(atom::ON_HEAP) => {
+                    local_data.flags.remove(process::Flag::OFF_HEAP_MSGQ);
+                    // bring the queued messages over
+                    let heap = &process.context_mut().heap;
+                    for fragment in local_data.mailbox.take_fragments() {
+                        heap.absorb(fragment);
+                    }
+                }
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            }
             Ok(old_value)
         }
-        Variant::Atom(atom::MESSAGE_QUEUE_DATA) => {
-            // TODO: unimplemented
-            Ok(atom!(ON_HEAP))
+        Variant::Atom(atom::MIN_HEAP_SIZE) => {
+            let value = non_neg_int_opt(args[1])?;
+            let context = process.context_mut();
+            let old_value = std::mem::replace(&mut context.gc.min_heap_size, value);
+            Ok(Term::uint64(&context.heap, old_value as u64))
+        }
+        Variant::Atom(atom::MIN_BIN_VHEAP_SIZE) => {
+            let value = non_neg_int_opt(args[1])?;
+            let context = process.context_mut();
+            let old_value = std::mem::replace(&mut context.gc.min_bin_vheap_size, value);
+            Ok(Term::uint64(&context.heap, old_value as u64))
+        }
+        Variant::Atom(atom::FULLSWEEP_AFTER) => {
+            let value = non_neg_int_opt(args[1])?;
+            let context = process.context_mut();
+            let old_value = std::mem::replace(&mut context.gc.fullsweep_after, value);
+            Ok(Term::uint64(&context.heap, old_value as u64))
+        }
+        Variant::Atom(atom::MAX_HEAP_SIZE) => {
+            let value = max_heap_size_opt(args[1])?;
+            let context = process.context_mut();
+            if value.size != 0 && value.size < context.gc.min_heap_size {
+                return Err(Exception::new(Reason::EXC_BADARG));
+            }
+            let old_value = std::mem::replace(&mut context.gc.max_heap_size, value);
+            Ok(old_value.to_term(&context.heap))
         }
         Variant::Atom(i) => unimplemented!(
             "erlang:process_flag/2 not implemented for {:?}",
@@ -1093,10 +1510,20 @@ fn bif_erlang_register_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -
             Variant::Port(..) => unimplemented!(),
             _ => return Err(Exception::new(Reason::EXC_BADARG)),
         };
-        let arc = vm.process_table.lock().get(pid).unwrap();
-        vm.process_registry.lock().register(name, arc);
-
-        process.local_data_mut().name = Some(name);
+        let target = match vm.process_table.lock().get(pid) {
+            Some(target) => target,
+            None => return Err(Exception::new(Reason::EXC_BADARG)),
+        };
+        let mut registry = vm.process_registry.lock();
+        // names are unique, and a process can only have one
+        if name == atom::UNDEFINED
+            || registry.whereis(name).is_some()
+            || target.local_data().name.is_some()
+        {
+            return Err(Exception::new(Reason::EXC_BADARG));
+        }
+        target.local_data_mut().name = Some(name);
+        registry.register(name, target);
         return Ok(atom!(TRUE));
     }
     Err(Exception::new(Reason::EXC_BADARG))
@@ -1107,6 +1534,9 @@ fn bif_erlang_unregister_1(vm: &vm::Machine, _process: &RcProcess, args: &[Term]
     /* (Atom, Pid|Port)   */
     if let Variant::Atom(name) = args[0].into_variant() {
         let res = vm.process_registry.lock().unregister(name);
+        if let Some(target) = &res {
+            target.local_data_mut().name = None;
+        }
 
         return Ok(Term::boolean(res.is_some()));
     }
@@ -1179,7 +1609,10 @@ pub fn bif_erlang_trunc_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term])
     let heap = &process.context_mut().heap;
     match &args[0].into_number() {
         Ok(value::Num::Integer(i)) => Ok(Term::int(*i)),
-        Ok(value::Num::Float(f)) => Ok(Term::from(f.trunc())),
+        Ok(value::Num::Float(f)) => match BigInt::from_f64(f.trunc()) {
+            Some(i) => Ok(Term::bigint(heap, i)),
+            None => Err(Exception::new(Reason::EXC_BADARITH)),
+        },
         Ok(value::Num::Bignum(v)) => Ok(Term::bigint(heap, v.clone())),
         Err(_) => Err(Exception::new(Reason::EXC_BADARG)),
     }
@@ -1189,13 +1622,61 @@ fn io_printable_range_0(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term])
     Ok(atom!(LATIN1))
 }
 
-fn garbage_collect_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> Result {
-    // TODO: GC unimplemented
+/// erts_internal:garbage_collect(Mode), backs erlang:garbage_collect/0.
+fn garbage_collect_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    let major = match args[0].into_variant() {
+        Variant::Atom(atom::MAJOR) => true,
+        Variant::Atom(atom::MINOR) => false,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+    // we're called via call_ext, so only the arguments are live
+    process.garbage_collect(vm, args.len(), major)?;
     Ok(atom!(TRUE))
 }
-fn scheduler_wall_time_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> Result {
-    // TODO: stats unimplemented
-    Ok(atom!(FALSE))
+
+/// erts_internal:request_system_task(Pid, Prio, Request), backs erlang:garbage_collect/1,2. The
+/// requester gets a {garbage_collect, ReqId, Result} reply.
+fn request_system_task_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    let pid = match args[0].into_variant() {
+        Variant::Pid(pid) => pid,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    let major = match Tuple::try_from(&args[2]) {
+        Ok(request) if request.len() == 3 && request[0] == atom!(GARBAGE_COLLECT) => {
+            request[2] != atom!(MINOR)
+        }
+        // TODO: check_process_code, copy_literals
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    let res = if pid == process.pid {
+        process.garbage_collect(vm, args.len(), major)?;
+        true
+    } else {
+        let target = vm.process_table.lock().get(pid);
+        match target {
+            // we can't collect someone else's heap, the target answers by itself once it did
+            Some(target) => {
+                let req_id = Tuple::try_from(&args[2])?[1];
+                let (request_id, heap) = signal_queue::copy_to_fragment(req_id);
+                target.send_signal(process::Signal::GarbageCollect {
+                    from: process.pid,
+                    request_id,
+                    heap,
+                });
+                return Ok(atom!(OK));
+            }
+            None => false,
+        }
+    };
+
+    // read the request id only now, the collection might have moved it
+    let req_id = Tuple::try_from(&args[2])?[1];
+    let heap = &process.context_mut().heap;
+    let reply = tup3!(heap, atom!(GARBAGE_COLLECT), req_id, Term::boolean(res));
+    process.send_message(process.pid, reply);
+    Ok(atom!(OK))
 }
 fn inet_open_8(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> Result {
     // TODO: ports unimplemented
@@ -1313,11 +1794,37 @@ fn phash2_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::immix::Heap;
     use num_bigint::ToBigInt;
 
+    /// A VM with a single process that isn't running any code.
+    fn setup() -> (vm::RcMachine, RcProcess) {
+        let vm = vm::Machine::new();
+        let process = spawn(&vm);
+        (vm, process)
+    }
+
+    fn spawn(vm: &vm::Machine) -> RcProcess {
+        let module: *const module::Module = std::ptr::null();
+        std::pin::Pin::new(process::allocate(vm, 0, 0, module).unwrap())
+    }
+
+    /// Handles the pending signals of a process, then takes all the messages out of its mailbox.
+    pub(crate) fn receive_all(process: &RcProcess) -> Vec<Term> {
+        process.process_incoming().unwrap();
+        let mut messages = Vec::new();
+        let local_data = process.local_data_mut();
+        while let Some(msg) = local_data.mailbox.receive() {
+            if let Some(heap) = local_data.mailbox.remove() {
+                process.context_mut().heap.absorb(heap);
+            }
+            messages.push(msg);
+        }
+        messages
+    }
+
     /// Converts an erlang list to a value vector.
     fn to_vec(value: Term) -> Vec<Term> {
         let mut vec = Vec::new();
@@ -1510,6 +2017,7 @@ mod tests {
         let args = vec![Term::closure(
             heap,
             value::Closure {
+                module: std::ptr::null(),
                 mfa: module::MFA(0, 0, 0),
                 ptr: 0,
                 binding: None,
@@ -1580,4 +2088,1000 @@ mod tests {
 
         assert_eq!(res, Ok(Term::int(2)));
     }
+
+    #[test]
+    fn test_bif_erlang_trunc_1() {
+        let (vm, process) = setup();
+
+        let res = bif_erlang_trunc_1(&vm, &process, &[Term::from(-2.5)]);
+        assert_eq!(res, Ok(Term::int(-2)));
+
+        let res = bif_erlang_trunc_1(&vm, &process, &[Term::from(std::f64::INFINITY)]);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_BADARITH);
+    }
+
+    #[test]
+    fn test_arithmetic_comparison() {
+        let (vm, process) = setup();
+        let heap = &process.context_mut().heap;
+        let (one, one_f) = (Term::int(1), Term::from(1.0));
+
+        let args = [one, one_f];
+        assert_eq!(erlang::seqeq_2(&vm, &process, &args), Ok(atom!(TRUE)));
+        assert_eq!(erlang::sneqeq_2(&vm, &process, &args), Ok(atom!(FALSE)));
+        assert_eq!(erlang::seq_2(&vm, &process, &args), Ok(atom!(FALSE)));
+        assert_eq!(erlang::slt_2(&vm, &process, &args), Ok(atom!(FALSE)));
+        assert_eq!(erlang::sge_2(&vm, &process, &args), Ok(atom!(TRUE)));
+
+        let args = [tup2!(heap, one, one), tup2!(heap, one_f, one)];
+        assert_eq!(erlang::seqeq_2(&vm, &process, &args), Ok(atom!(TRUE)));
+        let two = cons!(heap, Term::int(2), Term::nil());
+        let args = [two, cons!(heap, one_f, Term::nil())];
+        assert_eq!(erlang::sgt_2(&vm, &process, &args), Ok(atom!(TRUE)));
+    }
+
+    #[test]
+    fn test_garbage_collect_1() {
+        let (vm, process) = setup();
+        let context = process.context_mut();
+        let heap = &context.heap;
+
+        // build some garbage, then keep a list in x0 and a tuple in the dictionary
+        for i in 0..10_000 {
+            tup2!(heap, Term::int(i), Term::int(i));
+        }
+        context.x[0] = cons!(heap, Term::int(1), cons!(heap, Term::int(2), Term::nil()));
+        let value = tup2!(heap, atom!(OK), Term::bigint(heap, BigInt::from(1) << 70));
+        process.local_data_mut().dictionary.insert(atom!(OK), value);
+        let size = heap.size();
+
+        let res = garbage_collect_1(&vm, &process, &[atom!(MAJOR)]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+        assert!(process.context().heap.size() < size);
+
+        let heap = &Heap::new();
+        assert_eq!(
+            process.context().x[0],
+            cons!(heap, Term::int(1), cons!(heap, Term::int(2), Term::nil()))
+        );
+        assert_eq!(
+            process.local_data().dictionary[&atom!(OK)],
+            tup2!(heap, atom!(OK), Term::bigint(heap, BigInt::from(1) << 70))
+        );
+
+        let res = garbage_collect_1(&vm, &process, &[atom!(OK)]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_request_system_task_3() {
+        let (vm, process) = setup();
+        let target = spawn(&vm);
+        let heap = &Heap::new();
+        let request = |id| tup3!(heap, atom!(GARBAGE_COLLECT), Term::int(id), atom!(MAJOR));
+        let reply = |id, res| tup3!(heap, atom!(GARBAGE_COLLECT), Term::int(id), res);
+
+        // the target answers once it actually collected
+        let args = [Term::pid(target.pid), atom!(NORMAL), request(1)];
+        assert_eq!(request_system_task_3(&vm, &process, &args), Ok(atom!(OK)));
+        assert!(receive_all(&process).is_empty());
+        target.process_incoming().unwrap();
+        assert!(target.needs_gc());
+        assert!(receive_all(&process).is_empty());
+        target.garbage_collect(&vm, 0, false).unwrap();
+        assert_eq!(receive_all(&process), vec![reply(1, atom!(TRUE))]);
+
+        // one that exits before it gets to it answers false
+        let args = [Term::pid(target.pid), atom!(NORMAL), request(2)];
+        request_system_task_3(&vm, &process, &args).unwrap();
+        target.exit(&vm, Exception::with_value(Reason::EXC_EXIT, atom!(KILLED)));
+        assert_eq!(receive_all(&process), vec![reply(2, atom!(FALSE))]);
+
+        let args = [Term::pid(target.pid), atom!(NORMAL), request(3)];
+        request_system_task_3(&vm, &process, &args).unwrap();
+        assert_eq!(receive_all(&process), vec![reply(3, atom!(FALSE))]);
+    }
+
+    #[test]
+    fn test_bif_erlang_process_flag_2_gc_options() {
+        let (vm, process) = setup();
+
+        let args = [atom!(MIN_HEAP_SIZE), Term::int(1000)];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert_eq!(res, Ok(Term::int(233)));
+        assert_eq!(process.context().gc.min_heap_size, 1000);
+
+        let args = [atom!(FULLSWEEP_AFTER), Term::int(0)];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert_eq!(res, Ok(Term::int(65535)));
+        assert_eq!(process.context().gc.fullsweep_after, 0);
+
+        let args = [atom!(MIN_BIN_VHEAP_SIZE), Term::int(-1)];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert!(res.is_err());
+
+        // settings past 32 bits are reported in full
+        let size = 1 << 40;
+        process.context_mut().gc.min_heap_size = size;
+        let heap = &Heap::new();
+        let expected = Term::uint64(heap, size as u64);
+        let args = [Term::pid(process.pid), atom!(MIN_HEAP_SIZE)];
+        let res = info::process_info_2(&vm, &process, &args);
+        assert_eq!(res, Ok(tup2!(heap, atom!(MIN_HEAP_SIZE), expected)));
+        let args = [atom!(MIN_HEAP_SIZE), Term::int(1000)];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert_eq!(res, Ok(expected));
+
+        // and taken in full too, as long as they fit
+        let args = [atom!(FULLSWEEP_AFTER), expected];
+        bif_erlang_process_flag_2(&vm, &process, &args).unwrap();
+        assert_eq!(process.context().gc.fullsweep_after, size);
+        let huge = Term::bigint(heap, BigInt::from(1) << 128);
+        let args = [atom!(FULLSWEEP_AFTER), huge];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_max_heap_size_kills() {
+        let (vm, process) = setup();
+
+        let heap = &Heap::new();
+        let max = map!(
+            heap,
+            atom!(SIZE) => Term::int(1000),
+            atom!(ERROR_LOGGER) => atom!(FALSE)
+        );
+        let args = [atom!(MAX_HEAP_SIZE), max];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        let default = map!(
+            heap,
+            atom!(SIZE) => Term::int(0),
+            atom!(KILL) => atom!(TRUE),
+            atom!(ERROR_LOGGER) => atom!(TRUE)
+        );
+        assert_eq!(res, Ok(default));
+
+        // a single block is already over the limit
+        let res = garbage_collect_1(&vm, &process, &[atom!(MAJOR)]);
+        let exc = res.unwrap_err();
+        assert_eq!(exc.reason, Reason::EXT_EXIT);
+        assert_eq!(exc.value, atom!(KILLED));
+
+        let args = [atom!(MAX_HEAP_SIZE), Term::int(10)];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert!(res.is_err());
+
+        // limits past 32 bits are reported in full
+        let size = 1 << 40;
+        process.context_mut().gc.max_heap_size.size = size;
+        let res = process.context().gc.max_heap_size.to_term(heap);
+        let size = Term::uint64(heap, size as u64);
+        let expected = map!(
+            heap,
+            atom!(SIZE) => size,
+            atom!(KILL) => atom!(TRUE),
+            atom!(ERROR_LOGGER) => atom!(FALSE)
+        );
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn test_bif_erlang_process_flag_2_message_queue_data() {
+        let (vm, process) = setup();
+
+        let args = [atom!(MESSAGE_QUEUE_DATA), atom!(OFF_HEAP)];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert_eq!(res, Ok(atom!(ON_HEAP)));
+
+        // the message stays on its fragment
+        let heap = &Heap::new();
+        let message = tup2!(heap, atom!(OK), Term::int(1));
+        let signal = process::Signal::message(1, message);
+        process.local_data_mut().signal_queue.send_external(signal);
+        let size = process.context().heap.size();
+        process.process_incoming().unwrap();
+        assert_eq!(process.context().heap.size(), size);
+        assert_eq!(process.local_data_mut().mailbox.receive(), Some(message));
+
+        // until we switch back
+        let args = [atom!(MESSAGE_QUEUE_DATA), atom!(ON_HEAP)];
+        let res = bif_erlang_process_flag_2(&vm, &process, &args);
+        assert_eq!(res, Ok(atom!(OFF_HEAP)));
+        assert!(process.context().heap.size() > size);
+        assert_eq!(process.local_data_mut().mailbox.receive(), Some(message));
+    }
+
+    #[test]
+    fn test_memory_1() {
+        let (vm, process) = setup();
+
+        let heap = &Heap::new();
+        let keys = iter_to_list!(heap, [atom!(PROCESSES), atom!(TOTAL)].iter().copied());
+        let res = info::memory_1(&vm, &process, &[keys]).unwrap();
+        let sizes: Vec<_> = to_vec(res)
+            .into_iter()
+            .map(|pair| {
+                let pair = Tuple::try_from(&pair).unwrap();
+                (pair[0], pair[1].to_int().unwrap())
+            })
+            .collect();
+        assert_eq!(sizes[0].0, atom!(TOTAL));
+        assert_eq!(sizes[1].0, atom!(PROCESSES));
+        // at least our own heap
+        assert!(sizes[1].1 as usize >= process.context().heap.size());
+        assert!(sizes[0].1 >= sizes[1].1);
+
+        let res = info::memory_1(&vm, &process, &[atom!(BADARG)]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_statistics_1() {
+        let (vm, process) = setup();
+
+        let res = info::statistics_1(&vm, &process, &[atom!(GARBAGE_COLLECTION)]).unwrap();
+        assert_eq!(Tuple::try_from(&res).unwrap()[0], Term::int(0));
+        garbage_collect_1(&vm, &process, &[atom!(MAJOR)]).unwrap();
+        let res = info::statistics_1(&vm, &process, &[atom!(GARBAGE_COLLECTION)]).unwrap();
+        assert_eq!(Tuple::try_from(&res).unwrap()[0], Term::int(1));
+
+        let res = info::statistics_1(&vm, &process, &[atom!(RUN_QUEUE_LENGTHS)]).unwrap();
+        assert_eq!(to_vec(res).len(), 2);
+
+        let args = [atom!(SCHEDULER_WALL_TIME)];
+        let res = info::statistics_1(&vm, &process, &args);
+        assert_eq!(res, Ok(atom!(UNDEFINED)));
+        let res = info::scheduler_wall_time_1(&vm, &process, &[atom!(TRUE)]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+        let res = info::statistics_1(&vm, &process, &args).unwrap();
+        assert!(res.is_list());
+        let res = info::scheduler_wall_time_1(&vm, &process, &[atom!(FALSE)]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+
+        let res = info::statistics_1(&vm, &process, &[atom!(BADARG)]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_process_info() {
+        let (vm, process) = setup();
+        let target = spawn(&vm);
+
+        let heap = &Heap::new();
+        let message = tup2!(heap, atom!(OK), Term::int(1));
+        target.send_message(process.pid, message);
+        target.process_incoming().unwrap();
+
+        let items = iter_to_list!(
+            heap,
+            [
+                atom!(GROUP_LEADER),
+                atom!(STATUS),
+                atom!(MESSAGE_QUEUE_LEN),
+                atom!(MESSAGES),
+                atom!(REGISTERED_NAME)
+            ]
+            .iter()
+            .copied()
+        );
+        let args = [Term::pid(target.pid), items];
+        let res = info::process_info_2(&vm, &process, &args).unwrap();
+        let heap = &process.context_mut().heap;
+        let expected = vec![
+            tup2!(heap, atom!(REGISTERED_NAME), Term::nil()),
+            tup2!(heap, atom!(MESSAGES), cons!(heap, message, Term::nil())),
+            tup2!(heap, atom!(MESSAGE_QUEUE_LEN), Term::int(1)),
+            tup2!(heap, atom!(STATUS), atom!(RUNNABLE)),
+            tup2!(heap, atom!(GROUP_LEADER), Term::pid(0)),
+        ];
+        assert_eq!(to_vec(res), expected);
+
+        // registered_name is left out when not registered
+        let res = info::process_info_1(&vm, &process, &[Term::pid(target.pid)]).unwrap();
+        let first = Tuple::try_from(&to_vec(res)[0]).unwrap()[0];
+        assert_eq!(first, atom!(CURRENT_FUNCTION));
+
+        let args = [Term::pid(target.pid), atom!(BADARG)];
+        assert!(info::process_info_2(&vm, &process, &args).is_err());
+
+        let items = iter_to_list!(
+            heap,
+            [
+                atom!(LAST_CALLS),
+                atom!(BINARY),
+                atom!(SEQUENTIAL_TRACE_TOKEN),
+                atom!(MAGIC_REF)
+            ]
+            .iter()
+            .copied()
+        );
+        let args = [Term::pid(target.pid), items];
+        let res = info::process_info_2(&vm, &process, &args).unwrap();
+        let expected = vec![
+            tup2!(heap, atom!(MAGIC_REF), Term::nil()),
+            tup2!(heap, atom!(SEQUENTIAL_TRACE_TOKEN), Term::nil()),
+            tup2!(heap, atom!(BINARY), Term::nil()),
+            tup2!(heap, atom!(LAST_CALLS), atom!(FALSE)),
+        ];
+        assert_eq!(to_vec(res), expected);
+
+        let args = [Term::pid(target.pid), atom!(GARBAGE_COLLECTION_INFO)];
+        let res = info::process_info_2(&vm, &process, &args).unwrap();
+        let info = Tuple::try_from(&res).unwrap()[1];
+        assert_eq!(to_vec(info).len(), 11);
+        // the target hasn't started running any code yet
+        let args = [Term::pid(target.pid), atom!(CURRENT_LOCATION)];
+        let res = info::process_info_2(&vm, &process, &args).unwrap();
+        assert_eq!(res, tup2!(heap, atom!(CURRENT_LOCATION), atom!(UNDEFINED)));
+        let args = [Term::pid(target.pid), atom!(BACKTRACE)];
+        let res = info::process_info_2(&vm, &process, &args).unwrap();
+        assert!(Tuple::try_from(&res).unwrap()[1].is_binary());
+    }
+
+    #[test]
+    fn test_process_info_waits_for_the_target_to_park() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let (vm, process) = setup();
+        let target = spawn(&vm);
+
+        let heap = &Heap::new();
+        let message = tup2!(heap, atom!(OK), Term::int(1));
+        target.send_message(process.pid, message);
+        target.process_incoming().unwrap();
+
+        // the target is busy collecting its heap on another thread, moving the message around
+        let collected = std::sync::Arc::new(AtomicBool::new(false));
+        let (tx, started) = std::sync::mpsc::channel();
+        let (machine, busy, done) = (vm.clone(), target.clone(), collected.clone());
+        let thread = std::thread::spawn(move || {
+            busy.unpark();
+            tx.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            for _ in 0..10 {
+                busy.garbage_collect(&machine, 0, true).unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+            busy.park();
+        });
+        started.recv().unwrap();
+
+        let args = [Term::pid(target.pid), atom!(MESSAGES)];
+        let res = info::process_info_2(&vm, &process, &args).unwrap();
+        assert!(collected.load(Ordering::Relaxed));
+        let heap = &process.context_mut().heap;
+        let expected = tup2!(heap, atom!(MESSAGES), cons!(heap, message, Term::nil()));
+        assert_eq!(res, expected);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_processes_and_registered() {
+        let (vm, process) = setup();
+        let other = spawn(&vm);
+
+        let res = info::processes_0(&vm, &process, &[]).unwrap();
+        assert_eq!(
+            to_vec(res),
+            vec![Term::pid(process.pid), Term::pid(other.pid)]
+        );
+        let res = info::system_info_1(&vm, &process, &[atom!(PROCESS_COUNT)]);
+        assert_eq!(res, Ok(Term::int(2)));
+
+        // exited processes are skipped
+        let mut processes = vm.processes();
+        vm.process_table.lock().release(other.pid);
+        assert_eq!(processes.next().map(|p| p.pid), Some(process.pid));
+        assert!(processes.next().is_none());
+
+        let args = [atom!(OK), Term::pid(process.pid)];
+        bif_erlang_register_2(&vm, &process, &args).unwrap();
+        let res = info::registered_0(&vm, &process, &[]).unwrap();
+        assert_eq!(to_vec(res), vec![atom!(OK)]);
+    }
+
+    #[test]
+    fn test_cancel_timer() {
+        let (vm, process) = setup();
+
+        let args = [Term::int(3_600_000), Term::pid(process.pid), atom!(OK)];
+        let timer = timer::start_timer_3(&vm, &process, &args).unwrap();
+        let left = timer::read_timer_1(&vm, &process, &[timer]).unwrap();
+        assert!(left.to_int().unwrap() <= 3_600_000);
+
+        // the result comes back as a message
+        let heap = &Heap::new();
+        let opts = cons!(heap, tup2!(heap, atom!(ASYNC), atom!(TRUE)), Term::nil());
+        let res = timer::cancel_timer_2(&vm, &process, &[timer, opts]);
+        assert_eq!(res, Ok(atom!(OK)));
+        let reply = receive_all(&process);
+        let reply = Tuple::try_from(&reply[0]).unwrap();
+        assert_eq!(reply[0], atom!(CANCEL_TIMER));
+        assert_eq!(reply[1], timer);
+        assert!(reply[2].to_int().is_some());
+
+        let res = timer::read_timer_1(&vm, &process, &[timer]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+        let res = timer::cancel_timer_1(&vm, &process, &[timer]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+        let res = timer::cancel_timer_1(&vm, &process, &[atom!(OK)]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_abs_timer() {
+        let (vm, process) = setup();
+        let heap = &Heap::new();
+        let abs = cons!(heap, tup2!(heap, atom!(ABS), atom!(TRUE)), Term::nil());
+
+        // absolute times share the monotonic time base
+        let now = chrono::monotonic_time_1(&vm, &process, &[atom!(MILLISECOND)]).unwrap();
+        let now = now.into_number().unwrap().to_bigint().unwrap();
+        let time = Term::bigint(heap, now + BigInt::from(3_600_000));
+        let args = [time, Term::pid(process.pid), atom!(OK), abs];
+        let timer = timer::start_timer_4(&vm, &process, &args).unwrap();
+        let left = timer::read_timer_1(&vm, &process, &[timer]).unwrap();
+        let left = left.to_int().unwrap();
+        assert!(left > 3_500_000 && left <= 3_600_000);
+
+        // times past i32 are fine, past 64 bits they're not
+        let time = Term::bigint(heap, BigInt::from(1) << 40);
+        let args = [time, Term::pid(process.pid), atom!(OK)];
+        assert!(timer::start_timer_3(&vm, &process, &args).is_ok());
+        let time = Term::bigint(heap, BigInt::from(1) << 64);
+        let args = [time, Term::pid(process.pid), atom!(OK)];
+        assert!(timer::start_timer_3(&vm, &process, &args).is_err());
+
+        let res = chrono::monotonic_time_1(&vm, &process, &[atom!(OK)]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_exit_signals() {
+        let (vm, process) = setup();
+        let target = spawn(&vm);
+        let exit = |reason, kind| {
+            let reason = Exception::with_value(Reason::EXC_EXIT, reason);
+            process::Signal::exit(process.pid, &reason, kind)
+        };
+
+        // normal is ignored, kill can't be trapped
+        let res = target.handle_exit_signal(exit(atom!(NORMAL), process::ExitKind::Exit));
+        assert_eq!(res, Ok(()));
+        let res = target.handle_exit_signal(exit(atom!(KILL), process::ExitKind::Exit));
+        assert_eq!(res.unwrap_err().value, atom!(KILLED));
+
+        // signals from processes we're not linked to are dropped
+        let res = target.handle_exit_signal(exit(atom!(BADARG), process::ExitKind::ExitLinked));
+        assert_eq!(res, Ok(()));
+
+        // trapped signals turn into messages, even a linked kill
+        target
+            .local_data_mut()
+            .flags
+            .insert(process::Flag::TRAP_EXIT);
+        target.local_data_mut().links.insert(process.pid);
+        let res = target.handle_exit_signal(exit(atom!(KILL), process::ExitKind::ExitLinked));
+        assert_eq!(res, Ok(()));
+        let msg = receive_all(&target);
+        let msg = Tuple::try_from(&msg[0]).unwrap();
+        assert_eq!(msg[0], atom!(EXIT_U));
+        assert_eq!(msg[1], Term::pid(process.pid));
+        assert_eq!(msg[2], atom!(KILL));
+        let res = target.handle_exit_signal(exit(atom!(KILL), process::ExitKind::Exit));
+        assert_eq!(res.unwrap_err().value, atom!(KILLED));
+
+        // exit(self(), normal) terminates the caller
+        let args = [Term::pid(process.pid), atom!(NORMAL)];
+        let res = bif_erlang_exit_2(&vm, &process, &args);
+        assert_eq!(res.unwrap_err().value, atom!(NORMAL));
+
+        // names, tables and the pid get released on exit
+        let args = [atom!(OK), Term::pid(target.pid)];
+        bif_erlang_register_2(&vm, &process, &args).unwrap();
+        assert!(bif_erlang_register_2(&vm, &process, &args).is_err());
+        assert_eq!(target.local_data().name, Some(atom::OK));
+        let heap = &Heap::new();
+        let opts = cons!(heap, atom!(SET), Term::nil());
+        let table = ets::bif::new_2(&vm, &target, &[atom!(OK), opts]).unwrap();
+        let tid = table.to_ref().unwrap();
+
+        target.exit(&vm, Exception::with_value(Reason::EXC_EXIT, atom!(KILLED)));
+        assert!(vm.process_registry.lock().whereis(atom::OK).is_none());
+        assert!(vm.ets_tables.lock().get(tid).is_none());
+        assert!(!vm.process_table.lock().contains_key(target.pid));
+    }
+
+    #[test]
+    fn test_monitor_variants() {
+        let (vm, process) = setup();
+        let target = spawn(&vm);
+        let heap = &Heap::new();
+        let next_down = || {
+            let messages = receive_all(&process);
+            assert_eq!(messages.len(), 1);
+            let msg = Tuple::try_from(&messages[0]).unwrap();
+            assert_eq!(msg[0], atom!(DOWN_U));
+            (msg[1], msg[2], msg[3], msg[4])
+        };
+
+        // by registered name, the DOWN message carries {Name, Node}
+        let args = [atom!(OK), Term::pid(target.pid)];
+        bif_erlang_register_2(&vm, &process, &args).unwrap();
+        let name = tup2!(heap, atom!(OK), atom!(NO_NODE_NO_HOST));
+        let reference = bif_erlang_monitor_2(&vm, &process, &[atom!(PROCESS), name]).unwrap();
+        target.process_incoming().unwrap();
+        target.exit(&vm, Exception::with_value(Reason::EXC_EXIT, atom!(KILLED)));
+        process.process_incoming().unwrap();
+        let down = next_down();
+        assert_eq!(down, (reference, atom!(PROCESS), name, atom!(KILLED)));
+
+        // unknown names and other nodes go down right away
+        let res = bif_erlang_monitor_2(&vm, &process, &[atom!(PROCESS), atom!(OK)]).unwrap();
+        let (r, _, _, reason) = next_down();
+        assert_eq!((r, reason), (res, atom!(NOPROC)));
+        let name = tup2!(heap, atom!(OK), atom!(TRUE));
+        bif_erlang_monitor_2(&vm, &process, &[atom!(PROCESS), name]).unwrap();
+        assert_eq!(next_down().3, atom!(NOCONNECTION));
+
+        // ports
+        let (chan, _input) = futures::channel::mpsc::unbounded();
+        let id = vm.port_table.write().insert(target.pid, chan);
+        let args = [atom!(PORT), Term::port(id)];
+        let reference = bif_erlang_monitor_2(&vm, &process, &args).unwrap();
+        for port in vm.port_table.write().remove_owned(target.pid) {
+            port.close(&vm, &Exception::with_value(Reason::EXC_EXIT, atom!(NORMAL)));
+        }
+        process.process_incoming().unwrap();
+        let (r, kind, object, _) = next_down();
+        assert_eq!((r, kind, object), (reference, atom!(PORT), Term::port(id)));
+
+        // time offsets never change, but the monitor can be removed
+        let args = [atom!(TIME_OFFSET), atom!(CLOCK_SERVICE)];
+        let reference = bif_erlang_monitor_2(&vm, &process, &args).unwrap();
+        let opts = cons!(heap, atom!(INFO), Term::nil());
+        let res = bif_erlang_demonitor_2(&vm, &process, &[reference, opts]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+        let res = bif_erlang_demonitor_2(&vm, &process, &[reference, opts]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+        let args = [atom!(TIME_OFFSET), atom!(OK)];
+        assert!(bif_erlang_monitor_2(&vm, &process, &args).is_err());
+
+        // flush drops a DOWN message that was already delivered
+        let reference = bif_erlang_monitor_2(&vm, &process, &[atom!(PROCESS), atom!(OK)]).unwrap();
+        let opts = cons!(heap, atom!(FLUSH), opts);
+        let res = bif_erlang_demonitor_2(&vm, &process, &[reference, opts]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+        assert!(!process.local_data().mailbox.has_messages());
+    }
+
+    #[test]
+    fn test_aliases() {
+        let (vm, process) = setup();
+        vm::Machine::set_current(vm.clone());
+        let other = spawn(&vm);
+        let send = |alias| process::send_message(&vm, other.pid, alias, atom!(OK)).unwrap();
+
+        let alias = bif_erlang_alias_0(&vm, &process, &[]).unwrap();
+        send(alias);
+        assert_eq!(receive_all(&process), vec![atom!(OK)]);
+
+        // messages still in flight get dropped too
+        send(alias);
+        let res = bif_erlang_unalias_1(&vm, &process, &[alias]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+        send(alias);
+        assert!(receive_all(&process).is_empty());
+        let res = bif_erlang_unalias_1(&vm, &process, &[alias]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+
+        // reply aliases only let the first message through
+        let heap = &Heap::new();
+        let opts = cons!(heap, atom!(REPLY), Term::nil());
+        let alias = bif_erlang_alias_1(&vm, &process, &[opts]).unwrap();
+        send(alias);
+        send(alias);
+        assert_eq!(receive_all(&process), vec![atom!(OK)]);
+
+        // monitor aliases go away with the monitor
+        let opt = tup2!(heap, atom!(ALIAS), atom!(DEMONITOR));
+        let opts = cons!(heap, opt, Term::nil());
+        let args = [atom!(PROCESS), Term::pid(other.pid), opts];
+        let reference = bif_erlang_monitor_3(&vm, &process, &args).unwrap();
+        send(reference);
+        assert_eq!(receive_all(&process), vec![atom!(OK)]);
+        bif_erlang_demonitor_1(&vm, &process, &[reference]).unwrap();
+        send(reference);
+        assert!(receive_all(&process).is_empty());
+        assert!(process.local_data().aliases.is_empty());
+
+        let opts = cons!(heap, tup2!(heap, atom!(ALIAS), atom!(REPLY)), Term::nil());
+        let args = [atom!(PROCESS), Term::pid(other.pid), opts];
+        assert!(bif_erlang_monitor_3(&vm, &process, &args).is_err());
+    }
+
+    #[test]
+    fn test_spawn_request() {
+        let (vm, process) = setup();
+        let heap = &Heap::new();
+
+        // other nodes are never connected, the error comes back as a tagged reply
+        let opt = tup2!(heap, atom!(REPLY_TAG), atom!(OK));
+        let opts = cons!(heap, opt, Term::nil());
+        let args = [atom!(TRUE), atom!(OK), atom!(OK), Term::nil(), opts];
+        let request = bif_erlang_spawn_request_5(&vm, &process, &args).unwrap();
+        let reply = receive_all(&process);
+        let reply = Tuple::try_from(&reply[0]).unwrap();
+        assert_eq!(reply[0], atom!(OK));
+        assert_eq!(reply[1], request);
+        assert_eq!(reply[2], atom!(ERROR));
+        assert_eq!(reply[3], atom!(NOCONNECTION));
+        let res = bif_erlang_spawn_request_abandon_1(&vm, &process, &[request]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+
+        // abandoned requests don't get a reply
+        let args = [atom!(TRUE), atom!(OK), atom!(OK), Term::nil(), Term::nil()];
+        let request = bif_erlang_spawn_request_5(&vm, &process, &args).unwrap();
+        let res = bif_erlang_spawn_request_abandon_1(&vm, &process, &[request]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+        process.process_incoming().unwrap();
+        assert!(!process.local_data().mailbox.has_messages());
+
+        // a missing module isn't an error for the caller, the process exits with undef
+        let node = atom!(NO_NODE_NO_HOST);
+        let args = [node, atom!(OK), atom!(OK), Term::nil(), Term::nil()];
+        let request = bif_erlang_spawn_request_5(&vm, &process, &args).unwrap();
+        let mut messages = Vec::new();
+        for _ in 0..100 {
+            messages.extend(receive_all(&process));
+            if messages.len() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let reply = Tuple::try_from(&messages[0]).unwrap();
+        assert_eq!(reply[0], atom!(SPAWN_REPLY));
+        assert_eq!(reply[1], request);
+        assert_eq!(reply[2], atom!(OK));
+        let down = Tuple::try_from(&messages[1]).unwrap();
+        assert_eq!(down[0], atom!(DOWN_U));
+        assert_eq!(down[1], request);
+        assert_eq!(down[3], reply[3]);
+        let reason = Tuple::try_from(&down[4]).unwrap();
+        assert_eq!(reason[0], atom!(UNDEF));
+
+        let opts = cons!(heap, tup2!(heap, atom!(REPLY), atom!(OK)), Term::nil());
+        let args = [atom!(TRUE), atom!(OK), atom!(OK), Term::nil(), opts];
+        assert!(bif_erlang_spawn_request_5(&vm, &process, &args).is_err());
+
+        // unknown options are a badarg, not a crash
+        let opts = cons!(heap, tup2!(heap, atom!(OK), Term::int(1)), Term::nil());
+        let args = [atom!(TRUE), atom!(OK), atom!(OK), Term::nil(), opts];
+        let res = bif_erlang_spawn_request_5(&vm, &process, &args);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_BADARG);
+        let spawn = tup4!(heap, atom!(OK), atom!(OK), Term::nil(), opts);
+        let res = bif_erlang_spawn_opt_1(&vm, &process, &[spawn]);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_BADARG);
+        let nil = Term::nil();
+        let args = [atom!(TRUE), Term::int(1), atom!(OK), nil, nil];
+        assert!(bif_erlang_spawn_request_5(&vm, &process, &args).is_err());
+    }
+
+    #[test]
+    fn test_hibernate() {
+        use crate::instr_ptr::InstrPtr;
+        use process::Flag;
+        let (vm, process) = setup();
+        let (m, f) = (atom::from_str("hibernate_test"), atom::from_str("wake"));
+
+        let res = bif_erlang_hibernate_3(&vm, &process, &[Term::int(1), f, Term::nil()]);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_BADARG);
+        let res = bif_erlang_hibernate_3(&vm, &process, &[m, f, Term::int(1)]);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_BADARG);
+        let res = bif_erlang_hibernate_3(&vm, &process, &[m, f, Term::nil()]);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_UNDEF);
+
+        let ptr = InstrPtr {
+            module: std::ptr::null(),
+            ptr: 42,
+        };
+        vm.exports.write().register(module::MFA(m, f, 2), ptr);
+
+        let context = process.context_mut();
+        let heap = &context.heap;
+        for i in 0..10_000 {
+            tup2!(heap, Term::int(i), Term::int(i));
+        }
+        context.stack.push(Term::int(1));
+        context.catches = 1;
+        let args = cons!(heap, Term::int(1), cons!(heap, atom!(OK), Term::nil()));
+        let size = heap.size();
+
+        let res = bif_erlang_hibernate_3(&vm, &process, &[m, f, args]);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+        let context = process.context();
+        assert!(context.stack.is_empty());
+        assert_eq!(context.catches, 0);
+        assert!(context.heap.size() < size);
+        assert_eq!(context.ip.ptr, 42);
+        assert_eq!(context.x[0], Term::int(1));
+        assert_eq!(context.x[1], atom!(OK));
+        assert!(process.local_data().flags.contains(Flag::HIBERNATE));
+    }
+
+    #[test]
+    fn test_hibernate_waits_for_a_message() {
+        use std::sync::atomic::Ordering;
+        use std::time::Duration;
+        let (vm, process) = setup();
+        let other = spawn(&vm);
+        vm.enqueue(process.local_data().priority());
+
+        let (machine, parked) = (vm.clone(), process.clone());
+        let (tx, done) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let res = futures::executor::block_on(vm::hibernate(&machine, &parked));
+            tx.send(res.is_ok()).unwrap();
+        });
+        let timeout = Duration::from_millis(50);
+        let waiting = || process.waiting_for_message.load(Ordering::Relaxed);
+
+        assert!(done.recv_timeout(timeout).is_err());
+        assert!(waiting());
+
+        // other signals get handled without waking it up
+        let signal = process::Signal::Link { from: other.pid };
+        process::send_signal(&vm, process.pid, signal);
+        assert!(done.recv_timeout(timeout).is_err());
+        assert!(waiting());
+
+        process.send_message(other.pid, atom!(OK));
+        assert_eq!(done.recv_timeout(Duration::from_secs(5)), Ok(true));
+        assert!(!waiting());
+        assert!(process.local_data().links.contains(&other.pid));
+        assert_eq!(receive_all(&process), vec![atom!(OK)]);
+    }
+
+    fn on_dirty_cpu(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> Result {
+        let thread = std::thread::current();
+        let name = thread.name().unwrap_or_default();
+        Ok(Term::boolean(name.starts_with("dirty-cpu-")))
+    }
+
+    fn dirty_panic(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> Result {
+        panic!("dirty BIF failed")
+    }
+
+    #[test]
+    fn test_call_dirty() {
+        use futures::executor::block_on;
+        use std::sync::atomic::Ordering;
+        let (vm, process) = setup();
+
+        let res = block_on(vm.call_dirty(Dirty::Cpu, &process, on_dirty_cpu, Vec::new()));
+        assert_eq!(res, Ok(atom!(TRUE)));
+
+        // a panic turns into an error, and the pool keeps going
+        let res = block_on(vm.call_dirty(Dirty::Cpu, &process, dirty_panic, Vec::new()));
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_INTERNAL_ERROR);
+        assert_eq!(vm.dirty_cpu_tasks.load(Ordering::Relaxed), 0);
+        let res = block_on(vm.call_dirty(Dirty::Cpu, &process, on_dirty_cpu, Vec::new()));
+        assert_eq!(res, Ok(atom!(TRUE)));
+    }
+
+    fn runnable_normal(vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> Result {
+        let runnable = vm.run_queues[1].load(std::sync::atomic::Ordering::Relaxed);
+        Ok(Term::int(runnable as i32))
+    }
+
+    #[test]
+    fn test_priorities() {
+        use futures::executor::block_on;
+        use process::StateFlag;
+        use std::sync::atomic::Ordering;
+        use std::time::Duration;
+        let (vm, process) = setup();
+        let runnable = |priority: StateFlag| {
+            vm.run_queues[priority.bits() as usize - 1].load(Ordering::Relaxed)
+        };
+        vm.enqueue(process.local_data().priority());
+
+        let res = bif_erlang_process_flag_2(&vm, &process, &[atom!(PRIORITY), atom!(OK)]);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_BADARG);
+        // medium is only how the run queue is called, not a priority
+        let res = bif_erlang_process_flag_2(&vm, &process, &[atom!(PRIORITY), atom!(MEDIUM)]);
+        assert_eq!(res.unwrap_err().reason, Reason::EXC_BADARG);
+        let res = bif_erlang_process_flag_2(&vm, &process, &[atom!(PRIORITY), atom!(HIGH)]);
+        assert_eq!(res, Ok(atom!(NORMAL)));
+        assert_eq!(runnable(StateFlag::PRQ_MEDIUM), 0);
+        assert_eq!(runnable(StateFlag::PRQ_HIGH), 1);
+        let res = bif_erlang_process_flag_2(&vm, &process, &[atom!(PRIORITY), atom!(NORMAL)]);
+        assert_eq!(res, Ok(atom!(HIGH)));
+
+        // a process waiting for a dirty BIF isn't runnable
+        let res = block_on(vm.call_dirty(Dirty::Io, &process, runnable_normal, Vec::new()));
+        assert_eq!(res, Ok(Term::int(0)));
+        assert_eq!(runnable(StateFlag::PRQ_MEDIUM), 1);
+
+        // yielding sleeps until the higher priority processes are done
+        vm.enqueue(StateFlag::PRQ_HIGH);
+        let (machine, yielding) = (vm.clone(), process.clone());
+        let (tx, done) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            block_on(vm::yield_to_scheduler(&machine, &yielding));
+            tx.send(()).unwrap();
+        });
+        assert!(done.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(runnable(StateFlag::PRQ_MEDIUM), 0);
+        vm.dequeue(StateFlag::PRQ_HIGH);
+        assert_eq!(done.recv_timeout(Duration::from_secs(5)), Ok(()));
+        assert_eq!(runnable(StateFlag::PRQ_MEDIUM), 1);
+
+        // low priority processes only take turns with normal ones
+        bif_erlang_process_flag_2(&vm, &process, &[atom!(PRIORITY), atom!(LOW)]).unwrap();
+        vm.enqueue(StateFlag::PRQ_MEDIUM);
+        block_on(vm::yield_to_scheduler(&vm, &process));
+        assert_eq!(runnable(StateFlag::PRQ_LOW), 1);
+    }
+
+    #[test]
+    fn test_spawn_priority() {
+        use crate::loader::{Instruction, LValue};
+        let (vm, process) = setup();
+        let (name, func) = (atom::from_str("priority_test"), atom::from_str("loop"));
+        let mut module = empty_module(name);
+        module.instructions.push(Instruction {
+            op: crate::opcodes::Opcode::CallOnly,
+            args: vec![LValue::Literal(0), LValue::Label(0)],
+        });
+        module.funs.insert((func, 0), 0);
+        vm.modules.lock().add_module(name, module);
+
+        let mut opts = process::SpawnOpts::new(process::SpawnFlag::NONE);
+        opts.priority = process::StateFlag::PRQ_LOW;
+        let (pid, _) =
+            process::spawn_process(&vm, &process, name, func, Term::nil(), opts).unwrap();
+        let priority = || {
+            let args = [Term::pid(pid), atom!(PRIORITY)];
+            info::process_info_2(&vm, &process, &args).unwrap()
+        };
+        let res = priority();
+        assert_eq!(Tuple::try_from(&res).unwrap()[1], atom!(LOW));
+
+        let signal = process::Signal::exit(
+            process.pid,
+            &Exception::with_value(Reason::EXC_EXIT, atom!(KILL)),
+            process::ExitKind::Exit,
+        );
+        process::send_signal(&vm, pid, signal);
+        for _ in 0..100 {
+            if priority() == atom!(UNDEFINED) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(priority(), atom!(UNDEFINED));
+    }
+
+    #[test]
+    fn test_reductions_past_the_budget() {
+        let (vm, process) = setup();
+        let reductions = |p: &RcProcess| {
+            let args = [Term::pid(p.pid), atom!(REDUCTIONS)];
+            let res = info::process_info_2(&vm, &process, &args).unwrap();
+            Tuple::try_from(&res).unwrap()[1].to_int().unwrap() as usize
+        };
+        let statistics = || {
+            let res = info::statistics_1(&vm, &process, &[atom!(EXACT_REDUCTIONS)]).unwrap();
+            Tuple::try_from(&res).unwrap()[0].to_int().unwrap() as usize
+        };
+
+        let reds = 3 * process::CONTEXT_REDS;
+        let res = info::bump_reductions_1(&vm, &process, &[Term::int(reds as i32)]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+        assert_eq!(reductions(&process), reds);
+        assert_eq!(statistics(), reds);
+
+        // the overflow makes it into the VM total once the slice ends
+        vm.begin_slice(process.context_mut());
+        vm.end_slice(process.context_mut());
+        assert_eq!(reductions(&process), reds);
+        assert_eq!(statistics(), reds);
+        assert_eq!(process.context().reds, process::CONTEXT_REDS);
+    }
+
+    /// A module without any code, enough to stand in for a loaded version.
+    fn empty_module(name: u32) -> Box<module::Module> {
+        Box::new(module::Module {
+            imports: Vec::new(),
+            resolved_imports: Vec::new(),
+            exports: Vec::new(),
+            literals: Vec::new(),
+            literal_heap: Heap::new(),
+            lambdas: Vec::new(),
+            funs: HashMap::new(),
+            instructions: Vec::new(),
+            lines: Vec::new(),
+            name,
+            on_load: None,
+        })
+    }
+
+    #[test]
+    fn test_check_process_code_and_purge() {
+        let (vm, process) = setup();
+        let name = atom::from_str("purge_test");
+        let old = {
+            let mut registry = vm.modules.lock();
+            registry.add_module(name, empty_module(name));
+            registry.add_module(name, empty_module(name));
+            registry.lookup_old(name).unwrap() as *const module::Module
+        };
+        let res = load::check_old_code_1(&vm, &process, &[Term::atom(name)]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+
+        // one process runs the old code, one holds a fun into it, one only refers to a literal
+        let runner = spawn(&vm);
+        runner.context_mut().ip = crate::instr_ptr::InstrPtr {
+            module: old,
+            ptr: 0,
+        };
+        let holder = spawn(&vm);
+        let closure = value::Closure {
+            module: old,
+            mfa: module::MFA(name, atom!(OK), 0),
+            ptr: 0,
+            binding: None,
+        };
+        holder.context_mut().x[0] = Term::closure(&holder.context_mut().heap, closure);
+        let reader = spawn(&vm);
+        let literal_heap = unsafe { &(*old).literal_heap };
+        reader.context_mut().x[0] = tup2!(literal_heap, atom!(OK), atom!(OK));
+
+        let check = |target: &RcProcess| {
+            let args = [Term::pid(target.pid), Term::atom(name)];
+            load::check_process_code_2(&vm, &process, &args).unwrap()
+        };
+        assert_eq!(check(&runner), atom!(TRUE));
+        assert_eq!(check(&holder), atom!(TRUE));
+        assert_eq!(check(&reader), atom!(FALSE));
+
+        // async requests get answered by the target itself
+        vm::Machine::set_current(vm.clone());
+        let heap = &process.context_mut().heap;
+        let request_id = tup2!(heap, atom!(OK), Term::int(7));
+        let opts = cons!(heap, tup2!(heap, atom!(ASYNC), request_id), Term::nil());
+        let args = [Term::pid(holder.pid), Term::atom(name), opts];
+        let res = load::check_process_code_3(&vm, &process, &args);
+        assert_eq!(res, Ok(atom!(ASYNC)));
+        holder.process_incoming().unwrap();
+        let reply = tup3!(heap, atom!(CHECK_PROCESS_CODE), request_id, atom!(TRUE));
+        assert_eq!(receive_all(&process), vec![reply]);
+
+        // a soft purge leaves the processes (and the code) alone
+        let res = load::purger_soft_purge_1(&vm, &process, &[Term::atom(name)]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+        assert!(vm.modules.lock().has_old_code(name));
+
+        let res = load::purger_purge_1(&vm, &process, &[Term::atom(name)]).unwrap();
+        assert_eq!(res, tup2!(heap, atom!(TRUE), atom!(TRUE)));
+        assert!(!vm.modules.lock().is_loaded(name, old));
+        assert!(runner.process_incoming().is_err());
+        assert!(holder.process_incoming().is_err());
+        assert!(reader.process_incoming().is_ok());
+
+        // the code is freed once everyone using it is gone
+        let killed = Exception::with_value(Reason::EXC_EXIT, atom!(KILLED));
+        runner.exit(&vm, killed.clone());
+        holder.exit(&vm, killed);
+        assert_eq!(vm.modules.lock().pending_release(), 1);
+        reader.exit(&vm, Exception::with_value(Reason::EXC_EXIT, atom!(NORMAL)));
+        assert_eq!(vm.modules.lock().pending_release(), 0);
+
+        // nothing left to purge
+        let res = load::purger_soft_purge_1(&vm, &process, &[Term::atom(name)]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+        let res = load::check_old_code_1(&vm, &process, &[Term::atom(name)]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+    }
 }
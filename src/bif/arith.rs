@@ -1,21 +1,18 @@
 use crate::bif;
 use crate::exception::{Exception, Reason};
-use crate::numeric::division::{FlooredDiv, OverflowingFlooredDiv};
-use crate::numeric::modulo::{Modulo, OverflowingModulo};
 use crate::process::RcProcess;
 use crate::value::{self, Term};
 use crate::vm;
 use num_bigint::BigInt;
-use std::pin::Pin;
-// use num_bigint::ToBigInt;
-use num_traits::Signed;
+use num_traits::{Signed, Zero};
 use statrs;
-use std::ops::{Add, Mul, Sub};
+use std::ops::{Add, Div, Mul, Rem, Sub};
 
 pub fn abs_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let heap = &process.context_mut().heap;
     match &args[0].into_number() {
-        Ok(value::Num::Integer(i)) => Ok(Term::int(i.abs())),
+        // abs(-2147483648) doesn't fit
+        Ok(value::Num::Integer(i)) => Ok(Term::int64(heap, i64::from(*i).abs())),
         Ok(value::Num::Float(f)) => Ok(Term::from(f.abs())),
         Ok(value::Num::Bignum(i)) => Ok(Term::bigint(heap, i.abs())),
         _ => Err(Exception::new(Reason::EXC_BADARG)),
@@ -24,33 +21,48 @@ pub fn abs_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Resu
 
 pub fn add_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let heap = &process.context_mut().heap;
-    Ok(integer_overflow_op!(heap, args, add, overflowing_add))
+    Ok(number_op!(heap, args, add, overflowing_add))
 }
 
 pub fn sub_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let heap = &process.context_mut().heap;
-    Ok(integer_overflow_op!(heap, args, sub, overflowing_sub))
+    Ok(number_op!(heap, args, sub, overflowing_sub))
 }
 
 pub fn mult_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let heap = &process.context_mut().heap;
-    Ok(integer_overflow_op!(heap, args, mul, overflowing_mul))
+    Ok(number_op!(heap, args, mul, overflowing_mul))
+}
+
+/// Integer division and remainder raise badarith on a zero divisor.
+fn check_divisor(divisor: Term) -> Result<(), Exception> {
+    match divisor.into_number() {
+        Ok(value::Num::Integer(0)) => Err(Exception::new(Reason::EXC_BADARITH)),
+        Ok(value::Num::Bignum(ref i)) if i.is_zero() => Err(Exception::new(Reason::EXC_BADARITH)),
+        _ => Ok(()),
+    }
 }
 
 pub fn intdiv_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    // div truncates towards zero, the only overflow is i32::MIN div -1
+    check_divisor(args[1])?;
     let heap = &process.context_mut().heap;
-    Ok(integer_overflow_op!(
-        heap,
-        args,
-        floored_division,
-        overflowing_floored_division
-    ))
+    Ok(integer_overflow_op!(heap, args, div, overflowing_div))
 }
 
-pub fn mod_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    // TODO: should be rem but it's mod
+pub fn rem_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    // rem takes the sign of the dividend, same as rust's %
+    check_divisor(args[1])?;
     let heap = &process.context_mut().heap;
-    Ok(integer_overflow_op!(heap, args, modulo, overflowing_modulo))
+    Ok(integer_overflow_op!(heap, args, rem, overflowing_rem))
+}
+
+/// Converts a number argument to a float for the math functions.
+fn to_float(arg: Term) -> Result<f64, Exception> {
+    arg.into_number()
+        .ok()
+        .and_then(|n| n.to_f64())
+        .ok_or_else(|| Exception::new(Reason::EXC_BADARG))
 }
 
 macro_rules! trig_func {
@@ -58,12 +70,7 @@ macro_rules! trig_func {
     $arg:expr,
     $op:ident
 ) => {{
-        let res = match $arg.into_number() {
-            Ok(value::Num::Integer(i)) => f64::from(i),
-            Ok(value::Num::Float(f)) => f,
-            Ok(value::Num::Bignum(..)) => unimplemented!(),
-            Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-        };
+        let res = to_float($arg)?;
         Ok(Term::from(res.$op()))
     }};
 }
@@ -117,33 +124,18 @@ pub fn math_atanh_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> b
 }
 
 pub fn math_erf_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let res = match args[0].into_number() {
-        Ok(value::Num::Integer(i)) => f64::from(i),
-        Ok(value::Num::Float(f)) => f,
-        Ok(value::Num::Bignum(..)) => unimplemented!(),
-        Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
+    let res = to_float(args[0])?;
     Ok(Term::from(statrs::function::erf::erf(res)))
 }
 
 pub fn math_erfc_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let res = match args[0].into_number() {
-        Ok(value::Num::Integer(i)) => f64::from(i),
-        Ok(value::Num::Float(f)) => f,
-        Ok(value::Num::Bignum(..)) => unimplemented!(),
-        Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
+    let res = to_float(args[0])?;
     Ok(Term::from(1.0_f64 - statrs::function::erf::erf(res)))
 }
 
 pub fn math_exp_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let res: f64 = match args[0].into_number() {
-        Ok(value::Num::Integer(i)) => f64::from(i),
-        Ok(value::Num::Float(f)) => f,
-        Ok(value::Num::Bignum(..)) => unimplemented!(),
-        Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    Ok(Term::from(res.powf(std::f64::consts::E)))
+    let res = to_float(args[0])?;
+    Ok(Term::from(res.exp()))
 }
 
 pub fn math_log_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
@@ -163,34 +155,14 @@ pub fn math_sqrt_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bi
 }
 
 pub fn math_atan2_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let res = match args[0].into_number() {
-        Ok(value::Num::Integer(i)) => f64::from(i),
-        Ok(value::Num::Float(f)) => f,
-        Ok(value::Num::Bignum(..)) => unimplemented!(),
-        Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    let arg = match args[1].into_number() {
-        Ok(value::Num::Integer(i)) => f64::from(i),
-        Ok(value::Num::Float(f)) => f,
-        Ok(value::Num::Bignum(..)) => unimplemented!(),
-        Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
+    let res = to_float(args[0])?;
+    let arg = to_float(args[1])?;
     Ok(Term::from(res.atan2(arg)))
 }
 
 pub fn math_pow_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let base = match args[0].into_number() {
-        Ok(value::Num::Integer(i)) => f64::from(i),
-        Ok(value::Num::Float(f)) => f,
-        Ok(value::Num::Bignum(..)) => unimplemented!(),
-        Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    let index = match args[1].into_number() {
-        Ok(value::Num::Integer(i)) => f64::from(i),
-        Ok(value::Num::Float(f)) => f,
-        Ok(value::Num::Bignum(..)) => unimplemented!(),
-        Err(_) => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
+    let base = to_float(args[0])?;
+    let index = to_float(args[1])?;
 
     Ok(Term::from(base.powf(index)))
 }
@@ -274,15 +246,58 @@ mod tests {
     }
 
     #[test]
-    fn test_mod_2() {
+    fn test_rem_2() {
         let vm = vm::Machine::new();
         let module: *const module::Module = std::ptr::null();
         let process = std::pin::Pin::new(process::allocate(&vm, 0, 0, module).unwrap());
         let args = vec![Term::int(4), Term::int(3)];
-        let res = mod_2(&vm, &process, &args);
+        let res = rem_2(&vm, &process, &args);
         assert_eq!(res, Ok(Term::int(1)));
     }
 
+    #[test]
+    fn test_add_2_overflow() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = std::pin::Pin::new(process::allocate(&vm, 0, 0, module).unwrap());
+        let heap = &process.context_mut().heap;
+
+        let args = vec![Term::int(i32::max_value()), Term::int(1)];
+        let res = add_2(&vm, &process, &args).unwrap();
+        assert_eq!(res, Term::bigint(heap, BigInt::from(i64::from(i32::max_value()) + 1)));
+        assert!(!res.is_smallint());
+
+        // demoted back once it fits
+        let args = vec![res, Term::int(-1)];
+        let res = add_2(&vm, &process, &args).unwrap();
+        assert_eq!(res, Term::int(i32::max_value()));
+        assert!(res.is_smallint());
+
+        let args = vec![Term::from(1.5), Term::int(1)];
+        let res = add_2(&vm, &process, &args);
+        assert_eq!(res, Ok(Term::from(2.5)));
+    }
+
+    #[test]
+    fn test_intdiv_2_rem_2_negative() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = std::pin::Pin::new(process::allocate(&vm, 0, 0, module).unwrap());
+
+        let args = vec![Term::int(-7), Term::int(2)];
+        assert_eq!(intdiv_2(&vm, &process, &args), Ok(Term::int(-3)));
+        assert_eq!(rem_2(&vm, &process, &args), Ok(Term::int(-1)));
+
+        let args = vec![Term::int(i32::min_value()), Term::int(-1)];
+        let res = intdiv_2(&vm, &process, &args).unwrap();
+        assert_eq!(res, Term::int64(&process.context_mut().heap, -i64::from(i32::min_value())));
+        assert_eq!(rem_2(&vm, &process, &args), Ok(Term::int(0)));
+
+        let args = vec![Term::int(1), Term::int(0)];
+        assert!(intdiv_2(&vm, &process, &args).is_err());
+        assert!(rem_2(&vm, &process, &args).is_err());
+    }
+
     // trig
 
     #[test]
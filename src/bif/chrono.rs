@@ -58,16 +58,23 @@ pub fn monotonic_time_0(vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -
     ))
 }
 
-// TODO monotonic_time_1
-pub fn monotonic_time_1(vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
-    // TODO: needs https://github.com/rust-lang/rust/issues/50202
-    // .as_nanos()
+/// Monotonic time in the given unit, measured from the start of the VM like timers with
+/// {abs, true} are.
+pub fn monotonic_time_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let heap = &process.context_mut().heap;
+    let elapsed = vm.elapsed_time();
 
-    Ok(Term::bigint(
-        heap,
-        vm.elapsed_time().as_secs().to_bigint().unwrap(),
-    ))
+    let time = match args[0].into_variant() {
+        Variant::Atom(atom::SECOND) | Variant::Atom(atom::NATIVE) => u128::from(elapsed.as_secs()),
+        Variant::Atom(atom::PERF_COUNTER) => u128::from(elapsed.as_secs()),
+        Variant::Atom(atom::MILLISECOND) => elapsed.as_millis(),
+        Variant::Atom(atom::MICROSECOND) => elapsed.as_micros(),
+        Variant::Atom(atom::NANOSECOND) => elapsed.as_nanos(),
+        // parts per second
+        Variant::Integer(parts) if parts > 0 => elapsed.as_nanos() * parts as u128 / 1_000_000_000,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+    Ok(Term::bigint(heap, time.to_bigint().unwrap()))
 }
 
 pub fn system_time_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
@@ -6,6 +6,9 @@ use crate::process::RcProcess;
 use crate::value::{self, Cons, Term, TryFrom, TryInto, Tuple, Variant};
 use crate::vm;
 use lexical;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{One, Signed};
 use std::pin::Pin;
 
 pub fn make_tuple_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
@@ -719,27 +722,27 @@ pub fn not_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Res
 
 pub fn sgt_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     Ok(Term::boolean(
-        args[0].cmp(&args[1]) == std::cmp::Ordering::Greater,
+        args[0].erl_cmp(&args[1]) == std::cmp::Ordering::Greater,
     ))
 }
 
 pub fn sge_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     // greater or equal
     Ok(Term::boolean(
-        args[0].cmp(&args[1]) != std::cmp::Ordering::Less,
+        args[0].erl_cmp(&args[1]) != std::cmp::Ordering::Less,
     ))
 }
 
 pub fn slt_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     Ok(Term::boolean(
-        args[0].cmp(&args[1]) == std::cmp::Ordering::Less,
+        args[0].erl_cmp(&args[1]) == std::cmp::Ordering::Less,
     ))
 }
 
 pub fn sle_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     // less or equal
     Ok(Term::boolean(
-        args[0].cmp(&args[1]) != std::cmp::Ordering::Greater,
+        args[0].erl_cmp(&args[1]) != std::cmp::Ordering::Greater,
     ))
 }
 
@@ -749,7 +752,7 @@ pub fn seq_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Res
 
 pub fn seqeq_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     Ok(Term::boolean(
-        args[0].cmp(&args[1]) == std::cmp::Ordering::Equal,
+        args[0].erl_cmp(&args[1]) == std::cmp::Ordering::Equal,
     ))
 }
 
@@ -759,83 +762,112 @@ pub fn sneq_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Re
 
 pub fn sneqeq_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     Ok(Term::boolean(
-        args[0].cmp(&args[1]) != std::cmp::Ordering::Equal,
+        args[0].erl_cmp(&args[1]) != std::cmp::Ordering::Equal,
     ))
 }
 
-pub fn bor_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let i1 = match args[0].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    let i2 = match args[1].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    Ok(Term::int(i1 | i2))
+/// Applies a bitwise operation on two integers of any size.
+fn bitwise_op(
+    process: &RcProcess,
+    args: &[Term],
+    small: fn(i32, i32) -> i32,
+    big: fn(BigInt, BigInt) -> BigInt,
+) -> bif::Result {
+    match (args[0].into_number(), args[1].into_number()) {
+        // and, or and xor can't overflow on small integers
+        (Ok(value::Num::Integer(i1)), Ok(value::Num::Integer(i2))) => Ok(Term::int(small(i1, i2))),
+        (Ok(n1), Ok(n2)) => match (n1.to_bigint(), n2.to_bigint()) {
+            (Some(i1), Some(i2)) => Ok(Term::bigint(&process.context_mut().heap, big(i1, i2))),
+            _ => Err(Exception::new(Reason::EXC_BADARITH)),
+        },
+        _ => Err(Exception::new(Reason::EXC_BADARITH)),
+    }
 }
 
-pub fn band_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let i1 = match args[0].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    let i2 = match args[1].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    Ok(Term::int(i1 & i2))
+pub fn bor_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    bitwise_op(process, args, |i1, i2| i1 | i2, |i1, i2| i1 | i2)
 }
 
-pub fn bxor_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let i1 = match args[0].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    let i2 = match args[1].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    Ok(Term::int(i1 ^ i2))
+pub fn band_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    bitwise_op(process, args, |i1, i2| i1 & i2, |i1, i2| i1 & i2)
 }
 
-pub fn bsl_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let i1 = match args[0].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    let i2 = match args[1].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
-    Ok(Term::int(i1 << i2))
+pub fn bxor_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    bitwise_op(process, args, |i1, i2| i1 ^ i2, |i1, i2| i1 ^ i2)
 }
 
-pub fn bsr_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let i1 = match args[0].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+/// Largest shift we allow before giving up with system_limit, in bits.
+const MAX_SHIFT: i64 = 1 << 26;
+
+/// Arithmetic shift left by shift bits, negative amounts shift right (rounding down).
+fn shift(process: &RcProcess, value: Term, shift: Term) -> bif::Result {
+    let heap = &process.context_mut().heap;
+
+    let value = match value.into_number() {
+        Ok(value::Num::Float(_)) | Err(_) => return Err(Exception::new(Reason::EXC_BADARITH)),
+        Ok(value) => value,
     };
-    let i2 = match args[1].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    // shifting by a bignum amount is clamped, it either fails or shifts everything out
+    let shift = match shift.into_number() {
+        Ok(value::Num::Integer(i)) => i64::from(i),
+        Ok(value::Num::Bignum(ref i)) if i.is_negative() => -MAX_SHIFT - 1,
+        Ok(value::Num::Bignum(_)) => MAX_SHIFT + 1,
+        _ => return Err(Exception::new(Reason::EXC_BADARITH)),
     };
-    Ok(Term::int(i1 >> i2))
+
+    match value {
+        // fast paths, an i32 shifted by less than 32 bits always fits an i64
+        value::Num::Integer(0) => Ok(Term::int(0)),
+        value::Num::Integer(i) if shift >= 0 && shift < 32 => {
+            Ok(Term::int64(heap, i64::from(i) << shift))
+        }
+        value::Num::Integer(i) if shift < 0 => Ok(Term::int(i >> (-shift).min(31))),
+        value => {
+            let i = value.to_bigint().unwrap();
+            if shift > MAX_SHIFT {
+                return Err(Exception::new(Reason::EXC_SYSTEM_LIMIT));
+            }
+            if shift >= 0 {
+                return Ok(Term::bigint(heap, i << shift as usize));
+            }
+            if -shift > i.bits() as i64 {
+                // everything got shifted out, only the sign remains
+                return Ok(Term::int(if i.is_negative() { -1 } else { 0 }));
+            }
+            let divisor = BigInt::one() << (-shift) as usize;
+            Ok(Term::bigint(heap, i.div_floor(&divisor)))
+        }
+    }
 }
 
-pub fn bnot_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let i1 = match args[0].into_variant() {
-        Variant::Integer(i) => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+pub fn bsl_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    shift(process, args[0], args[1])
+}
+
+pub fn bsr_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let amount = match args[1].into_number() {
+        Ok(value::Num::Integer(i)) => Term::int64(&process.context_mut().heap, -i64::from(i)),
+        Ok(value::Num::Bignum(i)) => Term::bigint(&process.context_mut().heap, -i),
+        _ => return Err(Exception::new(Reason::EXC_BADARITH)),
     };
-    Ok(Term::int(!i1))
+    shift(process, args[0], amount)
+}
+
+pub fn bnot_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    match args[0].into_number() {
+        Ok(value::Num::Integer(i)) => Ok(Term::int(!i)),
+        // two's complement: bnot X = -X - 1
+        Ok(value::Num::Bignum(i)) => Ok(Term::bigint(&process.context_mut().heap, -i - 1)),
+        _ => Err(Exception::new(Reason::EXC_BADARITH)),
+    }
 }
 
 pub fn sminus_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     // if number, negate number else return error badarith
     let heap = &process.context_mut().heap;
     match args[0].into_number() {
-        Ok(value::Num::Integer(i)) => Ok(Term::int(-i)),
+        // -(-2147483648) doesn't fit
+        Ok(value::Num::Integer(i)) => Ok(Term::int64(heap, -i64::from(i))),
         Ok(value::Num::Float(i)) => Ok(Term::from(-i)),
         Ok(value::Num::Bignum(i)) => Ok(Term::bigint(heap, -i)),
         _ => Err(Exception::new(Reason::EXC_BADARITH)),
@@ -1363,4 +1395,24 @@ mod tests {
         let res = list_to_iodata(list);
         assert_eq!(Ok(vec![1, 2, 3, 0xAB, 0xCD, 0xEF]), res)
     }
+
+    #[test]
+    fn test_bsl_2_bsr_2() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = std::pin::Pin::new(process::allocate(&vm, 0, 0, module).unwrap());
+        let heap = &process.context_mut().heap;
+
+        let res = bsl_2(&vm, &process, &[Term::int(1), Term::int(70)]).unwrap();
+        assert_eq!(res, Term::bigint(heap, BigInt::from(1) << 70));
+
+        let res = bsr_2(&vm, &process, &[res, Term::int(69)]).unwrap();
+        assert_eq!(res, Term::int(2));
+
+        let res = bsr_2(&vm, &process, &[Term::int(-7), Term::int(1)]).unwrap();
+        assert_eq!(res, Term::int(-4));
+
+        let res = bsl_2(&vm, &process, &[Term::int(1), Term::int(1 << 30)]);
+        assert!(res.is_err());
+    }
 }
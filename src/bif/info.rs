@@ -1,15 +1,40 @@
 use crate::atom;
 use crate::bif;
+use crate::bitstring;
 use crate::exception::{Exception, Reason};
-use crate::process::{Process, RcProcess};
+use crate::process::{self, Process, RcProcess};
 use crate::value::{self, Cons, Term, TryFrom, Variant};
 use crate::vm;
-use crate::Itertools;
+use std::cmp;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+/// The items process_info/1 returns, in order.
+const PROCESS_INFO_ITEMS: [u32; 17] = [
+    atom::REGISTERED_NAME,
+    atom::CURRENT_FUNCTION,
+    atom::INITIAL_CALL,
+    atom::STATUS,
+    atom::MESSAGE_QUEUE_LEN,
+    atom::LINKS,
+    atom::DICTIONARY,
+    atom::TRAP_EXIT,
+    atom::ERROR_HANDLER,
+    atom::PRIORITY,
+    atom::GROUP_LEADER,
+    atom::TOTAL_HEAP_SIZE,
+    atom::HEAP_SIZE,
+    atom::STACK_SIZE,
+    atom::REDUCTIONS,
+    atom::GARBAGE_COLLECTION,
+    atom::SUSPENDING,
+];
+
+/// Builds a {Item, Value} tuple describing target, on the heap of the calling process.
 pub fn process_info_aux(
     _vm: &vm::Machine,
     process: &RcProcess,
+    target: &RcProcess,
     item: Term,
     always_wrap: bool,
 ) -> bif::Result {
@@ -19,8 +44,6 @@ pub fn process_info_aux(
     // TODO: bump process regs
     // (*reds)++;
 
-    // ASSERT(rp);
-
     /*
      * Q: Why this ERTS_PI_FLAG_ALWAYS_WRAP flag?
      *
@@ -43,7 +66,9 @@ pub fn process_info_aux(
         _ => return Err(Exception::new(Reason::EXC_BADARG)),
     };
 
-    let local_data = process.local_data();
+    let local_data = target.local_data_mut();
+    let context = target.context();
+    let words = |bytes: usize| Term::uint64(heap, (bytes / process::WORD_SIZE) as u64);
 
     let res = match item {
         atom::REGISTERED_NAME => {
@@ -58,17 +83,34 @@ pub fn process_info_aux(
             }
         }
         atom::CURRENT_FUNCTION => {
-            //process.context_mut().ip.lookup_func_info()
-            unimplemented!()
+            if context.ip.module.is_null() {
+                atom!(UNDEFINED)
+            } else {
+                match context.ip.lookup_func_info() {
+                    Some((mfa, _)) => tup3!(
+                        heap,
+                        Term::atom(mfa.0),
+                        Term::atom(mfa.1),
+                        Term::uint(heap, mfa.2)
+                    ),
+                    None => atom!(UNDEFINED),
+                }
+            }
         }
         atom::CURRENT_LOCATION => {
-            let fi = process.context_mut().ip.lookup_func_info().unwrap();
-            crate::exception::erts_build_mfa_item(&fi, heap, Term::nil())
+            if context.ip.module.is_null() {
+                atom!(UNDEFINED)
+            } else {
+                match context.ip.lookup_func_info() {
+                    Some(fi) => crate::exception::erts_build_mfa_item(&fi, heap, Term::nil()),
+                    None => atom!(UNDEFINED),
+                }
+            }
         }
         atom::CURRENT_STACKTRACE => {
             let mut trace = Vec::with_capacity(crate::exception::DEFAULT_BACKTRACE_SIZE as usize);
             crate::exception::erts_save_stacktrace(
-                process,
+                target,
                 &mut trace,
                 crate::exception::DEFAULT_BACKTRACE_SIZE,
             );
@@ -91,97 +133,247 @@ pub fn process_info_aux(
             )
         }
         atom::STATUS => {
-            // TODO: quick cheat
-            atom!(RUNNING)
+            if target.pid == process.pid {
+                atom!(RUNNING)
+            } else if target.waiting_for_message.load(Ordering::Relaxed) {
+                atom!(WAITING)
+            } else {
+                atom!(RUNNABLE)
+            }
         }
         atom::MESSAGES => {
-            // TODO: quick cheat
-            Term::nil()
+            let messages: Vec<_> = local_data.mailbox.iter().copied().collect();
+            messages
+                .into_iter()
+                .rev()
+                .fold(Term::nil(), |acc, message| {
+                    // the messages live on the target's heap (or its fragments)
+                    let message = if target.pid == process.pid {
+                        message
+                    } else {
+                        message.deep_clone(heap)
+                    };
+                    cons!(heap, message, acc)
+                })
         }
         atom::MESSAGE_QUEUE_LEN => Term::uint(heap, local_data.mailbox.len() as u32),
-        atom::MESSAGE_QUEUE_DATA => unimplemented!(),
+        atom::MESSAGE_QUEUE_DATA => {
+            if local_data.flags.contains(Flag::OFF_HEAP_MSGQ) {
+                atom!(OFF_HEAP)
+            } else {
+                atom!(ON_HEAP)
+            }
+        }
         atom::LINKS => local_data
             .links
             .iter()
             .fold(Term::nil(), |acc, pid| cons!(heap, Term::pid(*pid), acc)),
-        atom::MONITORED_BY => unimplemented!(),
+        atom::MONITORS => local_data
+            .monitors
+            .values()
+            .filter(|monitor| **monitor != process::Monitor::TimeOffset)
+            .fold(Term::nil(), |acc, monitor| {
+                let (kind, object) = monitor.to_term(heap);
+                cons!(heap, tup2!(heap, kind, object), acc)
+            }),
+        atom::MONITORED_BY => local_data
+            .lt_monitors
+            .iter()
+            .fold(Term::nil(), |acc, (pid, _)| {
+                cons!(heap, Term::pid(*pid), acc)
+            }),
         atom::DICTIONARY => {
-            let pdict = &process.local_data_mut().dictionary;
-            let heap = &process.context_mut().heap;
-
+            let pdict = &local_data.dictionary;
             pdict.iter().fold(Term::nil(), |res, (key, val)| {
-                let tuple = tup2!(heap, *key, *val);
+                let (key, val) = if target.pid == process.pid {
+                    (*key, *val)
+                } else {
+                    (key.deep_clone(heap), val.deep_clone(heap))
+                };
+                let tuple = tup2!(heap, key, val);
                 cons!(heap, tuple, res)
             })
         }
         atom::TRAP_EXIT => Term::boolean(local_data.flags.contains(Flag::TRAP_EXIT)),
-        atom::ERROR_HANDLER => unimplemented!(),
-        atom::HEAP_SIZE => {
-            // TODO: temporary
-            Term::int(512)
+        atom::ERROR_HANDLER => Term::atom(local_data.error_handler),
+        atom::HEAP_SIZE => words(context.heap.size()),
+        atom::STACK_SIZE => Term::uint64(heap, context.stack.len() as u64),
+        atom::TOTAL_HEAP_SIZE => words(
+            context.heap.size()
+                + local_data.mailbox.fragments_size()
+                + context.stack.len() * process::WORD_SIZE,
+        ),
+        atom::MEMORY => {
+            let size = std::mem::size_of::<Process>()
+                + context.heap.size()
+                + local_data.mailbox.fragments_size()
+                + context.stack.capacity() * process::WORD_SIZE;
+            Term::uint64(heap, size as u64)
+        }
+        atom::GARBAGE_COLLECTION => {
+            let gc = context.gc;
+            let items = [
+                tup2!(heap, atom!(MAX_HEAP_SIZE), gc.max_heap_size.to_term(heap)),
+                tup2!(
+                    heap,
+                    atom!(MIN_BIN_VHEAP_SIZE),
+                    Term::uint64(heap, gc.min_bin_vheap_size as u64)
+                ),
+                tup2!(
+                    heap,
+                    atom!(MIN_HEAP_SIZE),
+                    Term::uint64(heap, gc.min_heap_size as u64)
+                ),
+                tup2!(
+                    heap,
+                    atom!(FULLSWEEP_AFTER),
+                    Term::uint64(heap, gc.fullsweep_after as u64)
+                ),
+                tup2!(
+                    heap,
+                    atom!(MINOR_GCS),
+                    Term::uint64(heap, context.minor_gcs as u64)
+                ),
+            ];
+            iter_to_list!(heap, items.iter().rev().copied())
         }
-        atom::STACK_SIZE => {
-            // TODO: temporary
-            Term::int(512)
+        atom::GARBAGE_COLLECTION_INFO => {
+            // the heap isn't generational, so the old heap is always empty
+            let gc = context.gc;
+            let fragments = local_data.mailbox.fragments_size();
+            let stack = Term::uint64(heap, context.stack.len() as u64);
+            let vheap_block = Term::uint64(heap, gc.min_bin_vheap_size as u64);
+            let items = [
+                tup2!(heap, atom!(OLD_HEAP_BLOCK_SIZE), Term::int(0)),
+                tup2!(heap, atom!(HEAP_BLOCK_SIZE), words(context.heap.size())),
+                tup2!(heap, atom!(MBUF_SIZE), words(fragments)),
+                tup2!(heap, atom!(RECENT_SIZE), words(context.heap.used())),
+                tup2!(heap, atom!(STACK_SIZE), stack),
+                tup2!(heap, atom!(OLD_HEAP_SIZE), Term::int(0)),
+                tup2!(heap, atom!(HEAP_SIZE), words(context.heap.used())),
+                tup2!(heap, atom!(BIN_VHEAP_SIZE), words(context.heap.binaries())),
+                tup2!(heap, atom!(BIN_VHEAP_BLOCK_SIZE), vheap_block),
+                tup2!(heap, atom!(BIN_OLD_VHEAP_SIZE), Term::int(0)),
+                tup2!(heap, atom!(BIN_OLD_VHEAP_BLOCK_SIZE), vheap_block),
+            ];
+            iter_to_list!(heap, items.iter().rev().copied())
         }
-        atom::MEMORY => {
-            // TODO: temporary
-            Term::int(1024)
-        }
-        atom::GARBAGE_COLLECTION => unimplemented!(),
-        atom::GARBAGE_COLLECTION_INFO => unimplemented!(),
-        atom::GROUP_LEADER => unimplemented!(),
-        atom::REDUCTIONS => Term::uint(heap, process.context().reds as u32),
-        atom::PRIORITY => unimplemented!(),
-        atom::TRACE => unimplemented!(),
-        atom::BINARY => unimplemented!(),
-        atom::SEQUENTIAL_TRACE_TOKEN => unimplemented!(),
-        atom::CATCH_LEVEL => unimplemented!(),
-        atom::BACKTRACE => unimplemented!(),
-        atom::LAST_CALLS => unimplemented!(),
-        atom::TOTAL_HEAP_SIZE => unimplemented!(),
-        atom::SUSPENDING => unimplemented!(),
-        atom::MIN_HEAP_SIZE => unimplemented!(),
-        atom::MIN_BIN_VHEAP_SIZE => unimplemented!(),
-        atom::MAX_HEAP_SIZE => unimplemented!(),
-        atom::MAGIC_REF => unimplemented!(),
-        atom::FULLSWEEP_AFTER => unimplemented!(),
+        atom::GROUP_LEADER => Term::pid(local_data.group_leader),
+        atom::REDUCTIONS => Term::uint64(heap, context.reductions() as u64),
+        atom::PRIORITY => local_data.priority().to_priority(),
+        atom::TRACE => Term::uint(heap, u32::from(local_data.trace.bits())),
+        // off-heap binaries aren't tracked per process
+        atom::BINARY => Term::nil(),
+        // sequential tracing isn't supported, so there's never a token
+        atom::SEQUENTIAL_TRACE_TOKEN => Term::nil(),
+        atom::CATCH_LEVEL => Term::uint64(heap, context.catches as u64),
+        atom::BACKTRACE => {
+            let mut text = String::new();
+            if !context.ip.module.is_null() {
+                if let Some((mfa, _)) = context.ip.lookup_func_info() {
+                    text.push_str(&format!("Program counter: ({})\n", mfa));
+                }
+            }
+            let mut trace = Vec::with_capacity(crate::exception::DEFAULT_BACKTRACE_SIZE as usize);
+            crate::exception::erts_save_stacktrace(
+                target,
+                &mut trace,
+                crate::exception::DEFAULT_BACKTRACE_SIZE,
+            );
+            for (mfa, _) in trace.iter().filter_map(|ptr| ptr.lookup_func_info()) {
+                text.push_str(&format!("cp: ({})\n", mfa));
+            }
+            Term::binary(heap, bitstring::Binary::from(text.into_bytes()))
+        }
+        // call saving is never enabled
+        atom::LAST_CALLS => atom!(FALSE),
+        // processes can't be suspended yet
+        atom::SUSPENDING => Term::nil(),
+        atom::MIN_HEAP_SIZE => Term::uint64(heap, context.gc.min_heap_size as u64),
+        atom::MIN_BIN_VHEAP_SIZE => Term::uint64(heap, context.gc.min_bin_vheap_size as u64),
+        atom::MAX_HEAP_SIZE => context.gc.max_heap_size.to_term(heap),
+        // no magic binaries are tracked per process either
+        atom::MAGIC_REF => Term::nil(),
+        atom::FULLSWEEP_AFTER => Term::uint64(heap, context.gc.fullsweep_after as u64),
         _ => return Err(Exception::new(Reason::EXC_BADARG)),
     };
 
     Ok(tup2!(heap, Term::atom(item), res))
 }
 
-pub fn process_info_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    // args are pid, `[item, .. ]` or just `item`.
-    // response is `[tup,..]` or just `tup`
-    if !args[0].is_pid() {
+/// Looks up the process a process_info call is about.
+fn process_info_target(vm: &vm::Machine, pid: Term) -> Result<Option<RcProcess>, Exception> {
+    if !pid.is_pid() {
         return Err(Exception::new(Reason::EXC_BADARG));
     }
+    // TODO optimize for if process.pid == pid
+    Ok(vm.process_table.lock().get(pid.to_u32()))
+}
 
-    let pid = args[0].to_u32();
+pub fn process_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let target = match process_info_target(vm, args[0])? {
+        Some(target) => target,
+        None => return Ok(atom!(UNDEFINED)),
+    };
+    // another process is only looked at while it's parked, so it can't run (or collect its heap)
+    // underneath us
+    let _inspection = match target.inspect(process) {
+        Some(inspection) => inspection,
+        None => return Ok(atom!(UNDEFINED)),
+    };
 
-    // TODO optimize for if process.pid == pid
-    let proc = {
-        let table = vm.process_table.lock();
-        table.get(pid)
+    let mut items = Vec::with_capacity(PROCESS_INFO_ITEMS.len());
+    for item in PROCESS_INFO_ITEMS.iter() {
+        let res = process_info_aux(vm, process, &target, Term::atom(*item), false)?;
+        // registered_name is left out if the process isn't registered
+        if !res.is_nil() {
+            items.push(res);
+        }
+    }
+    let heap = &process.context_mut().heap;
+    Ok(iter_to_list!(heap, items.into_iter().rev()))
+}
+
+pub fn process_info_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    // args are pid, `[item, .. ]` or just `item`.
+    // response is `[tup,..]` or just `tup`
+    let target = match process_info_target(vm, args[0])? {
+        Some(target) => target,
+        None => return Ok(atom!(UNDEFINED)),
     };
 
-    if let Some(proc) = proc {
-        match Cons::try_from(&args[1]) {
-            Ok(cons) => {
-                let heap = &process.context_mut().heap;
-                cons.iter()
-                    .map(|val| process_info_aux(vm, &proc, *val, true))
-                    .fold_results(Term::nil(), |acc, val| cons!(heap, val, acc))
-            }
-            _ => process_info_aux(vm, &proc, args[1], false),
+    if args[1].is_nil() {
+        return Ok(Term::nil());
+    }
+    let _inspection = match target.inspect(process) {
+        Some(inspection) => inspection,
+        None => return Ok(atom!(UNDEFINED)),
+    };
+    match Cons::try_from(&args[1]) {
+        Ok(cons) => {
+            let items = cons
+                .iter()
+                .map(|item| process_info_aux(vm, process, &target, *item, true))
+                .collect::<Result<Vec<_>, _>>()?;
+            let heap = &process.context_mut().heap;
+            Ok(iter_to_list!(heap, items.into_iter().rev()))
         }
-    } else {
-        return Ok(atom!(UNDEFINED));
+        _ => process_info_aux(vm, process, &target, args[1], false),
     }
 }
 
+pub fn processes_0(vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let pids = vm.process_table.lock().pids();
+    Ok(iter_to_list!(heap, pids.into_iter().rev().map(Term::pid)))
+}
+
+pub fn registered_0(vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let names = vm.process_registry.lock().names();
+    Ok(iter_to_list!(heap, names.into_iter().map(Term::atom)))
+}
+
 pub fn fun_info_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let heap = &process.context_mut().heap;
     if let Ok(closure) = value::Closure::try_from(&args[0]) {
@@ -232,7 +424,6 @@ const OS_FAMILY: u32 = atom::UNIX;
 const OS_FAMILY: u32 = atom::WIN32;
 
 pub fn system_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    use std::sync::atomic::Ordering;
     let heap = &process.context_mut().heap;
 
     match args[0].into_variant() {
@@ -251,6 +442,9 @@ pub fn system_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bi
         Variant::Atom(atom::OTP_RELEASE) => {
             Ok(bitstring!(heap, "22"))
         }
+        Variant::Atom(atom::PROCESS_COUNT) => {
+            Ok(Term::uint64(heap, vm.process_table.lock().len() as u64))
+        }
         // Variant::Atom(atom::START_TIME) => {
         //     Ok(Term::int(vm.start_time))
         // }
@@ -269,8 +463,228 @@ pub fn system_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bi
     }
 }
 
+/// Returns {Total, SinceLastCall}, remembering total for the next call.
+fn since_last(heap: &crate::immix::Heap, total: usize, last: &AtomicUsize) -> Term {
+    let last = last.swap(total, Ordering::Relaxed);
+    tup2!(
+        heap,
+        Term::uint64(heap, total as u64),
+        Term::uint64(heap, total.saturating_sub(last) as u64)
+    )
+}
+
+pub fn statistics_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+
+    // processes (and dirty BIF calls) that are runnable or running: (normal, dirty cpu)
+    let active = || {
+        let normal: usize = vm
+            .run_queues
+            .iter()
+            .map(|queue| queue.load(Ordering::Relaxed))
+            .sum();
+        (normal, vm.dirty_cpu_tasks.load(Ordering::Relaxed))
+    };
+    // same, minus the ones currently running
+    let queued = || {
+        let (normal, dirty) = active();
+        (
+            normal.saturating_sub(vm.running.load(Ordering::Relaxed)),
+            dirty.saturating_sub(vm.dirty_cpu_running.load(Ordering::Relaxed)),
+        )
+    };
+
+    match args[0].into_variant() {
+        Variant::Atom(atom::REDUCTIONS) | Variant::Atom(atom::EXACT_REDUCTIONS) => {
+            // include the reductions of our own time slice
+            let used = process.context().slice_reductions();
+            let total = vm.reductions.load(Ordering::Relaxed) + used;
+            let last = if args[0] == atom!(REDUCTIONS) {
+                &vm.last_reductions
+            } else {
+                &vm.last_exact_reductions
+            };
+            Ok(since_last(heap, total, last))
+        }
+        Variant::Atom(atom::RUNTIME) => {
+            let total = vm.run_time.load(Ordering::Relaxed) / 1000;
+            Ok(since_last(heap, total, &vm.last_run_time))
+        }
+        Variant::Atom(atom::WALL_CLOCK) => {
+            let total = vm.start_time.elapsed().as_millis() as usize;
+            Ok(since_last(heap, total, &vm.last_wall_clock))
+        }
+        Variant::Atom(atom::CONTEXT_SWITCHES) => {
+            let switches = vm.context_switches.load(Ordering::Relaxed);
+            Ok(tup2!(
+                heap,
+                Term::uint64(heap, switches as u64),
+                Term::int(0)
+            ))
+        }
+        Variant::Atom(atom::GARBAGE_COLLECTION) => {
+            let gcs = vm.garbage_collections.load(Ordering::Relaxed);
+            let words = vm.words_reclaimed.load(Ordering::Relaxed);
+            Ok(tup3!(
+                heap,
+                Term::uint64(heap, gcs as u64),
+                Term::uint64(heap, words as u64),
+                Term::int(0)
+            ))
+        }
+        Variant::Atom(atom::IO) => {
+            let input = vm.io_input.load(Ordering::Relaxed);
+            let output = vm.io_output.load(Ordering::Relaxed);
+            Ok(tup2!(
+                heap,
+                tup2!(heap, atom!(INPUT), Term::uint64(heap, input as u64)),
+                tup2!(heap, atom!(OUTPUT), Term::uint64(heap, output as u64))
+            ))
+        }
+        Variant::Atom(atom::RUN_QUEUE) => {
+            let (normal, dirty) = queued();
+            Ok(Term::uint64(heap, (normal + dirty) as u64))
+        }
+        // All the schedulers share a single run queue, followed by the dirty CPU one.
+        Variant::Atom(atom::RUN_QUEUE_LENGTHS) => {
+            let (normal, dirty) = queued();
+            Ok(cons!(
+                heap,
+                Term::uint64(heap, normal as u64),
+                cons!(heap, Term::uint64(heap, dirty as u64), Term::nil())
+            ))
+        }
+        Variant::Atom(atom::ACTIVE_TASKS) => {
+            let (normal, dirty) = active();
+            Ok(cons!(
+                heap,
+                Term::uint64(heap, normal as u64),
+                cons!(heap, Term::uint64(heap, dirty as u64), Term::nil())
+            ))
+        }
+        Variant::Atom(atom::SCHEDULER_WALL_TIME) => {
+            let start = match *vm.scheduler_wall_time.lock() {
+                Some(start) => start,
+                None => return Ok(atom!(UNDEFINED)),
+            };
+            let total = start.elapsed().as_micros() as u64;
+            let schedulers = vm.schedulers.lock();
+            Ok(iter_to_list!(
+                heap,
+                schedulers.iter().rev().map(|scheduler| {
+                    let active = scheduler.active.load(Ordering::Relaxed) as u64;
+                    tup3!(
+                        heap,
+                        Term::uint64(heap, scheduler.id as u64),
+                        Term::uint64(heap, cmp::min(active, total)),
+                        Term::uint64(heap, total)
+                    )
+                })
+            ))
+        }
+        _ => Err(Exception::new(Reason::EXC_BADARG)),
+    }
+}
+
+/// Memory the emulator uses, in bytes, for each of the memory/0 keys.
+fn memory_usage(vm: &vm::Machine, kind: u32) -> Option<usize> {
+    use crate::immix::{block, Kind};
+    let processes = || {
+        let count = vm.process_table.lock().len();
+        count * std::mem::size_of::<Process>()
+    };
+    let system = || {
+        crate::atom::ATOMS.memory().0
+            + crate::bitstring::memory()
+            + vm.modules.lock().memory()
+            + block::allocated(Kind::Literal)
+            + block::allocated(Kind::Ets)
+            + block::allocated(Kind::PersistentTerm)
+            + block::pooled()
+    };
+
+    let size = match kind {
+        atom::TOTAL => block::allocated(Kind::Process) + processes() + system(),
+        atom::PROCESSES => block::allocated(Kind::Process) + processes(),
+        atom::PROCESSES_USED => block::used(Kind::Process) + processes(),
+        atom::SYSTEM => system(),
+        atom::ATOM => crate::atom::ATOMS.memory().0,
+        atom::ATOM_USED => crate::atom::ATOMS.memory().1,
+        atom::BINARY => crate::bitstring::memory(),
+        atom::CODE => vm.modules.lock().memory() + block::allocated(Kind::Literal),
+        atom::ETS => block::allocated(Kind::Ets),
+        _ => return None,
+    };
+    Some(size)
+}
+
+const MEMORY_KEYS: [u32; 9] = [
+    atom::TOTAL,
+    atom::PROCESSES,
+    atom::PROCESSES_USED,
+    atom::SYSTEM,
+    atom::ATOM,
+    atom::ATOM_USED,
+    atom::BINARY,
+    atom::CODE,
+    atom::ETS,
+];
+
+pub fn memory_0(vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+
+    Ok(iter_to_list!(
+        heap,
+        MEMORY_KEYS.iter().rev().map(|key| {
+            let size = memory_usage(vm, *key).unwrap();
+            tup2!(heap, Term::atom(*key), Term::uint64(heap, size as u64))
+        })
+    ))
+}
+
+pub fn memory_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+
+    let usage = |term: &Term| match term.into_variant() {
+        Variant::Atom(key) => {
+            memory_usage(vm, key).ok_or_else(|| Exception::new(Reason::EXC_BADARG))
+        }
+        _ => Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    if args[0].is_nil() {
+        return Ok(Term::nil());
+    }
+    if args[0].is_atom() {
+        let size = usage(&args[0])?;
+        return Ok(Term::uint64(heap, size as u64));
+    }
+
+    let cons = Cons::try_from(&args[0])?;
+    let sizes = cons
+        .iter()
+        .map(|key| usage(key).map(|size| (*key, size)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(iter_to_list!(
+        heap,
+        sizes.into_iter().rev().map(|(key, size)| tup2!(
+            heap,
+            key,
+            Term::uint64(heap, size as u64)
+        ))
+    ))
+}
+
+pub fn bump_reductions_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let reds = match args[0].into_variant() {
+        Variant::Integer(i) if i >= 0 => i as usize,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+    process.context_mut().bump_reductions(reds);
+    Ok(atom!(TRUE))
+}
+
 pub fn system_flag_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    use std::sync::atomic::Ordering;
     match args[0].into_variant() {
         Variant::Atom(atom::SYSTEM_LOGGER) => {
             let pid = match args[1].into_variant() {
@@ -281,10 +695,25 @@ pub fn system_flag_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> b
             let old_pid = vm.system_logger.swap(pid as usize, Ordering::Relaxed);
             Ok(Term::pid(old_pid as u32)) // TODO: unsafe
         }
+        Variant::Atom(atom::SCHEDULER_WALL_TIME) => scheduler_wall_time(vm, args[1]),
         _ => unimplemented!(),
     }
 }
 
+/// Turns scheduler wall time accounting on or off, returns the previous setting.
+fn scheduler_wall_time(vm: &vm::Machine, enable: Term) -> bif::Result {
+    let enable = match enable.into_variant() {
+        Variant::Atom(atom::TRUE) => true,
+        Variant::Atom(atom::FALSE) => false,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+    Ok(Term::boolean(vm.set_scheduler_wall_time(enable)))
+}
+
+pub fn scheduler_wall_time_1(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
+    scheduler_wall_time(vm, args[0])
+}
+
 pub fn group_leader_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
     Ok(Term::pid(process.local_data().group_leader))
 }
@@ -3,7 +3,8 @@ use crate::bif;
 use crate::exception::{Exception, Reason};
 use crate::loader::Loader;
 use crate::module::{self, Module};
-use crate::process::RcProcess;
+use crate::process::{self, RcProcess};
+use crate::signal_queue;
 use crate::value::{self, Cons, Term, TryFrom, TryInto, Variant};
 use crate::vm;
 use std::pin::Pin;
@@ -60,19 +61,254 @@ pub fn has_prepared_code_on_load_1(
     }
 }
 
-pub fn finish_loading_1(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    value::Cons::try_from(&args[0])?
+pub fn finish_loading_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let mods = value::Cons::try_from(&args[0])?
         .iter()
-        .map(|v| {
-            v.try_into()
-                .map(|value: &*mut Module| unsafe { Box::from_raw(*value) })
-        })
-        .collect::<Result<Vec<Box<Module>>, _>>()
-        .map_err(|_| Exception::new(Reason::EXC_BADARG))
-        .and_then(|mods| {
-            module::finish_loading_modules(vm, mods);
-            Ok(atom!(OK))
-        })
+        .map(|v| v.try_into().map(|value: &*mut Module| *value))
+        .collect::<Result<Vec<*mut Module>, _>>()
+        .map_err(|_| Exception::new(Reason::EXC_BADARG))?;
+
+    // we only keep two versions of a module around, the old code has to be purged first.
+    let not_purged: Vec<Term> = {
+        let registry = vm.modules.lock();
+        mods.iter()
+            .map(|module| unsafe { (**module).name })
+            .filter(|name| registry.has_old_code(*name))
+            .map(Term::atom)
+            .collect()
+    };
+
+    if !not_purged.is_empty() {
+        let heap = &process.context_mut().heap;
+        return Ok(tup2!(
+            heap,
+            atom!(NOT_PURGED),
+            Cons::from_iter(not_purged.into_iter(), heap)
+        ));
+    }
+
+    let mods = mods
+        .into_iter()
+        .map(|module| unsafe { Box::from_raw(module) })
+        .collect();
+    module::finish_loading_modules(vm, mods);
+    Ok(atom!(OK))
+}
+
+pub fn check_old_code_1(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
+    let name = match args[0].into_variant() {
+        Variant::Atom(i) => i,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    Ok(Term::boolean(vm.modules.lock().has_old_code(name)))
+}
+
+pub fn delete_module_1(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
+    let name = match args[0].into_variant() {
+        Variant::Atom(i) => i,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    let mut registry = vm.modules.lock();
+
+    // the old code needs to be purged before we can make the current code old
+    if registry.has_old_code(name) {
+        return Err(Exception::new(Reason::EXC_BADARG));
+    }
+
+    if !registry.delete(name) {
+        return Ok(atom!(UNDEFINED));
+    }
+
+    vm.exports.write().remove_module(name);
+    Ok(atom!(TRUE))
+}
+
+pub fn check_process_code_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    check_process_code_3(vm, process, &[args[0], args[1], Term::nil()])
+}
+
+pub fn check_process_code_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let pid = match args[0].into_variant() {
+        Variant::Pid(pid) => pid,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    let name = match args[1].into_variant() {
+        Variant::Atom(i) => i,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    let mut request_id = None;
+
+    if !args[2].is_nil() {
+        for opt in Cons::try_from(&args[2])?.iter() {
+            let tup = value::Tuple::try_from(opt)?;
+            if tup.len() != 2 {
+                return Err(Exception::new(Reason::EXC_BADARG));
+            }
+            match tup[0].into_variant() {
+                Variant::Atom(atom::ASYNC) => request_id = Some(tup[1]),
+                // we don't need to gc to find out if literals are referenced
+                Variant::Atom(atom::ALLOW_GC) => (),
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            }
+        }
+    }
+
+    let old = vm
+        .modules
+        .lock()
+        .lookup_old(name)
+        .map(|old| old as *const Module);
+    let target = vm.process_table.lock().get(pid);
+
+    let (old, target) = match (old, target) {
+        (Some(old), Some(target)) => (old, target),
+        _ => return check_process_code_reply(process, request_id, false),
+    };
+
+    match request_id {
+        // the target answers by itself once it handles the signal
+        Some(request_id) if target.pid != process.pid => {
+            let (request_id, heap) = signal_queue::copy_to_fragment(request_id);
+            target.send_signal(process::Signal::CheckProcessCode {
+                from: process.pid,
+                module: old,
+                request_id,
+                heap,
+            });
+            Ok(atom!(ASYNC))
+        }
+        _ => {
+            let res = match target.inspect(process) {
+                Some(_inspection) => module::check_process_code(&target, old),
+                None => false, // exited in the meantime
+            };
+            check_process_code_reply(process, request_id, res)
+        }
+    }
+}
+
+/// Returns the result of check_process_code, or sends it back in a message for async requests.
+fn check_process_code_reply(
+    process: &RcProcess,
+    request_id: Option<Term>,
+    res: bool,
+) -> bif::Result {
+    match request_id {
+        Some(request_id) => {
+            let heap = &process.context_mut().heap;
+            let msg = tup3!(
+                heap,
+                atom!(CHECK_PROCESS_CODE),
+                request_id,
+                Term::boolean(res)
+            );
+            process.send_message(process.pid, msg);
+            Ok(atom!(ASYNC))
+        }
+        None => Ok(Term::boolean(res)),
+    }
+}
+
+/// Purges the old code of a module, killing any processes still executing it. If soft is set,
+/// processes are left alone and the purge fails instead. Returns (purged, killed).
+fn purge(vm: &vm::Machine, process: &RcProcess, name: u32, soft: bool) -> (bool, bool) {
+    let (old, literals) = match vm.modules.lock().lookup_old(name) {
+        Some(old) => {
+            let literals: Vec<_> = old.literal_heap.blocks().collect();
+            (old as *const Module, literals)
+        }
+        None => return (false, false),
+    };
+
+    // The registry isn't locked while we wait for each process to park, since they might need it
+    // to get there.
+    let mut killed = Vec::new();
+    let mut users = Vec::new();
+    for target in vm.processes() {
+        let _inspection = match target.inspect(process) {
+            Some(inspection) => inspection,
+            None => continue, // exited in the meantime
+        };
+        if module::check_process_code(&target, old) {
+            killed.push(target.pid);
+        } else if module::check_process_heap(&target, &literals) {
+            // there's no literal area collector to copy the literals over, so they're kept
+            // around for as long as the process lives
+            users.push(target.pid);
+        }
+    }
+
+    if soft && !killed.is_empty() {
+        return (false, false);
+    }
+
+    for pid in &killed {
+        let signal = process::Signal::exit(
+            process.pid,
+            &Exception::with_value(Reason::EXC_EXIT, atom!(KILL)),
+            process::ExitKind::Exit,
+        );
+        process::send_signal(vm, *pid, signal);
+    }
+
+    let mut registry = vm.modules.lock();
+    let old = match registry.lookup_old(name) {
+        Some(current) if current as *const Module == old => registry.purge(name).unwrap(),
+        // someone else purged it while we were looking
+        _ => return (true, !killed.is_empty()),
+    };
+
+    // the killed processes keep running the code until they handle the signal
+    users.extend(&killed);
+    registry.release(old, users);
+    let process_table = vm.process_table.lock();
+    registry.release_unused(|pid| process_table.contains_key(pid));
+
+    (true, !killed.is_empty())
+}
+
+pub fn purge_module_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let name = match args[0].into_variant() {
+        Variant::Atom(i) => i,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    match purge(vm, process, name, false) {
+        (true, _) => Ok(atom!(TRUE)),
+        (false, _) => Err(Exception::new(Reason::EXC_BADARG)),
+    }
+}
+
+/// erts_code_purger:purge/1, done synchronously instead of via the purger process.
+pub fn purger_purge_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let name = match args[0].into_variant() {
+        Variant::Atom(i) => i,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    let (purged, killed) = purge(vm, process, name, false);
+    let heap = &process.context_mut().heap;
+    Ok(tup2!(heap, Term::boolean(purged), Term::boolean(killed)))
+}
+
+/// erts_code_purger:soft_purge/1, done synchronously instead of via the purger process.
+pub fn purger_soft_purge_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let name = match args[0].into_variant() {
+        Variant::Atom(i) => i,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
+
+    // nothing to purge counts as a successful purge
+    if !vm.modules.lock().has_old_code(name) {
+        return Ok(atom!(TRUE));
+    }
+
+    let (purged, _) = purge(vm, process, name, true);
+    Ok(Term::boolean(purged))
 }
 
 pub fn get_module_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
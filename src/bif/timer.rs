@@ -1,40 +1,193 @@
+use crate::atom;
 use crate::bif;
 use crate::exception::{Exception, Reason};
 use crate::process::{self, RcProcess};
-use crate::value::{self, Term, TryFrom, Tuple, Variant};
+use crate::timer::Dest;
+use crate::value::{self, Cons, Term, TryFrom, Tuple, Variant};
 use crate::vm;
-use std::pin::Pin;
+use num_traits::ToPrimitive;
 
 use std::time::{Duration, Instant};
-use tokio::prelude::*;
-use tokio::timer::Delay;
 
-pub fn send_after_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    // time, dest, msg
-    let delay = match args[0].to_int() {
-        Some(i) if i >= 0 => i,
-        _ => return Err(Exception::new(Reason::EXC_BADARG)),
-    };
+/// Parses the options of start_timer/4 and send_after/4, returns true if the time is absolute.
+fn timer_opts(opts: Term) -> Result<bool, Exception> {
+    let mut abs = false;
+    if opts.is_nil() {
+        return Ok(abs);
+    }
+    for opt in Cons::try_from(&opts)?.iter() {
+        let tup = Tuple::try_from(opt)?;
+        if tup.len != 2 {
+            return Err(Exception::new(Reason::EXC_BADARG));
+        }
+        match (tup[0].into_variant(), tup[1].into_variant()) {
+            (Variant::Atom(atom::ABS), Variant::Atom(atom::TRUE)) => abs = true,
+            (Variant::Atom(atom::ABS), Variant::Atom(atom::FALSE)) => abs = false,
+            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+        }
+    }
+    Ok(abs)
+}
 
-    if !args[1].is_pid() {
-        return Err(Exception::new(Reason::EXC_BADARG));
+/// Parses a timer's time in milliseconds. Like OTP, any integer that fits in 64 bits is taken.
+fn timer_time(time: Term) -> Result<i64, Exception> {
+    match time.into_number() {
+        Ok(value::Num::Integer(i)) => Ok(i64::from(i)),
+        Ok(value::Num::Bignum(i)) => i.to_i64().ok_or_else(|| Exception::new(Reason::EXC_BADARG)),
+        _ => Err(Exception::new(Reason::EXC_BADARG)),
     }
+}
 
-    let dest = args[1];
-    let msg = args[2];
-    let from = process.pid;
+/// Starts a timer. Time is in milliseconds, relative to now, or to the start of the VM (the
+/// monotonic time base) if abs is set. If timeout is set, the message gets wrapped in a
+/// {timeout, TimerRef, Msg} tuple.
+fn start_timer(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    args: &[Term],
+    abs: bool,
+    timeout: bool,
+) -> bif::Result {
+    // time, dest, msg
+    let time = timer_time(args[0])?;
+    let deadline = if abs {
+        // absolute times in the past fire right away
+        let offset = Duration::from_millis(time.max(0) as u64);
+        vm.start_time
+            .checked_add(offset)
+            .map(|when| when.max(Instant::now()))
+    } else if time >= 0 {
+        Instant::now().checked_add(Duration::from_millis(time as u64))
+    } else {
+        None
+    };
+    let deadline = deadline.ok_or_else(|| Exception::new(Reason::EXC_BADARG))?;
 
-    let when = Instant::now() + Duration::from_millis(u64::from(delay));
-    let fut = Delay::new(when)
-        .and_then(move |_| {
-            vm::Machine::with_current(|vm| process::send_message(vm, from, dest, msg));
-            Ok(())
-        })
-        .map_err(|e| panic!("delay errored; err={:?}", e));
-    vm.runtime.executor().spawn(fut);
+    let dest = match args[1].into_variant() {
+        Variant::Pid(pid) => Dest::Pid(pid),
+        Variant::Atom(name) => Dest::Name(name),
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
+    };
 
     let heap = &process.context_mut().heap;
     let reference = vm.next_ref();
     let ref_term = Term::reference(heap, reference);
+
+    let message = if timeout {
+        tup3!(heap, atom!(TIMEOUT), ref_term, args[2])
+    } else {
+        args[2]
+    };
+    // build the message now, it gets copied out of our heap which might be gone by then
+    let signal = process::Signal::message(process.pid, message);
+
+    vm.timers.start(vm, reference, deadline, dest, signal);
     Ok(ref_term)
 }
+
+pub fn send_after_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    start_timer(vm, process, args, false, false)
+}
+
+pub fn send_after_4(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let abs = timer_opts(args[3])?;
+    start_timer(vm, process, args, abs, false)
+}
+
+pub fn start_timer_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    start_timer(vm, process, args, false, true)
+}
+
+pub fn start_timer_4(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let abs = timer_opts(args[3])?;
+    start_timer(vm, process, args, abs, true)
+}
+
+/// Parses the options of cancel_timer/2 and read_timer/2: returns (async, info). Only
+/// cancel_timer takes the info option.
+fn cancel_opts(opts: Term, allow_info: bool) -> Result<(bool, bool), Exception> {
+    let (mut is_async, mut info) = (false, true);
+    if opts.is_nil() {
+        return Ok((is_async, info));
+    }
+    for opt in Cons::try_from(&opts)?.iter() {
+        let tup = Tuple::try_from(opt)?;
+        if tup.len != 2 {
+            return Err(Exception::new(Reason::EXC_BADARG));
+        }
+        let value = match tup[1].into_variant() {
+            Variant::Atom(atom::TRUE) => true,
+            Variant::Atom(atom::FALSE) => false,
+            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+        };
+        match tup[0].into_variant() {
+            Variant::Atom(atom::ASYNC) => is_async = value,
+            Variant::Atom(atom::INFO) if allow_info => info = value,
+            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+        }
+    }
+    Ok((is_async, info))
+}
+
+fn timer_ref(term: Term) -> Result<process::Ref, Exception> {
+    if term.get_boxed_header() == Ok(value::BOXED_REF) {
+        return Ok(*term.get_boxed_value::<process::Ref>().unwrap());
+    }
+    Err(Exception::new(Reason::EXC_BADARG))
+}
+
+/// The time left on a timer in milliseconds, or false if it's gone.
+fn time_left(process: &RcProcess, left: Option<Duration>) -> Term {
+    let heap = &process.context_mut().heap;
+    match left {
+        Some(left) => Term::uint64(heap, left.as_millis() as u64),
+        None => atom!(FALSE),
+    }
+}
+
+/// Handles a cancel_timer or read_timer request. Asynchronous requests are answered with a
+/// {Kind, TimerRef, Result} message, since our timers can be accessed right away they get sent
+/// before we return.
+fn timer_request(
+    process: &RcProcess,
+    reference: Term,
+    kind: u32,
+    res: Term,
+    is_async: bool,
+    info: bool,
+) -> bif::Result {
+    if !info {
+        return Ok(atom!(OK));
+    }
+    if is_async {
+        let heap = &process.context_mut().heap;
+        let reply = tup3!(heap, Term::atom(kind), reference, res);
+        process.send_message(process.pid, reply);
+        return Ok(atom!(OK));
+    }
+    Ok(res)
+}
+
+pub fn cancel_timer_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let reference = timer_ref(args[0])?;
+    Ok(time_left(process, vm.timers.cancel(reference)))
+}
+
+pub fn cancel_timer_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let reference = timer_ref(args[0])?;
+    let (is_async, info) = cancel_opts(args[1], true)?;
+    let res = time_left(process, vm.timers.cancel(reference));
+    timer_request(process, args[0], atom::CANCEL_TIMER, res, is_async, info)
+}
+
+pub fn read_timer_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let reference = timer_ref(args[0])?;
+    Ok(time_left(process, vm.timers.read(reference)))
+}
+
+pub fn read_timer_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let reference = timer_ref(args[0])?;
+    let (is_async, _) = cancel_opts(args[1], false)?;
+    let res = time_left(process, vm.timers.read(reference));
+    timer_request(process, args[0], atom::READ_TIMER, res, is_async, true)
+}
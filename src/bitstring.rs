@@ -1,11 +1,13 @@
+use crate::exception::{Exception, Reason};
 use crate::immix::Heap;
 use crate::process::RcProcess;
 use crate::servo_arc::Arc;
 use crate::value::{self, Term, TryFrom};
+use num_bigint::{BigInt, Sign};
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 use std::pin::Pin;
-// use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 // use std::cell::UnsafeCell;
 
 /// make_mask(n) constructs a mask with n bits.
@@ -52,10 +54,21 @@ pub struct Binary {
 
     /// The actual underlying bits.
     pub data: Vec<u8>,
+
+    /// The capacity of data last added to BINARY_MEMORY.
+    accounted: AtomicUsize,
 }
 
 pub type RcBinary = Arc<Binary>;
 
+/// Total capacity of all the binaries, in bytes.
+static BINARY_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// Memory used by the off-heap binaries, in bytes.
+pub fn memory() -> usize {
+    BINARY_MEMORY.load(AtomicOrdering::Relaxed)
+}
+
 impl Binary {
     pub fn new() -> Self {
         Binary {
@@ -63,7 +76,9 @@ impl Binary {
             // WRITABLE | ACTIVE_WRITER
             is_writable: true,
             data: Vec::new(),
+            accounted: AtomicUsize::new(0),
         }
+        .track()
     }
 
     pub fn with_capacity(cap: usize) -> Self {
@@ -72,7 +87,9 @@ impl Binary {
             // WRITABLE | ACTIVE_WRITER
             is_writable: true,
             data: Vec::with_capacity(cap),
+            accounted: AtomicUsize::new(0),
         }
+        .track()
     }
 
     #[allow(clippy::mut_from_ref)]
@@ -81,26 +98,36 @@ impl Binary {
         unsafe { &mut *(&self.data as *const Vec<u8> as *mut Vec<u8>) }
     }
 
-    pub fn put_integer(&mut self, size: usize, int: Term) {
-        if let value::Variant::Integer(value) = int.into_variant() {
-            match size {
-                8 => self.data.push(value as u8),
-                _ => unimplemented!("bs_put_binary size {:?}", size),
-            }
+    /// Makes sure there's room for at least size bytes, same as get_mut but keeps the memory
+    /// accounting up to date.
+    pub fn reserve(&self, size: usize) {
+        let data = self.get_mut();
+        if data.capacity() < size {
+            data.reserve(2 * size); // why 2*?
+            self.account();
+        }
+    }
+
+    /// Updates BINARY_MEMORY after the capacity of data changed.
+    fn account(&self) {
+        let capacity = self.data.capacity();
+        let old = self.accounted.swap(capacity, AtomicOrdering::Relaxed);
+        if capacity > old {
+            BINARY_MEMORY.fetch_add(capacity - old, AtomicOrdering::Relaxed);
         } else {
-            panic!("Bad argument to put_integer")
+            BINARY_MEMORY.fetch_sub(old - capacity, AtomicOrdering::Relaxed);
         }
     }
 
-    pub fn put_binary(&mut self, binary: &RcBinary) {
-        self.data.extend_from_slice(&binary.data);
+    fn track(self) -> Self {
+        self.account();
+        self
     }
+}
 
-    pub fn put_float(&mut self, float: f64) {
-        unsafe {
-            let bytes: [u8; 8] = std::mem::transmute(float);
-            self.data.extend_from_slice(&bytes);
-        }
+impl Drop for Binary {
+    fn drop(&mut self) {
+        BINARY_MEMORY.fetch_sub(*self.accounted.get_mut(), AtomicOrdering::Relaxed);
     }
 }
 
@@ -111,7 +138,9 @@ impl From<Vec<u8>> for Binary {
             // WRITABLE | ACTIVE_WRITER
             is_writable: true,
             data: value,
+            accounted: AtomicUsize::new(0),
         }
+        .track()
     }
 }
 
@@ -122,7 +151,9 @@ impl From<&[u8]> for Binary {
             // WRITABLE | ACTIVE_WRITER
             is_writable: true,
             data: value.to_vec(),
+            accounted: AtomicUsize::new(0),
         }
+        .track()
     }
 }
 
@@ -1078,6 +1109,209 @@ macro_rules! copy_binary {
     };
 }
 
+/// Largest bitstring we're willing to build, in bits.
+pub const MAX_BITS: usize = std::u32::MAX as usize;
+
+/// Returns the binary backing a bitstring, along with the offset and size of the bitstring in bits.
+pub fn bits(term: &Term) -> Option<(&RcBinary, usize, usize)> {
+    match term.get_boxed_header() {
+        Ok(value::BOXED_BINARY) => {
+            let bin = term.get_boxed_value::<RcBinary>().ok()?;
+            Some((bin, 0, bin.data.len() * 8))
+        }
+        Ok(value::BOXED_SUBBINARY) => {
+            let sb = term.get_boxed_value::<SubBinary>().ok()?;
+            Some((
+                &sb.original,
+                sb.offset * 8 + sb.bit_offset as usize,
+                sb.size * 8 + sb.bitsize,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Calculates the size of a segment in bits, from the Size and Unit operands of a bs_* instruction.
+pub fn segment_size(size: Term, unit: u32) -> Result<usize, Exception> {
+    match size.into_variant() {
+        value::Variant::Integer(i) if i >= 0 => (i as usize)
+            .checked_mul(unit as usize)
+            .filter(|bits| *bits <= MAX_BITS)
+            .ok_or_else(|| Exception::new(Reason::EXC_SYSTEM_LIMIT)),
+        value::Variant::Pointer(..) if size.get_boxed_header() == Ok(value::BOXED_BIGINT) => {
+            match size.get_boxed_value::<BigInt>().unwrap().sign() {
+                Sign::Minus => Err(Exception::new(Reason::EXC_BADARG)),
+                _ => Err(Exception::new(Reason::EXC_SYSTEM_LIMIT)),
+            }
+        }
+        _ => Err(Exception::new(Reason::EXC_BADARG)),
+    }
+}
+
+/// Two's complement little endian bytes of an integer, sign extended or truncated to num_bytes.
+fn integer_bytes(int: Term, num_bytes: usize) -> Option<Vec<u8>> {
+    let (bytes, negative) = match int.into_variant() {
+        value::Variant::Integer(i) => (i64::from(i).to_le_bytes().to_vec(), i < 0),
+        value::Variant::Pointer(..) if int.get_boxed_header() == Ok(value::BOXED_BIGINT) => {
+            let i = int.get_boxed_value::<BigInt>().unwrap();
+            (i.to_signed_bytes_le(), i.sign() == Sign::Minus)
+        }
+        _ => return None,
+    };
+    let sign = if negative { 0xFF } else { 0 };
+
+    Some(
+        (0..num_bytes)
+            .map(|n| bytes.get(n).cloned().unwrap_or(sign))
+            .collect(),
+    )
+}
+
+/// Converts a float into the bits of an IEEE 754 half precision float, rounding to nearest even.
+/// Returns None if the value is too large to be represented.
+fn to_half(float: f64) -> Option<u16> {
+    let bits = float.to_bits();
+    let sign = ((bits >> 48) & 0x8000) as u16;
+    let exp = ((bits >> 52) & 0x7FF) as i32;
+    let mantissa = (bits & 0x000F_FFFF_FFFF_FFFF) | (1 << 52);
+
+    if exp == 0x7FF {
+        return None;
+    }
+    if exp == 0 {
+        // zero, or a double subnormal which is way below the half precision range
+        return Some(sign);
+    }
+
+    let exp = exp - 1023 + 15;
+    let (half, shift) = if exp >= 1 {
+        // normal, the implicit bit gets masked out
+        (((exp as u64) << 10) | ((mantissa >> 42) & 0x3FF), 42)
+    } else {
+        // subnormal
+        let shift = (43 - exp) as u64;
+        if shift > 54 {
+            return Some(sign);
+        }
+        (mantissa >> shift, shift)
+    };
+
+    let rest = mantissa & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    // a carry out of the mantissa correctly bumps the exponent
+    let half = if rest > halfway || (rest == halfway && half & 1 == 1) {
+        half + 1
+    } else {
+        half
+    };
+
+    if half >= 0x7C00 {
+        return None;
+    }
+    Some(sign | half as u16)
+}
+
+/// Binary construction. Bitstrings are built in place: context.bs points at the binary that's
+/// being written to, and context.bs_offset is the current position in bits.
+impl Binary {
+    /// Writes num_bits bits from src (starting at bit src_offset) at bit offset, growing the
+    /// binary as needed.
+    pub fn put_bits(&mut self, offset: usize, src: &[u8], src_offset: usize, num_bits: usize) {
+        if num_bits == 0 {
+            return;
+        }
+
+        let len = nbytes!(offset + num_bits);
+        if self.data.len() < len {
+            self.data.resize(len, 0);
+            self.account();
+        }
+
+        copy_binary!(
+            self.data.as_mut_ptr(),
+            offset,
+            src.as_ptr(),
+            src_offset,
+            num_bits
+        );
+    }
+
+    /// Writes an integer of num_bits bits at offset. Returns false if int isn't an integer.
+    pub fn put_integer(&mut self, offset: usize, num_bits: usize, int: Term, mut flags: Flag) -> bool {
+        let num_bytes = nbytes!(num_bits);
+        let mut bytes = match integer_bytes(int, num_bytes) {
+            Some(bytes) => bytes,
+            None => return false,
+        };
+
+        native_endian!(flags);
+
+        let rest = num_bits & 7;
+        if flags.contains(Flag::BSF_LITTLE) {
+            // only the low bits of the last (most significant) byte are used
+            if rest != 0 {
+                bytes[num_bytes - 1] <<= 8 - rest;
+            }
+        } else {
+            bytes.reverse();
+            // only the low bits of the first byte are used, shift everything up to them
+            if rest != 0 {
+                let shift = 8 - rest;
+                for i in 0..num_bytes {
+                    let next = bytes.get(i + 1).map_or(0, |b| b >> rest);
+                    bytes[i] = (bytes[i] << shift) | next;
+                }
+            }
+        }
+
+        self.put_bits(offset, &bytes, 0, num_bits);
+        true
+    }
+
+    /// Writes a float of num_bits (16, 32 or 64) bits at offset. Returns false if the size isn't
+    /// supported or the value doesn't fit.
+    pub fn put_float(&mut self, offset: usize, num_bits: usize, float: f64, mut flags: Flag) -> bool {
+        native_endian!(flags);
+        let little = flags.contains(Flag::BSF_LITTLE);
+
+        let bytes = match num_bits {
+            16 => match to_half(float) {
+                Some(half) if little => half.to_le_bytes().to_vec(),
+                Some(half) => half.to_be_bytes().to_vec(),
+                None => return false,
+            },
+            32 => {
+                let float = float as f32;
+                if !float.is_finite() {
+                    return false;
+                }
+                if little {
+                    float.to_bits().to_le_bytes().to_vec()
+                } else {
+                    float.to_bits().to_be_bytes().to_vec()
+                }
+            }
+            64 if little => float.to_bits().to_le_bytes().to_vec(),
+            64 => float.to_bits().to_be_bytes().to_vec(),
+            _ => return false,
+        };
+
+        self.put_bits(offset, &bytes, 0, num_bits);
+        true
+    }
+
+    /// Writes num_bits bits of src (starting at bit src_offset) at offset.
+    pub fn put_binary(&mut self, offset: usize, src: &Binary, src_offset: usize, num_bits: usize) {
+        if std::ptr::eq(src, self) {
+            // appending a binary to itself, growing the data would move it from under us
+            let data = src.data.clone();
+            self.put_bits(offset, &data, src_offset, num_bits)
+        } else {
+            self.put_bits(offset, &src.data, src_offset, num_bits)
+        }
+    }
+}
+
 // check the binary
 //if !boxed (sub)binary type return badarg
 // if !type subbin or !subbin.is_writable or not subbin.origin.flags is_witable
@@ -1109,18 +1343,15 @@ pub fn append(
     build_size: Term,
     _extra_words: usize,
     unit: usize,
-) -> Option<Term> {
+) -> Result<Term, Exception> {
     // Check and untag the requested build size.
-    // if size < 0 || not smallint
     let build_size_in_bits = match build_size.into_variant() {
-        value::Variant::Integer(i) if i >= 0 => i,
-        // TODO: return err reason probs instead of tweaking freason
-        // _ => return Err(Exception::new(Reason::EXC_BADARG)),
-        _ => return None,
-        // p->freason = BADARG;
+        value::Variant::Integer(i) if i >= 0 => i as usize,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
     };
 
-    let heap = &process.context_mut().heap;
+    let context = process.context_mut();
+    let heap = &context.heap;
 
     // Check the binary argument.
 
@@ -1133,7 +1364,7 @@ pub fn append(
             sb.is_writable && sb.original.is_writable
         }
         Ok(value::BOXED_BINARY) => false,
-        _ => return None, // TODO: BADARG
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
     };
 
     if writable {
@@ -1145,10 +1376,8 @@ pub fn append(
         // OK, the binary is writable.
 
         let bin_size = 8 * sb.size + sb.bitsize;
-        if unit > 1 {
-            if (unit == 8 && (bin_size & 7) != 0) || (bin_size % unit) != 0 {
-                return None; // TODO: BADARG
-            }
+        if unit > 1 && ((unit == 8 && (bin_size & 7) != 0) || (bin_size % unit) != 0) {
+            return Err(Exception::new(Reason::EXC_BADARG));
         }
 
         if build_size_in_bits == 0 {
@@ -1156,26 +1385,23 @@ pub fn append(
             //     (void) erts_garbage_collect(c_p, extra_words, reg, live+1);
             //     bin = reg[live];
             // }
-            return Some(binary);
+            return Ok(binary);
         }
 
-        // if (ERTS_UINT_MAX - build_size_in_bits) < bin_size {
-        //     c_p->freason = SYSTEM_LIMIT;
-        //     return THE_NON_VALUE;
-        // }
+        if MAX_BITS - build_size_in_bits < bin_size {
+            return Err(Exception::new(Reason::EXC_SYSTEM_LIMIT));
+        }
 
-        let used_size_in_bits = bin_size + build_size_in_bits as usize;
+        let used_size_in_bits = bin_size + build_size_in_bits;
 
         sb.is_writable = false; /* Make sure that no one else can write. */
         let size = nbytes!(used_size_in_bits);
         // pb.flags |= PB_ACTIVE_WRITER;
 
         // Reserve extra capacity if needed.
-        let data = pb.get_mut();
-        if data.capacity() < size {
-            data.reserve(2 * size); // why 2*?
-        }
-        process.context_mut().bs = &**pb as *const Binary as *mut Binary;
+        pb.reserve(size);
+        context.bs = &**pb as *const Binary as *mut Binary;
+        context.bs_offset = bin_size;
 
         // Allocate heap space and build a new sub binary.
 
@@ -1184,7 +1410,7 @@ pub fn append(
         //     (void) erts_garbage_collect(c_p, heap_need, reg, live+1);
         // }
 
-        Some(Term::subbinary(
+        Ok(Term::subbinary(
             heap,
             SubBinary::new(pb.clone(), used_size_in_bits, 0, true),
         ))
@@ -1197,36 +1423,20 @@ pub fn append(
          * build size and the size of the old binary. Allow some room
          * for growing.
          */
-        let (bin, bitoffs, bitsize) = match binary.get_boxed_header() {
-            Ok(value::BOXED_BINARY) => {
-                // TODO use ok_or to cast to some, then use ?
-                let value = &binary.get_boxed_value::<RcBinary>().unwrap();
-                (*value, 0, 0)
-            }
-            Ok(value::BOXED_SUBBINARY) => {
-                // TODO use ok_or to cast to some, then use ?
-                let value = &binary.get_boxed_value::<SubBinary>().unwrap();
-                (&value.original, value.bit_offset, value.bitsize)
-            }
-            _ => unreachable!(),
-        };
-        let bin_size = 8 * binary_size!(binary) + bitsize;
-        if unit > 1 {
-            if (unit == 8 && (bin_size & 7) != 0) || (bin_size % unit) != 0 {
-                return None; // TODO: BADARG
-            }
+        let (bin, bitoffs, bin_size) = bits(&binary).unwrap();
+        if unit > 1 && ((unit == 8 && (bin_size & 7) != 0) || (bin_size % unit) != 0) {
+            return Err(Exception::new(Reason::EXC_BADARG));
         }
 
         if build_size_in_bits == 0 {
-            return Some(binary);
+            return Ok(binary);
         }
 
-        //if (ERTS_UINT_MAX - build_size_in_bits) < bin_size {
-        //    //p->freason = SYSTEM_LIMIT;
-        //    return None;
-        //}
+        if MAX_BITS - build_size_in_bits < bin_size {
+            return Err(Exception::new(Reason::EXC_SYSTEM_LIMIT));
+        }
 
-        let used_size_in_bits = bin_size + build_size_in_bits as usize;
+        let used_size_in_bits = bin_size + build_size_in_bits;
         let used_size_in_bytes = nbytes!(used_size_in_bits);
 
         let mut size = if used_size_in_bits < (std::u32::MAX as usize / 2) {
@@ -1243,19 +1453,14 @@ pub fn append(
         let new_binary = heap.alloc(Arc::new(Binary::with_capacity(size))).clone();
         // ACTIVE_WRITER
 
-        process.context_mut().bs = &*new_binary as *const Binary as *mut Binary;
+        context.bs = &*new_binary as *const Binary as *mut Binary;
+        context.bs_offset = bin_size;
 
         // Now copy the data into the binary.
-        copy_binary!(
-            new_binary.get_mut().as_mut_ptr(),
-            0,
-            bin.data.as_ptr(),
-            bitoffs as usize,
-            bin_size
-        );
+        unsafe { (*context.bs).put_binary(0, bin, bitoffs, bin_size) };
 
         // Now allocate the sub binary and set its size to include the data about to be built.
-        Some(Term::subbinary(
+        Ok(Term::subbinary(
             heap,
             SubBinary::new(new_binary, used_size_in_bits, 0, true),
         ))
@@ -1267,15 +1472,11 @@ pub fn private_append(
     binary: Term,
     build_size: Term,
     _unit: usize,
-) -> Option<Term> {
+) -> Result<Term, Exception> {
     // Check and untag the requested build size.
-    // if size < 0 || not smallint
     let build_size_in_bits = match build_size.into_variant() {
-        value::Variant::Integer(i) if !i < 0 => i,
-        // TODO: return err reason probs instead of tweaking freason
-        // _ => return Err(Exception::new(Reason::EXC_BADARG)),
-        _ => return None,
-        // p->freason = BADARG;
+        value::Variant::Integer(i) if i >= 0 => i as usize,
+        _ => return Err(Exception::new(Reason::EXC_BADARG)),
     };
 
     let sb = &mut binary.get_boxed_value_mut::<SubBinary>().unwrap();
@@ -1286,25 +1487,23 @@ pub fn private_append(
     // Calculate size in bytes.
     let bin_size = 8 * sb.size + sb.bitsize;
 
-    let size_in_bits_after_build = bin_size + build_size_in_bits as usize;
+    if MAX_BITS - build_size_in_bits < bin_size {
+        return Err(Exception::new(Reason::EXC_SYSTEM_LIMIT));
+    }
+
+    let size_in_bits_after_build = bin_size + build_size_in_bits;
     let size = (size_in_bits_after_build + 7) >> 3;
     // pb.flags |= PB_ACTIVE_WRITER; // TODO atomic set
 
-    //if (ERTS_UINT_MAX - build_size_in_bits) < bin_size {
-    //    //p->freason = SYSTEM_LIMIT;
-    //    return None;
-    //}
-
     // Reserve extra capacity if needed.
-    let data = pb.get_mut();
-    if data.capacity() < size {
-        data.reserve(2 * size); // why 2*?
-    }
-    process.context_mut().bs = &**pb as *const Binary as *mut Binary;
+    pb.reserve(size);
+    let context = process.context_mut();
+    context.bs = &**pb as *const Binary as *mut Binary;
+    context.bs_offset = bin_size;
 
     sb.size = size_in_bits_after_build >> 3;
     sb.bitsize = size_in_bits_after_build & 7;
-    Some(binary)
+    Ok(binary)
 }
 
 // TODO: transform into SubBinary::new() + is_writable
@@ -1643,6 +1842,52 @@ mod tests {
         // TODO: signed, bigints
     }
 
+    #[test]
+    fn test_put_integer() {
+        let mut binary = Binary::new();
+
+        binary.put_integer(0, 4, Term::int(0xA), Flag::BSF_NONE);
+        binary.put_integer(4, 12, Term::int(0xBCD), Flag::BSF_NONE);
+        binary.put_integer(16, 3, Term::int(0b101), Flag::BSF_NONE);
+        assert_eq!(binary.data, vec![0xAB, 0xCD, 0xA0]);
+
+        // <<257:12/little>>
+        let mut binary = Binary::new();
+        binary.put_integer(0, 12, Term::int(257), Flag::BSF_LITTLE);
+        assert_eq!(binary.data, vec![0x01, 0x10]);
+
+        // <<-1:12>>
+        let mut binary = Binary::new();
+        binary.put_integer(0, 12, Term::int(-1), Flag::BSF_SIGNED);
+        assert_eq!(binary.data, vec![0xFF, 0xF0]);
+
+        assert!(!binary.put_integer(12, 8, Term::atom(1), Flag::BSF_NONE));
+    }
+
+    #[test]
+    fn test_put_float() {
+        let mut binary = Binary::new();
+        assert!(binary.put_float(0, 16, 1.5, Flag::BSF_NONE));
+        assert!(binary.put_float(16, 32, 1.0, Flag::BSF_NONE));
+        assert!(binary.put_float(48, 16, 1.0, Flag::BSF_LITTLE));
+        assert_eq!(binary.data, vec![0x3E, 0x00, 0x3F, 0x80, 0x00, 0x00, 0x00, 0x3C]);
+
+        assert_eq!(to_half(65504.0), Some(0x7BFF));
+        assert_eq!(to_half(-2.0), Some(0xC000));
+        assert_eq!(to_half(2f64.powi(-24)), Some(0x0001));
+        assert_eq!(to_half(65520.0), None);
+        assert!(!binary.put_float(0, 32, 1.0e40, Flag::BSF_NONE));
+        assert!(!binary.put_float(0, 24, 1.0, Flag::BSF_NONE));
+    }
+
+    #[test]
+    fn test_put_binary_unaligned() {
+        let mut binary = Binary::new();
+        binary.put_integer(0, 4, Term::int(0xA), Flag::BSF_NONE);
+        binary.put_binary(4, &Binary::from(vec![0xBC, 0xDE]), 4, 8);
+        assert_eq!(binary.data, vec![0xAC, 0xD0]);
+    }
+
     #[test]
     fn bitstring_unaligned() {
         let binary = Arc::new(Binary::from(vec![0x0B, 0xCD, 0xE]));
@@ -213,6 +213,20 @@ impl TableRegistry {
         true
     }
 
+    /// Removes all the tables owned by a process, returning them.
+    pub fn remove_owned(&mut self, owner: process::PID) -> Vec<RcTable> {
+        let owned: Vec<RcTable> = self
+            .tables
+            .values()
+            .filter(|table| table.meta().owner == owner)
+            .cloned()
+            .collect();
+        for table in &owned {
+            self.remove(table);
+        }
+        owned
+    }
+
     pub fn whereis(&self, name: usize) -> Option<process::Ref> {
         self.named_tables.get(&name).map(|table| table.meta().tid)
     }
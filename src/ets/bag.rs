@@ -1,5 +1,5 @@
 use super::*;
-use crate::immix::Heap;
+use crate::immix::{Heap, Kind};
 use crate::value::{Cons, Term, TryFrom, TryInto, TryIntoMut, Tuple, Variant};
 use error::*;
 use parking_lot::RwLock;
@@ -19,7 +19,7 @@ impl Bag {
         Self {
             meta,
             hashmap: RwLock::new(HashMap::new()),
-            heap: Heap::new(),
+            heap: Heap::with_kind(Kind::Ets),
         }
     }
 }
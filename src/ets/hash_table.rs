@@ -1,6 +1,6 @@
 use super::*;
 use crate::chashmap::CHashMap;
-use crate::immix::Heap;
+use crate::immix::{Heap, Kind};
 use crate::value::{Cons, Term, TryFrom, TryInto, TryIntoMut, Tuple, Variant};
 use error::*;
 
@@ -18,7 +18,7 @@ impl HashTable {
         Self {
             meta,
             hashmap: CHashMap::new(),
-            heap: Heap::new(),
+            heap: Heap::with_kind(Kind::Ets),
         }
     }
 }
@@ -170,13 +170,13 @@ impl Table for HashTable {
             .hashmap
             .clone() // TODO: eww, temporary until I implement my own buckets
             .into_iter()
-            .fold(Term::nil(), |acc, (_key, val)| {
+            .try_fold(Term::nil(), |acc, (_key, val)| -> Result<Term> {
                 // println!("running select for {}", val);
-                match pam::r#match::run(vm, process, pattern, val, flags) {
-                    Some(val) => cons!(heap, val, acc),
-                    None => acc,
+                match pam::r#match::run(vm, process, pattern, val, flags)? {
+                    Some(val) => Ok(cons!(heap, val, acc)),
+                    None => Ok(acc),
                 }
-            });
+            })?;
         // println!("PAM res: {}", res);
         Ok(res)
     }
@@ -195,17 +195,25 @@ impl Table for HashTable {
         let heap = &process.context_mut().heap;
         let mut count = 0;
         let am_true = atom!(TRUE);
+        let mut error = None;
         self.hashmap.retain(|_key, val| {
             // println!("running retain for {}", val);
             match pam::r#match::run(vm, process, pattern, *val, flags) {
-                Some(res) if res == am_true => {
+                Ok(Some(res)) if res == am_true => {
                     // println!("deleting {}", val);
                     count += 1;
                     false
                 } // don't keep
-                _ => true,
+                Ok(_) => true,
+                Err(err) => {
+                    error = Some(err);
+                    true
+                }
             }
         });
+        if let Some(err) = error {
+            return Err(err);
+        }
         Ok(Term::uint(heap, count as u32))
     }
 
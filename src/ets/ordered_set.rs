@@ -1,5 +1,5 @@
 use super::*;
-use crate::immix::Heap;
+use crate::immix::{Heap, Kind};
 use crate::value::{Cons, Term, TryFrom, TryInto, TryIntoMut, Tuple, Variant};
 use error::*;
 use parking_lot::RwLock;
@@ -18,7 +18,7 @@ impl OrderedSet {
         Self {
             meta,
             hashmap: RwLock::new(BTreeMap::new()),
-            heap: Heap::new(),
+            heap: Heap::with_kind(Kind::Ets),
         }
     }
 }
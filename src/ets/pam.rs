@@ -7,7 +7,7 @@ pub mod r#match;
 use once_cell::sync::Lazy;
 
 use crate::value::{self, Variant, Cons, Tuple, Map, TryFrom, TryInto};
-use crate::immix::Heap;
+use crate::immix::{Heap, Kind};
 use crate::atom;
 use crate::bif::{self};
 
@@ -351,7 +351,7 @@ impl Compiler {
             text: Vec::new(),
             stack: Vec::new(),
             vars: HashMap::new(),
-            constant_heap: Heap::new(),
+            constant_heap: Heap::with_kind(Kind::Ets),
             stack_need: 0,
             stack_used: 0,
             // save: NULL,
@@ -29,6 +29,7 @@
 // return
 // halt
 use super::*;
+use crate::ets::error::{new_error, ErrorKind};
 use crate::vm;
 
 bitflags! {
@@ -47,6 +48,17 @@ macro_rules! fail {
     }};
 }
 
+/// Steps to the next term. The compiler lays out the program so that there always is one, if
+/// there isn't the program is broken.
+macro_rules! next {
+    ($ep:expr) => {
+        match $ep.next() {
+            Some(e) => e,
+            None => return Err(new_error(ErrorKind::BadParameter)),
+        }
+    };
+}
+
 /// Execution of the match program, this is Pam.
 /// May return THE_NON_VALUE, which is a bailout.
 /// the parameter 'arity' is only used if 'term' is actually an array,
@@ -63,7 +75,7 @@ pub fn run(
     pat: &pam::Pattern,
     term: Term,     /*Eterm *termp, arity: usize*/
     in_flags: Flag, /*, Uint32 *return_flags*/
-) -> Option<Term> {
+) -> crate::ets::Result<Option<Term>> {
     // MatchProg *prog = Binary2MatchProg(bprog);
     // const Eterm *ep, *tp, **sp;
     // Eterm t;
@@ -210,18 +222,25 @@ pub fn run(
                         fail!()
                     }
                 }
-                //                Opcode::List() => {
-                //                    if !is_list(*ep) {
-                //                        fail!();
-                //                    }
-                //                    ep = list_val(*ep);
-                //                }
-                //                Opcode::PushL() => {
-                //                    if !is_list(*ep)
-                //                        fail!();
-                //                    *sp++ = list_val(*ep);
-                //                    ++ep;
-                //                }
+                Opcode::List() => {
+                    // *ep is a cons cell, step into it: head first, then tail
+                    e = next!(ep);
+
+                    if let Ok(cons) = Cons::try_from(&e) {
+                        ep = Box::new(vec![&cons.head, &cons.tail].into_iter());
+                    } else {
+                        fail!()
+                    }
+                }
+                Opcode::PushL(_) => {
+                    // *ep is a cons cell, push ptr to the head
+                    e = next!(ep);
+                    if let Ok(cons) = Cons::try_from(&e) {
+                        sp.push(Box::new(vec![&cons.head, &cons.tail].into_iter()));
+                    } else {
+                        fail!()
+                    }
+                }
                 //                Opcode::Map(n) => {
                 //                    if !is_map(*ep) {
                 //                        fail!();
@@ -805,7 +824,7 @@ pub fn run(
                     //      esdp->current_process = current_scheduled;
                     //  }
 
-                    return Some(ret);
+                    return Ok(Some(ret));
                 }
                 _ => unreachable!(
                     "Internal error: unexpected opcode in match program. {}",
@@ -823,7 +842,7 @@ pub fn run(
         // cleanup_match_pseudo_process(mpsp, 1);
         // break 'restart;
         } else {
-            return None;
+            return Ok(None);
         }
     }
 }
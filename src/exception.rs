@@ -45,6 +45,7 @@ impl From<crate::ets::error::Error> for Exception {
     fn from(value: crate::ets::error::Error) -> Self {
         match value.kind() {
             crate::ets::error::ErrorKind::BadItem => Exception::new(Reason::EXC_BADARG),
+            crate::ets::error::ErrorKind::BadParameter => Exception::new(Reason::EXC_BADARG),
             _ => unimplemented!(),
         }
     }
@@ -1,9 +1,11 @@
 use crate::bif;
 use crate::instr_ptr::InstrPtr;
 use crate::module::MFA;
+use crate::trace;
 use hashbrown::HashMap;
 use parking_lot::RwLock;
 use std::fmt;
+use std::sync::Arc;
 
 /// Reference counted ExportsTable.
 pub type RcExportsTable = RwLock<ExportsTable>; // TODO: I don't like this lock at all
@@ -12,6 +14,17 @@ pub type RcExportsTable = RwLock<ExportsTable>; // TODO: I don't like this lock
 pub enum Export {
     Fun(InstrPtr),
     Bif(bif::Fn),
+    /// A BIF that runs on one of the dirty scheduler pools.
+    DirtyBif(bif::Fn, bif::Dirty),
+}
+
+impl Export {
+    pub fn bif(fun: bif::Fn, dirty: Option<bif::Dirty>) -> Self {
+        match dirty {
+            Some(dirty) => Export::DirtyBif(fun, dirty),
+            None => Export::Bif(fun),
+        }
+    }
 }
 
 impl fmt::Debug for Export {
@@ -19,13 +32,21 @@ impl fmt::Debug for Export {
         match self {
             Export::Fun(..) => write!(f, "Export(fn)"),
             Export::Bif(..) => write!(f, "Export(bif)"),
+            Export::DirtyBif(_, dirty) => write!(f, "Export(dirty bif {:?})", dirty),
         }
     }
 }
 
+/// An export entry. Once created an entry never moves, so loaded code links to it directly instead
+/// of looking it up on every call. (Re)loading a module updates the entries in place, None marks a
+/// stub for a function that isn't loaded (yet).
+pub type RcExport = Arc<RwLock<Option<Export>>>;
+
 #[derive(Debug)]
 pub struct ExportsTable {
-    exports: HashMap<MFA, Export>, // hashbrown is send & sync, so no locks?
+    exports: HashMap<MFA, RcExport>, // hashbrown is send & sync, so no locks?
+    /// Call trace patterns, set via erlang:trace_pattern/3.
+    patterns: HashMap<MFA, Arc<trace::Pattern>>,
 }
 
 impl ExportsTable {
@@ -33,27 +54,65 @@ impl ExportsTable {
         let mut exports = HashMap::new();
 
         // load all the bif exports
-        for (key, val) in bif::BIFS.iter() {
-            exports.insert(*key, Export::Bif(*val));
+        for (key, (fun, dirty)) in bif::BIFS.iter() {
+            exports.insert(*key, Arc::new(RwLock::new(Some(Export::bif(*fun, *dirty)))));
         }
 
-        RwLock::new(ExportsTable { exports })
+        RwLock::new(ExportsTable {
+            exports,
+            patterns: HashMap::new(),
+        })
     }
 
     pub fn register(&mut self, mfa: MFA, ptr: InstrPtr) {
-        self.exports.insert(mfa, Export::Fun(ptr));
+        self.insert(mfa, Export::Fun(ptr));
     }
 
     pub fn insert(&mut self, mfa: MFA, export: Export) {
-        self.exports.insert(mfa, export);
+        *self.entry(mfa).write() = Some(export);
     }
 
     pub fn lookup(&self, mfa: &MFA) -> Option<Export> {
-        self.exports.get(mfa).cloned()
-        // need to clone to avoid keeping a ref too long and lock the table
+        self.exports.get(mfa).and_then(|entry| *entry.read())
+        // need to copy to avoid keeping a ref too long and lock the entry
     }
 
-    // get or get stub
+    /// Returns the entry for an export, creating a stub if there's none yet.
+    pub fn entry(&mut self, mfa: MFA) -> RcExport {
+        self.exports
+            .entry(mfa)
+            .or_insert_with(|| Arc::new(RwLock::new(None)))
+            .clone()
+    }
+
+    /// Turns all the exports of a module back into stubs, except for BIFs.
+    pub fn remove_module(&mut self, module: u32) {
+        for (mfa, entry) in self.exports.iter() {
+            if mfa.0 == module && !bif::is_bif(mfa) {
+                *entry.write() = None;
+            }
+        }
+    }
+
+    /// Iterates over all the exported functions.
+    pub fn keys(&self) -> impl Iterator<Item = &MFA> {
+        self.exports
+            .iter()
+            .filter(|(_, entry)| entry.read().is_some())
+            .map(|(mfa, _)| mfa)
+    }
+
+    pub fn insert_pattern(&mut self, mfa: MFA, pattern: Arc<trace::Pattern>) {
+        self.patterns.insert(mfa, pattern);
+    }
+
+    pub fn remove_pattern(&mut self, mfa: &MFA) -> bool {
+        self.patterns.remove(mfa).is_some()
+    }
+
+    pub fn lookup_pattern(&self, mfa: &MFA) -> Option<Arc<trace::Pattern>> {
+        self.patterns.get(mfa).cloned()
+    }
 }
 
 #[cfg(test)]
@@ -1,16 +1,34 @@
 //use std::alloc::{Alloc, Global, Layout};
 use allocator_api::{Alloc, Global, Layout};
-use std::cell::Cell;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::cell::{Cell, RefCell};
 use std::cmp;
 use std::mem;
 use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-// TODO: implement immix lines later on
+/// The number of bytes in a block (including the header). Blocks are aligned to their size, so
+/// the block any address belongs to can be found by masking.
+pub const BLOCK_SIZE: usize = 32 * 1024;
 
-/// The number of bytes in a block (+ the header).
-pub const DEFAULT_BLOCK_SIZE: usize = 32 * 1024 + mem::size_of::<Block>();
+/// The number of bytes in a line, the granularity free space is reclaimed at.
+pub const LINE_SIZE: usize = 128;
 
-pub const DEFAULT_BLOCK_ALIGN: usize = mem::align_of::<Block>();
+pub const LINES_PER_BLOCK: usize = BLOCK_SIZE / LINE_SIZE;
+
+/// Values at least this large get a block of their own.
+pub const LARGE_OBJECT: usize = 8 * 1024;
+
+/// The number of bytes in a block of a heap fragment (without the header).
+pub const FRAGMENT_BLOCK_SIZE: usize = 1024;
+
+/// Blocks that had at least this many free lines after the last collection are sparse enough to
+/// be evacuated on the next one.
+const EVACUATE_FREE_LINES: usize = LINES_PER_BLOCK / 2;
+
+/// Free blocks kept around for reuse, anything above this is given back to the system.
+const MAX_POOLED_BLOCKS: usize = 1024;
 
 pub struct Block {
     /// Points to the start of the block (including this header)
@@ -24,15 +42,112 @@ pub struct Block {
 
     /// Cursor to the current free spot when bump allocating.
     ptr: Cell<NonNull<u8>>,
+
+    /// End of the hole we're currently bump allocating into.
+    limit: Cell<NonNull<u8>>,
+
+    /// One bit per line, set if the line holds (part of) a live value. Only used by standard
+    /// blocks.
+    lines: [Cell<u64>; LINES_PER_BLOCK / 64],
+
+    /// Set if a large or fragment block holds a live value.
+    marked: Cell<bool>,
+
+    /// Free lines left after the last collection.
+    free_lines: Cell<usize>,
 }
 
 // -- block
-// metadata (Block)
 // oo memory space <-- Block.data
 // oo memory space
-// oo memory space <-- Block.end
+// oo memory space
+// metadata (Block)
 // -- end
 
+/// A free block, parked in the pool until a heap needs it.
+struct PooledBlock(NonNull<Block>);
+
+// pooled blocks don't belong to any heap
+unsafe impl Send for PooledBlock {}
+
+/// Free standard blocks, shared by the heaps of all the processes.
+static BLOCK_POOL: Lazy<Mutex<Vec<PooledBlock>>> = sync_lazy! {
+    Mutex::new(Vec::new())
+};
+
+/// What a heap is used for, memory is accounted separately for each kind (see erlang:memory/0).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Process heaps, and the fragments messages are built on.
+    Process = 0,
+    Ets = 1,
+    /// Module literals.
+    Literal = 2,
+    PersistentTerm = 3,
+}
+
+/// Bytes in the blocks owned by heaps of each kind.
+static ALLOCATED: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Bytes in the free lines of recyclable blocks (not allocated into yet), for each kind.
+static FREE: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Total size of the blocks owned by heaps of the given kind, in bytes.
+pub fn allocated(kind: Kind) -> usize {
+    ALLOCATED[kind as usize].load(Ordering::Relaxed)
+}
+
+/// Like `allocated`, minus the free lines left in partially free blocks.
+pub fn used(kind: Kind) -> usize {
+    allocated(kind).saturating_sub(FREE[kind as usize].load(Ordering::Relaxed))
+}
+
+/// Total size of the free blocks parked in the pool, in bytes.
+pub fn pooled() -> usize {
+    BLOCK_POOL.lock().len() * BLOCK_SIZE
+}
+
+/// Moves a counter from `old` to `new`.
+fn account(counter: &AtomicUsize, old: usize, new: usize) {
+    if new > old {
+        counter.fetch_add(new - old, Ordering::Relaxed);
+    } else {
+        counter.fetch_sub(old - new, Ordering::Relaxed);
+    }
+}
+
+/// A heap allocated value that owns resources outside of the heap (a bignum's digits, a reference
+/// to a refc binary...), along with the glue needed to drop it.
+#[derive(Debug)]
+struct Finalizer {
+    ptr: NonNull<u8>,
+    drop: unsafe fn(*mut u8),
+}
+
+unsafe fn drop_value<T>(ptr: *mut u8) {
+    ptr::drop_in_place(ptr as *mut T)
+}
+
+/// What the collector found out about a value with a finalizer.
+pub enum Liveness {
+    /// Unreachable, so it can be dropped.
+    Dead,
+    /// Reachable, and still in place.
+    Live,
+    /// Reachable, but moved somewhere else (which has a finalizer of its own).
+    Moved,
+}
+
 #[derive(Debug)]
 pub struct Heap {
     // The current block we are bump allocating within.
@@ -41,6 +156,27 @@ pub struct Heap {
     // The first block we were ever given, which is the head of the intrusive
     // linked list of all blocks this arena has been bump allocating within.
     all_blocks: Cell<NonNull<Block>>,
+
+    // Blocks with free lines left by the last collection, that we haven't allocated into yet.
+    recyclable: RefCell<Vec<NonNull<Block>>>,
+
+    // Total size of all the blocks, in bytes.
+    size: Cell<usize>,
+
+    // Free lines in the recyclable blocks, in bytes.
+    free: Cell<usize>,
+
+    // Size of the off-heap binaries referenced since the last collection, in bytes.
+    binaries: Cell<usize>,
+
+    // Fragments allocate small blocks of their own instead of using the pool.
+    fragment: bool,
+
+    // What the heap is used for, so its memory can be accounted for.
+    kind: Kind,
+
+    // Values that need to be dropped once they die, or when the heap goes away.
+    finalizers: RefCell<Vec<Finalizer>>,
 }
 
 unsafe impl Sync for Heap {}
@@ -53,45 +189,33 @@ pub(crate) fn round_up_to(n: usize, divisor: usize) -> usize {
 }
 
 impl Block {
-    fn default_block_layout() -> Layout {
-        if cfg!(debug_assertions) {
-            Layout::from_size_align(DEFAULT_BLOCK_SIZE, DEFAULT_BLOCK_ALIGN).unwrap()
-        } else {
-            unsafe { Layout::from_size_align_unchecked(DEFAULT_BLOCK_SIZE, DEFAULT_BLOCK_ALIGN) }
+    /// Returns a standard block, reusing a pooled one if there's any.
+    fn standard() -> NonNull<Block> {
+        if let Some(PooledBlock(block)) = BLOCK_POOL.lock().pop() {
+            unsafe { block.as_ref().reset() };
+            return block;
         }
+        Block::new(Layout::from_size_align(BLOCK_SIZE, BLOCK_SIZE).unwrap())
+    }
+
+    /// Allocates a block that only holds values of the given layout (a large value, or the
+    /// contents of a fragment).
+    fn exclusive(size: usize, align: usize) -> NonNull<Block> {
+        // Round the size up to a multiple of our header's alignment so
+        // that we can be sure that our header is properly aligned.
+        let size = round_up_to(size, mem::align_of::<Block>());
+        let align = cmp::max(align, mem::align_of::<Block>());
+        Block::new(Layout::from_size_align(size + mem::size_of::<Block>(), align).unwrap())
     }
 
     /// Allocate a new block and return its initialized header.
-    ///
-    /// If given, `alloc_layout` is the layout of the allocation request that
-    /// triggered us to fall back to allocating a new block of memory.
     #[allow(clippy::cast_ptr_alignment)]
-    fn new(alloc_layout: Option<Layout>) -> NonNull<Block> {
-        let layout = alloc_layout.map_or_else(Block::default_block_layout, |l| {
-            let align = cmp::max(l.align(), mem::align_of::<Block>());
-            if l.size() < DEFAULT_BLOCK_SIZE {
-                // If it is a small allocation, just use our default block size,
-                // but make sure it is aligned for the requested allocation.
-                Layout::from_size_align(DEFAULT_BLOCK_SIZE, align).unwrap()
-            } else {
-                // If the requested allocation is bigger than we can fit in one
-                // of our default blocks, make a special block just for this
-                // allocation.
-                //
-                // Round the size up to a multiple of our header's alignment so
-                // that we can be sure that our header is properly aligned.
-                let size = round_up_to(l.size(), mem::align_of::<Block>());
-                Layout::from_size_align(size + mem::size_of::<Block>(), align).unwrap()
-            }
-        });
-
+    fn new(layout: Layout) -> NonNull<Block> {
         let size = layout.size();
 
         unsafe {
             let data = Global.alloc(layout).unwrap();
 
-            let next = Cell::new(None);
-            let ptr = Cell::new(data);
             let footer_ptr = data.as_ptr() as usize + size - mem::size_of::<Block>();
             let footer_ptr = footer_ptr as *mut Block;
 
@@ -100,22 +224,401 @@ impl Block {
                 Block {
                     data,
                     layout,
-                    next,
-                    ptr,
+                    next: Cell::new(None),
+                    ptr: Cell::new(data),
+                    limit: Cell::new(NonNull::new_unchecked(footer_ptr as *mut u8)),
+                    lines: Default::default(),
+                    marked: Cell::new(false),
+                    free_lines: Cell::new(0),
                 },
             );
             NonNull::new_unchecked(footer_ptr)
         }
     }
+
+    /// Returns the standard block an address belongs to.
+    pub(super) unsafe fn containing(addr: usize) -> NonNull<Block> {
+        let start = addr & !(BLOCK_SIZE - 1);
+        NonNull::new_unchecked((start + BLOCK_SIZE - mem::size_of::<Block>()) as *mut Block)
+    }
+
+    /// Gives the block back to the pool, or to the system if it's not a standard one.
+    unsafe fn release(block: NonNull<Block>) {
+        if block.as_ref().is_standard() {
+            let mut pool = BLOCK_POOL.lock();
+            if pool.len() < MAX_POOLED_BLOCKS {
+                pool.push(PooledBlock(block));
+                return;
+            }
+        }
+        // the header lives inside the block, so read it out before freeing
+        let Block { data, layout, .. } = ptr::read(block.as_ptr());
+        Global.dealloc(data, layout);
+    }
+
+    pub(super) fn start(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    pub(super) fn end(&self) -> usize {
+        self as *const Block as usize
+    }
+
+    pub(super) fn is_standard(&self) -> bool {
+        self.layout.align() == BLOCK_SIZE
+    }
+
+    /// Blocks that came with an absorbed heap fragment.
+    pub(super) fn is_fragment(&self) -> bool {
+        !self.is_standard() && self.end() - self.start() < LARGE_OBJECT
+    }
+
+    /// Should the values in this block be moved out on the next collection? Fragments are always
+    /// evacuated, standard blocks only if they were sparse after the last collection.
+    pub(super) fn should_evacuate(&self) -> bool {
+        if self.is_standard() {
+            self.free_lines.get() >= EVACUATE_FREE_LINES
+        } else {
+            self.is_fragment()
+        }
+    }
+
+    /// The line marks (or the block mark for the other blocks) left by the last collection.
+    pub(super) fn marks(&self) -> ([u64; LINES_PER_BLOCK / 64], bool) {
+        let mut lines = [0; LINES_PER_BLOCK / 64];
+        for (word, mark) in lines.iter_mut().zip(&self.lines) {
+            *word = mark.get();
+        }
+        (lines, self.marked.get())
+    }
+
+    fn reset(&self) {
+        self.next.set(None);
+        self.ptr.set(self.data);
+        self.limit
+            .set(unsafe { NonNull::new_unchecked(self.end() as *mut u8) });
+        self.clear_marks();
+        self.free_lines.set(0);
+    }
+
+    pub(super) fn clear_marks(&self) {
+        for word in &self.lines {
+            word.set(0);
+        }
+        self.marked.set(false);
+    }
+
+    /// Marks the lines covered by a live value.
+    pub(super) fn mark(&self, addr: usize, size: usize) {
+        if !self.is_standard() {
+            self.marked.set(true);
+            return;
+        }
+        let first = (addr - self.start()) / LINE_SIZE;
+        let last = (addr + cmp::max(size, 1) - 1 - self.start()) / LINE_SIZE;
+        for line in first..=last {
+            let word = &self.lines[line / 64];
+            word.set(word.get() | (1 << (line % 64)));
+        }
+    }
+
+    fn is_marked(&self, line: usize) -> bool {
+        self.lines[line / 64].get() & (1 << (line % 64)) != 0
+    }
+
+    /// Number of usable lines, the last one might be cut short by the header.
+    fn line_count(&self) -> usize {
+        (self.end() - self.start() + LINE_SIZE - 1) / LINE_SIZE
+    }
+
+    fn count_free_lines(&self) -> usize {
+        (0..self.line_count())
+            .filter(|line| !self.is_marked(*line))
+            .count()
+    }
+
+    /// Bump allocates inside of the current hole, if there's enough room left.
+    #[inline(always)]
+    fn bump(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let ptr = round_up_to(self.ptr.get().as_ptr() as usize, layout.align());
+        let limit = self.limit.get().as_ptr() as usize;
+        let new_ptr = ptr.checked_add(layout.size())?;
+
+        if new_ptr <= limit {
+            unsafe {
+                self.ptr.set(NonNull::new_unchecked(new_ptr as *mut u8));
+                return Some(NonNull::new_unchecked(ptr as *mut u8));
+            }
+        }
+        None
+    }
+
+    /// Moves the cursor to the next run of free lines after the current hole. Returns false if
+    /// there are none left.
+    fn next_hole(&self) -> bool {
+        if !self.is_standard() {
+            return false;
+        }
+        let count = self.line_count();
+        let mut line =
+            (self.limit.get().as_ptr() as usize - self.start() + LINE_SIZE - 1) / LINE_SIZE;
+        while line < count && self.is_marked(line) {
+            line += 1;
+        }
+        if line >= count {
+            return false;
+        }
+        let mut end = line;
+        while end < count && !self.is_marked(end) {
+            end += 1;
+        }
+        let limit = cmp::min(self.start() + end * LINE_SIZE, self.end());
+        unsafe {
+            self.ptr.set(NonNull::new_unchecked(
+                (self.start() + line * LINE_SIZE) as *mut u8,
+            ));
+            self.limit.set(NonNull::new_unchecked(limit as *mut u8));
+        }
+        true
+    }
+
+    /// Rewinds the cursor, so that allocation starts over from the first hole.
+    fn recycle(&self) {
+        self.ptr.set(self.data);
+        self.limit.set(self.data);
+    }
 }
 
 impl Heap {
     pub fn new() -> Self {
-        let block = Block::new(None);
-        Heap {
+        Heap::with_kind(Kind::Process)
+    }
+
+    /// A heap that isn't a process heap, accounted under the given kind.
+    pub fn with_kind(kind: Kind) -> Self {
+        Heap::with_block(Block::standard(), false, kind)
+    }
+
+    /// A heap with small blocks, for values that only live on it briefly (messages in flight)
+    /// before they get absorbed into a process heap.
+    pub fn fragment() -> Self {
+        Heap::with_block(
+            Block::exclusive(FRAGMENT_BLOCK_SIZE, mem::align_of::<Block>()),
+            true,
+            Kind::Process,
+        )
+    }
+
+    fn with_block(block: NonNull<Block>, fragment: bool, kind: Kind) -> Self {
+        let heap = Heap {
             current_block: Cell::new(block),
             all_blocks: Cell::new(block),
+            recyclable: RefCell::new(Vec::new()),
+            size: Cell::new(0),
+            free: Cell::new(0),
+            binaries: Cell::new(0),
+            fragment,
+            kind,
+            finalizers: RefCell::new(Vec::new()),
+        };
+        heap.set_size(unsafe { block.as_ref().layout.size() });
+        heap
+    }
+
+    fn set_size(&self, size: usize) {
+        account(&ALLOCATED[self.kind as usize], self.size.get(), size);
+        self.size.set(size);
+    }
+
+    fn set_free(&self, free: usize) {
+        account(&FREE[self.kind as usize], self.free.get(), free);
+        self.free.set(free);
+    }
+
+    /// What the heap is used for.
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// Total size of the heap in bytes, including any unused space left in the blocks.
+    pub fn size(&self) -> usize {
+        self.size.get()
+    }
+
+    /// Like `size`, minus the free lines of the blocks we haven't allocated into since the last
+    /// collection.
+    pub fn used(&self) -> usize {
+        self.size.get().saturating_sub(self.free.get())
+    }
+
+    /// Size of the off-heap binaries referenced since the last collection (OTP's virtual binary
+    /// heap), in bytes.
+    pub fn binaries(&self) -> usize {
+        self.binaries.get()
+    }
+
+    /// Accounts for a reference to an off-heap binary of the given size.
+    pub fn add_binary(&self, size: usize) {
+        self.binaries.set(self.binaries.get() + size);
+    }
+
+    /// Returns true if ptr points inside one of the blocks of this heap.
+    pub fn contains<T>(&self, ptr: *const T) -> bool {
+        let ptr = ptr as usize;
+        self.blocks().any(|(start, end)| ptr >= start && ptr < end)
+    }
+
+    /// Iterates over the address ranges (start, end) of the blocks in this heap.
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, usize)> {
+        self.block_ptrs().map(|block| unsafe {
+            let block = block.as_ref();
+            (block.start(), block.end())
+        })
+    }
+
+    pub(super) fn block_ptrs(&self) -> impl Iterator<Item = NonNull<Block>> {
+        let mut next = Some(self.all_blocks.get());
+        std::iter::from_fn(move || {
+            next.map(|block| {
+                next = unsafe { block.as_ref().next.get() };
+                block
+            })
+        })
+    }
+
+    /// Takes over all the blocks (and values) of another heap, usually a message that was copied
+    /// into a separate fragment while its receiver was busy.
+    pub fn absorb(&self, other: Heap) {
+        let finalizers = other.finalizers.replace(Vec::new());
+        let recyclable = other.recyclable.replace(Vec::new());
+        let head = other.all_blocks.get();
+        let size = other.size.get();
+        let free = other.free.get();
+        let binaries = other.binaries.get();
+        // the blocks now belong to us
+        other.set_size(0);
+        other.set_free(0);
+        mem::forget(other);
+
+        unsafe {
+            // find the tail of the other heap
+            let mut tail = head;
+            while let Some(next) = tail.as_ref().next.get() {
+                tail = next;
+            }
+
+            // splice the blocks in front of ours, keeping our current block
+            tail.as_ref().next.set(Some(self.all_blocks.get()));
         }
+        self.all_blocks.set(head);
+        self.set_size(self.size.get() + size);
+        self.set_free(self.free.get() + free);
+        self.binaries.set(self.binaries.get() + binaries);
+        self.recyclable.borrow_mut().extend(recyclable);
+        self.finalizers.borrow_mut().extend(finalizers);
+    }
+
+    /// Drops the values that died during a collection, and forgets the ones that moved to
+    /// another heap.
+    pub(super) fn finalize<F>(&self, liveness: F)
+    where
+        F: Fn(usize) -> Liveness,
+    {
+        self.finalizers.borrow_mut().retain(|finalizer| {
+            match liveness(finalizer.ptr.as_ptr() as usize) {
+                Liveness::Live => true,
+                Liveness::Moved => false,
+                Liveness::Dead => {
+                    unsafe { (finalizer.drop)(finalizer.ptr.as_ptr()) };
+                    false
+                }
+            }
+        });
+    }
+
+    /// Frees the blocks without any marked lines, and queues up the ones with free lines left
+    /// for reuse. The blocks of `survivors` (where the evacuated values went) are taken over.
+    pub(super) fn sweep(&self, survivors: Heap) {
+        let mut kept = Vec::new();
+        let mut recyclable = Vec::new();
+
+        unsafe {
+            let ours: Vec<_> = self.block_ptrs().collect();
+            for block in ours {
+                let b = block.as_ref();
+                if b.is_standard() {
+                    let free = b.count_free_lines();
+                    if free == b.line_count() {
+                        Block::release(block);
+                        continue;
+                    }
+                    b.free_lines.set(free);
+                    if free > 0 {
+                        b.recycle();
+                        recyclable.push(block);
+                    }
+                } else if !b.marked.get() {
+                    Block::release(block);
+                    continue;
+                }
+                kept.push(block);
+            }
+
+            let finalizers = survivors.finalizers.replace(Vec::new());
+            let theirs: Vec<_> = survivors.block_ptrs().collect();
+            survivors.set_size(0);
+            survivors.set_free(0);
+            mem::forget(survivors);
+            for block in theirs {
+                let b = block.as_ref();
+                if !b.is_standard() {
+                    // a large value that got evacuated
+                    kept.push(block);
+                    continue;
+                }
+                let free = b.count_free_lines();
+                if free == b.line_count() {
+                    Block::release(block);
+                    continue;
+                }
+                // freshly compacted, so no point in evacuating it again right away
+                b.free_lines.set(0);
+                if free > 0 {
+                    b.recycle();
+                    recyclable.push(block);
+                }
+                kept.push(block);
+            }
+            self.finalizers.borrow_mut().extend(finalizers);
+
+            let current = match recyclable.pop() {
+                Some(block) => block,
+                None => {
+                    let block = Block::standard();
+                    kept.push(block);
+                    block
+                }
+            };
+
+            // relink the surviving blocks
+            for pair in kept.windows(2) {
+                pair[0].as_ref().next.set(Some(pair[1]));
+            }
+            let last = kept.last().unwrap();
+            last.as_ref().next.set(None);
+
+            self.all_blocks.set(kept[0]);
+            self.current_block.set(current);
+            self.set_size(kept.iter().map(|block| block.as_ref().layout.size()).sum());
+            self.set_free(
+                recyclable
+                    .iter()
+                    .map(|block| block.as_ref().count_free_lines() * LINE_SIZE)
+                    .sum(),
+            );
+        }
+        *self.recyclable.borrow_mut() = recyclable;
+        self.binaries.set(0);
     }
 
     /// Allocate an object.
@@ -133,6 +636,12 @@ impl Heap {
 
         unsafe {
             let p = self.alloc_layout(layout);
+            if mem::needs_drop::<T>() {
+                self.finalizers.borrow_mut().push(Finalizer {
+                    ptr: p,
+                    drop: drop_value::<T>,
+                });
+            }
             let p = p.as_ptr() as *mut T;
             ptr::write(p, val);
             &mut *p
@@ -141,73 +650,84 @@ impl Heap {
 
     #[inline(always)]
     pub fn alloc_layout(&self, layout: Layout) -> NonNull<u8> {
-        unsafe {
-            let footer = self.current_block.get();
-            let footer = footer.as_ref();
-            let ptr = footer.ptr.get().as_ptr() as usize;
-            let ptr = round_up_to(ptr, layout.align());
-            let end = footer as *const _ as usize;
-            debug_assert!(ptr <= end);
-
-            let new_ptr = match ptr.checked_add(layout.size()) {
-                Some(p) => p,
-                None => self.overflow(),
-            };
-
-            if new_ptr <= end {
-                let p = ptr as *mut u8;
-                debug_assert!(new_ptr <= footer as *const _ as usize);
-                footer.ptr.set(NonNull::new_unchecked(new_ptr as *mut u8));
-                return NonNull::new_unchecked(p);
-            }
+        let footer = unsafe { self.current_block.get().as_ref() };
+        if let Some(p) = footer.bump(layout) {
+            return p;
         }
 
-        // TODO: verify new block alloc works
         self.alloc_layout_slow(layout)
     }
 
-    #[inline(never)]
-    #[cold]
-    fn overflow(&self) -> ! {
-        panic!("allocation too large, caused overflow")
+    fn link(&self, block: NonNull<Block>) {
+        unsafe {
+            block.as_ref().next.set(Some(self.all_blocks.get()));
+            self.set_size(self.size.get() + block.as_ref().layout.size());
+        }
+        self.all_blocks.set(block);
     }
 
-    // Slow path allocation for when we need to allocate a new block from the
-    // parent bump set because there isn't enough room in our current block.
+    // Slow path allocation for when there isn't enough room in the current hole. Large values
+    // get a block of their own, the rest goes into the next hole, a recycled block, or a
+    // fresh one from the pool.
     #[inline(never)]
     fn alloc_layout_slow(&self, layout: Layout) -> NonNull<u8> {
         unsafe {
-            // Get a new block from the global allocator.
-            let size = layout.size();
-            let footer = Block::new(Some(layout));
-
-            // Set our current block's next link to this new block.
-            self.current_block.get().as_ref().next.set(Some(footer));
-
-            // Set the new block as our new current block.
-            self.current_block.set(footer);
-
-            // Move the bump ptr finger ahead to allocate room for `val`.
-            let footer = footer.as_ref();
-            let ptr = footer.ptr.get().as_ptr() as usize + size;
-            debug_assert!(
-                ptr <= footer as *const _ as usize,
-                "{} <= {}",
-                ptr,
-                footer as *const _ as usize
-            );
-            footer.ptr.set(NonNull::new_unchecked(ptr as *mut u8));
+            if self.fragment || layout.size() >= LARGE_OBJECT {
+                let size = if self.fragment {
+                    cmp::max(layout.size(), FRAGMENT_BLOCK_SIZE)
+                } else {
+                    layout.size()
+                };
+                let block = Block::exclusive(size, layout.align());
+                self.link(block);
+                if self.fragment {
+                    // keep filling it up with small values
+                    self.current_block.set(block);
+                }
+                return block.as_ref().bump(layout).unwrap();
+            }
 
-            // Return a pointer to the start of this block.
-            footer.data.cast::<u8>()
+            loop {
+                let footer = self.current_block.get().as_ref();
+                if let Some(p) = footer.bump(layout) {
+                    return p;
+                }
+                if footer.next_hole() {
+                    continue;
+                }
+
+                let next = self.recyclable.borrow_mut().pop();
+                let block = match next {
+                    Some(block) => {
+                        let free = block.as_ref().count_free_lines() * LINE_SIZE;
+                        self.set_free(self.free.get().saturating_sub(free));
+                        block
+                    }
+                    None => {
+                        let block = Block::standard();
+                        self.link(block);
+                        block
+                    }
+                };
+                self.current_block.set(block);
+            }
         }
     }
+}
 
-    // pub fn dealloc_block(ptr: BlockPtr, size: BlockSize) {
-    //     unsafe {
-    //         let layout = Layout::from_size_align_unchecked(size, size);
+impl Drop for Heap {
+    fn drop(&mut self) {
+        unsafe {
+            for finalizer in self.finalizers.get_mut().drain(..) {
+                (finalizer.drop)(finalizer.ptr.as_ptr());
+            }
 
-    //         Global.dealloc(ptr, layout);
-    //     }
-    // }
+            let blocks: Vec<_> = self.block_ptrs().collect();
+            for block in blocks {
+                Block::release(block);
+            }
+        }
+        self.set_size(0);
+        self.set_free(0);
+    }
 }
@@ -0,0 +1,459 @@
+//! A mark-region garbage collector for process heaps.
+//!
+//! Starting from the roots, every live value gets its lines marked, so that the space around it
+//! can be reused once the collection is done. Values in sparse blocks (and in absorbed message
+//! fragments) are evacuated instead, so those blocks can be freed as a whole. Pointers outside
+//! of the heap (module literals, persistent terms...) are left untouched.
+//!
+//! Values that survive a collection are old, and stay marked until the next major collection.
+//! Minor collections only visit young values: since terms are immutable, an old value can never
+//! point to a younger one.
+use super::block::{Block, Liveness, LARGE_OBJECT, LINES_PER_BLOCK, LINE_SIZE};
+use super::Heap;
+use crate::value::{self, Boxed, Closure, Cons, Header, Map, Term, Tuple, Variant, HAMT};
+use hashbrown::HashMap;
+use std::mem;
+use std::ptr::{self, NonNull};
+
+/// Calls a generic function with the type of a boxed value, based on its header. Covers every
+/// header declared by `boxed_types!`, tuples are variable sized and have to be handled first.
+macro_rules! with_boxed_type {
+    ($header:expr, $fun:ident($($arg:expr),*)) => {
+        crate::boxed_types!(match_boxed_type!($header, $fun($($arg),*)))
+    };
+}
+
+macro_rules! match_boxed_type {
+    ($header:expr, $fun:ident($($arg:expr),*); $($name:ident = $value:expr => $ty:ty,)*) => {
+        match $header {
+            $(value::$name => $fun::<$ty>($($arg),*),)*
+            // headers only ever come from the list above
+            i => unreachable!("unknown boxed header {}", i),
+        }
+    };
+}
+
+/// A block of the heap being collected.
+struct Region {
+    start: usize,
+    end: usize,
+    block: NonNull<Block>,
+    /// Live values get moved out instead of being marked in place.
+    evacuate: bool,
+    /// Lines holding old values (for standard blocks).
+    old_lines: [u64; LINES_PER_BLOCK / 64],
+    /// Set if the block holds old values (for the other blocks).
+    old: bool,
+}
+
+impl Region {
+    unsafe fn new(block: NonNull<Block>, evacuate: bool) -> Self {
+        let b = block.as_ref();
+        let (old_lines, old) = b.marks();
+        Region {
+            start: b.start(),
+            end: b.end(),
+            block,
+            evacuate,
+            old_lines,
+            old,
+        }
+    }
+
+    /// Did the value survive a previous collection?
+    fn is_old(&self, addr: usize) -> bool {
+        if unsafe { self.block.as_ref().is_standard() } {
+            let line = (addr - self.start) / LINE_SIZE;
+            self.old_lines[line / 64] & (1 << (line % 64)) != 0
+        } else {
+            self.old
+        }
+    }
+}
+
+pub struct Collector<'a> {
+    /// The heap being collected.
+    heap: &'a Heap,
+    /// The blocks of the heap, sorted by start address.
+    regions: Vec<Region>,
+    /// Where evacuated values go, merged back into the heap once we're done.
+    to: Heap,
+    /// Maps the address of each live value to its location after the collection.
+    forwarded: HashMap<usize, Term>,
+    /// Live values that might still point to values we haven't visited yet.
+    pending: Vec<Term>,
+}
+
+impl<'a> Collector<'a> {
+    /// A major collection, which visits every live value and compacts sparse blocks.
+    pub fn new(heap: &'a Heap) -> Self {
+        let regions = heap
+            .block_ptrs()
+            .map(|block| unsafe {
+                let b = block.as_ref();
+                b.clear_marks();
+                Region::new(block, b.should_evacuate())
+            })
+            .collect();
+        Collector::with_regions(heap, regions)
+    }
+
+    /// A minor collection, which only visits the young values. Old values stay in place (even
+    /// the dead ones) until the next major collection.
+    pub fn minor(heap: &'a Heap) -> Self {
+        let regions = heap
+            .block_ptrs()
+            .map(|block| unsafe { Region::new(block, block.as_ref().is_fragment()) })
+            .collect();
+        Collector::with_regions(heap, regions)
+    }
+
+    fn with_regions(heap: &'a Heap, mut regions: Vec<Region>) -> Self {
+        regions.sort_unstable_by_key(|region| region.start);
+
+        Collector {
+            heap,
+            regions,
+            to: Heap::with_kind(heap.kind()),
+            forwarded: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Marks a root as live (moving it if it's in a sparse block), and returns the updated
+    /// term. The values it points to are visited once the collection finishes.
+    pub fn copy(&mut self, term: Term) -> Term {
+        let addr = match term.into_variant() {
+            Variant::Cons(ptr) => ptr as usize,
+            Variant::Pointer(ptr) => ptr as usize,
+            _ => return term, // immediate
+        };
+
+        if let Some(new) = self.forwarded.get(&addr) {
+            return *new;
+        }
+
+        let (block, evacuate) = match find_region(&self.regions, addr) {
+            Some(region) if !region.is_old(addr) => (region.block, region.evacuate),
+            _ => return term, // old, or not on this heap
+        };
+
+        let new = unsafe {
+            if evacuate {
+                let new = self.evacuate(term);
+                let (addr, size) = extent(new);
+                if size < LARGE_OBJECT {
+                    Block::containing(addr).as_ref().mark(addr, size);
+                }
+                new
+            } else {
+                let (addr, size) = extent(term);
+                block.as_ref().mark(addr, size);
+                term
+            }
+        };
+        self.forwarded.insert(addr, new);
+        self.pending.push(new);
+        new
+    }
+
+    /// Makes a shallow copy of the value in the survivor blocks. Boxed values are moved bit for
+    /// bit, the old copy is forgotten and never dropped.
+    unsafe fn evacuate(&self, term: Term) -> Term {
+        match term.into_variant() {
+            Variant::Cons(ptr) => {
+                let cons = &*ptr;
+                value::cons(&self.to, cons.head, cons.tail)
+            }
+            Variant::Pointer(ptr) => match *ptr {
+                value::BOXED_TUPLE => {
+                    let tuple = &*(ptr as *const Tuple);
+                    let new = value::tuple(&self.to, tuple.len);
+                    new.copy_from_slice(tuple);
+                    Term::from(new)
+                }
+                header => with_boxed_type!(header, move_boxed(&self.to, ptr)),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Updates the pointers inside of a moved value.
+    unsafe fn scan(&mut self, term: Term) {
+        match term.into_variant() {
+            Variant::Cons(ptr) => {
+                let cons = &mut *(ptr as *mut Cons);
+                cons.head = self.copy(cons.head);
+                cons.tail = self.copy(cons.tail);
+            }
+            Variant::Pointer(ptr) => match *ptr {
+                value::BOXED_TUPLE => {
+                    let tuple = &mut *(ptr as *mut Tuple);
+                    for element in tuple.iter_mut() {
+                        *element = self.copy(*element);
+                    }
+                }
+                value::BOXED_MAP => {
+                    // map nodes might be shared with other maps, so rebuild instead of patching
+                    let map = &mut (*(ptr as *mut Boxed<Map>)).value;
+                    let mut new_map = HAMT::new();
+                    for (key, value) in map.0.iter() {
+                        new_map = new_map.plus(self.copy(*key), self.copy(*value));
+                    }
+                    map.0 = new_map;
+                }
+                value::BOXED_CLOSURE => {
+                    let closure = &mut (*(ptr as *mut Boxed<Closure>)).value;
+                    if let Some(binding) = &mut closure.binding {
+                        for value in binding.iter_mut() {
+                            *value = self.copy(*value);
+                        }
+                    }
+                }
+                _ => (), // the rest doesn't contain any terms
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Visits everything still reachable from the roots, then reclaims the space of dead values.
+    pub fn finish(mut self) {
+        while let Some(term) = self.pending.pop() {
+            unsafe { self.scan(term) }
+        }
+
+        let forwarded = self.forwarded;
+        let regions = &self.regions;
+        let is_old = |addr| find_region(regions, addr).map_or(false, |region| region.is_old(addr));
+        self.heap.finalize(|addr| match forwarded.get(&addr) {
+            None if is_old(addr) => Liveness::Live,
+            None => Liveness::Dead,
+            Some(term) if term_addr(*term) == addr => Liveness::Live,
+            Some(_) => Liveness::Moved,
+        });
+        self.heap.sweep(self.to);
+    }
+}
+
+/// Finds the block an address belongs to. Regions are sorted by start address.
+fn find_region(regions: &[Region], addr: usize) -> Option<&Region> {
+    // find the last block starting at or before addr
+    let i = match regions.binary_search_by_key(&addr, |region| region.start) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let region = &regions[i];
+    if addr < region.end {
+        Some(region)
+    } else {
+        None
+    }
+}
+
+unsafe fn move_boxed<T>(to: &Heap, ptr: *const Header) -> Term {
+    let boxed = ptr::read(ptr as *const Boxed<T>);
+    Term::from(to.alloc(boxed))
+}
+
+fn boxed_size<T>() -> usize {
+    mem::size_of::<Boxed<T>>()
+}
+
+fn term_addr(term: Term) -> usize {
+    match term.into_variant() {
+        Variant::Cons(ptr) => ptr as usize,
+        Variant::Pointer(ptr) => ptr as usize,
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the address and size of the heap value a term points to.
+unsafe fn extent(term: Term) -> (usize, usize) {
+    match term.into_variant() {
+        Variant::Cons(ptr) => (ptr as usize, mem::size_of::<Cons>()),
+        Variant::Pointer(ptr) => {
+            let size = match *ptr {
+                value::BOXED_TUPLE => {
+                    let tuple = &*(ptr as *const Tuple);
+                    mem::size_of::<Term>() * (tuple.len as usize + 1)
+                }
+                header => with_boxed_type!(header, boxed_size()),
+            };
+            (ptr as usize, size)
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstring;
+    use crate::servo_arc::Arc;
+    use crate::value::TryFrom;
+    use num_bigint::BigInt;
+
+    fn address(term: Term) -> *const Header {
+        match term.into_variant() {
+            Variant::Pointer(ptr) => ptr,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        let heap = Heap::new();
+
+        let tuple = tup2!(&heap, Term::bigint(&heap, BigInt::from(1) << 80), atom!(OK));
+        // the tuple is shared, so it should only get visited once
+        let list = cons!(&heap, tuple, cons!(&heap, tuple, Term::nil()));
+        let _garbage = tup2!(&heap, Term::int(1), Term::int(2));
+
+        let mut gc = Collector::new(&heap);
+        let list = gc.copy(list);
+        gc.finish();
+
+        let expected = &Heap::new();
+        let t = tup2!(
+            expected,
+            Term::bigint(expected, BigInt::from(1) << 80),
+            atom!(OK)
+        );
+        assert_eq!(list, cons!(expected, t, cons!(expected, t, Term::nil())));
+
+        let cons = Cons::try_from(&list).unwrap();
+        let tail = Cons::try_from(&cons.tail).unwrap();
+        // the block was full, so nothing moved
+        assert_eq!(address(cons.head), address(tuple));
+        assert_eq!(address(tail.head), address(tuple));
+        assert!(heap.contains(address(tuple)));
+    }
+
+    #[test]
+    fn test_collect_leaves_foreign_pointers() {
+        let heap = Heap::new();
+        let literals = Heap::new();
+
+        let literal = tup2!(&literals, atom!(OK), Term::int(1));
+        let list = cons!(&heap, literal, Term::nil());
+
+        let mut gc = Collector::new(&heap);
+        let list = gc.copy(list);
+        gc.finish();
+
+        let cons = Cons::try_from(&list).unwrap();
+        assert_eq!(address(cons.head), address(literal));
+        assert!(literals.contains(address(cons.head)));
+    }
+
+    #[test]
+    fn test_collect_evacuates_sparse_blocks() {
+        let heap = Heap::new();
+
+        let live = tup2!(
+            &heap,
+            Term::bigint(&heap, BigInt::from(1) << 80),
+            Term::int(1)
+        );
+        for i in 0..1000 {
+            tup2!(&heap, Term::int(i), Term::int(i));
+        }
+
+        // the first collection finds out the block is mostly empty
+        let mut gc = Collector::new(&heap);
+        let first = gc.copy(live);
+        gc.finish();
+        assert_eq!(address(first), address(live));
+
+        // ...so the second one moves the survivors out and frees it
+        let mut gc = Collector::new(&heap);
+        let second = gc.copy(first);
+        gc.finish();
+        assert_ne!(address(second), address(live));
+        assert!(heap.contains(address(second)));
+
+        let expected = &Heap::new();
+        assert_eq!(
+            second,
+            tup2!(
+                expected,
+                Term::bigint(expected, BigInt::from(1) << 80),
+                Term::int(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_collect_reuses_free_lines() {
+        let heap = Heap::new();
+
+        let live = tup2!(&heap, Term::int(1), Term::int(2));
+        for i in 0..1000 {
+            tup2!(&heap, Term::int(i), Term::int(i));
+        }
+        let size = heap.size();
+
+        let mut gc = Collector::new(&heap);
+        let live = gc.copy(live);
+        gc.finish();
+
+        // the dead tuples left holes behind, which get filled up before asking for more blocks
+        for i in 0..1000 {
+            let tuple = tup2!(&heap, Term::int(i), Term::int(i));
+            assert!(heap.contains(address(tuple)));
+        }
+        assert_eq!(heap.size(), size);
+        assert_eq!(live, tup2!(&heap, Term::int(1), Term::int(2)));
+    }
+
+    #[test]
+    fn test_minor_collect_leaves_old_values() {
+        let heap = Heap::new();
+
+        let binary = Arc::new(bitstring::Binary::from(vec![1, 2, 3]));
+        let old = Term::from(heap.alloc(Boxed {
+            header: value::BOXED_BINARY,
+            value: binary.clone(),
+        }));
+
+        // surviving a collection makes it old
+        let mut gc = Collector::new(&heap);
+        gc.copy(old);
+        gc.finish();
+
+        // young values pointing to it get collected as usual
+        let young = tup2!(&heap, old, Term::int(1));
+        let mut gc = Collector::minor(&heap);
+        assert_eq!(gc.copy(young), young);
+        gc.finish();
+
+        // the old value is unreachable now, but only a major collection notices
+        let gc = Collector::minor(&heap);
+        gc.finish();
+        assert!(!binary.is_unique());
+
+        let gc = Collector::new(&heap);
+        gc.finish();
+        assert!(binary.is_unique());
+    }
+
+    macro_rules! assert_boxed_sizes {
+        (; $($name:ident = $value:expr => $ty:ty,)*) => {
+            $(
+                let boxed = &mut Boxed {
+                    header: value::$name,
+                    value: (),
+                };
+                let term = Term::from(boxed);
+                let size = unsafe { extent(term).1 };
+                assert_eq!(size, mem::size_of::<Boxed<$ty>>(), stringify!($name));
+            )*
+        };
+    }
+
+    #[test]
+    fn test_extent_covers_all_boxed_types() {
+        crate::boxed_types!(assert_boxed_sizes!());
+    }
+}
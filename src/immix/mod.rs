@@ -1,2 +1,3 @@
 pub mod block;
-pub use self::block::Heap;
+pub mod gc;
+pub use self::block::{Heap, Kind};
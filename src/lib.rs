@@ -24,7 +24,6 @@ pub mod loader;
 pub mod mailbox;
 pub mod module;
 pub mod module_registry;
-mod numeric;
 pub mod opcodes;
 pub mod persistent_term;
 pub mod port;
@@ -32,6 +31,8 @@ pub mod process;
 pub mod regex;
 pub mod servo_arc;
 pub mod signal_queue;
+pub mod timer;
+pub mod trace;
 pub mod value;
 
 #[macro_use]
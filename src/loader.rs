@@ -1,8 +1,9 @@
 use crate::atom::{self, ATOMS};
+use crate::bif;
 use crate::bitstring;
 use crate::etf;
-use crate::immix::Heap;
-use crate::module::{Lambda, Module, MFA};
+use crate::immix::{Heap, Kind};
+use crate::module::{Import, Lambda, Module, MFA};
 use crate::opcodes::*;
 use crate::servo_arc::Arc;
 use crate::value::Term;
@@ -22,6 +23,7 @@ pub const LINE_INVALID_LOCATION: FuncInfo = (0, 0);
 pub struct Loader<'a> {
     atoms: Vec<&'a str>,
     imports: Vec<MFA>,
+    resolved_imports: Vec<Import>,
     exports: Vec<MFA>,
     literals: Vec<Term>,
     strings: Vec<u8>,
@@ -34,6 +36,7 @@ pub struct Loader<'a> {
     code: &'a [u8],
     literal_heap: Heap,
     instructions: Vec<Instruction>,
+    specialize: bool, // rewrite instructions into specialized forms
     on_load: Option<u32>,
 }
 
@@ -78,9 +81,10 @@ impl<'a> Loader<'a> {
         Loader {
             atoms: Vec::new(),
             imports: Vec::new(),
+            resolved_imports: Vec::new(),
             exports: Vec::new(),
             literals: Vec::new(),
-            literal_heap: Heap::new(),
+            literal_heap: Heap::with_kind(Kind::Literal),
             strings: Vec::new(),
             lambdas: Vec::new(),
             atom_map: HashMap::new(),
@@ -90,10 +94,18 @@ impl<'a> Loader<'a> {
             file_names: Vec::new(),
             code: &[],
             instructions: Vec::new(),
+            specialize: true,
             on_load: None,
         }
     }
 
+    /// Loads the code exactly as it was decoded, without specializing any instructions. Only
+    /// really useful to compare against.
+    pub fn generic(mut self) -> Self {
+        self.specialize = false;
+        self
+    }
+
     pub fn load_file(mut self, bytes: &'a [u8]) -> Result<Module, nom::Err<&'a [u8]>> {
         let (_, data) = scan_beam(bytes).unwrap();
         let mut chunks = HashMap::new();
@@ -128,12 +140,12 @@ impl<'a> Loader<'a> {
 
         // parse the instructions, swapping for global vals
         // - swap load atoms with global atoms
-        // - patch jump instructions to labels (store patches if label wasn't seen yet)
-        // - make imports work via pointers..
+        // - patch labels into absolute instruction offsets
         self.prepare();
 
         Ok(Module {
             imports: self.imports,
+            resolved_imports: self.resolved_imports,
             exports: self.exports,
             literals: self.literals,
             literal_heap: self.literal_heap,
@@ -265,9 +277,6 @@ impl<'a> Loader<'a> {
     fn prepare(&mut self) {
         self.postprocess_raw_code();
         self.postprocess_lambdas();
-
-        //self.postprocess_fix_labels()?;
-        //self.postprocess_setup_imports()?;
     }
 
     /// Loops twice, once to parse annotations, once to remap the args.
@@ -277,6 +286,16 @@ impl<'a> Loader<'a> {
 
         let mut code_iter = code.into_iter();
 
+        // Labels get patched into absolute offsets, but Label(0) marks a missing fail label. Offset 0
+        // is taken up by a dummy so that no jump target can ever be mistaken for it.
+        self.instructions.push(Instruction {
+            op: Opcode::IntCodeEnd,
+            args: vec![],
+        });
+
+        // offset of the last label, instructions can only be fused after it
+        let mut last_label = 0;
+
         while let Some(mut instruction) = code_iter.next() {
             let instruction = match &instruction.op {
                 Opcode::Line => {
@@ -324,6 +343,7 @@ impl<'a> Loader<'a> {
                     // one operand, Integer
                     if let LValue::Literal(i) = instruction.args[0] {
                         self.labels.insert(i as u32, self.instructions.len() as u32);
+                        last_label = self.instructions.len();
                     } else {
                         panic!("Bad argument to {:?}", instruction.op)
                     }
@@ -419,7 +439,7 @@ impl<'a> Loader<'a> {
                 _ => instruction,
             };
 
-            self.instructions.push(instruction);
+            self.push_instruction(instruction, last_label);
         }
 
         let atom_map = &self.atom_map;
@@ -455,7 +475,125 @@ impl<'a> Loader<'a> {
                     _ => postprocess_value(arg),
                 })
                 .collect();
-        })
+        });
+
+        // resolve BIF imports straight to the function, the rest get linked to export entries
+        // once the module is loaded.
+        self.resolved_imports = self
+            .imports
+            .iter()
+            .map(|mfa| match bif::BIFS.get(mfa) {
+                Some((fun, dirty)) => Import::Bif(*fun, *dirty),
+                None => Import::Unresolved,
+            })
+            .collect();
+    }
+
+    /// Pushes an instruction, rewriting it into one of the specialized instructions where possible
+    /// (the equivalent of the transformations in OTP's ops.tab). Sequences are fused into the first
+    /// instruction's slot, so offsets that were already recorded for it stay valid.
+    fn push_instruction(&mut self, mut instruction: Instruction, last_label: usize) {
+        if !self.specialize {
+            self.instructions.push(instruction);
+            return;
+        }
+
+        if instruction.op == Opcode::SelectVal {
+            if let Some(args) = jump_table(&instruction.args) {
+                instruction.op = Opcode::SelectValJump;
+                instruction.args = args;
+            }
+        }
+
+        // A label pointing into the middle of a sequence would be jumping into the fused
+        // instruction, so we only fuse with instructions after the last label.
+        let prev = if self.instructions.len() > last_label {
+            self.instructions.last()
+        } else {
+            None
+        };
+
+        let fused = match (prev, &instruction) {
+            (
+                Some(Instruction {
+                    op: Opcode::IsTuple,
+                    args: prev,
+                }),
+                Instruction {
+                    op: Opcode::TestArity,
+                    args,
+                },
+            ) if prev[..] == args[..2] => Some(Instruction {
+                op: Opcode::IsTupleOfArity,
+                args: args.clone(),
+            }),
+            (
+                Some(Instruction {
+                    op: Opcode::Move,
+                    args: prev,
+                }),
+                Instruction { op, args },
+            ) if *op == Opcode::Call || *op == Opcode::CallLast || *op == Opcode::CallOnly => {
+                let op = match op {
+                    Opcode::Call => Opcode::MoveCall,
+                    Opcode::CallLast => Opcode::MoveCallLast,
+                    _ => Opcode::MoveCallOnly,
+                };
+                Some(Instruction {
+                    op,
+                    args: prev.iter().chain(args.iter()).cloned().collect(),
+                })
+            }
+            (
+                Some(Instruction {
+                    op: Opcode::Move,
+                    args: prev,
+                }),
+                Instruction {
+                    op: Opcode::Return,
+                    ..
+                },
+            ) if prev[1] == LValue::X(0) => Some(Instruction {
+                op: Opcode::MoveReturn,
+                args: vec![prev[0].clone()],
+            }),
+            (
+                Some(Instruction {
+                    op: Opcode::Deallocate,
+                    args: prev,
+                }),
+                Instruction {
+                    op: Opcode::Return,
+                    ..
+                },
+            ) => Some(Instruction {
+                op: Opcode::DeallocateReturn,
+                args: prev.clone(),
+            }),
+            (
+                Some(Instruction {
+                    op: Opcode::Move,
+                    args: prev,
+                }),
+                Instruction {
+                    op: Opcode::DeallocateReturn,
+                    args,
+                },
+            ) if prev[1] == LValue::X(0) => Some(Instruction {
+                op: Opcode::MoveDeallocateReturn,
+                args: vec![prev[0].clone(), args[0].clone()],
+            }),
+            _ => None,
+        };
+
+        match fused {
+            Some(fused) => {
+                self.instructions.pop();
+                // the fused instruction might in turn fuse with the one before it
+                self.push_instruction(fused, last_label)
+            }
+            None => self.instructions.push(instruction),
+        }
     }
 
     fn postprocess_lambdas(&mut self) {
@@ -466,6 +604,49 @@ impl<'a> Loader<'a> {
     }
 }
 
+/// Minimum amount of values a select_val needs to be turned into a jump table.
+const JUMP_TABLE_MIN: usize = 3;
+
+/// Turns the arguments of a select_val on integers into the arguments of a select_val_jump, if
+/// the values are dense enough that at least half of the table is used.
+fn jump_table(args: &[LValue]) -> Option<Vec<LValue>> {
+    if let [arg, fail, LValue::ExtendedList(vec)] = args {
+        let count = vec.len() / 2;
+        if count < JUMP_TABLE_MIN {
+            return None;
+        }
+
+        let mut keys = Vec::with_capacity(count);
+        for pair in vec.chunks(2) {
+            match pair {
+                [LValue::Integer(i), label] => keys.push((i64::from(*i), label)),
+                _ => return None,
+            }
+        }
+
+        let min = keys.iter().map(|(i, _)| *i).min().unwrap();
+        let max = keys.iter().map(|(i, _)| *i).max().unwrap();
+        let size = (max - min + 1) as usize;
+        if size > count * 2 {
+            return None;
+        }
+
+        let mut table = vec![fail.clone(); size];
+        // iterate in reverse so that the first match wins on duplicate keys, same as select_val
+        for (i, label) in keys.into_iter().rev() {
+            table[(i - min) as usize] = label.clone();
+        }
+
+        return Some(vec![
+            arg.clone(),
+            fail.clone(),
+            LValue::Integer(min as i32),
+            LValue::ExtendedList(Box::new(table)),
+        ]);
+    }
+    None
+}
+
 fn decode_literals<'a>(rest: &'a [u8], heap: &Heap) -> IResult<&'a [u8], Vec<Term>> {
     do_parse!(
         rest,
@@ -849,4 +1030,185 @@ mod tests {
             Ok((&[] as &[u8], LValue::Literal(15)))
         );
     }
+
+    #[test]
+    fn test_labels_patched_to_offsets() {
+        let bytes = std::fs::read("examples/fib.beam").unwrap();
+        let module = Loader::new().load_file(&bytes).unwrap();
+        let len = module.instructions.len() as u32;
+
+        let check = |arg: &LValue| {
+            if let LValue::Label(offset) = arg {
+                assert!(*offset < len, "label points outside of the code: {}", offset);
+            }
+        };
+
+        for ins in &module.instructions {
+            assert_ne!(ins.op, Opcode::Label);
+            for arg in &ins.args {
+                match arg {
+                    LValue::ExtendedList(list) => list.iter().for_each(check),
+                    arg => check(arg),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_imports_resolved() {
+        use crate::exception::Reason;
+        use crate::exports_table::ExportsTable;
+        let bytes = std::fs::read("examples/Elixir.Bin.beam").unwrap();
+        let mut module = Loader::new().load_file(&bytes).unwrap();
+        assert_eq!(module.resolved_imports.len(), module.imports.len());
+
+        // BIFs resolve right away, the rest once the module gets linked
+        let (mut bifs, mut others) = (0, 0);
+        for (import, mfa) in module.resolved_imports.iter().zip(module.imports.iter()) {
+            match import {
+                Import::Bif(..) => {
+                    assert!(bif::BIFS.contains_key(mfa));
+                    bifs += 1;
+                }
+                Import::Unresolved => {
+                    assert!(!bif::BIFS.contains_key(mfa));
+                    others += 1;
+                }
+                Import::Export(..) => panic!("{} linked before loading", mfa),
+            }
+        }
+        assert!(bifs > 0 && others > 0);
+
+        let mut exports = ExportsTable::with_rc().into_inner();
+        module.link_imports(&mut exports);
+        let imports = module.resolved_imports.iter().zip(module.imports.iter());
+        for (i, (import, mfa)) in imports.enumerate() {
+            match import {
+                Import::Bif(..) => assert!(module.bif(i as u32).is_ok()),
+                Import::Export(entry) => {
+                    assert!(std::sync::Arc::ptr_eq(entry, &exports.entry(*mfa)));
+                    let res = module.bif(i as u32);
+                    assert_eq!(res.err().map(|exc| exc.reason), Some(Reason::EXC_UNDEF));
+                }
+                Import::Unresolved => panic!("{} wasn't linked", mfa),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuse_instructions() {
+        let mut loader = Loader::new();
+        loader.push_instruction(
+            Instruction {
+                op: Opcode::Move,
+                args: vec![LValue::X(1), LValue::X(0)],
+            },
+            0,
+        );
+        loader.push_instruction(
+            Instruction {
+                op: Opcode::Call,
+                args: vec![LValue::Literal(1), LValue::Label(2)],
+            },
+            0,
+        );
+        loader.push_instruction(
+            Instruction {
+                op: Opcode::Deallocate,
+                args: vec![LValue::Literal(1)],
+            },
+            0,
+        );
+        loader.push_instruction(
+            Instruction {
+                op: Opcode::Return,
+                args: vec![],
+            },
+            0,
+        );
+
+        assert_eq!(loader.instructions.len(), 2);
+        assert_eq!(loader.instructions[0].op, Opcode::MoveCall);
+        assert_eq!(
+            loader.instructions[0].args,
+            vec![
+                LValue::X(1),
+                LValue::X(0),
+                LValue::Literal(1),
+                LValue::Label(2)
+            ]
+        );
+        assert_eq!(loader.instructions[1].op, Opcode::DeallocateReturn);
+    }
+
+    #[test]
+    fn test_fuse_instructions_across_label() {
+        let mut loader = Loader::new();
+        loader.push_instruction(
+            Instruction {
+                op: Opcode::Move,
+                args: vec![LValue::X(1), LValue::X(0)],
+            },
+            0,
+        );
+        // a label points at the return, so it can't be merged into the move
+        loader.push_instruction(
+            Instruction {
+                op: Opcode::Return,
+                args: vec![],
+            },
+            1,
+        );
+
+        assert_eq!(loader.instructions.len(), 2);
+        assert_eq!(loader.instructions[0].op, Opcode::Move);
+        assert_eq!(loader.instructions[1].op, Opcode::Return);
+    }
+
+    #[test]
+    fn test_jump_table() {
+        let args = vec![
+            LValue::X(0),
+            LValue::Label(1),
+            LValue::ExtendedList(Box::new(vec![
+                LValue::Integer(3),
+                LValue::Label(4),
+                LValue::Integer(1),
+                LValue::Label(2),
+                LValue::Integer(5),
+                LValue::Label(6),
+            ])),
+        ];
+
+        assert_eq!(
+            jump_table(&args),
+            Some(vec![
+                LValue::X(0),
+                LValue::Label(1),
+                LValue::Integer(1),
+                LValue::ExtendedList(Box::new(vec![
+                    LValue::Label(2),
+                    LValue::Label(1),
+                    LValue::Label(4),
+                    LValue::Label(1),
+                    LValue::Label(6),
+                ])),
+            ])
+        );
+
+        // too sparse
+        let args = vec![
+            LValue::X(0),
+            LValue::Label(1),
+            LValue::ExtendedList(Box::new(vec![
+                LValue::Integer(1),
+                LValue::Label(2),
+                LValue::Integer(100),
+                LValue::Label(3),
+                LValue::Integer(1000),
+                LValue::Label(4),
+            ])),
+        ];
+        assert_eq!(jump_table(&args), None);
+    }
 }
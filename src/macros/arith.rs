@@ -10,10 +10,12 @@ macro_rules! to_expr {
 ///
 /// This macro takes the following arguments:
 ///
-/// * `$process`: the process that is performing the operation.
-/// * `$context`: the current ExecutionContext.
+/// * `$heap`: the heap to allocate bigints on.
+/// * `$args`: the two operands.
 /// * `$op`: the binary operator to use for non overflowing operations.
 /// * `$overflow`: the method to use for an overflowing operation.
+///
+/// Bigint results that fit into a small integer are demoted by Term::bigint.
 #[macro_export]
 macro_rules! integer_overflow_op {
     (
@@ -22,7 +24,6 @@ macro_rules! integer_overflow_op {
         $op:ident,
         $overflow:ident
     ) => {{
-        use num_traits::cast::ToPrimitive;
         // TODO: figure out if we can reduce amount of cloning here.
         match [$args[0].into_number(), $args[1].into_number()] {
             [Ok(value::Num::Integer(rec)), Ok(value::Num::Integer(arg))] => {
@@ -39,53 +40,54 @@ macro_rules! integer_overflow_op {
                     let result = to_expr!(BigInt::from(rec).$op(BigInt::from(arg)));
 
                     Term::bigint($heap, result)
-                // $heap.allocate(object_value::bigint(result))
-                // } else if ObjectPointer::integer_too_large(result) {
-                //     // An operation that doesn't overflow may still produce a number
-                //     // too large to store in a tagged pointer. In this case we'll
-                //     // allocate the result as a heap integer.
-                //     $heap.allocate(object_value::integer(result))
                 } else {
                     Term::int(result)
                 }
             }
             [Ok(value::Num::Bignum(rec)), Ok(value::Num::Integer(arg))] => {
                 // Example: bigint + int -> bigint
-
-                let bigint = to_expr!(rec.$op(arg));
-
-                if let Some(i) = bigint.to_i32() {
-                    Term::int(i)
-                } else {
-                    Term::bigint($heap, bigint)
-                }
+                Term::bigint($heap, to_expr!(rec.$op(BigInt::from(arg))))
             }
             [Ok(value::Num::Integer(rec)), Ok(value::Num::Bignum(arg))] => {
                 // Example: int + bigint -> bigint
-
-                let rec = BigInt::from(rec);
-                let bigint = to_expr!(rec.$op(arg));
-
-                if let Some(i) = bigint.to_i32() {
-                    Term::int(i)
-                } else {
-                    Term::bigint($heap, bigint)
-                }
+                Term::bigint($heap, to_expr!(BigInt::from(rec).$op(arg)))
             }
             [Ok(value::Num::Bignum(rec)), Ok(value::Num::Bignum(arg))] => {
                 // Example: bigint + bigint -> bigint
-
-                let bigint = to_expr!(rec.$op(arg));
-
-                if let Some(i) = bigint.to_i32() {
-                    Term::int(i)
-                } else {
-                    Term::bigint($heap, bigint)
-                }
+                Term::bigint($heap, to_expr!(rec.$op(arg)))
             }
             _ => {
-                return Err(Exception::new(Reason::EXC_BADARG));
+                return Err(Exception::new(Reason::EXC_BADARITH));
+            }
+        }
+    }};
+}
+
+/// Same as integer_overflow_op, but floats are allowed too. If either operand is a float, the
+/// operation is done on floats.
+#[macro_export]
+macro_rules! number_op {
+    (
+        $heap:expr,
+        $args:expr,
+        $op:ident,
+        $overflow:ident
+    ) => {{
+        if $args[0].is_float() || $args[1].is_float() {
+            let rec = $args[0].into_number().ok().and_then(|n| n.to_f64());
+            let arg = $args[1].into_number().ok().and_then(|n| n.to_f64());
+            match (rec, arg) {
+                (Some(rec), Some(arg)) => {
+                    let result = to_expr!(rec.$op(arg));
+                    if !result.is_finite() {
+                        return Err(Exception::new(Reason::EXC_BADARITH));
+                    }
+                    Term::from(result)
+                }
+                _ => return Err(Exception::new(Reason::EXC_BADARITH)),
             }
+        } else {
+            integer_overflow_op!($heap, $args, $op, $overflow)
         }
     }};
 }
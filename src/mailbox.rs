@@ -1,10 +1,19 @@
 use std::collections::VecDeque;
 
+use crate::immix::Heap;
 use crate::value::Term;
 
+/// A message, along with the heap fragment it lives on if it's kept off the process heap (see
+/// the message_queue_data process flag).
+#[derive(Debug)]
+struct Message {
+    value: Term,
+    heap: Option<Heap>,
+}
+
 #[derive(Debug, Default)]
 pub struct Mailbox {
-    queue: VecDeque<Term>,
+    queue: VecDeque<Message>,
 
     /// Save pointer to track position to the current offset when scanning through the mailbox.
     save: usize,
@@ -21,11 +30,22 @@ impl Mailbox {
     }
 
     pub fn send(&mut self, message: Term) {
-        self.queue.push_back(message);
+        self.queue.push_back(Message {
+            value: message,
+            heap: None,
+        });
+    }
+
+    /// Queues a message that stays on its own heap fragment until it gets removed.
+    pub fn send_off_heap(&mut self, message: Term, heap: Heap) {
+        self.queue.push_back(Message {
+            value: message,
+            heap: Some(heap),
+        });
     }
 
     pub fn receive(&mut self) -> Option<Term> {
-        self.queue.get(self.save).copied()
+        self.queue.get(self.save).map(|message| message.value)
     }
 
     // recv_mark
@@ -50,9 +70,32 @@ impl Mailbox {
     }
 
     /// We use a reference when we receive, because of pattern matching.
-    /// Once we're done with a message, we have to specifically pop
-    pub fn remove(&mut self) {
-        self.queue.remove(self.save);
+    /// Once we're done with a message, we have to specifically pop. If the message was kept off
+    /// heap, its fragment is returned so that the process can absorb it.
+    pub fn remove(&mut self) -> Option<Heap> {
+        self.queue
+            .remove(self.save)
+            .and_then(|message| message.heap)
+    }
+
+    /// Removes the first message matching a predicate, outside of a receive. Used to flush
+    /// stale monitor messages. Returns true if a message was removed.
+    pub fn remove_first<F: Fn(Term) -> bool>(&mut self, predicate: F) -> bool {
+        let pos = match self.queue.iter().position(|message| predicate(message.value)) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        self.queue.remove(pos);
+        // keep the save pointer and mark on the same messages
+        if pos < self.save {
+            self.save -= 1;
+        }
+        if let Some(mark) = self.mark.as_mut() {
+            if pos < *mark {
+                *mark -= 1;
+            }
+        }
+        true
     }
 
     pub fn has_messages(&self) -> bool {
@@ -64,4 +107,29 @@ impl Mailbox {
     pub fn len(&self) -> usize {
         self.queue.len()
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Term> {
+        self.queue.iter().map(|message| &message.value)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Term> {
+        self.queue.iter_mut().map(|message| &mut message.value)
+    }
+
+    /// Total size of the fragments of the messages kept off heap, in bytes.
+    pub fn fragments_size(&self) -> usize {
+        self.queue
+            .iter()
+            .filter_map(|message| message.heap.as_ref())
+            .map(|heap| heap.size())
+            .sum()
+    }
+
+    /// Detaches the fragments of all the messages kept off heap, the messages stay queued.
+    pub fn take_fragments(&mut self) -> Vec<Heap> {
+        self.queue
+            .iter_mut()
+            .filter_map(|message| message.heap.take())
+            .collect()
+    }
 }
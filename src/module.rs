@@ -1,12 +1,14 @@
 use crate::atom;
 use crate::bif;
-use crate::exports_table::ExportsTable;
+use crate::exception::{Exception, Reason};
+use crate::exports_table::{ExportsTable, RcExport};
 use crate::immix::Heap;
 use crate::instr_ptr::InstrPtr;
 use crate::loader::{FuncInfo, Instruction};
+use crate::process::Process;
 use crate::value::{self, Term, TryFrom, Variant};
 use crate::vm::Machine;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct MFA(pub u32, pub u32, pub u32);
@@ -51,10 +53,32 @@ pub struct Lambda {
     pub ouniq: u32, // ?
 }
 
+/// An import, resolved ahead of time so that calls don't need to look it up.
+#[derive(Clone)]
+pub enum Import {
+    /// BIFs can't be redefined, so the loader resolves them straight to the function.
+    Bif(bif::Fn, Option<bif::Dirty>),
+    /// Anything else gets linked to its export entry when the module is loaded.
+    Export(RcExport),
+    Unresolved,
+}
+
+impl std::fmt::Debug for Import {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Import::Bif(..) => write!(f, "Import(bif)"),
+            Import::Export(..) => write!(f, "Import(export)"),
+            Import::Unresolved => write!(f, "Import(unresolved)"),
+        }
+    }
+}
+
 // TODO: add new, remove pub for all these fields
 #[derive(Debug)]
 pub struct Module {
     pub imports: Vec<MFA>, // mod,  func, arity
+    /// Resolved imports, indexed the same as imports.
+    pub resolved_imports: Vec<Import>,
     pub exports: Vec<MFA>, // func, arity, label
     pub literals: Vec<Term>,
     pub literal_heap: Heap,
@@ -69,6 +93,40 @@ pub struct Module {
 }
 
 impl Module {
+    /// Links the imports that aren't BIFs to their export entries.
+    pub fn link_imports(&mut self, exports: &mut ExportsTable) {
+        for (import, mfa) in self.resolved_imports.iter_mut().zip(self.imports.iter()) {
+            if let Import::Unresolved = import {
+                *import = Import::Export(exports.entry(*mfa));
+            }
+        }
+    }
+
+    /// Returns the BIF an import resolved to, used by the bif and gc_bif instructions. BIFs we
+    /// don't implement resolve to an export entry instead, calling them fails with undef.
+    #[inline]
+    pub fn bif(&self, index: u32) -> Result<(bif::Fn, Option<bif::Dirty>), Exception> {
+        match self.resolved_imports[index as usize] {
+            Import::Bif(fun, dirty) => Ok((fun, dirty)),
+            _ => Err(Exception::new(Reason::EXC_UNDEF)),
+        }
+    }
+
+    /// Memory used by the module's code and tables, in bytes. The literal heap is accounted for
+    /// separately.
+    pub fn memory(&self) -> usize {
+        use std::mem::size_of;
+        size_of::<Module>()
+            + self.imports.capacity() * size_of::<MFA>()
+            + self.resolved_imports.capacity() * size_of::<Import>()
+            + self.exports.capacity() * size_of::<MFA>()
+            + self.literals.capacity() * size_of::<Term>()
+            + self.lambdas.capacity() * size_of::<Lambda>()
+            + self.funs.capacity() * size_of::<((u32, u32), u32)>()
+            + self.instructions.capacity() * size_of::<Instruction>()
+            + self.lines.capacity() * size_of::<FuncInfo>()
+    }
+
     fn process_exports(&self, exports: &mut ExportsTable) {
         // process_exports
         let funs = &self.funs;
@@ -88,11 +146,11 @@ impl Module {
         });
     }
 
-    pub fn load_nifs(&mut self, vm: &Machine, nifs: &[(u32, u32, bif::Fn)]) {
+    pub fn load_nifs(&mut self, vm: &Machine, nifs: &[(u32, u32, bif::Fn, Option<bif::Dirty>)]) {
         use crate::loader::LValue;
         let mut exports = vm.exports.write();
 
-        for (name, arity, fun) in nifs {
+        for (name, arity, fun, dirty) in nifs {
             // find func_info
             if let Some(i) = self.instructions.iter().position(|ins| {
                 let lname = LValue::Atom(*name);
@@ -102,10 +160,12 @@ impl Module {
                     && ins.args[2] == larity
             }) {
                 let mfa = MFA(self.name, *name, *arity);
-                exports.insert(mfa, crate::exports_table::Export::Bif(*fun));
+                exports.insert(mfa, crate::exports_table::Export::bif(*fun, *dirty));
 
                 let pos = self.imports.len();
                 self.imports.push(mfa);
+                let entry = exports.entry(mfa);
+                self.resolved_imports.push(Import::Export(entry));
                 // replace instruction immediately after with call_nif
                 self.instructions[i + 1] = Instruction {
                     op: crate::opcodes::Opcode::CallExtOnly,
@@ -123,15 +183,16 @@ pub fn load_module(vm: &Machine, path: &str) -> Result<*const Module, std::io::E
     let mut registry = vm.modules.lock();
     let mut exports = vm.exports.write();
 
-    registry.parse_module(path).map(|module| {
+    registry.parse_module(path, &mut exports).map(|module| {
         module.process_exports(&mut *exports);
         module as *const Module
     })
 }
 
 pub fn finish_loading_modules(vm: &Machine, modules: Vec<Box<Module>>) {
-    for module in modules {
+    for mut module in modules {
         let mut registry = vm.modules.lock();
+        module.link_imports(&mut vm.exports.write());
         let module = registry.add_module(module.name, module);
 
         {
@@ -149,6 +210,121 @@ pub fn finish_loading_modules(vm: &Machine, modules: Vec<Box<Module>>) {
     }
 }
 
+/// Returns true if the process is executing code in the given module: either currently, by
+/// returning to it (continuation pointers and catches on the stack), or by holding on to a fun
+/// it created. Only valid on the process itself, or while it's being inspected.
+pub fn check_process_code(process: &Process, module: *const Module) -> bool {
+    let context = process.context();
+
+    if context.ip.module == module {
+        return true;
+    }
+
+    if let Some(cp) = context.cp {
+        if cp.module == module {
+            return true;
+        }
+    }
+
+    any_term(process, |term, _| match term.get_boxed_header() {
+        Ok(value::BOXED_CP) => match term.get_boxed_value::<Option<InstrPtr>>() {
+            Ok(Some(cp)) => cp.module == module,
+            _ => false,
+        },
+        Ok(value::BOXED_CATCH) => match term.get_boxed_value::<InstrPtr>() {
+            Ok(ptr) => ptr.module == module,
+            _ => false,
+        },
+        Ok(value::BOXED_CLOSURE) => match value::Closure::try_from(&term) {
+            Ok(closure) => closure.module == module,
+            _ => false,
+        },
+        _ => false,
+    })
+}
+
+/// Returns true if any of the terms the process can reach lives in one of the given blocks, the
+/// address ranges of a (literal) heap. Same rules as check_process_code apply.
+pub fn check_process_heap(process: &Process, blocks: &[(usize, usize)]) -> bool {
+    any_term(process, |_, addr| {
+        blocks
+            .iter()
+            .any(|(start, end)| addr >= *start && addr < *end)
+    })
+}
+
+/// Walks the boxed values and list cells the process can reach, until f returns true for one of
+/// them. Values outside of the process heap (like literals) are passed to f, but not looked into.
+fn any_term(process: &Process, mut f: impl FnMut(Term, usize) -> bool) -> bool {
+    let local_data = process.local_data();
+    let context = process.context();
+
+    // x registers past the live ones are cleared on collection, so they're all safe to look at
+    let mut pending: Vec<Term> = context
+        .x
+        .iter()
+        .chain(context.stack.iter())
+        .chain(local_data.mailbox.iter())
+        .copied()
+        .collect();
+    for (key, value) in local_data.dictionary.iter() {
+        pending.push(*key);
+        pending.push(*value);
+    }
+    if let Some(exc) = &context.exc {
+        pending.push(exc.value);
+        pending.push(exc.trace);
+    }
+
+    let mut visited = HashSet::new();
+    while let Some(term) = pending.pop() {
+        let addr = match term.into_variant() {
+            Variant::Cons(ptr) => ptr as usize,
+            Variant::Pointer(ptr) => ptr as usize,
+            _ => continue, // immediate
+        };
+        if !visited.insert(addr) {
+            continue;
+        }
+        if f(term, addr) {
+            return true;
+        }
+        if !context.heap.contains(addr as *const u8) {
+            continue;
+        }
+
+        unsafe {
+            match term.into_variant() {
+                Variant::Cons(ptr) => {
+                    pending.push((*ptr).head);
+                    pending.push((*ptr).tail);
+                }
+                Variant::Pointer(ptr) => match *ptr {
+                    value::BOXED_TUPLE => {
+                        pending.extend((*(ptr as *const value::Tuple)).iter());
+                    }
+                    value::BOXED_MAP => {
+                        let map = &(*(ptr as *const value::Boxed<value::Map>)).value;
+                        for (key, value) in map.0.iter() {
+                            pending.push(*key);
+                            pending.push(*value);
+                        }
+                    }
+                    value::BOXED_CLOSURE => {
+                        let closure = &(*(ptr as *const value::Boxed<value::Closure>)).value;
+                        if let Some(binding) = &closure.binding {
+                            pending.extend(binding.iter());
+                        }
+                    }
+                    _ => (), // the rest doesn't contain any terms
+                },
+                _ => unreachable!(),
+            }
+        }
+    }
+    false
+}
+
 // Ugh
 // TODO: to be TryFrom once rust stabilizes the trait
 impl TryFrom<Term> for *mut Module {
@@ -166,3 +342,30 @@ impl TryFrom<Term> for *mut Module {
         Err(value::WrongBoxError)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exports_table::Export;
+
+    #[test]
+    fn test_reload_updates_export_entries() {
+        let vm = crate::vm::Machine::new();
+        let first = load_module(&vm, "examples/fib.beam").unwrap();
+        let (name, export) = unsafe { ((*first).name, (*first).exports[0]) };
+        let mfa = MFA(name, export.0, export.1);
+        let entry = vm.exports.write().entry(mfa);
+        let linked = |entry: &RcExport| match *entry.read() {
+            Some(Export::Fun(ptr)) => ptr.module,
+            _ => panic!("{} isn't loaded", mfa),
+        };
+        assert_eq!(linked(&entry), first);
+
+        // code that linked against the entry before the reload reaches the new version
+        let second = load_module(&vm, "examples/fib.beam").unwrap();
+        assert_ne!(second, first);
+        assert_eq!(linked(&entry), second);
+        let old = vm.modules.lock().lookup_old(name).unwrap() as *const Module;
+        assert_eq!(old, first);
+    }
+}
@@ -1,38 +1,113 @@
+use crate::exports_table::ExportsTable;
 use crate::loader::Loader;
 use crate::module::Module;
+use crate::process::PID;
 use hashbrown::HashMap;
 use parking_lot::Mutex;
 
 pub type RcModuleRegistry = Mutex<ModuleRegistry>;
 
+/// Keeps up to two versions of each module, like OTP: the current one, which external calls go
+/// to, and an old one that's kept around for processes still executing it after a reload.
 pub struct ModuleRegistry {
     pub modules: HashMap<u32, Box<Module>>,
+    pub old_modules: HashMap<u32, Box<Module>>,
+    /// Purged code that processes might still be using: killed ones that haven't exited yet,
+    /// and ones holding on to its literals. It's freed once they're all gone.
+    purged: Vec<(Box<Module>, Vec<PID>)>,
 }
 
 impl ModuleRegistry {
     pub fn with_rc() -> RcModuleRegistry {
         Mutex::new(ModuleRegistry {
             modules: HashMap::new(),
+            old_modules: HashMap::new(),
+            purged: Vec::new(),
         })
     }
 
     /// Parses a full file path pointing to a module.
-    pub fn parse_module(&mut self, path: &str) -> Result<&Module, std::io::Error> {
+    pub fn parse_module(
+        &mut self,
+        path: &str,
+        exports: &mut ExportsTable,
+    ) -> Result<&Module, std::io::Error> {
         let bytes = std::fs::read(path)?;
 
         let loader = Loader::new();
-        let module = loader.load_file(&bytes[..]).unwrap();
+        let mut module = loader.load_file(&bytes[..]).unwrap();
+        module.link_imports(exports);
 
         let name = module.name;
         Ok(self.add_module(name, Box::new(module)))
     }
 
+    /// Adds a new version of a module, the previous version becomes old code. Any old code that
+    /// was still around gets purged, callers should check for it beforehand.
     pub fn add_module(&mut self, atom: u32, module: Box<Module>) -> &Module {
-        self.modules.insert(atom, module);
+        if let Some(current) = self.modules.insert(atom, module) {
+            self.old_modules.insert(atom, current);
+        }
         &*self.modules[&atom]
     }
 
+    /// Memory used by the code of all the loaded modules (current and old), in bytes.
+    pub fn memory(&self) -> usize {
+        self.modules
+            .values()
+            .chain(self.old_modules.values())
+            .map(|module| module.memory())
+            .sum()
+    }
+
     pub fn lookup(&self, atom: u32) -> Option<&Module> {
         self.modules.get(&atom).map(|module| &(**module))
     }
+
+    pub fn lookup_old(&self, atom: u32) -> Option<&Module> {
+        self.old_modules.get(&atom).map(|module| &(**module))
+    }
+
+    pub fn has_old_code(&self, atom: u32) -> bool {
+        self.old_modules.contains_key(&atom)
+    }
+
+    /// Returns true if the module version is still loaded, as either current or old code.
+    pub fn is_loaded(&self, atom: u32, module: *const Module) -> bool {
+        let is = |loaded: Option<&Module>| loaded.map_or(false, |m| m as *const Module == module);
+        is(self.lookup(atom)) || is(self.lookup_old(atom))
+    }
+
+    /// Turns the current version of a module into old code. Returns false if the module isn't
+    /// loaded. Like add_module, any old code that was still around gets purged.
+    pub fn delete(&mut self, atom: u32) -> bool {
+        match self.modules.remove(&atom) {
+            Some(current) => {
+                self.old_modules.insert(atom, current);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the old version of a module, handing it back to the caller.
+    pub fn purge(&mut self, atom: u32) -> Option<Box<Module>> {
+        self.old_modules.remove(&atom)
+    }
+
+    /// Frees a purged module once none of the given processes are alive anymore.
+    pub fn release(&mut self, module: Box<Module>, users: Vec<PID>) {
+        self.purged.push((module, users));
+    }
+
+    /// Frees the purged modules that are no longer in use.
+    pub fn release_unused(&mut self, is_alive: impl Fn(PID) -> bool) {
+        self.purged
+            .retain(|(_, users)| users.iter().any(|pid| is_alive(*pid)));
+    }
+
+    /// Number of purged modules that are waiting on processes to exit before they're freed.
+    pub fn pending_release(&self) -> usize {
+        self.purged.len()
+    }
 }
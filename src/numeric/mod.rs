@@ -1,2 +0,0 @@
-pub mod division;
-pub mod modulo;
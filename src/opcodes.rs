@@ -561,6 +561,43 @@ pub enum Opcode {
     /// @spec bs_set_positon Ctx Pos
     /// @doc  Sets the current position of Ctx to Pos
     BsSetPosition = 168,
+
+    // Specialized instructions. These are never present in a .beam file, the loader rewrites
+    // generic instructions into them (see Loader::push_instruction). They have no entry in
+    // ARITY_MAP, so a file that tries to use them directly fails to decode.
+
+    /// @spec is_tuple_of_arity Lbl Arg Arity
+    /// @doc  is_tuple Lbl Arg followed by test_arity Lbl Arg Arity.
+    IsTupleOfArity = 169,
+
+    /// @spec move_call Source Destination Arity Label
+    /// @doc  move Source Destination followed by call Arity Label.
+    MoveCall = 170,
+
+    /// @spec move_call_last Source Destination Arity Label Deallocate
+    /// @doc  move Source Destination followed by call_last Arity Label Deallocate.
+    MoveCallLast = 171,
+
+    /// @spec move_call_only Source Destination Arity Label
+    /// @doc  move Source Destination followed by call_only Arity Label.
+    MoveCallOnly = 172,
+
+    /// @spec move_return Source
+    /// @doc  move Source x(0) followed by return.
+    MoveReturn = 173,
+
+    /// @spec deallocate_return Deallocate
+    /// @doc  deallocate Deallocate followed by return.
+    DeallocateReturn = 174,
+
+    /// @spec move_deallocate_return Source Deallocate
+    /// @doc  move Source x(0) followed by deallocate Deallocate and return.
+    MoveDeallocateReturn = 175,
+
+    /// @spec select_val_jump Arg FailLabel Min Labels
+    /// @doc  select_val on a dense range of integers. Jump to Labels[Arg - Min], or to FailLabel
+    ///       if Arg is outside of the table.
+    SelectValJump = 176,
 }
 
 pub static ARITY_MAP: &'static [usize] = &[
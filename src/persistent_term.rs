@@ -1,4 +1,4 @@
-use crate::immix::Heap;
+use crate::immix::{Heap, Kind};
 use crate::value::Term;
 use hashbrown::HashMap;
 use parking_lot::RwLock;
@@ -16,7 +16,7 @@ impl Table {
     pub fn new() -> Self {
         Self {
             map: RwLock::new(HashMap::new()),
-            heap: Heap::new(),
+            heap: Heap::with_kind(Kind::PersistentTerm),
         }
     }
 
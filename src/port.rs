@@ -7,6 +7,7 @@ use crate::servo_arc::Arc;
 
 use hashbrown::HashMap;
 use parking_lot::{RwLock, Mutex, MutexGuard};
+use std::sync::atomic::Ordering;
 
 use tokio::prelude::*;
 
@@ -32,6 +33,8 @@ pub struct Port {
     owner: PID,
     // chan: mpsc::UnboundedSender<Signal>,
     pub chan: mpsc::UnboundedSender<Signal>,
+    /// Processes monitoring the port.
+    monitors: Vec<(PID, Ref)>,
 }
 
 impl Port {
@@ -39,7 +42,25 @@ impl Port {
         Port {
             id,
             owner,
-            chan
+            chan,
+            monitors: Vec::new(),
+        }
+    }
+
+    pub fn monitor(&mut self, from: PID, reference: Ref) {
+        self.monitors.push((from, reference));
+    }
+
+    pub fn demonitor(&mut self, reference: Ref) {
+        self.monitors.retain(|(_, r)| *r != reference);
+    }
+
+    /// Closes the port and notifies the processes monitoring it. Dropping the port closes its
+    /// channel, which stops the driver.
+    pub fn close(self, vm: &Machine, reason: &Exception) {
+        for (pid, reference) in self.monitors {
+            let signal = crate::process::Signal::monitor_down(self.id, reason, reference);
+            crate::process::send_signal(vm, pid, signal);
         }
     }
 
@@ -91,6 +112,20 @@ impl Table {
         self.ports.get(&pid).map(|port| port.lock())
     }
 
+    /// Removes all the ports owned by a process, returning them.
+    pub fn remove_owned(&mut self, owner: PID) -> Vec<Port> {
+        let ids: Vec<ID> = self
+            .ports
+            .iter()
+            .filter(|(_, port)| port.lock().owner == owner)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.ports.remove(&id))
+            .map(Mutex::into_inner)
+            .collect()
+    }
+
     fn next_pid(&mut self) -> ID {
         let pid = self.next_pid;
 
@@ -159,6 +194,7 @@ pub fn send_message(
                     Variant::Atom(atom::COMMAND) => {
                         // TODO: validate tuple len 2
                         let bytes = crate::bif::erlang::list_to_iodata(cmd[1]).unwrap();
+                        vm.io_output.fetch_add(bytes.len(), Ordering::Relaxed);
                         // let fut = chan
                         //     .send(port::Signal::Command(bytes))
                         //     .map_err(|_| ())
@@ -197,6 +233,7 @@ pub fn control(
         // TODO: error unhandled
         use futures::sink::SinkExt as FuturesSinkExt;
         let bytes = crate::bif::erlang::list_to_iodata(msg).unwrap();
+        vm.io_output.fetch_add(bytes.len(), Ordering::Relaxed);
         // let fut = chan
         //     .send(port::Signal::Command(bytes))
         //     .map_err(|_| ())
@@ -768,10 +805,10 @@ async fn tty(id: ID, owner: PID, input: mpsc::UnboundedReceiver<Signal>) {
                             5 => {
                                 renderer.put_chars(&bytes[1..]);
 
-                                crate::process::send_signal(&Machine::current(), owner, crate::process::Signal::Message {
-                                    from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::port(id), atom!(OK))
-                                });
+                                crate::process::send_signal(&Machine::current(), owner, crate::process::Signal::message(
+                                    id, // TODO: this was supposed to be port id, not pid
+                                    tup2!(&heap, Term::port(id), atom!(OK))
+                                ));
                             }
                             n => unimplemented!("command {} for tty", n),
                         }
@@ -805,30 +842,30 @@ async fn tty(id: ID, owner: PID, input: mpsc::UnboundedReceiver<Signal>) {
                                     list = cons!(&heap, Term::int(i32::from(char)), list);
                                 }
 
-                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
-                                    from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), list)
-                                });
+                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::message(
+                                    id, // TODO: this was supposed to be port id, not pid
+                                    tup2!(&heap, Term::reference(&heap, reference), list)
+                                ));
                             },
                             // GET_UNICODE_STATE
                             101 => {
-                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
-                                    from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil()))
-                                });
+                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::message(
+                                    id, // TODO: this was supposed to be port id, not pid
+                                    tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil()))
+                                ));
                             },
                             // SET_UNICODE_STATE
                             102 => {
-                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
-                                    from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil()))
-                                });
+                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::message(
+                                    id, // TODO: this was supposed to be port id, not pid
+                                    tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil()))
+                                ));
                             },
                             _ => {
-                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
-                                    from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), atom!(BADARG))
-                                });
+                                crate::process::send_signal(&Machine::current(), from, crate::process::Signal::message(
+                                    id, // TODO: this was supposed to be port id, not pid
+                                    tup2!(&heap, Term::reference(&heap, reference), atom!(BADARG))
+                                ));
                                 println!("badarg yo");
                                 break;
                             }
@@ -844,6 +881,7 @@ async fn tty(id: ID, owner: PID, input: mpsc::UnboundedReceiver<Signal>) {
                 match res {
                     Ok(bytes) => {
                         let vm = Machine::current();
+                        vm.io_input.fetch_add(bytes, Ordering::Relaxed);
                         // need to return a tuple, but want to avoid heap alloc here..
                         let bin = Arc::new(crate::bitstring::Binary::from(&buf[..bytes]));
                         crate::process::send_signal(&vm, owner, crate::process::Signal::PortMessage {
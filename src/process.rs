@@ -2,20 +2,23 @@ pub use self::table::PID;
 use crate::atom;
 use crate::bitstring;
 use crate::exception::{Exception, Reason};
+use crate::immix::gc::Collector;
 use crate::immix::Heap;
 use crate::instr_ptr::InstrPtr;
 use crate::loader::LValue;
 use crate::mailbox::Mailbox;
 use crate::module::{Module, MFA};
+use crate::port;
 // use crate::servo_arc::Arc; can't do receiver self
-use crate::signal_queue::SignalQueue;
+use crate::signal_queue::{self, SignalQueue};
 pub use crate::signal_queue::{ExitKind, Signal};
+use crate::trace;
 use crate::value::{self, Term, TryInto};
 use crate::vm::Machine;
 use hashbrown::{HashMap, HashSet};
 use std::cell::UnsafeCell;
 use std::panic::RefUnwindSafe;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::Arc;
 
 use futures::compat::*;
@@ -41,10 +44,114 @@ pub type Ref = usize;
 
 pub const MAX_REG: usize = 1024;
 
+/// Number of reductions a process may execute before it has to yield (OTP's CONTEXT_REDS).
+pub const CONTEXT_REDS: usize = 4000;
+
+/// Heap size (in bytes) a process can grow to before it gets garbage collected.
+pub const MIN_HEAP_SIZE: usize = 128 * 1024;
+
+/// Size of a heap word, the unit of the heap size options.
+pub const WORD_SIZE: usize = std::mem::size_of::<Term>();
+
+/// Garbage collection settings, see the min_heap_size, min_bin_vheap_size and fullsweep_after
+/// options of spawn_opt. Sizes are in words, like on OTP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GcOpts {
+    pub min_heap_size: usize,
+    pub min_bin_vheap_size: usize,
+    /// Number of minor collections before a major one.
+    pub fullsweep_after: usize,
+    pub max_heap_size: MaxHeapSize,
+}
+
+impl Default for GcOpts {
+    fn default() -> Self {
+        // same as OTP
+        GcOpts {
+            min_heap_size: 233,
+            min_bin_vheap_size: 46422,
+            fullsweep_after: 65535,
+            max_heap_size: MaxHeapSize::default(),
+        }
+    }
+}
+
+impl GcOpts {
+    /// Heap size (in bytes) that triggers a collection, given the heap size after the last one.
+    pub fn next_gc(&self, size: usize) -> usize {
+        let min = std::cmp::max(MIN_HEAP_SIZE, self.min_heap_size * WORD_SIZE);
+        std::cmp::max(min, size * 2)
+    }
+}
+
+/// What happens when a process outgrows its heap limit, see the max_heap_size option of
+/// process_flag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaxHeapSize {
+    /// In words, 0 means there's no limit.
+    pub size: usize,
+    /// Kill the process once it grows over the limit.
+    pub kill: bool,
+    /// Report it to the logger.
+    pub error_logger: bool,
+}
+
+impl Default for MaxHeapSize {
+    fn default() -> Self {
+        MaxHeapSize {
+            size: 0,
+            kill: true,
+            error_logger: true,
+        }
+    }
+}
+
+impl MaxHeapSize {
+    /// Parses either a plain size, or a map with size, kill and error_logger keys. Missing keys
+    /// keep their default.
+    pub fn from_term(term: Term) -> Option<Self> {
+        let mut max = MaxHeapSize::default();
+        match term.into_variant() {
+            value::Variant::Integer(size) if size >= 0 => max.size = size as usize,
+            _ => {
+                let value::Map(map) = term.try_into().ok()?;
+                for (key, val) in map.iter() {
+                    match (key.into_variant(), val.into_variant()) {
+                        (value::Variant::Atom(atom::SIZE), value::Variant::Integer(size))
+                            if size >= 0 =>
+                        {
+                            max.size = size as usize
+                        }
+                        (value::Variant::Atom(atom::KILL), _) => max.kill = val.to_bool()?,
+                        (value::Variant::Atom(atom::ERROR_LOGGER), _) => {
+                            max.error_logger = val.to_bool()?
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+        }
+        Some(max)
+    }
+
+    pub fn to_term(&self, heap: &Heap) -> Term {
+        map!(
+            heap,
+            atom!(SIZE) => Term::uint64(heap, self.size as u64),
+            atom!(KILL) => Term::boolean(self.kill),
+            atom!(ERROR_LOGGER) => Term::boolean(self.error_logger)
+        )
+    }
+}
+
 bitflags! {
     pub struct Flag: u8 {
         const INITIAL = 0;
         const TRAP_EXIT = (1 << 0);
+        /// Keep queued messages on their own heap fragments (message_queue_data = off_heap).
+        const OFF_HEAP_MSGQ = (1 << 1);
+        /// Parked by erlang:hibernate/3 until a message arrives.
+        const HIBERNATE = (1 << 2);
     }
 }
 
@@ -57,6 +164,11 @@ pub struct ExecutionContext {
     /// Stack (accessible through Y registers).
     pub stack: Vec<Term>,
     pub heap: Heap,
+    /// Heap size (in bytes) that triggers the next garbage collection.
+    pub next_gc: usize,
+    pub gc: GcOpts,
+    /// Minor collections since the last major one.
+    pub minor_gcs: usize,
     /// Number of catches on stack.
     pub catches: usize,
     /// Program pointer, points to the current instruction.
@@ -68,10 +180,20 @@ pub struct ExecutionContext {
     pub live: usize,
     /// binary construction state
     pub bs: *mut bitstring::Binary,
+    /// Write position into bs, in bits.
+    pub bs_offset: usize,
     ///
     pub exc: Option<Exception>,
     /// Reductions left
     pub reds: usize,
+    /// Reductions charged past the budget during the current time slice.
+    pub extra_reds: usize,
+    /// Reductions executed in previous time slices.
+    pub reductions: usize,
+    /// When the current time slice started.
+    pub slice_start: std::time::Instant,
+    /// Pending return trace frames: (continuation, stack depth, traced function).
+    pub return_trace: Vec<(Option<InstrPtr>, usize, MFA)>,
 
     /// Waker associated with the wait
     pub recv_channel: Option<futures::channel::oneshot::Receiver<()>>,
@@ -97,12 +219,47 @@ impl ExecutionContext {
 }
 
 impl ExecutionContext {
+    /// Total reductions executed, including the current time slice.
+    pub fn reductions(&self) -> usize {
+        self.reductions + self.slice_reductions()
+    }
+
+    /// Reductions used so far in the current time slice.
+    pub fn slice_reductions(&self) -> usize {
+        CONTEXT_REDS - self.reds + self.extra_reds
+    }
+
+    /// Consumes reductions from the budget, without yielding. If the budget runs out, the
+    /// process yields on the next call.
+    pub fn bump_reductions(&mut self, reds: usize) {
+        if reds > self.reds {
+            self.extra_reds += reds - self.reds;
+            self.reds = 0;
+        } else {
+            self.reds -= reds;
+        }
+    }
+
+    /// Ends the current time slice: folds the reductions used into the total and refills the
+    /// budget. Returns the amount of reductions used during the slice, including any charged
+    /// past the budget.
+    pub fn end_slice(&mut self) -> usize {
+        let used = self.slice_reductions();
+        self.reductions += used;
+        self.reds = CONTEXT_REDS;
+        self.extra_reds = 0;
+        used
+    }
+
     pub fn new(module: *const Module) -> ExecutionContext {
         ExecutionContext {
             x: [Term::nil(); MAX_REG],
             f: [0.0f64; 16],
             stack: Vec::new(),
             heap: Heap::new(),
+            next_gc: MIN_HEAP_SIZE,
+            gc: GcOpts::default(),
+            minor_gcs: 0,
             catches: 0,
             ip: InstrPtr { ptr: 0, module },
             cp: None,
@@ -118,7 +275,12 @@ impl ExecutionContext {
 
             // TODO: not great
             bs: unsafe { std::mem::uninitialized() },
-            reds: 0,
+            bs_offset: 0,
+            reds: CONTEXT_REDS,
+            extra_reds: 0,
+            reductions: 0,
+            slice_start: std::time::Instant::now(),
+            return_trace: Vec::new(),
             timeout: None,
             recv_channel: None,
         }
@@ -141,6 +303,118 @@ bitflags! {
     }
 }
 
+impl StateFlag {
+    /// Parses a priority level atom (low, normal, high, max).
+    pub fn from_priority(level: Term) -> Option<Self> {
+        match level.into_variant() {
+            value::Variant::Atom(atom::MAX) => Some(StateFlag::PRQ_MAX),
+            value::Variant::Atom(atom::HIGH) => Some(StateFlag::PRQ_HIGH),
+            value::Variant::Atom(atom::NORMAL) => Some(StateFlag::PRQ_MEDIUM),
+            value::Variant::Atom(atom::LOW) => Some(StateFlag::PRQ_LOW),
+            _ => None,
+        }
+    }
+
+    /// Returns the priority level as an atom.
+    pub fn to_priority(self) -> Term {
+        match self & StateFlag::PRQ_MASK {
+            StateFlag::PRQ_MAX => atom!(MAX),
+            StateFlag::PRQ_HIGH => atom!(HIGH),
+            StateFlag::PRQ_LOW => atom!(LOW),
+            _ => atom!(NORMAL),
+        }
+    }
+}
+
+/// Something a process is monitoring, see erlang:monitor/2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Monitor {
+    Process(PID),
+    /// A process monitored by its registered name, the DOWN message carries {Name, Node}.
+    Name(PID, u32),
+    Port(port::ID),
+    /// The time offset never changes in no time warp mode, so these never fire.
+    TimeOffset,
+}
+
+impl Monitor {
+    /// The type and the monitored object, as they show up in DOWN messages.
+    pub fn to_term(self, heap: &Heap) -> (Term, Term) {
+        match self {
+            Monitor::Process(pid) => (atom!(PROCESS), Term::pid(pid)),
+            Monitor::Name(_, name) => (
+                atom!(PROCESS),
+                tup2!(heap, Term::atom(name), atom!(NO_NODE_NO_HOST)),
+            ),
+            Monitor::Port(id) => (atom!(PORT), Term::port(id)),
+            Monitor::TimeOffset => (atom!(TIME_OFFSET), atom!(CLOCK_SERVICE)),
+        }
+    }
+
+    /// Tells the monitored entity to stop reporting to us.
+    pub fn cancel(self, vm: &Machine, from: PID, reference: Ref) {
+        match self {
+            Monitor::Process(pid) | Monitor::Name(pid, _) => {
+                self::send_signal(vm, pid, Signal::Demonitor { from, reference });
+            }
+            Monitor::Port(id) => {
+                if let Some(mut port) = vm.port_table.read().lookup(id) {
+                    port.demonitor(reference);
+                }
+            }
+            Monitor::TimeOffset => (),
+        }
+    }
+}
+
+/// How an alias gets deactivated, see erlang:alias/1 and the alias option of erlang:monitor/3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alias {
+    /// Only by unalias/1.
+    Explicit,
+    /// After the first message that arrives through it.
+    Reply,
+    /// When the monitor sharing its reference gets removed.
+    Demonitor,
+    /// Either of Reply and Demonitor, whichever comes first.
+    ReplyDemonitor,
+}
+
+/// Which spawn_reply messages a spawn_request asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnReplyMode {
+    Yes,
+    No,
+    ErrorOnly,
+    SuccessOnly,
+}
+
+/// A spawn_request waiting for its reply to be processed, see erlang:spawn_request/5.
+#[derive(Debug)]
+pub struct SpawnRequest {
+    /// The reply tag, it lives on its own fragment until the reply gets built.
+    tag: Term,
+    heap: Option<Heap>,
+    reply: SpawnReplyMode,
+    /// The spawned process, if spawning succeeded.
+    pub pid: Option<PID>,
+    /// Whether we got linked to the spawned process.
+    pub link: bool,
+}
+
+impl SpawnRequest {
+    pub fn new(tag: Term, reply: SpawnReplyMode, pid: Option<PID>, link: bool) -> Self {
+        let (tag, heap) = signal_queue::copy_to_fragment(tag);
+        SpawnRequest {
+            tag,
+            heap,
+            reply,
+            pid,
+            link,
+        }
+    }
+}
+
 pub struct LocalData {
     // allocator, panic handler
     context: Box<ExecutionContext>,
@@ -162,9 +436,15 @@ pub struct LocalData {
     // links (tree)
     pub links: HashSet<PID>,
     // monitors (tree)
-    pub monitors: HashMap<Ref, PID>,
+    pub monitors: HashMap<Ref, Monitor>,
     // lt_monitors (list)
     pub lt_monitors: Vec<(PID, Ref)>,
+    /// Active aliases, messages sent to them get delivered to us.
+    pub aliases: HashMap<Ref, Alias>,
+    /// Outstanding spawn requests, by request id.
+    pub spawn_requests: HashMap<Ref, SpawnRequest>,
+    /// Collections other processes asked for, they get answered after our next one.
+    pub gc_requests: Vec<(PID, Term, Option<Heap>)>,
 
     // signals are sent on death, and the receiving side cleans up it's link/mon structures
     pub signal_queue: SignalQueue,
@@ -178,6 +458,18 @@ pub struct LocalData {
 
     /// A [process dictionary](https://www.erlang.org/course/advanced#dict)
     pub dictionary: HashMap<Term, Term>,
+
+    /// Trace flags, set via erlang:trace/3.
+    pub trace: trace::Flag,
+    /// The process receiving our trace messages.
+    pub tracer: Option<PID>,
+}
+
+impl LocalData {
+    /// The priority level the process is scheduled with.
+    pub fn priority(&self) -> StateFlag {
+        self.state & StateFlag::PRQ_MASK
+    }
 }
 
 pub struct Process {
@@ -190,6 +482,33 @@ pub struct Process {
 
     /// If the process is waiting for a message.
     pub waiting_for_message: AtomicBool,
+
+    /// Set when another process asks us to garbage collect.
+    pub gc_requested: AtomicBool,
+
+    /// Whether the process is running, parked, being inspected by another process, or exited.
+    access: AtomicU8,
+}
+
+// A process has exclusive access to its own state while it runs. Once it's parked (suspended in a
+// receive, rescheduled, or waiting on a dirty BIF that already finished) other processes can
+// inspect it, and it doesn't resume until they're done. Exited processes can't be inspected,
+// their terms might point into code that's been freed since.
+const RUNNING: u8 = 0;
+const PARKED: u8 = 1;
+const INSPECTED: u8 = 2;
+const EXITED: u8 = 3;
+
+/// Keeps a parked process from resuming while another process reads its state. A process
+/// looking at itself doesn't need to hold anything.
+pub struct Inspection<'a>(Option<&'a Process>);
+
+impl<'a> Drop for Inspection<'a> {
+    fn drop(&mut self) {
+        if let Some(process) = self.0 {
+            process.access.store(PARKED, Ordering::Release);
+        }
+    }
 }
 
 unsafe impl Sync for LocalData {}
@@ -212,7 +531,7 @@ impl Process {
             // allocator: LocalAllocator::new(global_allocator.clone(), config),
             context: Box::new(context),
             flags: Flag::INITIAL,
-            state: StateFlag::INITIAL,
+            state: StateFlag::PRQ_MEDIUM,
             parent,
             group_leader,
             name: None,
@@ -221,16 +540,24 @@ impl Process {
             links: HashSet::new(),
             monitors: HashMap::new(),
             lt_monitors: Vec::new(),
+            aliases: HashMap::new(),
+            spawn_requests: HashMap::new(),
+            gc_requests: Vec::new(),
             signal_queue: SignalQueue::new(),
             mailbox: Mailbox::new(),
             thread_id: None,
             dictionary: HashMap::new(),
+            trace: trace::Flag::NONE,
+            tracer: None,
         };
 
         Arc::pin(Process {
             pid,
             local_data: UnsafeCell::new(local_data),
             waiting_for_message: AtomicBool::new(false),
+            gc_requested: AtomicBool::new(false),
+            // not running until it gets scheduled
+            access: AtomicU8::new(PARKED),
         })
     }
 
@@ -283,14 +610,12 @@ impl Process {
     pub fn send_message(&self, from: PID, message: Term) {
         if from == self.pid {
             // skip the signal_queue completely
+            self.trace_receive(message);
             self.local_data_mut().mailbox.send(message);
         } else {
             self.local_data_mut()
                 .signal_queue
-                .send_external(Signal::Message {
-                    value: message,
-                    from,
-                });
+                .send_external(Signal::message(from, message));
         }
         self.wake_up()
     }
@@ -319,6 +644,238 @@ impl Process {
         self.waiting_for_message.store(value, Ordering::Relaxed)
     }
 
+    /// Gives up exclusive access to the process state, before suspending.
+    pub fn park(&self) {
+        self.access.store(PARKED, Ordering::Release);
+    }
+
+    /// Takes back exclusive access to the process state, waiting for an ongoing inspection to
+    /// finish first.
+    pub fn unpark(&self) {
+        loop {
+            match self.access.compare_exchange_weak(
+                PARKED,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) | Err(RUNNING) | Err(EXITED) => return,
+                Err(_) => std::sync::atomic::spin_loop_hint(),
+            }
+        }
+    }
+
+    /// Waits for the process to park, and keeps it parked while the inspection is alive. `by`
+    /// parks itself while waiting, so two processes inspecting each other don't deadlock.
+    /// Returns None if the process exited.
+    pub fn inspect(&self, by: &Process) -> Option<Inspection> {
+        if self.pid == by.pid {
+            return Some(Inspection(None));
+        }
+        loop {
+            match self.access.compare_exchange(
+                PARKED,
+                INSPECTED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Inspection(Some(self))),
+                Err(EXITED) => return None,
+                Err(_) => (),
+            }
+            let running = by.access.load(Ordering::Relaxed) == RUNNING;
+            if running {
+                by.park();
+            }
+            std::thread::yield_now();
+            if running {
+                by.unpark();
+            }
+        }
+    }
+
+    /// Returns true if the heap (or the binaries it references) outgrew its limit, or if someone
+    /// asked for a collection.
+    pub fn needs_gc(&self) -> bool {
+        let context = self.context();
+        context.heap.size() >= context.next_gc
+            || context.heap.binaries() >= context.gc.min_bin_vheap_size * WORD_SIZE
+            || self.gc_requested.load(Ordering::Relaxed)
+    }
+
+    /// Collects the process heap, reclaiming the space of everything that isn't reachable.
+    /// The roots are the X registers (only the first `live` ones are kept, the rest are
+    /// considered dead), the stack, the current exception, the mailbox and the process
+    /// dictionary. Messages kept off heap aren't on our heap, so they're left alone.
+    ///
+    /// Unless a major collection is asked for (or fullsweep_after minor collections went by),
+    /// only the young generation is collected. If that doesn't free up enough space, a major
+    /// collection follows.
+    ///
+    /// An Err signals that the heap is still over max_heap_size, and that we're now exiting.
+    pub fn garbage_collect(&self, vm: &Machine, live: usize, major: bool) -> Result<(), Exception> {
+        let context = self.context_mut();
+        let major = major
+            || context.minor_gcs >= context.gc.fullsweep_after
+            || self.gc_requested.load(Ordering::Relaxed);
+        let limit = context.next_gc;
+        let used = context.heap.used();
+
+        self.collect(live, major);
+        let mut collections = 1;
+        if !major && context.heap.size() >= limit {
+            self.collect(live, true);
+            collections += 1;
+        }
+
+        let reclaimed = used.saturating_sub(context.heap.used()) / WORD_SIZE;
+        vm.garbage_collections
+            .fetch_add(collections, Ordering::Relaxed);
+        vm.words_reclaimed.fetch_add(reclaimed, Ordering::Relaxed);
+
+        context.next_gc = context.gc.next_gc(context.heap.size());
+        self.gc_requested.store(false, Ordering::Relaxed);
+        self.reply_gc_requests(vm, true);
+
+        let max = context.gc.max_heap_size;
+        if max.size > 0 && context.heap.size() > max.size * WORD_SIZE {
+            if max.error_logger {
+                self.report_max_heap_size(vm);
+            }
+            if max.kill {
+                // kill catches, this can't be caught
+                context.catches = 0;
+                return Err(Exception::with_value(Reason::EXT_EXIT, atom!(KILLED)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the collections other processes asked us for.
+    fn reply_gc_requests(&self, vm: &Machine, res: bool) {
+        for (from, request_id, heap) in self.local_data_mut().gc_requests.drain(..) {
+            // the reply goes out on the fragment that carries the request id
+            let heap = heap.unwrap_or_else(Heap::fragment);
+            let value = tup3!(
+                &heap,
+                atom!(GARBAGE_COLLECT),
+                request_id,
+                Term::boolean(res)
+            );
+            let reply = Signal::Message {
+                from: self.pid,
+                value,
+                heap: Some(heap),
+            };
+            self::send_signal(vm, from, reply);
+        }
+    }
+
+    /// Lets the system logger know we outgrew max_heap_size.
+    fn report_max_heap_size(&self, vm: &Machine) {
+        let context = self.context_mut();
+        let heap = &context.heap;
+        let max = context.gc.max_heap_size;
+
+        let format = concat!(
+            "     Process:            ~p ~n",
+            "     Context:            maximum heap size reached~n",
+            "     Max Heap Size:      ~p~n",
+            "     Total Heap Size:    ~p~n",
+            "     Kill:               ~p~n",
+            "     Error Logger:       ~p~n",
+            "     Message Queue Len:  ~p~n",
+            "     GC Info:            ~p~n"
+        );
+        let words = (heap.size() / WORD_SIZE) as u64;
+        let gc_info = cons!(
+            heap,
+            tup2!(heap, atom!(HEAP_SIZE), Term::uint64(heap, words)),
+            cons!(
+                heap,
+                tup2!(heap, atom!(MINOR_GCS), Term::uint64(heap, context.minor_gcs as u64)),
+                Term::nil()
+            )
+        );
+        let args = [
+            Term::pid(self.pid),
+            Term::uint64(heap, max.size as u64),
+            Term::uint64(heap, words),
+            Term::boolean(max.kill),
+            Term::boolean(max.error_logger),
+            Term::uint64(heap, self.local_data().mailbox.len() as u64),
+            gc_info,
+        ];
+        let args = iter_to_list!(heap, args.iter().rev().cloned());
+
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+        let meta = map!(
+            heap,
+            atom!(PID) => Term::pid(self.pid),
+            atom!(GL) => Term::pid(self.local_data().group_leader),
+            atom!(TIME) => Term::uint64(heap, time as u64),
+            atom!(ERROR_LOGGER) => map!(heap, atom!(TAG) => atom!(ERROR), atom!(EMULATOR) => atom!(TRUE))
+        );
+        let report = tup!(
+            heap,
+            atom!(LOG),
+            atom!(ERROR),
+            bitstring!(heap, format),
+            args,
+            meta
+        );
+
+        let logger = vm.system_logger.load(Ordering::Relaxed) as PID;
+        send_signal(vm, logger, Signal::message(self.pid, report));
+    }
+
+    fn collect(&self, live: usize, major: bool) {
+        let local_data = self.local_data_mut();
+        let context = &mut *local_data.context;
+
+        let mut gc = if major {
+            Collector::new(&context.heap)
+        } else {
+            Collector::minor(&context.heap)
+        };
+
+        for x in &mut context.x[..live] {
+            *x = gc.copy(*x);
+        }
+        // the rest are dead and about to dangle, clear them so they can be scanned safely
+        for x in &mut context.x[live..] {
+            *x = Term::nil();
+        }
+        for y in &mut context.stack {
+            *y = gc.copy(*y);
+        }
+        if let Some(exc) = &mut context.exc {
+            exc.value = gc.copy(exc.value);
+            exc.trace = gc.copy(exc.trace);
+        }
+        for message in local_data.mailbox.iter_mut() {
+            *message = gc.copy(*message);
+        }
+        // keys can move too, so the dictionary has to be rebuilt
+        let dictionary = local_data
+            .dictionary
+            .drain()
+            .map(|(key, value)| (gc.copy(key), gc.copy(value)))
+            .collect();
+        local_data.dictionary = dictionary;
+
+        gc.finish();
+
+        if major {
+            context.minor_gcs = 0;
+        } else {
+            context.minor_gcs += 1;
+        }
+    }
+
     // we're in receive(), but ran out of internal messages, process external queue
     /// An Err signals that we're now exiting.
     pub fn process_incoming(&self) -> Result<(), Exception> {
@@ -334,12 +891,31 @@ impl Process {
         // get internal, if we ran out, start processing external
         while let Some(signal) = self.local_data_mut().signal_queue.receive() {
             match signal {
-                Signal::Message { value, .. } => {
-                    self.local_data_mut().mailbox.send(value);
+                Signal::Message { value, heap, .. } => {
+                    self.deliver_message(value, heap);
+                }
+                Signal::AliasMessage {
+                    alias, value, heap, ..
+                } => {
+                    // messages to inactive aliases are dropped
+                    let kind = self.local_data().aliases.get(&alias).copied();
+                    if let Some(kind) = kind {
+                        if kind == Alias::Reply || kind == Alias::ReplyDemonitor {
+                            let monitor = self.local_data_mut().monitors.remove(&alias);
+                            Machine::with_current(|vm| {
+                                self.unalias(vm, alias);
+                                if let Some(monitor) = monitor {
+                                    monitor.cancel(vm, self.pid, alias);
+                                }
+                            });
+                        }
+                        self.deliver_message(value, heap);
+                    }
                 }
                 Signal::PortMessage { from, value, .. } => {
                     // we only get the binary, so construct message on heap
                     let heap = &self.context_mut().heap;
+                    heap.add_binary(value.data.len());
                     let binary = Term::from(heap.alloc(value::Boxed {
                         header: value::BOXED_BINARY,
                         value,
@@ -363,6 +939,9 @@ impl Process {
                 Signal::Monitor { from, reference } => {
                     self.local_data_mut().lt_monitors.push((from, reference));
                 }
+                Signal::SpawnReply { reference, result } => {
+                    self.handle_spawn_reply(reference, result);
+                }
                 Signal::Demonitor { from, reference } => {
                     if let Some(pos) = self
                         .local_data_mut()
@@ -373,26 +952,116 @@ impl Process {
                         self.local_data_mut().lt_monitors.remove(pos);
                     }
                 }
+                Signal::CheckProcessCode {
+                    from,
+                    module,
+                    request_id,
+                    heap,
+                } => {
+                    // the reply goes out on the fragment that carries the request id
+                    let heap = heap.unwrap_or_else(Heap::fragment);
+                    let res = crate::module::check_process_code(self, module);
+                    let value = tup3!(
+                        &heap,
+                        atom!(CHECK_PROCESS_CODE),
+                        request_id,
+                        Term::boolean(res)
+                    );
+                    let reply = Signal::Message {
+                        from: self.pid,
+                        value,
+                        heap: Some(heap),
+                    };
+                    Machine::with_current(|vm| self::send_signal(vm, from, reply));
+                }
+                Signal::GarbageCollect {
+                    from,
+                    request_id,
+                    heap,
+                } => {
+                    // we can only collect where we know which registers are live, so the
+                    // answer waits for the next collection
+                    let local_data = self.local_data_mut();
+                    local_data.gc_requests.push((from, request_id, heap));
+                    self.gc_requested.store(true, Ordering::Relaxed);
+                }
             }
         }
         Ok(())
     }
 
+    fn deliver_message(&self, value: Term, heap: Option<Heap>) {
+        self.trace_receive(value);
+        let local_data = self.local_data_mut();
+        match heap {
+            Some(heap) if local_data.flags.contains(Flag::OFF_HEAP_MSGQ) => {
+                local_data.mailbox.send_off_heap(value, heap)
+            }
+            Some(heap) => {
+                self.context_mut().heap.absorb(heap);
+                local_data.mailbox.send(value);
+            }
+            None => local_data.mailbox.send(value),
+        }
+    }
+
+    /// Activates an alias, messages sent to it get delivered to us until it's deactivated.
+    pub fn alias(&self, vm: &Machine, reference: Ref, kind: Alias) {
+        vm.aliases.lock().insert(reference, self.pid);
+        self.local_data_mut().aliases.insert(reference, kind);
+    }
+
+    /// Deactivates an alias. Returns false if it wasn't an active alias of ours.
+    pub fn unalias(&self, vm: &Machine, reference: Ref) -> bool {
+        if self.local_data_mut().aliases.remove(&reference).is_some() {
+            vm.aliases.lock().remove(&reference);
+            return true;
+        }
+        false
+    }
+
+    /// Deactivates the alias tied to a monitor that just got removed, if there is one.
+    pub fn monitor_removed(&self, vm: &Machine, reference: Ref) {
+        match self.local_data().aliases.get(&reference) {
+            Some(Alias::Demonitor) | Some(Alias::ReplyDemonitor) => {
+                self.unalias(vm, reference);
+            }
+            _ => (),
+        }
+    }
+
+    fn trace_receive(&self, message: Term) {
+        if self.local_data().trace.contains(trace::Flag::RECEIVE) {
+            Machine::with_current(|vm| trace::receive(vm, self, message));
+        }
+    }
+
     fn handle_monitor_down_signal(&self, signal: Signal) {
         // Create a 'DOWN' message and replace the signal with it...
         if let Signal::MonitorDown {
-            from,
             reason,
             reference,
+            heap: fragment,
+            ..
         } = signal
         {
-            // assert!(is_immed(reason));
+            // the monitor might have been removed in the meantime
+            let monitor = match self.local_data_mut().monitors.remove(&reference) {
+                Some(monitor) => monitor,
+                None => return,
+            };
+            if !self.local_data().aliases.is_empty() {
+                Machine::with_current(|vm| self.monitor_removed(vm, reference));
+            }
             let heap = &self.context_mut().heap;
-            let from = Term::pid(from);
+            if let Some(fragment) = fragment {
+                heap.absorb(fragment);
+            }
+            let (kind, object) = monitor.to_term(heap);
             let reference = Term::reference(heap, reference as usize);
             let reason = reason.value;
 
-            let msg = tup!(heap, atom!(DOWN_U), reference, atom!(PROCESS), from, reason);
+            let msg = tup!(heap, atom!(DOWN_U), reference, kind, object, reason);
             self.local_data_mut().mailbox.send(msg);
         // bump reds by 8?
         } else {
@@ -400,15 +1069,57 @@ impl Process {
         }
     }
 
-    /// Return value is true if the process is now terminating.
+    fn handle_spawn_reply(&self, reference: Ref, result: Result<PID, u32>) {
+        // abandoned requests don't get a reply
+        let request = match self.local_data_mut().spawn_requests.remove(&reference) {
+            Some(request) => request,
+            None => return,
+        };
+        let reply = request.reply;
+        let (status, value) = match result {
+            Ok(pid) if reply == SpawnReplyMode::Yes || reply == SpawnReplyMode::SuccessOnly => {
+                (atom!(OK), Term::pid(pid))
+            }
+            Err(reason) if reply == SpawnReplyMode::Yes || reply == SpawnReplyMode::ErrorOnly => {
+                (atom!(ERROR), Term::atom(reason))
+            }
+            _ => return,
+        };
+        let heap = &self.context_mut().heap;
+        if let Some(fragment) = request.heap {
+            heap.absorb(fragment);
+        }
+        let reference = Term::reference(heap, reference);
+        let msg = tup!(heap, request.tag, reference, status, value);
+        self.local_data_mut().mailbox.send(msg);
+    }
+
+    /// Handles an incoming exit signal. Returns an exit exception if the process has to terminate.
+    ///
+    /// - exit/2 with reason kill can't be trapped, the process terminates with reason killed.
+    ///   A linked process exiting with reason kill is just another reason.
+    /// - if we're trapping exits, the signal turns into an {'EXIT', From, Reason} message.
+    /// - reason normal is ignored, unless we sent it to ourselves with exit/2.
+    /// - any other reason terminates the process.
     pub fn handle_exit_signal(&self, signal: Signal) -> Result<(), Exception> {
         // this is extremely awkward, wish we could enforce a signal variant on the function signature
         // we're also technically matching twice since process_incoming also pattern matches.
         // TODO: inline?
-        if let Signal::Exit { kind, from, reason } = signal {
-            let mut reason = reason.value;
+        if let Signal::Exit {
+            kind,
+            from,
+            reason,
+            heap,
+        } = signal
+        {
+            let reason = reason.value;
             let local_data = self.local_data_mut();
 
+            if let Some(heap) = heap {
+                // the reason was deep cloned onto the fragment by the sender
+                self.context_mut().heap.absorb(heap);
+            }
+
             if kind == ExitKind::ExitLinked {
                 // delete from link tree
                 if local_data.links.take(&from).is_none() {
@@ -417,49 +1128,26 @@ impl Process {
                 }
             }
 
-            if reason != atom!(KILL) && local_data.flags.contains(Flag::TRAP_EXIT) {
-                // if reason is immed, create an EXIT message tuple instead and replace
-                // (push to internal msg queue as message)
+            let untrappable = kind == ExitKind::Exit && reason == atom!(KILL);
+
+            if !untrappable && local_data.flags.contains(Flag::TRAP_EXIT) {
+                // deliver an EXIT message tuple instead
                 let msg = tup3!(
                     &self.context_mut().heap,
                     atom!(EXIT_U),
                     Term::pid(from),
                     reason
                 );
-                // TODO: ensure we do process wakeup
-                // erts_proc_notify_new_message(c_p, ERTS_PROC_LOCK_MAIN);
+                self.trace_receive(msg);
                 local_data.mailbox.send(msg);
                 Ok(())
-            } else if reason == atom!(NORMAL)
-            /*&& xsigd.u.normal_kills */
-            {
-                /* TODO: for exit/2, exit_signal/2 implement normal kills
-                 * Preserve the very old and *very strange* behaviour
-                 * of erlang:exit/2...
-                 *
-                 * - terminate ourselves even though exit reason
-                 *   is normal (unless we trap exit)
-                 * - terminate ourselves before exit/2 return
-                 */
-
-                // ignore
+            } else if reason == atom!(NORMAL) && !(kind == ExitKind::Exit && from == self.pid) {
+                // Preserve the very old and *very strange* behaviour of erlang:exit/2: a process
+                // that sends itself a normal exit terminates (unless it traps exits). Everyone
+                // else ignores it.
                 Ok(())
             } else {
-                // terminate
-                // save = true;
-                if
-                /*op == ERTS_SIG_Q_OP_EXIT && */
-                reason == atom!(KILL) {
-                    reason = atom!(KILLED);
-                }
-
-                // if save { // something to do with heap fragments I think mainly to remove it from proc
-                //     sig->data.attached = ERTS_MSG_COMBINED_HFRAG;
-                //     ERL_MESSAGE_TERM(sig) = xsigd->message;
-                //     erts_save_message_in_proc(c_p, sig);
-                // }
-
-                // Exit process...
+                let reason = if untrappable { atom!(KILLED) } else { reason };
 
                 // kill catches
                 self.context_mut().catches = 0;
@@ -467,7 +1155,6 @@ impl Process {
                 // return an exception to trigger process exit
                 Err(Exception::with_value(Reason::EXT_EXIT, reason))
             }
-        // if destroy { cleanup messages up to signal? }
         } else {
             unreachable!()
         }
@@ -481,44 +1168,70 @@ impl Process {
 
         // set state to exiting
 
-        // TODO: cancel timers
+        vm.timers.cancel_owned(self.pid);
+
+        if !local_data.aliases.is_empty() {
+            let mut aliases = vm.aliases.lock();
+            for (reference, _) in local_data.aliases.drain() {
+                aliases.remove(&reference);
+            }
+        }
+
+        if let Some(name) = local_data.name.take() {
+            vm.process_registry.lock().unregister(name);
+        }
+
+        // delete owned ETS tables
+        // TODO: give them away to the heir once ets:new supports it
+        vm.ets_tables.lock().remove_owned(self.pid);
 
-        // TODO: unregister process name
+        // close owned ports, dropping the port closes its channel
+        let ports = vm.port_table.write().remove_owned(self.pid);
+        for port in ports {
+            port.close(vm, &reason);
+        }
 
         // delete links
         for pid in local_data.links.drain() {
-            // TODO: reason has to be deep cloned, make a constructor
             // println!("pid={} sending exit signal to from={}", self.pid, pid);
-            let msg = Signal::Exit {
-                reason: reason.clone(),
-                from: self.pid,
-                kind: ExitKind::ExitLinked,
-            };
+            let msg = Signal::exit(self.pid, &reason, ExitKind::ExitLinked);
             self::send_signal(vm, pid, msg);
             // erts_proc_sig_send_link_exit(c_p, c_p->common.id, lnk, reason, SEQ_TRACE_TOKEN(c_p));
         }
 
         // delete monitors
-        for (reference, pid) in local_data.monitors.drain() {
+        for (reference, monitor) in local_data.monitors.drain() {
             // we're watching someone else
-            // send_demonitor(mon)
-            let msg = Signal::Demonitor {
-                from: self.pid,
-                reference,
-            };
-            self::send_signal(vm, pid, msg);
+            monitor.cancel(vm, self.pid, reference);
         }
 
         for (pid, reference) in local_data.lt_monitors.drain(..) {
             // we're being watched
             // send_monitor_down(mon, reason)
-            let msg = Signal::MonitorDown {
-                reason: reason.clone(),
-                from: self.pid,
-                reference,
-            };
+            let msg = Signal::monitor_down(self.pid, &reason, reference);
             self::send_signal(vm, pid, msg);
         }
+
+        // we won't be collecting anymore
+        while let Some(signal) = local_data.signal_queue.receive() {
+            if let Signal::GarbageCollect {
+                from,
+                request_id,
+                heap,
+            } = signal
+            {
+                local_data.gc_requests.push((from, request_id, heap));
+            }
+        }
+        self.reply_gc_requests(vm, false);
+
+        vm.process_table.lock().release(self.pid);
+        self.access.store(EXITED, Ordering::Release);
+
+        // we might have been the last one using some purged code
+        let mut modules = vm.modules.lock();
+        let process_table = vm.process_table.lock();
+        modules.release_unused(|pid| process_table.contains_key(pid));
     }
 }
 
@@ -553,36 +1266,102 @@ bitflags! {
         const MONITOR = 2;
         // const USE_ARGS = 4;
         // const SYSTEM_PROC = 8;
-        // const OFF_HEAP_MSGQ = 16;
-        // const ON_HEAP_MSGQ = 32;
+        const OFF_HEAP_MSGQ = 16;
+        const ON_HEAP_MSGQ = 32;
+    }
+}
+
+/// Options a process is spawned with, see spawn_opt.
+#[derive(Clone, Copy)]
+pub struct SpawnOpts {
+    pub flags: SpawnFlag,
+    pub priority: StateFlag,
+    pub gc: GcOpts,
+    /// The reference to monitor the new process with, instead of a new one. spawn_request uses
+    /// its request id.
+    pub monitor_ref: Option<Ref>,
+}
+
+impl SpawnOpts {
+    pub fn new(flags: SpawnFlag) -> Self {
+        SpawnOpts {
+            flags,
+            priority: StateFlag::PRQ_MEDIUM,
+            gc: GcOpts::default(),
+            monitor_ref: None,
+        }
     }
 }
 
+/// Spawns a process, returns its pid, or {Pid, MonitorRef} if it's monitored.
 pub fn spawn(
     vm: &Machine,
     parent: &RcProcess,
-    module: *const Module,
+    module: u32,
     func: u32,
     args: Term,
-    flags: SpawnFlag,
+    opts: SpawnOpts,
 ) -> Result<Term, Exception> {
+    let (pid, monitor) = spawn_process(vm, parent, module, func, args, opts)?;
+    let ret = Term::pid(pid);
+    match monitor {
+        Some(reference) => {
+            let heap = &parent.context_mut().heap;
+            Ok(tup2!(heap, ret, Term::reference(heap, reference)))
+        }
+        None => Ok(ret),
+    }
+}
+
+/// Spawns a process, returns its pid and the reference of the monitor if one was requested.
+/// Like apply/3, calling a function that doesn't exist isn't the spawner's problem: the new
+/// process exits with undef.
+pub fn spawn_process(
+    vm: &Machine,
+    parent: &RcProcess,
+    module_name: u32,
+    func: u32,
+    args: Term,
+    opts: SpawnOpts,
+) -> Result<(PID, Option<Ref>), Exception> {
+    let flags = opts.flags;
+    let module = vm
+        .modules
+        .lock()
+        .lookup(module_name)
+        .map_or(std::ptr::null(), |module| module as *const Module);
     let new_proc = allocate(vm, parent.pid, parent.local_data().group_leader, module)?;
     let context = new_proc.context_mut();
-    let mut ret = Term::pid(new_proc.pid);
+    let pid = new_proc.pid;
+    let mut monitor = None;
+
+    let parent_data = parent.local_data();
+    if parent_data.trace.contains(trace::Flag::SET_ON_SPAWN) {
+        let local_data = new_proc.local_data_mut();
+        local_data.trace = parent_data.trace;
+        local_data.tracer = parent_data.tracer;
+    }
 
-    // Set the arglist into process registers.
-    // TODO: it also needs to deep clone all the vals (for example lists etc)
+    // Set the arglist into process registers. The arguments are copied over, since the new
+    // process can't point into our heap.
     let mut i = 0;
     let mut cons = &args;
     while let Ok(value::Cons { head, tail }) = cons.try_into() {
-        context.x[i] = *head;
+        context.x[i] = head.deep_clone(&context.heap);
         i += 1;
         cons = tail;
     }
     // lastly, the tail
-    context.x[i] = *cons;
+    context.x[i] = cons.deep_clone(&context.heap);
 
-    new_proc.local_data_mut().initial_call = MFA(unsafe { (*module).name }, func, i as u32);
+    let local_data = new_proc.local_data_mut();
+    local_data.initial_call = MFA(module_name, func, i as u32);
+    local_data.state = (local_data.state & !StateFlag::PRQ_MASK) | opts.priority;
+    if flags.contains(SpawnFlag::OFF_HEAP_MSGQ) {
+        local_data.flags.insert(Flag::OFF_HEAP_MSGQ);
+    }
+    context.gc = opts.gc;
+    context.next_gc = opts.gc.next_gc(0);
 
     // print!(
     //     "Spawning... pid={} mfa={} args={}\r\n",
@@ -592,14 +1371,7 @@ pub fn spawn(
     // );
 
     // TODO: func to ip offset
-    let func = unsafe {
-        (*module)
-            .funs
-            .get(&(func, i as u32)) // arglist arity
-            .expect("process::spawn could not locate func")
-    };
-
-    context.ip.ptr = *func;
+    let entry = unsafe { module.as_ref() }.and_then(|module| module.funs.get(&(func, i as u32)));
 
     // Check if this process should be initially linked to its parent.
     if flags.contains(SpawnFlag::LINK) {
@@ -609,28 +1381,47 @@ pub fn spawn(
     }
 
     if flags.contains(SpawnFlag::MONITOR) {
-        let reference = vm.next_ref();
+        let reference = opts.monitor_ref.unwrap_or_else(|| vm.next_ref());
 
         parent
             .local_data_mut()
             .monitors
-            .insert(reference, new_proc.pid);
+            .insert(reference, Monitor::Process(new_proc.pid));
 
         new_proc
             .local_data_mut()
             .lt_monitors
             .push((parent.pid, reference));
 
-        let heap = &parent.context_mut().heap;
-        ret = tup2!(heap, ret, Term::reference(heap, reference))
+        monitor = Some(reference);
     }
 
-    let future = crate::vm::run_with_error_handling(new_proc);
+    let future = match entry {
+        Some(ptr) => {
+            context.ip.ptr = *ptr;
+            crate::vm::run_with_error_handling(new_proc).boxed()
+        }
+        None => exit_undefined(new_proc).boxed(),
+    };
     vm.process_pool
         .executor()
         .spawn(future.unit_error().boxed().compat());
 
-    Ok(ret)
+    Ok((pid, monitor))
+}
+
+/// Runs a process whose initial call doesn't exist, it exits right away with
+/// {undef, [{Module, Function, Args, []}]}.
+async fn exit_undefined(process: RcProcess) {
+    let vm = Machine::current();
+    let MFA(module, func, arity) = process.local_data().initial_call;
+    let context = process.context_mut();
+    let heap = &context.heap;
+    let args = value::Cons::from_iter(context.x[..arity as usize].iter().cloned(), heap);
+    let (module, func) = (Term::atom(module), Term::atom(func));
+    let frame = tup!(heap, module, func, args, Term::nil());
+    let reason = tup2!(heap, atom!(UNDEF), cons!(heap, frame, Term::nil()));
+    process.exit(&vm, Exception::with_value(Reason::EXC_UNDEF, reason));
 }
 
 pub fn send_message(vm: &Machine, sender: PID, pid: Term, msg: Term) -> Result<Term, Exception> {
@@ -645,6 +1436,15 @@ pub fn send_message(vm: &Machine, sender: PID, pid: Term, msg: Term) -> Result<T
             }
         }
         value::Variant::Pid(pid) => vm.process_table.lock().get(pid),
+        value::Variant::Pointer(..) if pid.is_ref() => {
+            // an alias, messages to inactive ones get dropped
+            let alias = *pid.get_boxed_value::<Ref>().unwrap();
+            let owner = vm.aliases.lock().get(&alias).copied();
+            if let Some(owner) = owner {
+                self::send_signal(vm, owner, Signal::alias_message(sender, alias, msg));
+            }
+            return Ok(msg);
+        }
         _ => return Err(Exception::new(Reason::EXC_BADARG)),
     };
 
@@ -28,6 +28,11 @@ impl<T: Clone> Registry<T> {
     pub fn whereis(&self, atom: u32) -> Option<&T> {
         self.processes.get(&atom)
     }
+
+    /// Returns all the registered names.
+    pub fn names(&self) -> Vec<u32> {
+        self.processes.keys().copied().collect()
+    }
 }
 
 /*
@@ -109,6 +109,33 @@ impl<T: Clone> Table<T> {
         self.processes.contains_key(&pid)
     }
 
+    /// Returns an iterator over all the mapped processes.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.processes.values().filter_map(Option::as_ref)
+    }
+
+    /// Returns the PIDs of all the mapped processes, in ascending order.
+    pub fn pids(&self) -> Vec<PID> {
+        let mut pids: Vec<_> = self
+            .processes
+            .iter()
+            .filter(|(_, slot)| slot.is_some())
+            .map(|(pid, _)| *pid)
+            .collect();
+        pids.sort_unstable();
+        pids
+    }
+
+    /// Returns the number of mapped processes.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns true if no process is mapped.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     fn next_pid(&mut self) -> PID {
         let pid = self.next_pid;
 
@@ -199,4 +226,25 @@ mod tests {
         assert!(table.get(pid).is_some());
         assert_eq!(table.get(pid).unwrap(), 10);
     }
+
+    #[test]
+    fn test_pids() {
+        let mut table = Table::new();
+        let pid = table.reserve().unwrap();
+        let pid2 = table.reserve().unwrap();
+        let pid3 = table.reserve().unwrap();
+
+        table.map(pid3, 30);
+        table.map(pid, 10);
+
+        // reserved but not mapped yet
+        assert_eq!(table.pids(), vec![pid, pid3]);
+        assert_eq!(table.len(), 2);
+
+        table.map(pid2, 20);
+        table.release(pid);
+
+        assert_eq!(table.pids(), vec![pid2, pid3]);
+        assert_eq!(table.len(), 2);
+    }
 }
@@ -3,9 +3,11 @@ use std::collections::VecDeque;
 
 use crate::bitstring;
 use crate::exception::Exception;
+use crate::immix::Heap;
+use crate::module::Module;
 use crate::port;
 use crate::process::{Ref, PID};
-use crate::value::Term;
+use crate::value::{Term, Variant};
 
 #[derive(Debug, PartialEq)]
 pub enum ExitKind {
@@ -13,6 +15,9 @@ pub enum ExitKind {
     ExitLinked = 1,
 }
 
+/// Signals that carry terms also carry the heap fragment the terms live on, since the sender's
+/// heap can get collected (or freed) before the receiver gets to them. The receiver absorbs the
+/// fragment into its own heap.
 // #[derive(Copy)]
 #[derive(Debug)]
 pub enum Signal {
@@ -20,10 +25,19 @@ pub enum Signal {
         from: PID,
         reason: Exception,
         kind: ExitKind,
+        heap: Option<Heap>,
     },
     Message {
         from: PID,
         value: Term,
+        heap: Option<Heap>,
+    },
+    /// A message sent to an alias, it gets dropped if the alias is no longer active on arrival.
+    AliasMessage {
+        from: PID,
+        alias: Ref,
+        value: Term,
+        heap: Option<Heap>,
     },
     PortMessage {
         from: port::ID,
@@ -39,15 +53,89 @@ pub enum Signal {
         from: PID,
         reason: Exception,
         reference: Ref,
+        heap: Option<Heap>,
     },
     Monitor {
         from: PID,
         reference: Ref,
     },
+    /// The outcome of our own spawn_request: the new pid, or the reason (an atom) it failed.
+    SpawnReply {
+        reference: Ref,
+        result: Result<PID, u32>,
+    },
     Demonitor {
         from: PID,
         reference: Ref,
     },
+    /// Asks whether we're executing the module's code, the answer goes back to the sender as a
+    /// {check_process_code, RequestId, Result} message.
+    CheckProcessCode {
+        from: PID,
+        module: *const Module,
+        request_id: Term,
+        heap: Option<Heap>,
+    },
+    /// Asks us to garbage collect, once we did the sender gets a
+    /// {garbage_collect, RequestId, true} message.
+    GarbageCollect {
+        from: PID,
+        request_id: Term,
+        heap: Option<Heap>,
+    },
+}
+
+// CheckProcessCode's module pointer is only ever compared against, never dereferenced.
+unsafe impl Send for Signal {}
+
+/// Copies a term into a new heap fragment. Immediates don't need one.
+pub(crate) fn copy_to_fragment(value: Term) -> (Term, Option<Heap>) {
+    match value.into_variant() {
+        Variant::Cons(..) | Variant::Pointer(..) => {
+            let heap = Heap::fragment();
+            (value.deep_clone(&heap), Some(heap))
+        }
+        _ => (value, None),
+    }
+}
+
+impl Signal {
+    pub fn message(from: PID, value: Term) -> Self {
+        let (value, heap) = copy_to_fragment(value);
+        Signal::Message { from, value, heap }
+    }
+
+    pub fn alias_message(from: PID, alias: Ref, value: Term) -> Self {
+        let (value, heap) = copy_to_fragment(value);
+        Signal::AliasMessage {
+            from,
+            alias,
+            value,
+            heap,
+        }
+    }
+
+    pub fn exit(from: PID, reason: &Exception, kind: ExitKind) -> Self {
+        let (value, heap) = copy_to_fragment(reason.value);
+        let reason = Exception::with_value(reason.reason, value);
+        Signal::Exit {
+            from,
+            reason,
+            kind,
+            heap,
+        }
+    }
+
+    pub fn monitor_down(from: PID, reason: &Exception, reference: Ref) -> Self {
+        let (value, heap) = copy_to_fragment(reason.value);
+        let reason = Exception::with_value(reason.reason, value);
+        Signal::MonitorDown {
+            from,
+            reason,
+            reference,
+            heap,
+        }
+    }
 }
 
 #[derive(Default, Debug)]
@@ -1,38 +1,161 @@
-//use std::ptr;
-use crate::process::Ref;
-use hashbrown::HashMap;
-use once_cell::sync::Lazy;
-use parking_lot::RwLock;
-
-#[derive(Debug)]
-pub struct TimerTable {
-    /// Direct mapping string to atom index
-    timers: RwLock<HashMap<String, u32>>,
+//! Timers started by erlang:send_after/3,4 and erlang:start_timer/3,4.
+//!
+//! The timers themselves run on the timer wheel of the VM's tokio runtime, the table keeps track
+//! of the pending ones so that they can be read or cancelled by reference. A timer that fires
+//! removes itself from the table before delivering its message, so a timer is either cancelled or
+//! delivered, never both.
+use crate::process::{self, Ref, Signal, PID};
+use crate::vm::Machine;
+use futures::compat::*;
+use futures::future::{abortable, AbortHandle};
+use futures::prelude::*;
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Where a timer delivers its message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dest {
+    Pid(PID),
+    /// A registered name, looked up when the timer fires.
+    Name(u32),
+}
+
+struct Timer {
+    deadline: Instant,
+    /// Timers started with a pid get cancelled when that process exits.
+    owner: Option<PID>,
+    handle: AbortHandle,
 }
 
-/// Stores atom lookup tables.
-impl TimerTable {
-    pub fn new() -> TimerTable {
-        TimerTable {
-            timers: HashMap::new(),
+#[derive(Default)]
+struct Timers {
+    timers: HashMap<Ref, Timer>,
+    /// References of the timers owned by each process.
+    owned: HashMap<PID, HashSet<Ref>>,
+}
+
+/// The pending timers of the VM.
+#[derive(Default)]
+pub struct Table {
+    inner: Mutex<Timers>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Table::default()
+    }
+
+    /// Starts a timer that sends signal to dest once the deadline passes.
+    pub fn start(
+        &self,
+        vm: &Machine,
+        reference: Ref,
+        deadline: Instant,
+        dest: Dest,
+        signal: Signal,
+    ) {
+        let future = async move {
+            let _ = Delay::new(deadline).compat().await;
+            let vm = Machine::current();
+            if vm.timers.remove(reference).is_some() {
+                deliver(&vm, dest, signal);
+            }
+        };
+        let (future, handle) = abortable(future);
+
+        let owner = match dest {
+            Dest::Pid(pid) => Some(pid),
+            Dest::Name(_) => None,
+        };
+        {
+            // registered before spawning, in case the deadline already passed
+            let mut inner = self.inner.lock();
+            inner.timers.insert(
+                reference,
+                Timer {
+                    deadline,
+                    owner,
+                    handle,
+                },
+            );
+            if let Some(pid) = owner {
+                inner
+                    .owned
+                    .entry(pid)
+                    .or_insert_with(HashSet::new)
+                    .insert(reference);
+            }
+        }
+
+        let future = future.map(|_| ());
+        vm.runtime
+            .executor()
+            .spawn(future.unit_error().boxed().compat());
+    }
+
+    /// Cancels a timer, returning the time that was left. Returns None if there's no such timer
+    /// (it already fired, or got cancelled).
+    pub fn cancel(&self, reference: Ref) -> Option<Duration> {
+        self.remove(reference).map(|timer| {
+            timer.handle.abort();
+            remaining(timer.deadline)
+        })
+    }
+
+    /// Returns the time left until a timer fires, if it's still pending.
+    pub fn read(&self, reference: Ref) -> Option<Duration> {
+        self.inner
+            .lock()
+            .timers
+            .get(&reference)
+            .map(|timer| remaining(timer.deadline))
+    }
+
+    /// Cancels all the timers owned by an exiting process.
+    pub fn cancel_owned(&self, pid: PID) {
+        let mut inner = self.inner.lock();
+        if let Some(references) = inner.owned.remove(&pid) {
+            for reference in references {
+                if let Some(timer) = inner.timers.remove(&reference) {
+                    timer.handle.abort();
+                }
+            }
         }
     }
 
-    pub fn register(&mut self, reference: process::Ref, timer: T) -> &T {
-        // create a timer that will run for <delay>
-        // need to either send a timeout message after that
-        // or send a message to another process
-        // --> can be abstracted by taking a pid + message, for timeout just send to self and wrap
-        // in a tuple.
-        // the timer needs to unregister itself on completion
-        //
-        // this is kind of annoying, I'd prefer to not keep a global store since tokio already
-        // manages things for us.
-        //instead, let's box a tokio::timer?
-        self.timers.entry(atom).or_insert(process)
+    fn remove(&self, reference: Ref) -> Option<Timer> {
+        let mut inner = self.inner.lock();
+        let timer = inner.timers.remove(&reference)?;
+        if let Some(pid) = timer.owner {
+            if let Some(references) = inner.owned.get_mut(&pid) {
+                references.remove(&reference);
+                if references.is_empty() {
+                    inner.owned.remove(&pid);
+                }
+            }
+        }
+        Some(timer)
     }
+}
 
-    pub fn unregister(&mut self, atom: u32) -> Option<T> {
-        self.timers.remove(&atom)
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_millis(0)
     }
 }
+
+fn deliver(vm: &Machine, dest: Dest, signal: Signal) {
+    let pid = match dest {
+        Dest::Pid(pid) => pid,
+        Dest::Name(name) => match vm.process_registry.lock().whereis(name) {
+            Some(process) => process.pid,
+            None => return,
+        },
+    };
+    process::send_signal(vm, pid, signal);
+}
@@ -0,0 +1,651 @@
+//! Call and message tracing, as set up by erlang:trace/3 and erlang:trace_pattern/3.
+//!
+//! Trace messages are delivered to the tracer process as regular messages:
+//!
+//! - `{trace, Pid, call, {M, F, Args}}`
+//! - `{trace, Pid, return_from, {M, F, Arity}, Value}`
+//! - `{trace, Pid, send, Msg, To}`
+//! - `{trace, Pid, 'receive', Msg}`
+use crate::atom;
+use crate::ets::pam;
+use crate::exception::{Exception, Reason};
+use crate::immix::Heap;
+use crate::module::MFA;
+use crate::process::{self, ExecutionContext, Process, RcProcess};
+use crate::value::{Cons, Term, TryFrom, Tuple};
+use crate::vm;
+use std::fmt;
+
+bitflags! {
+    /// Trace flags set on a process.
+    pub struct Flag: u8 {
+        const NONE = 0;
+        const CALL = (1 << 0);
+        const SEND = (1 << 1);
+        const RECEIVE = (1 << 2);
+        /// Processes spawned by the traced process inherit its flags and tracer.
+        const SET_ON_SPAWN = (1 << 3);
+
+        const ALL = Self::CALL.bits | Self::SEND.bits | Self::RECEIVE.bits | Self::SET_ON_SPAWN.bits;
+    }
+}
+
+/// A call trace pattern, installed on a function by erlang:trace_pattern/3.
+pub struct Pattern {
+    /// The match spec, as passed in. `[]` if every call matches.
+    pub spec: Term,
+    /// The compiled match spec, none if every call matches.
+    program: Option<pam::Pattern>,
+    /// If the pattern was installed with the `local` flag.
+    pub local: bool,
+    /// Holds the copy of the match spec.
+    heap: Heap,
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pattern({})", self.spec)
+    }
+}
+
+impl Pattern {
+    pub fn new(spec: Term, local: bool) -> Result<Self, Exception> {
+        let heap = Heap::new();
+        let spec = spec.deep_clone(&heap);
+
+        let program = if spec.is_nil() {
+            None
+        } else {
+            Some(compile(&heap, spec)?)
+        };
+
+        Ok(Pattern {
+            spec,
+            program,
+            local,
+            heap,
+        })
+    }
+}
+
+fn compile(heap: &Heap, spec: Term) -> Result<pam::Pattern, Exception> {
+    let clauses = Cons::try_from(&spec)?;
+
+    let mut matches = Vec::new();
+    let mut guards = Vec::new();
+    let mut bodies = Vec::new();
+
+    for clause in clauses.iter() {
+        let clause = Tuple::try_from(clause)?;
+        if clause.len() != 3 {
+            return Err(Exception::new(Reason::EXC_BADARG));
+        }
+        matches.push(clause[0]);
+        guards.push(clause[1]);
+        bodies.push(rewrite_body(heap, clause[2])?);
+    }
+
+    let num_match = matches.len();
+    pam::Compiler::new(matches, guards, bodies, num_match, pam::Flag::DCOMP_TABLE)
+        .match_compile()
+        .map_err(|_| Exception::new(Reason::EXC_BADARG))
+}
+
+/// The match spec compiler only speaks the table dialect, so we pull out the `{return_trace}`
+/// action and replace it with a trailing `return_trace` atom, which the clause then evaluates to.
+fn rewrite_body(heap: &Heap, body: Term) -> Result<Term, Exception> {
+    let mut return_trace = false;
+    let mut exprs = Vec::new();
+
+    if !body.is_nil() {
+        for expr in Cons::try_from(&body)?.iter() {
+            match Tuple::try_from(expr) {
+                Ok(action)
+                    if action.len() == 1
+                        && (action[0] == atom!(RETURN_TRACE)
+                            || action[0] == atom!(EXCEPTION_TRACE)) =>
+                {
+                    return_trace = true
+                }
+                _ => exprs.push(*expr),
+            }
+        }
+    }
+
+    if return_trace {
+        exprs.push(atom!(RETURN_TRACE));
+    } else if exprs.is_empty() {
+        exprs.push(atom!(TRUE));
+    }
+
+    Ok(Cons::from_iter(exprs.into_iter(), heap))
+}
+
+/// Sends a trace message to the tracer of the process. If the tracer is gone, tracing gets
+/// turned off.
+fn deliver(vm: &vm::Machine, process: &Process, msg: Term) {
+    let local_data = process.local_data_mut();
+
+    if let Some(tracer) = local_data.tracer {
+        let sent = process::send_signal(vm, tracer, process::Signal::message(process.pid, msg));
+
+        if !sent {
+            local_data.trace = Flag::NONE;
+            local_data.tracer = None;
+        }
+    }
+}
+
+/// Emits a call trace message if the function has a matching call pattern. Local calls only
+/// match patterns set with the `local` flag. Returns true if the matching clause asked for a
+/// return trace.
+pub fn call(vm: &vm::Machine, process: &RcProcess, mfa: &MFA, args: &[Term], local: bool) -> bool {
+    let pattern = match vm.exports.read().lookup_pattern(mfa) {
+        Some(pattern) if pattern.local || !local => pattern,
+        _ => return false,
+    };
+
+    let heap = &process.context_mut().heap;
+    let arglist = Cons::from_iter(args.iter().cloned(), heap);
+
+    let res = match &pattern.program {
+        Some(program) => {
+            // a match spec that can't run on the arguments doesn't match them
+            match pam::r#match::run(vm, process, program, arglist, pam::r#match::Flag::empty()) {
+                Ok(Some(res)) => res,
+                _ => return false,
+            }
+        }
+        None => atom!(TRUE),
+    };
+
+    let msg = tup4!(
+        heap,
+        atom!(TRACE),
+        Term::pid(process.pid),
+        atom!(CALL),
+        tup3!(heap, Term::atom(mfa.0), Term::atom(mfa.1), arglist)
+    );
+    deliver(vm, process, msg);
+
+    res == atom!(RETURN_TRACE)
+}
+
+/// Registers a return trace frame, reported once the call returns to the current continuation.
+pub fn push_return(context: &mut ExecutionContext, mfa: MFA) {
+    context
+        .return_trace
+        .push((context.cp, context.stack.len(), mfa));
+}
+
+/// Called on return, emits a return_from message for every frame we're returning through.
+pub fn return_from(vm: &vm::Machine, process: &Process) {
+    let context = process.context_mut();
+    let depth = context.stack.len();
+
+    // frames that were unwound by an exception never return
+    while let Some((_, frame_depth, _)) = context.return_trace.last() {
+        if *frame_depth <= depth {
+            break;
+        }
+        context.return_trace.pop();
+    }
+
+    while let Some((cp, frame_depth, mfa)) = context.return_trace.last().cloned() {
+        if frame_depth != depth || cp != context.cp {
+            break;
+        }
+        context.return_trace.pop();
+        return_value(vm, process, &mfa, context.x[0]);
+    }
+}
+
+/// Emits a return_from message for the function.
+pub fn return_value(vm: &vm::Machine, process: &Process, mfa: &MFA, value: Term) {
+    let heap = &process.context_mut().heap;
+    let msg = tup!(
+        heap,
+        atom!(TRACE),
+        Term::pid(process.pid),
+        atom!(RETURN_FROM),
+        tup3!(
+            heap,
+            Term::atom(mfa.0),
+            Term::atom(mfa.1),
+            Term::uint(heap, mfa.2)
+        ),
+        value
+    );
+    deliver(vm, process, msg);
+}
+
+pub fn send(vm: &vm::Machine, process: &Process, to: Term, msg: Term) {
+    let heap = &process.context_mut().heap;
+    let msg = tup!(
+        heap,
+        atom!(TRACE),
+        Term::pid(process.pid),
+        atom!(SEND),
+        msg,
+        to
+    );
+    deliver(vm, process, msg);
+}
+
+pub fn receive(vm: &vm::Machine, process: &Process, msg: Term) {
+    let heap = &process.context_mut().heap;
+    let msg = tup4!(
+        heap,
+        atom!(TRACE),
+        Term::pid(process.pid),
+        atom!(RECEIVE),
+        msg
+    );
+    deliver(vm, process, msg);
+}
+
+pub mod bif {
+    use super::{Flag, Pattern};
+    use crate::atom;
+    use crate::bif::Result;
+    use crate::exception::{Exception, Reason};
+    use crate::immix::Heap;
+    use crate::loader::LINE_INVALID_LOCATION;
+    use crate::module::MFA;
+    use crate::process::RcProcess;
+    use crate::value::{Cons, Term, TryFrom, Tuple, Variant};
+    use crate::vm;
+    use std::sync::Arc;
+
+    fn flags_to_list(heap: &Heap, flags: Flag) -> Term {
+        let mut res = Term::nil();
+        if flags.contains(Flag::SET_ON_SPAWN) {
+            res = cons!(heap, atom!(SET_ON_SPAWN), res);
+        }
+        if flags.contains(Flag::RECEIVE) {
+            res = cons!(heap, atom!(RECEIVE), res);
+        }
+        if flags.contains(Flag::SEND) {
+            res = cons!(heap, atom!(SEND), res);
+        }
+        if flags.contains(Flag::CALL) {
+            res = cons!(heap, atom!(CALL), res);
+        }
+        res
+    }
+
+    /// trace(PidSpec, How, FlagList)
+    pub fn trace_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let on = match args[1].into_variant() {
+            Variant::Atom(atom::TRUE) => true,
+            Variant::Atom(atom::FALSE) => false,
+            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+        };
+
+        let mut flags = Flag::NONE;
+        let mut tracer = process.pid;
+
+        if !args[2].is_nil() {
+            for val in Cons::try_from(&args[2])?.iter() {
+                match val.into_variant() {
+                    Variant::Atom(atom::CALL) => flags |= Flag::CALL,
+                    Variant::Atom(atom::SEND) => flags |= Flag::SEND,
+                    Variant::Atom(atom::RECEIVE) => flags |= Flag::RECEIVE,
+                    Variant::Atom(atom::SET_ON_SPAWN) => flags |= Flag::SET_ON_SPAWN,
+                    Variant::Atom(atom::ALL) => flags |= Flag::ALL,
+                    _ => {
+                        let tup = Tuple::try_from(val)?;
+                        if tup.len() != 2 {
+                            return Err(Exception::new(Reason::EXC_BADARG));
+                        }
+                        match (tup[0].into_variant(), tup[1].into_variant()) {
+                            (Variant::Atom(atom::TRACER), Variant::Pid(pid)) => tracer = pid,
+                            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+                        }
+                    }
+                }
+            }
+        }
+
+        let targets: Vec<RcProcess> = {
+            let table = vm.process_table.lock();
+            match args[0].into_variant() {
+                Variant::Pid(pid) => {
+                    // a process can't trace itself
+                    if pid == tracer {
+                        return Err(Exception::new(Reason::EXC_BADARG));
+                    }
+                    match table.get(pid) {
+                        Some(target) => vec![target],
+                        None => return Err(Exception::new(Reason::EXC_BADARG)),
+                    }
+                }
+                Variant::Atom(atom::ALL) | Variant::Atom(atom::EXISTING) => table
+                    .iter()
+                    .filter(|target| target.pid != tracer)
+                    .cloned()
+                    .collect(),
+                _ => return Err(Exception::new(Reason::EXC_BADARG)),
+            }
+        };
+
+        for target in &targets {
+            let local_data = target.local_data_mut();
+            if on {
+                local_data.trace.insert(flags);
+                local_data.tracer = Some(tracer);
+            } else {
+                local_data.trace.remove(flags);
+                if local_data.trace.is_empty() {
+                    local_data.tracer = None;
+                }
+            }
+        }
+
+        Ok(Term::uint(
+            &process.context_mut().heap,
+            targets.len() as u32,
+        ))
+    }
+
+    /// trace_pattern(MFA, MatchSpec, FlagList)
+    pub fn trace_pattern_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let tup = Tuple::try_from(&args[0])?;
+        if tup.len() != 3 {
+            return Err(Exception::new(Reason::EXC_BADARG));
+        }
+
+        let module = match tup[0].into_variant() {
+            Variant::Atom(atom::UNDERSCORE) => None,
+            Variant::Atom(module) => Some(module),
+            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+        };
+        let func = match tup[1].into_variant() {
+            Variant::Atom(atom::UNDERSCORE) => None,
+            Variant::Atom(func) => Some(func),
+            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+        };
+        let arity = match tup[2].into_variant() {
+            Variant::Atom(atom::UNDERSCORE) => None,
+            Variant::Integer(arity) if arity >= 0 => Some(arity as u32),
+            _ => return Err(Exception::new(Reason::EXC_BADARG)),
+        };
+
+        // once a wildcard is used, everything following it has to be a wildcard too
+        if (module.is_none() && func.is_some()) || (func.is_none() && arity.is_some()) {
+            return Err(Exception::new(Reason::EXC_BADARG));
+        }
+
+        let mut local = false;
+        if !args[2].is_nil() {
+            for val in Cons::try_from(&args[2])?.iter() {
+                match val.into_variant() {
+                    Variant::Atom(atom::GLOBAL) => local = false,
+                    Variant::Atom(atom::LOCAL) => local = true,
+                    _ => return Err(Exception::new(Reason::EXC_BADARG)),
+                }
+            }
+        }
+
+        let pattern = match args[1].into_variant() {
+            Variant::Atom(atom::FALSE) => None,
+            Variant::Atom(atom::TRUE) => Some(Arc::new(Pattern::new(Term::nil(), local)?)),
+            _ => Some(Arc::new(Pattern::new(args[1], local)?)),
+        };
+
+        // local patterns also apply to the functions a module doesn't export
+        let mut functions = Vec::new();
+        if local {
+            let registry = vm.modules.lock();
+            for (name, module) in &registry.modules {
+                let funs = module
+                    .funs
+                    .keys()
+                    .filter(|key| **key != LINE_INVALID_LOCATION);
+                functions.extend(funs.map(|(func, arity)| MFA(*name, *func, *arity)));
+            }
+        }
+
+        let mut exports = vm.exports.write();
+        functions.extend(exports.keys().cloned());
+        functions.sort();
+        functions.dedup();
+
+        let targets: Vec<MFA> = functions
+            .into_iter()
+            .filter(|mfa| {
+                module.map_or(true, |m| m == mfa.0)
+                    && func.map_or(true, |f| f == mfa.1)
+                    && arity.map_or(true, |a| a == mfa.2)
+            })
+            .collect();
+
+        for mfa in &targets {
+            match &pattern {
+                Some(pattern) => exports.insert_pattern(*mfa, pattern.clone()),
+                None => {
+                    exports.remove_pattern(mfa);
+                }
+            }
+        }
+
+        Ok(Term::uint(
+            &process.context_mut().heap,
+            targets.len() as u32,
+        ))
+    }
+
+    /// trace_info(PidOrFunc, Item)
+    pub fn trace_info_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let heap = &process.context_mut().heap;
+
+        match args[0].into_variant() {
+            Variant::Pid(pid) => {
+                let target = match vm.process_table.lock().get(pid) {
+                    Some(target) => target,
+                    None => return Ok(atom!(UNDEFINED)),
+                };
+                let local_data = target.local_data();
+
+                match args[1].into_variant() {
+                    Variant::Atom(atom::FLAGS) => Ok(tup2!(
+                        heap,
+                        atom!(FLAGS),
+                        flags_to_list(heap, local_data.trace)
+                    )),
+                    Variant::Atom(atom::TRACER) => {
+                        let tracer = match local_data.tracer {
+                            Some(tracer) => Term::pid(tracer),
+                            None => Term::nil(),
+                        };
+                        Ok(tup2!(heap, atom!(TRACER), tracer))
+                    }
+                    _ => Err(Exception::new(Reason::EXC_BADARG)),
+                }
+            }
+            Variant::Pointer(..) => {
+                let tup = Tuple::try_from(&args[0])?;
+                if tup.len() != 3 {
+                    return Err(Exception::new(Reason::EXC_BADARG));
+                }
+                let mfa = match (
+                    tup[0].into_variant(),
+                    tup[1].into_variant(),
+                    tup[2].into_variant(),
+                ) {
+                    (Variant::Atom(m), Variant::Atom(f), Variant::Integer(a)) if a >= 0 => {
+                        MFA(m, f, a as u32)
+                    }
+                    _ => return Err(Exception::new(Reason::EXC_BADARG)),
+                };
+
+                let pattern = vm.exports.read().lookup_pattern(&mfa);
+
+                let traced = match &pattern {
+                    Some(pattern) if pattern.local => atom!(LOCAL),
+                    Some(_) => atom!(GLOBAL),
+                    None => atom!(FALSE),
+                };
+                let match_spec = match &pattern {
+                    Some(pattern) => pattern.spec,
+                    None => atom!(FALSE),
+                };
+
+                match args[1].into_variant() {
+                    Variant::Atom(atom::TRACED) => Ok(tup2!(heap, atom!(TRACED), traced)),
+                    Variant::Atom(atom::MATCH_SPEC) => {
+                        Ok(tup2!(heap, atom!(MATCH_SPEC), match_spec))
+                    }
+                    Variant::Atom(atom::ALL) => {
+                        let res = if pattern.is_some() {
+                            cons!(
+                                heap,
+                                tup2!(heap, atom!(TRACED), traced),
+                                cons!(
+                                    heap,
+                                    tup2!(heap, atom!(MATCH_SPEC), match_spec),
+                                    Term::nil()
+                                )
+                            )
+                        } else {
+                            atom!(FALSE)
+                        };
+                        Ok(tup2!(heap, atom!(ALL), res))
+                    }
+                    _ => Err(Exception::new(Reason::EXC_BADARG)),
+                }
+            }
+            _ => Err(Exception::new(Reason::EXC_BADARG)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module;
+
+    #[test]
+    fn test_rewrite_body() {
+        let heap = Heap::new();
+
+        let body = cons!(&heap, tup!(&heap, atom!(RETURN_TRACE)), Term::nil());
+        let res = rewrite_body(&heap, body).unwrap();
+        assert_eq!(res, cons!(&heap, atom!(RETURN_TRACE), Term::nil()));
+
+        let res = rewrite_body(&heap, Term::nil()).unwrap();
+        assert_eq!(res, cons!(&heap, atom!(TRUE), Term::nil()));
+    }
+
+    #[test]
+    fn test_trace_pattern_3() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = std::pin::Pin::new(process::allocate(&vm, 0, 0, module).unwrap());
+        let heap = &process.context_mut().heap;
+
+        let mfa = tup3!(heap, atom!(ERLANG), str_to_atom!("self"), Term::int(0));
+        let args = vec![mfa, atom!(TRUE), Term::nil()];
+        let res = bif::trace_pattern_3(&vm, &process, &args);
+        assert_eq!(res, Ok(Term::int(1)));
+
+        let args = vec![mfa, atom!(TRACED)];
+        let res = bif::trace_info_2(&vm, &process, &args);
+        assert_eq!(res, Ok(tup2!(heap, atom!(TRACED), atom!(GLOBAL))));
+
+        let args = vec![mfa, atom!(FALSE), Term::nil()];
+        bif::trace_pattern_3(&vm, &process, &args).unwrap();
+
+        let args = vec![mfa, atom!(TRACED)];
+        let res = bif::trace_info_2(&vm, &process, &args);
+        assert_eq!(res, Ok(tup2!(heap, atom!(TRACED), atom!(FALSE))));
+    }
+
+    #[test]
+    fn test_traced_process() {
+        use crate::instr_ptr::InstrPtr;
+        use crate::loader::{Instruction, LValue};
+        use crate::opcodes::Opcode;
+        let vm = vm::Machine::new();
+        vm::Machine::set_current(vm.clone());
+        let null: *const module::Module = std::ptr::null();
+        let mut process = std::pin::Pin::new(process::allocate(&vm, 0, 0, null).unwrap());
+        let tracer = std::pin::Pin::new(process::allocate(&vm, 0, 0, null).unwrap());
+        let heap = &tracer.context_mut().heap;
+
+        // main() sends x1 to x0 and calls helper(X) locally, which returns X
+        let (name, main, helper) = (
+            str_to_atom!("trace_test"),
+            str_to_atom!("main"),
+            str_to_atom!("helper"),
+        );
+        let op = |op, args| Instruction { op, args };
+        let code = vec![
+            op(Opcode::Send, vec![]),
+            op(Opcode::Call, vec![LValue::Literal(1), LValue::Label(3)]),
+            op(Opcode::Return, vec![]),
+            op(Opcode::Return, vec![]),
+        ];
+        let module = Box::new(module::Module {
+            imports: Vec::new(),
+            resolved_imports: Vec::new(),
+            exports: Vec::new(),
+            literals: Vec::new(),
+            literal_heap: Heap::new(),
+            lambdas: Vec::new(),
+            funs: [((main, 0), 0), ((helper, 1), 3)].iter().cloned().collect(),
+            instructions: code,
+            lines: Vec::new(),
+            name,
+            on_load: None,
+        });
+        let module = vm.modules.lock().add_module(name, module) as *const module::Module;
+
+        let flags = [atom!(CALL), atom!(SEND), atom!(RECEIVE)];
+        let flags = Cons::from_iter(flags.iter().cloned(), heap);
+        let flags = cons!(
+            heap,
+            tup2!(heap, atom!(TRACER), Term::pid(tracer.pid)),
+            flags
+        );
+        let args = vec![Term::pid(process.pid), atom!(TRUE), flags];
+        bif::trace_3(&vm, &tracer, &args).unwrap();
+
+        // only local patterns apply to local calls
+        let mfa = tup3!(heap, Term::atom(name), Term::atom(helper), Term::int(1));
+        let clause = tup3!(heap, atom!(UNDERSCORE), Term::nil(), Term::nil());
+        let spec = cons!(heap, clause, Term::nil());
+        bif::trace_pattern_3(&vm, &tracer, &[mfa, spec, Term::nil()]).unwrap();
+        let body = cons!(heap, tup!(heap, atom!(RETURN_TRACE)), Term::nil());
+        let clause = tup3!(heap, atom!(UNDERSCORE), Term::nil(), body);
+        let spec = cons!(heap, clause, Term::nil());
+        let local = cons!(heap, atom!(LOCAL), Term::nil());
+        let res = bif::trace_pattern_3(&vm, &tracer, &[mfa, spec, local]);
+        assert_eq!(res, Ok(Term::int(1)));
+
+        let context = process.context_mut();
+        context.ip = InstrPtr { module, ptr: 0 };
+        context.x[0] = Term::pid(tracer.pid);
+        context.x[1] = atom!(OK);
+        match futures::executor::block_on(vm.run(&mut process)) {
+            Ok(process::State::Done) => (),
+            _ => panic!("trace_test:main/0 didn't return"),
+        }
+
+        process::send_message(&vm, tracer.pid, Term::pid(process.pid), atom!(TRUE)).unwrap();
+        process.process_incoming().unwrap();
+
+        let messages = crate::bif::tests::receive_all(&tracer);
+
+        let pid = Term::pid(process.pid);
+        let to = Term::pid(tracer.pid);
+        let send = tup!(heap, atom!(TRACE), pid, atom!(SEND), atom!(OK), to);
+        let args = cons!(heap, atom!(OK), Term::nil());
+        let call = tup3!(heap, Term::atom(name), Term::atom(helper), args);
+        let call = tup4!(heap, atom!(TRACE), pid, atom!(CALL), call);
+        let func = tup3!(heap, Term::atom(name), Term::atom(helper), Term::int(1));
+        let ret = tup!(heap, atom!(TRACE), pid, atom!(RETURN_FROM), func, atom!(OK));
+        let receive = tup4!(heap, atom!(TRACE), pid, atom!(RECEIVE), atom!(TRUE));
+        assert_eq!(messages, vec![send, atom!(OK), call, ret, receive]);
+    }
+}
@@ -13,6 +13,7 @@ use crate::process;
 use crate::servo_arc::Arc;
 use allocator_api::Layout;
 use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 
@@ -280,24 +281,43 @@ impl Term {
 /// alignment. That means there would be some wasted space.
 pub type Header = u8;
 
-pub const BOXED_REF: u8 = 0;
 pub const BOXED_TUPLE: u8 = 1;
-pub const BOXED_BINARY: u8 = 2;
-pub const BOXED_MAP: u8 = 3;
-pub const BOXED_BIGINT: u8 = 4;
-pub const BOXED_CLOSURE: u8 = 5;
-// TODO: these should be direct pointers, no heap
-pub const BOXED_CP: u8 = 6;
-pub const BOXED_CATCH: u8 = 7;
-pub const BOXED_STACKTRACE: u8 = 8;
-
-pub const BOXED_MATCHSTATE: u8 = 9;
-pub const BOXED_SUBBINARY: u8 = 10;
-
-pub const BOXED_MODULE: u8 = 20;
-pub const BOXED_EXPORT: u8 = 21;
-pub const BOXED_FILE: u8 = 22;
-pub const BOXED_BUFFER: u8 = 23;
+
+/// Lists the fixed size boxed values, along with their header and the type stored after it, and
+/// passes them on to another macro. The header constants are declared from this list, so code
+/// that needs to handle every boxed type (like the garbage collector) can't miss one.
+#[macro_export]
+macro_rules! boxed_types {
+    ($callback:ident!($($args:tt)*)) => {
+        $callback!($($args)*;
+            BOXED_REF = 0 => crate::process::Ref,
+            BOXED_BINARY = 2 => crate::bitstring::RcBinary,
+            BOXED_MAP = 3 => crate::value::Map,
+            BOXED_BIGINT = 4 => num_bigint::BigInt,
+            BOXED_CLOSURE = 5 => crate::value::Closure,
+            // TODO: these should be direct pointers, no heap
+            BOXED_CP = 6 => Option<crate::instr_ptr::InstrPtr>,
+            BOXED_CATCH = 7 => crate::instr_ptr::InstrPtr,
+            BOXED_STACKTRACE = 8 => crate::exception::StackTrace,
+
+            BOXED_MATCHSTATE = 9 => crate::bitstring::MatchState,
+            BOXED_SUBBINARY = 10 => crate::bitstring::SubBinary,
+
+            BOXED_MODULE = 20 => *mut crate::module::Module,
+            BOXED_EXPORT = 21 => crate::module::MFA,
+            BOXED_FILE = 22 => std::fs::File,
+            BOXED_BUFFER = 23 => crate::bif::prim_buffer::Buffer,
+        )
+    };
+}
+
+macro_rules! declare_headers {
+    (; $($name:ident = $value:expr => $ty:ty,)*) => {
+        $(pub const $name: Header = $value;)*
+    };
+}
+
+boxed_types!(declare_headers!());
 
 #[derive(Debug)]
 #[repr(C)]
@@ -334,6 +354,60 @@ pub enum Num {
     Bignum(BigInt),
 }
 
+impl Num {
+    /// Converts the number into a float, or None if it's too large to be represented as one.
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            Num::Float(f) => Some(*f),
+            Num::Integer(i) => Some(f64::from(*i)),
+            Num::Bignum(i) => i.to_f64().filter(|f| f.is_finite()),
+        }
+    }
+
+    /// Converts an integer into a bignum, None for floats.
+    pub fn to_bigint(self) -> Option<BigInt> {
+        match self {
+            Num::Float(_) => None,
+            Num::Integer(i) => Some(BigInt::from(i)),
+            Num::Bignum(i) => Some(i),
+        }
+    }
+}
+
+/// Compares two element sequences with the arithmetic comparison, shorter sequences first.
+fn erl_cmp_elements<'a>(
+    mut a: impl Iterator<Item = &'a Term>,
+    mut b: impl Iterator<Item = &'a Term>,
+) -> Ordering {
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => match x.erl_cmp(y) {
+                Ordering::Equal => continue,
+                ord => return ord,
+            },
+        }
+    }
+}
+
+/// Compares a float against a bignum by value. Floats this large are always integral, so the
+/// conversion to a bignum is exact; the fraction only matters when the integral parts are equal.
+fn cmp_float_bigint(f: f64, i: &BigInt) -> Ordering {
+    let int = f.trunc();
+    let int_big = match BigInt::from_f64(int) {
+        Some(int_big) => int_big,
+        // erlang floats are always finite, but keep infinities ordered past any bignum
+        None if f > 0.0 => return Ordering::Greater,
+        None => return Ordering::Less,
+    };
+    match int_big.cmp(i) {
+        Ordering::Equal => (f - int).partial_cmp(&0.0).unwrap(),
+        ord => ord,
+    }
+}
+
 impl Term {
     #[inline(always)]
     pub fn tag(self) -> u8 {
@@ -379,7 +453,7 @@ impl Term {
 
     #[inline]
     pub fn int64(heap: &Heap, value: i64) -> Self {
-        if value > (i32::max_value() as i64) {
+        if value > i64::from(i32::max_value()) || value < i64::from(i32::min_value()) {
             Term::bigint(heap, BigInt::from(value))
         } else {
             unsafe {
@@ -453,7 +527,12 @@ impl Term {
         }))
     }
 
+    /// Builds an integer from a bignum. Values that fit into a small integer get demoted, so that
+    /// every integer has a single representation (comparisons rely on this).
     pub fn bigint(heap: &Heap, value: BigInt) -> Self {
+        if let Some(i) = value.to_i32() {
+            return Term::int(i);
+        }
         Term::from(heap.alloc(Boxed {
             header: BOXED_BIGINT,
             value,
@@ -461,6 +540,7 @@ impl Term {
     }
 
     pub fn binary(heap: &Heap, value: bitstring::Binary) -> Self {
+        heap.add_binary(value.data.len());
         Term::from(heap.alloc(Boxed {
             header: BOXED_BINARY,
             value: Arc::new(value),
@@ -858,14 +938,20 @@ impl Term {
                 // immediates
                 *self
             }
-            Variant::Cons(cons) => {
-                let cons = unsafe { &*cons };
-                // TODO: badly formed lists
-
-                // TODO: the intermediary collect into vec is not great but can't map without
-                // ExactSizeIterator
-                let vec: Vec<_> = cons.iter().collect();
-                Cons::from_iter(vec.iter().map(|v| v.deep_clone(heap)), heap)
+            Variant::Cons(..) => {
+                // copy the elements first, then link them up back to front. This also keeps the
+                // tail of improper lists.
+                let mut elements = Vec::new();
+                let mut list = self;
+                while let Ok(Cons { head, tail }) = list.try_into() {
+                    elements.push(head.deep_clone(heap));
+                    list = tail;
+                }
+                let tail = list.deep_clone(heap);
+                elements
+                    .into_iter()
+                    .rev()
+                    .fold(tail, |acc, head| self::cons(heap, head, acc))
             }
             Variant::Pointer(ptr) => unsafe {
                 match *ptr {
@@ -898,18 +984,70 @@ impl Term {
                         let bigint = &(*(ptr as *const Boxed<BigInt>)).value;
                         Term::bigint(heap, bigint.clone())
                     }
+                    BOXED_BINARY => {
+                        // refc binaries are shared, not copied
+                        let binary = &(*(ptr as *const Boxed<bitstring::RcBinary>)).value;
+                        heap.add_binary(binary.data.len());
+                        Term::from(heap.alloc(Boxed {
+                            header: BOXED_BINARY,
+                            value: binary.clone(),
+                        }))
+                    }
+                    BOXED_SUBBINARY => {
+                        let binary = &(*(ptr as *const Boxed<bitstring::SubBinary>)).value;
+                        Term::subbinary(heap, binary.clone())
+                    }
+                    BOXED_CLOSURE => {
+                        let closure = &(*(ptr as *const Boxed<Closure>)).value;
+                        let binding = closure
+                            .binding
+                            .as_ref()
+                            .map(|binding| binding.iter().map(|v| v.deep_clone(heap)).collect());
+                        Term::closure(
+                            heap,
+                            Closure {
+                                module: closure.module,
+                                ptr: closure.ptr,
+                                mfa: closure.mfa,
+                                binding,
+                            },
+                        )
+                    }
                     _ => unimplemented!("deep_clone for {}", self), // TODO: deep clone for Ref<>
                 }
             },
         }
     }
 
+    /// Arithmetic comparison (==, /=, <, ...), where numbers compare by value: 1 == 1.0, also
+    /// nested inside of tuples and lists. `Ord` is the exact term order used by =:= and sorting.
+    pub fn erl_cmp(&self, other: &Self) -> Ordering {
+        self.compare(other, false)
+    }
+
+    /// Non strict comparison (==, /=), where numbers compare by value: 1 == 1.0.
     pub fn erl_partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // TODO: loosely compare int and floats
-        // non strict comparisons need to handle these + bigint
-        // (Variant::Integer(_), Variant::Float(_)) => unimplemented!(),
-        // (Variant::Float(_), Variant::Integer(_)) => unimplemented!(),
-        Some(self.cmp(other))
+        Some(self.erl_cmp(other))
+    }
+
+    fn compare(&self, other: &Self, exact: bool) -> Ordering {
+        // TODO: prevent blowing out the stack from recursion in the future
+
+        // TODO: atom, smallint and float have fastpaths here
+
+        // compare types first, if not equal, we can compare them as raw Type casts
+        // else, start comparing immediates
+
+        let t1 = self.get_type();
+        let t2 = other.get_type();
+
+        if t1 != t2 {
+            // types don't match, use term ordering
+            return t1.cmp(&t2);
+        }
+
+        // types match, let's keep going
+        self.into_variant().compare(&other.into_variant(), exact)
     }
 }
 
@@ -965,6 +1103,11 @@ impl PartialEq for Variant {
                             let e2 = &*(*p2 as *const Boxed<module::MFA>);
                             e1.value.eq(&e2.value)
                         }
+                        BOXED_BIGINT => {
+                            let i1 = &*(*p1 as *const Boxed<BigInt>);
+                            let i2 = &*(*p2 as *const Boxed<BigInt>);
+                            i1.value.eq(&i2.value)
+                        }
                         i => unimplemented!("boxed_value eq for {}", i),
                     }
                 } else {
@@ -984,25 +1127,10 @@ impl PartialOrd for Term {
 
 // TODO: make faster by not doing into_variant in some cases
 impl Ord for Term {
+    /// The exact term order, consistent with `Eq`: an integer and a float of the same value are
+    /// told apart by sorting the integer first.
     fn cmp(&self, other: &Term) -> Ordering {
-        // TODO: prevent blowing out the stack from recursion in the future
-
-        // TODO: atom, smallint and float have fastpaths here
-
-        // compare types first, if not equal, we can compare them as raw Type casts
-        // else, start comparing immediates
-        // allow inexact number comparison
-
-        let t1 = self.get_type();
-        let t2 = other.get_type();
-
-        if t1 != t2 {
-            // types don't match, use term ordering
-            return t1.cmp(&t2);
-        }
-
-        // types match, let's keep going
-        self.into_variant().cmp(&other.into_variant())
+        self.compare(other, true)
     }
 }
 
@@ -1014,6 +1142,18 @@ impl PartialOrd for Variant {
 
 impl Ord for Variant {
     fn cmp(&self, other: &Variant) -> Ordering {
+        self.compare(other, true)
+    }
+}
+
+impl Variant {
+    /// Mixed integers and floats compare by value. When `exact`, equal values are broken as
+    /// integer < float, otherwise they're equal, all the way down nested tuples and lists.
+    fn compare(&self, other: &Variant, exact: bool) -> Ordering {
+        let tie = |ord: Ordering, int_first: Ordering| match ord {
+            Ordering::Equal if exact => int_first,
+            ord => ord,
+        };
         match (self, other) {
             (Variant::Nil(..), Variant::Nil(..)) => Ordering::Equal,
             (Variant::Integer(i1), Variant::Integer(i2)) => i1.cmp(i2),
@@ -1023,7 +1163,13 @@ impl Ord for Variant {
             (Variant::Pid(p1), Variant::Pid(p2)) => p1.cmp(p2),
             (Variant::Port(p1), Variant::Port(p2)) => p1.cmp(p2),
 
-            (Variant::Cons(l1), Variant::Cons(l2)) => unsafe { (**l1).cmp(&(**l2)) },
+            (Variant::Cons(l1), Variant::Cons(l2)) => unsafe {
+                if exact {
+                    (**l1).cmp(&(**l2))
+                } else {
+                    erl_cmp_elements((**l1).iter(), (**l2).iter())
+                }
+            },
 
             (Variant::Pointer(p1), Variant::Pointer(p2)) => unsafe {
                 let header = **p1;
@@ -1032,7 +1178,13 @@ impl Ord for Variant {
                         BOXED_TUPLE => {
                             let t1 = &*(*p1 as *const Tuple);
                             let t2 = &*(*p2 as *const Tuple);
-                            t1.cmp(t2)
+                            if exact {
+                                t1.cmp(t2)
+                            } else {
+                                t1.len.cmp(&t2.len).then_with(|| {
+                                    erl_cmp_elements(t1.as_slice().iter(), t2.as_slice().iter())
+                                })
+                            }
                         }
                         BOXED_REF => {
                             let r1 = &(*(*p1 as *const Boxed<process::Ref>)).value;
@@ -1070,6 +1222,27 @@ impl Ord for Variant {
                 let i1 = &(*(*p1 as *const Boxed<BigInt>)).value;
                 i1.cmp(&BigInt::from(*i2))
             },
+            // mixed integers and floats compare by value
+            (Variant::Integer(i1), Variant::Float(self::Float(f2))) => {
+                tie(f64::from(*i1).partial_cmp(f2).unwrap(), Ordering::Less)
+            }
+            (Variant::Float(self::Float(f1)), Variant::Integer(i2)) => {
+                tie(f1.partial_cmp(&f64::from(*i2)).unwrap(), Ordering::Greater)
+            }
+            (Variant::Float(self::Float(f1)), Variant::Pointer(p2)) => unsafe {
+                if **p2 != BOXED_BIGINT {
+                    unreachable!()
+                }
+                let i2 = &(*(*p2 as *const Boxed<BigInt>)).value;
+                tie(cmp_float_bigint(*f1, i2), Ordering::Greater)
+            },
+            (Variant::Pointer(p1), Variant::Float(self::Float(f2))) => unsafe {
+                if **p1 != BOXED_BIGINT {
+                    unreachable!()
+                }
+                let i1 = &(*(*p1 as *const Boxed<BigInt>)).value;
+                tie(cmp_float_bigint(*f2, i1).reverse(), Ordering::Less)
+            },
             // int and bigint
             _ => unimplemented!("cmp for {:?} and {:?}", self, other),
         }
@@ -1202,13 +1375,20 @@ impl std::fmt::Display for Variant {
 
 #[allow(clippy::mut_from_ref)]
 pub fn tuple(heap: &Heap, len: u32) -> &mut Tuple {
-    let tuple = heap.alloc(self::Tuple {
-        header: BOXED_TUPLE,
-        len,
-    });
-    let layout = Layout::new::<Term>().repeat(len as usize).unwrap().0;
-    heap.alloc_layout(layout);
-    tuple
+    // allocate in one go, the elements need to directly follow the header. The header takes up a
+    // word, which also keeps the elements aligned.
+    let layout = Layout::new::<Term>().repeat(len as usize + 1).unwrap().0;
+    unsafe {
+        let tuple = heap.alloc_layout(layout).as_ptr() as *mut Tuple;
+        std::ptr::write(
+            tuple,
+            self::Tuple {
+                header: BOXED_TUPLE,
+                len,
+            },
+        );
+        &mut *tuple
+    }
 }
 
 pub fn cons(heap: &Heap, head: Term, tail: Term) -> Term {
@@ -1241,4 +1421,49 @@ mod tests {
         let v3 = tup3!(heap, Term::int(1), Term::int(1), Term::int(1));
         assert!(!v1.eq(&v3));
     }
+
+    #[test]
+    fn test_mixed_number_comparison() {
+        let heap = &Heap::new();
+        let big = Term::bigint(heap, BigInt::from(1) << 70);
+        assert_eq!(Term::int(1).cmp(&Term::from(1.5)), std::cmp::Ordering::Less);
+        assert_eq!(
+            Term::from(2.0).cmp(&Term::int(1)),
+            std::cmp::Ordering::Greater
+        );
+        assert_eq!(Term::from(1.0e30).cmp(&big), std::cmp::Ordering::Greater);
+        assert_eq!(Term::int(-5).cmp(&big), std::cmp::Ordering::Less);
+        // bignums that fit are demoted, so they compare equal to small ints
+        assert_eq!(Term::bigint(heap, BigInt::from(42)), Term::int(42));
+    }
+
+    #[test]
+    fn test_exact_order_is_consistent_with_eq() {
+        use std::cmp::Ordering;
+        let heap = &Heap::new();
+        let (one, one_f) = (Term::int(1), Term::from(1.0));
+        assert_ne!(one, one_f);
+        assert_eq!(one.cmp(&one_f), Ordering::Less);
+        assert_eq!(one_f.cmp(&one), Ordering::Greater);
+        assert_eq!(one.erl_cmp(&one_f), Ordering::Equal);
+
+        let big = Term::bigint(heap, BigInt::from(1) << 70);
+        let big_f = Term::from(2f64.powi(70));
+        assert_eq!(big.cmp(&big_f), Ordering::Less);
+        assert_eq!(big.erl_cmp(&big_f), Ordering::Equal);
+
+        // the arithmetic compare recurses into containers
+        let t1 = tup2!(heap, Term::int(1), Term::int(2));
+        let t2 = tup2!(heap, Term::from(1.0), Term::int(2));
+        assert_eq!(t1.cmp(&t2), Ordering::Less);
+        assert_eq!(t1.erl_cmp(&t2), Ordering::Equal);
+        let l1 = cons!(heap, Term::int(1), Term::nil());
+        let l2 = cons!(heap, Term::from(1.0), Term::nil());
+        assert_eq!(l1.cmp(&l2), Ordering::Less);
+        assert_eq!(l1.erl_cmp(&l2), Ordering::Equal);
+
+        let mut terms = vec![one_f, one];
+        terms.sort();
+        assert_eq!(terms, vec![one, one_f]);
+    }
 }
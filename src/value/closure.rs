@@ -4,6 +4,8 @@ use crate::value::{Boxed, Term, TryFrom, Variant, WrongBoxError, BOXED_CLOSURE};
 #[derive(Debug)]
 #[repr(C)]
 pub struct Closure {
+    /// The version of the module that created the fun, calls run its code.
+    pub module: *const module::Module,
     pub ptr: u32,
     pub mfa: module::MFA,
     pub binding: Option<Vec<Term>>,
@@ -15,10 +15,13 @@ use crate::process::table::Table as ProcessTable;
 use crate::port::{Table as PortTable, RcTable as RcPortTable};
 use crate::process::{self, RcProcess};
 use crate::persistent_term::{Table as PersistentTermTable};
+use crate::timer::Table as TimerTable;
 use crate::servo_arc::Arc;
+use crate::trace;
 use crate::value::{self, Cons, Term, TryFrom, TryInto, TryIntoMut, Tuple, Variant};
 use std::cell::RefCell;
 // use log::debug;
+use hashbrown::HashMap;
 use parking_lot::Mutex;
 use std::panic;
 use std::sync::atomic::AtomicUsize;
@@ -45,13 +48,54 @@ pub struct Machine {
     pub process_registry: Mutex<ProcessRegistry<RcProcess>>,
 
     pub port_table: RcPortTable,
-    /// TODO: Use priorities later on
+
+    /// Number of runnable processes per priority level (low, normal, high, max).
+    pub run_queues: [AtomicUsize; 4],
+    /// Processes that gave way to higher priority ones, woken up whenever a run queue empties.
+    pub yielded: Mutex<Vec<futures::channel::oneshot::Sender<()>>>,
 
     /// The start time of the VM (more or less).
     pub start_time: time::Instant,
 
     pub next_ref: AtomicUsize,
 
+    /// Total reductions executed by all processes.
+    pub reductions: AtomicUsize,
+    /// Total reductions at the time of the last statistics(reductions) call.
+    pub last_reductions: AtomicUsize,
+    /// Same, for statistics(exact_reductions).
+    pub last_exact_reductions: AtomicUsize,
+
+    /// Time spent executing processes, in microseconds.
+    pub run_time: AtomicUsize,
+    /// Run time at the last statistics(runtime) call.
+    pub last_run_time: AtomicUsize,
+    /// Wall clock time at the last statistics(wall_clock) call, in milliseconds since start_time.
+    pub last_wall_clock: AtomicUsize,
+
+    /// Number of time slices executed.
+    pub context_switches: AtomicUsize,
+
+    /// Number of garbage collections, and the amount of words they reclaimed.
+    pub garbage_collections: AtomicUsize,
+    pub words_reclaimed: AtomicUsize,
+
+    /// Bytes received from, and sent to ports.
+    pub io_input: AtomicUsize,
+    pub io_output: AtomicUsize,
+
+    /// Number of processes currently being executed.
+    pub running: AtomicUsize,
+
+    /// Dirty CPU BIF calls in progress (queued or running), and the ones currently running.
+    pub dirty_cpu_tasks: AtomicUsize,
+    pub dirty_cpu_running: AtomicUsize,
+
+    /// The process schedulers (threads of process_pool), in the order they started.
+    pub schedulers: Mutex<Vec<Arc<Scheduler>>>,
+    /// When scheduler_wall_time accounting got enabled, if it is.
+    pub scheduler_wall_time: Mutex<Option<time::Instant>>,
+
     /// PID pointing to the process handling system-wide logging.
     pub system_logger: AtomicUsize,
 
@@ -59,6 +103,10 @@ pub struct Machine {
     pub process_pool: tokio::runtime::Runtime,
     pub runtime: tokio::runtime::Runtime,
 
+    /// Dirty scheduler pools, running BIFs that would otherwise block process_pool.
+    pub dirty_cpu_pool: tokio_threadpool::ThreadPool,
+    pub dirty_io_pool: tokio_threadpool::ThreadPool,
+
     pub exit: Option<futures::channel::oneshot::Sender::<()>>,
 
     // env config, arguments, panic handler
@@ -73,6 +121,12 @@ pub struct Machine {
     pub ets_tables: RcTableRegistry,
 
     pub persistent_terms: PersistentTermTable,
+
+    /// Pending timers (send_after, start_timer).
+    pub timers: TimerTable,
+
+    /// Owners of the active process aliases, used to route messages sent to an alias.
+    pub aliases: Mutex<HashMap<process::Ref, process::PID>>,
 }
 
 impl Machine {
@@ -80,12 +134,186 @@ impl Machine {
         self.next_ref
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
     }
+
+    fn run_queue(&self, priority: process::StateFlag) -> &AtomicUsize {
+        &self.run_queues[priority.bits() as usize - 1]
+    }
+
+    /// Marks a process with the given priority as runnable.
+    pub fn enqueue(&self, priority: process::StateFlag) {
+        self.run_queue(priority)
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Marks a process with the given priority as no longer runnable (waiting or exited). If
+    /// that was the last one, the processes that gave way to it get to run again.
+    pub fn dequeue(&self, priority: process::StateFlag) {
+        let runnable = self
+            .run_queue(priority)
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        if runnable == 1 {
+            for waiter in self.yielded.lock().drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+    }
+
+    /// Returns a channel that fires once there's no runnable process with a priority above the
+    /// given one, or None if there already isn't. The check happens under the same lock dequeue
+    /// takes to wake waiters, so a run queue emptying in between can't get lost.
+    fn give_way(
+        &self,
+        priority: process::StateFlag,
+    ) -> Option<futures::channel::oneshot::Receiver<()>> {
+        let mut yielded = self.yielded.lock();
+        if !self.has_precedence(priority) {
+            return None;
+        }
+        let (tx, rx) = futures::channel::oneshot::channel();
+        yielded.push(tx);
+        Some(rx)
+    }
+
+    /// Starts a process' time slice.
+    pub fn begin_slice(&self, context: &mut process::ExecutionContext) {
+        context.slice_start = time::Instant::now();
+        self.running
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Ends a process' time slice, adding the reductions and time it used to the VM totals.
+    pub fn end_slice(&self, context: &mut process::ExecutionContext) {
+        use std::sync::atomic::Ordering;
+        let used = context.end_slice();
+        self.reductions.fetch_add(used, Ordering::Relaxed);
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+        self.running.fetch_sub(1, Ordering::Relaxed);
+
+        let micros = context.slice_start.elapsed().as_micros() as usize;
+        self.run_time.fetch_add(micros, Ordering::Relaxed);
+        if let Some(scheduler) = Scheduler::current() {
+            scheduler.active.fetch_add(micros, Ordering::Relaxed);
+        }
+    }
+
+    /// Registers the calling thread as a new process scheduler.
+    fn start_scheduler(&self) {
+        let mut schedulers = self.schedulers.lock();
+        let scheduler = Arc::new(Scheduler {
+            id: schedulers.len() + 1,
+            active: AtomicUsize::new(0),
+        });
+        schedulers.push(scheduler.clone());
+        SCHEDULER.with(|cell| *cell.borrow_mut() = Some(scheduler));
+    }
+
+    /// Turns scheduler_wall_time accounting on or off, returning the previous state. Enabling it
+    /// restarts the measurements.
+    pub fn set_scheduler_wall_time(&self, enable: bool) -> bool {
+        let mut start = self.scheduler_wall_time.lock();
+        let old = start.is_some();
+        if enable && !old {
+            for scheduler in self.schedulers.lock().iter() {
+                scheduler
+                    .active
+                    .store(0, std::sync::atomic::Ordering::Relaxed);
+            }
+            *start = Some(time::Instant::now());
+        } else if !enable {
+            *start = None;
+        }
+        old
+    }
+
+    /// Runs a dirty BIF on the matching dirty scheduler pool. The calling process stays
+    /// suspended until the result comes back, so the BIF is free to use its heap.
+    pub async fn call_dirty(
+        &self,
+        dirty: bif::Dirty,
+        process: &RcProcess,
+        bif: bif::Fn,
+        args: Vec<Term>,
+    ) -> bif::Result {
+        use std::sync::atomic::Ordering;
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let caller = process;
+        let process = process.clone();
+
+        // the process isn't runnable while it waits for the result
+        let priority = caller.local_data().priority();
+        self.dequeue(priority);
+
+        let cpu = dirty == bif::Dirty::Cpu;
+        if cpu {
+            self.dirty_cpu_tasks.fetch_add(1, Ordering::Relaxed);
+        }
+        let future = async move {
+            let vm = Machine::current();
+            if cpu {
+                vm.dirty_cpu_running.fetch_add(1, Ordering::Relaxed);
+            }
+            // a panicking BIF fails the call instead of taking the pool thread down with it
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| bif(&vm, &process, &args)))
+                .unwrap_or_else(|_| Err(Exception::new(Reason::EXC_INTERNAL_ERROR)));
+            if cpu {
+                vm.dirty_cpu_running.fetch_sub(1, Ordering::Relaxed);
+                vm.dirty_cpu_tasks.fetch_sub(1, Ordering::Relaxed);
+            }
+            // done with the process, it's parked until it picks up the result
+            process.park();
+            let _ = tx.send(res);
+        };
+
+        let pool = match dirty {
+            bif::Dirty::Cpu => &self.dirty_cpu_pool,
+            bif::Dirty::Io => &self.dirty_io_pool,
+        };
+        pool.spawn(future.unit_error().boxed().compat());
+
+        // the result only goes missing if the pool dropped the task
+        let res = rx.await;
+        caller.unpark();
+        self.enqueue(priority);
+        res.unwrap_or_else(|_| Err(Exception::new(Reason::EXC_INTERNAL_ERROR)))
+    }
+
+    /// Iterates over the live processes. The PIDs are collected up front so that the process
+    /// table isn't locked while iterating: processes spawned in the meantime are left out, and
+    /// the ones that exit in the meantime are skipped.
+    pub fn processes(&self) -> impl Iterator<Item = RcProcess> + '_ {
+        let pids = self.process_table.lock().pids();
+        pids.into_iter()
+            .filter_map(move |pid| self.process_table.lock().get(pid))
+    }
+
+    /// Returns true if there are runnable processes with a priority above the given one.
+    pub fn has_precedence(&self, priority: process::StateFlag) -> bool {
+        self.run_queues[priority.bits() as usize..]
+            .iter()
+            .any(|queue| queue.load(std::sync::atomic::Ordering::Relaxed) > 0)
+    }
 }
 
 thread_local!(
     static CURRENT: RefCell<Option<Arc<Machine>>> = RefCell::new(None);
+    static SCHEDULER: RefCell<Option<Arc<Scheduler>>> = RefCell::new(None);
 );
 
+/// Counters kept by each process scheduler.
+pub struct Scheduler {
+    /// Scheduler identifier, starting at 1.
+    pub id: usize,
+    /// Time spent executing processes since scheduler_wall_time got enabled, in microseconds.
+    pub active: AtomicUsize,
+}
+
+impl Scheduler {
+    /// The scheduler running on this thread, if any.
+    pub fn current() -> Option<Arc<Scheduler>> {
+        SCHEDULER.with(|cell| cell.borrow().clone())
+    }
+}
+
 impl Machine {
     /// Get current running machine.
     pub fn current() -> Arc<Machine> {
@@ -256,10 +484,23 @@ macro_rules! op_call_ext {
 
         // println!("pid={} action=call_ext mfa={}", $process.pid, mfa);
 
-        let export = { $vm.exports.read().lookup(mfa) }; // drop the exports lock
+        let export = match unsafe { &(*$context.ip.module).resolved_imports[*$dest as usize] } {
+            module::Import::Bif(fun, dirty) => Some(Export::bif(*fun, *dirty)),
+            module::Import::Export(entry) => *entry.read(), // drop the entry lock
+            module::Import::Unresolved => None,
+        };
+
+        // only look up call patterns if the process is being traced
+        let return_trace = $process.local_data().trace.contains(trace::Flag::CALL)
+            && trace::call($vm, $process, mfa, &$context.x[0..*$arity as usize], false);
 
         match export {
-            Some(Export::Fun(ptr)) => op_jump_ptr!($context, ptr),
+            Some(Export::Fun(ptr)) => {
+                if return_trace {
+                    trace::push_return($context, *mfa);
+                }
+                op_jump_ptr!($context, ptr)
+            }
             Some(Export::Bif(APPLY_2)) => {
                 // I'm cheating here, *shrug*
                 op_apply_fun!($vm, $context, $process)
@@ -269,24 +510,29 @@ macro_rules! op_call_ext {
                 op_apply!($vm, $context, $process, $return);
             }
             Some(Export::Bif(bif)) => {
-                // TODO: precompute which exports are bifs
-                // also precompute the bif lookup
                 // call_ext_only Ar=u Bif=u$is_bif => \
                 // allocate u Ar | call_bif Bif | deallocate_return u
 
                 // make a slice out of arity x registers
                 let args = &$context.x[0..*$arity as usize];
-                match bif($vm, $process, args) {
-                    // TODO: should use call_bif?
-                    Ok(val) => {
-                        set_register!($context, &LValue::X(0), val); // HAXX
-                        if $return {
-                            // TODO: figure out returns
-                            op_return!($process, $context);
-                        }
+                let res = bif($vm, $process, args);
+                if return_trace {
+                    if let Ok(val) = &res {
+                        trace::return_value($vm, $process, mfa, *val);
                     }
-                    Err(exc) => return Err(exc),
                 }
+                // TODO: should use call_bif?
+                op_bif_return!($vm, $context, $process, res, $return)
+            }
+            Some(Export::DirtyBif(bif, dirty)) => {
+                let args = $context.x[0..*$arity as usize].to_vec();
+                let res = $vm.call_dirty(dirty, $process, bif, args).await;
+                if return_trace {
+                    if let Ok(val) = &res {
+                        trace::return_value($vm, $process, mfa, *val);
+                    }
+                }
+                op_bif_return!($vm, $context, $process, res, $return)
             }
             None => {
                 // call error_handler here
@@ -296,6 +542,24 @@ macro_rules! op_call_ext {
     }};
 }
 
+/// Emits the call trace for a local call to the function at the label, if it has a pattern set
+/// with the local flag. Must run once cp and the stack are set up for the callee.
+macro_rules! op_trace_local_call {
+    ($vm:expr, $context:expr, $process:expr, $label:expr) => {{
+        if $process.local_data().trace.contains(trace::Flag::CALL) {
+            let current = $context.ip.get_module();
+            let func = current.funs.iter().find(|(_, offset)| **offset == $label);
+            if let Some((&(func, arity), _)) = func {
+                let mfa = module::MFA(current.name, func, arity);
+                let args = &$context.x[0..arity as usize];
+                if trace::call($vm, $process, &mfa, args, true) {
+                    trace::push_return($context, mfa);
+                }
+            }
+        }
+    }};
+}
+
 macro_rules! op_call_bif {
     ($vm:expr, $context:expr, $process:expr, $bif:expr, $arity:expr, $return:expr) => {{
         // precompute export lookup. once Pin<> is a thing we can be sure that
@@ -305,12 +569,39 @@ macro_rules! op_call_bif {
 
         // make a slice out of arity x registers
         let args = &$context.x[0..$arity];
-        match $bif($vm, $process, args) {
+        let res = $bif($vm, $process, args);
+        op_bif_return!($vm, $context, $process, res, $return)
+    }};
+}
+
+/// Same as op_call_bif, but the BIF runs on a dirty scheduler while the process is suspended.
+macro_rules! op_call_dirty_bif {
+    ($vm:expr, $context:expr, $process:expr, $bif:expr, $dirty:expr, $arity:expr, $return:expr) => {{
+        let args = $context.x[0..$arity].to_vec();
+        let res = $vm.call_dirty($dirty, $process, $bif, args).await;
+        op_bif_return!($vm, $context, $process, res, $return)
+    }};
+}
+
+/// Calls a BIF resolved by an import, dirty BIFs get handed off to their pool just like in
+/// `op_call_dirty_bif!`.
+macro_rules! op_bif {
+    ($vm:expr, $process:expr, $bif:expr, $args:expr) => {{
+        match $bif {
+            (bif, Some(dirty)) => $vm.call_dirty(dirty, $process, bif, $args.to_vec()).await,
+            (bif, None) => bif($vm, $process, $args),
+        }
+    }};
+}
+
+macro_rules! op_bif_return {
+    ($vm:expr, $context:expr, $process:expr, $res:expr, $return:expr) => {{
+        match $res {
             Ok(val) => {
                 set_register!($context, &LValue::X(0), val); // HAXX
                 if $return {
                     // TODO: figure out returns
-                    op_return!($process, $context);
+                    op_return!($vm, $process, $context);
                 }
             }
             Err(exc) => return Err(exc),
@@ -318,7 +609,7 @@ macro_rules! op_call_bif {
     }};
 }
 macro_rules! op_call_fun {
-    ($vm:expr, $context:expr, $closure:expr, $arity:expr) => {{
+    ($vm:expr, $context:expr, $fun:expr, $closure:expr, $arity:expr) => {{
         // keep X regs set based on arity
         // set additional X regs based on lambda.binding
         // set x from 1 + arity (x0 is func, followed by call params) onwards to binding
@@ -328,15 +619,15 @@ macro_rules! op_call_fun {
             $context.x[arity..arity + binding.len()].copy_from_slice(&binding[..]);
         }
 
-        // TODO: closure needs to jump_ptr to the correct module.
+        // funs run the version of the module that created them, as long as it's loaded
         let ptr = {
-            // temporary HAXX
             let registry = $vm.modules.lock();
-            // let module = module::load_module(&self.modules, path).unwrap();
-            let module = registry.lookup(closure.mfa.0).unwrap();
+            if !registry.is_loaded(closure.mfa.0, closure.module) {
+                return Err(Exception::with_value(Reason::EXC_BADFUN, $fun));
+            }
 
             InstrPtr {
-                module,
+                module: closure.module,
                 ptr: closure.ptr,
             }
         };
@@ -402,12 +693,36 @@ macro_rules! op_apply {
 
         let export = { $vm.exports.read().lookup(&mfa) }; // drop the exports lock
 
+        let return_trace = $process.local_data().trace.contains(trace::Flag::CALL)
+            && trace::call($vm, $process, &mfa, &$context.x[0..arity], false);
+
         match export {
-            Some(Export::Fun(ptr)) => op_jump_ptr!($context, ptr),
+            Some(Export::Fun(ptr)) => {
+                if return_trace {
+                    trace::push_return($context, mfa);
+                }
+                op_jump_ptr!($context, ptr)
+            }
             Some(Export::Bif(bif)) => {
                 // TODO: apply_bif_error_adjustment(p, ep, reg, arity, I, stack_offset);
                 // ^ only happens in apply/fixed_apply
-                op_call_bif!($vm, $context, $process, bif, arity, $return)
+                let res = bif($vm, $process, &$context.x[0..arity]);
+                if return_trace {
+                    if let Ok(val) = &res {
+                        trace::return_value($vm, $process, &mfa, *val);
+                    }
+                }
+                op_bif_return!($vm, $context, $process, res, $return)
+            }
+            Some(Export::DirtyBif(bif, dirty)) => {
+                let args = $context.x[0..arity].to_vec();
+                let res = $vm.call_dirty(dirty, $process, bif, args).await;
+                if return_trace {
+                    if let Ok(val) = &res {
+                        trace::return_value($vm, $process, &mfa, *val);
+                    }
+                }
+                op_bif_return!($vm, $context, $process, res, $return)
             }
             None => {
                 // println!("apply setup_error_handler pid={}", $process.pid);
@@ -453,7 +768,7 @@ macro_rules! op_apply_fun {
 
         // TODO: this part matches CallFun, extract
         if let Ok(closure) = value::Closure::try_from(&fun) {
-            op_call_fun!($vm, $context, closure, arity);
+            op_call_fun!($vm, $context, fun, closure, arity);
         } else if let Ok(mfa) = module::MFA::try_from(&fun) {
             // TODO: deduplicate this part
             let export = { $vm.exports.read().lookup(&mfa) }; // drop the exports lock
@@ -463,6 +778,9 @@ macro_rules! op_apply_fun {
                 Some(Export::Bif(bif)) => {
                     op_call_bif!($vm, $context, &$process, bif, mfa.2 as usize, true) // TODO is return true ok
                 }
+                Some(Export::DirtyBif(bif, dirty)) => {
+                    op_call_dirty_bif!($vm, $context, &$process, bif, dirty, mfa.2 as usize, true)
+                }
                 None => {
                     // println!("apply setup_error_handler");
                     call_error_handler!($vm, &$process, &mfa);
@@ -477,7 +795,11 @@ macro_rules! op_apply_fun {
 }
 
 macro_rules! op_return {
-    ($process:expr, $context:expr) => {{
+    ($vm:expr, $process:expr, $context:expr) => {{
+        if !$context.return_trace.is_empty() {
+            trace::return_from($vm, $process);
+        }
+
         if let Some(i) = $context.cp {
             op_jump_ptr!($context, i);
             $context.cp = None;
@@ -539,13 +861,36 @@ macro_rules! op_fixed_apply {
 
         let export = { $vm.exports.read().lookup(&mfa) }; // drop the exports lock
 
+        let return_trace = $process.local_data().trace.contains(trace::Flag::CALL)
+            && trace::call($vm, $process, &mfa, &$context.x[0..arity], false);
+
         match export {
-            Some(Export::Fun(ptr)) => op_jump_ptr!($context, ptr),
+            Some(Export::Fun(ptr)) => {
+                if return_trace {
+                    trace::push_return($context, mfa);
+                }
+                op_jump_ptr!($context, ptr)
+            }
             Some(Export::Bif(bif)) => {
                 // TODO: apply_bif_error_adjustment(p, ep, reg, arity, I, stack_offset);
                 // ^ only happens in apply/fixed_apply
-
-                op_call_bif!($vm, $context, $process, bif, arity, $return)
+                let res = bif($vm, $process, &$context.x[0..arity]);
+                if return_trace {
+                    if let Ok(val) = &res {
+                        trace::return_value($vm, $process, &mfa, *val);
+                    }
+                }
+                op_bif_return!($vm, $context, $process, res, $return)
+            }
+            Some(Export::DirtyBif(bif, dirty)) => {
+                let args = $context.x[0..arity].to_vec();
+                let res = $vm.call_dirty(dirty, $process, bif, args).await;
+                if return_trace {
+                    if let Ok(val) = &res {
+                        trace::return_value($vm, $process, &mfa, *val);
+                    }
+                }
+                op_bif_return!($vm, $context, $process, res, $return)
             }
             None => {
                 // println!("fixed_apply setup_error_handler pid={}", $process.pid);
@@ -571,7 +916,6 @@ macro_rules! op_is_type {
         let val = $context.expand_arg(&$args[1]);
 
         if !val.$op() {
-            // TODO: patch the labels to point to exact offsets to avoid labels lookup
             let fail = $args[0].to_u32();
 
             op_jump!($context, fail);
@@ -617,6 +961,16 @@ macro_rules! safepoint_and_reduce {
     }};
 }
 
+/// Garbage collects the process if needed. Only valid on instructions that tell us how many X
+/// registers are live.
+macro_rules! gc_safepoint {
+    ($vm:expr, $process:expr, $live:expr) => {{
+        if $process.needs_gc() {
+            $process.garbage_collect($vm, $live as usize, false)?;
+        }
+    }};
+}
+
 pub const PRE_LOADED: &[&str] = &[
     "otp/erts/preloaded/ebin/erts_code_purger.beam",
     "otp/erts/preloaded/ebin/erl_init.beam",
@@ -638,6 +992,9 @@ pub const PRE_LOADED: &[&str] = &[
     "otp/erts/preloaded/ebin/persistent_term.beam",
 ];
 
+/// Same as OTP's default amount of dirty I/O schedulers.
+const DIRTY_IO_SCHEDULERS: usize = 10;
+
 impl Machine {
     pub fn new() -> Arc<Machine> {
         let vm = Arc::new(Machine {
@@ -647,13 +1004,40 @@ impl Machine {
             start_time: time::Instant::now(),
             process_pool: unsafe { std::mem::uninitialized() }, // I'm sorry, but we need a ref to vm in threadpool
             runtime: unsafe { std::mem::uninitialized() }, // I'm sorry, but we need a ref to vm in threadpool
+            dirty_cpu_pool: unsafe { std::mem::uninitialized() },
+            dirty_io_pool: unsafe { std::mem::uninitialized() },
             exit: None,
             next_ref: AtomicUsize::new(1),
+            reductions: AtomicUsize::new(0),
+            last_reductions: AtomicUsize::new(0),
+            last_exact_reductions: AtomicUsize::new(0),
+            run_time: AtomicUsize::new(0),
+            last_run_time: AtomicUsize::new(0),
+            last_wall_clock: AtomicUsize::new(0),
+            context_switches: AtomicUsize::new(0),
+            garbage_collections: AtomicUsize::new(0),
+            words_reclaimed: AtomicUsize::new(0),
+            io_input: AtomicUsize::new(0),
+            io_output: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            dirty_cpu_tasks: AtomicUsize::new(0),
+            dirty_cpu_running: AtomicUsize::new(0),
+            schedulers: Mutex::new(Vec::new()),
+            scheduler_wall_time: Mutex::new(None),
+            run_queues: [
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+            ],
+            yielded: Mutex::new(Vec::new()),
             system_logger: AtomicUsize::new(0),
             exports: ExportsTable::with_rc(),
             modules: ModuleRegistry::with_rc(),
             ets_tables: TableRegistry::with_rc(),
             persistent_terms: PersistentTermTable::new(),
+            timers: TimerTable::new(),
+            aliases: Mutex::new(HashMap::new()),
         });
 
         // initialize tokio here
@@ -675,13 +1059,34 @@ impl Machine {
             // .panic_handler(|err| std::panic::resume_unwind(err))
             .after_start(move || {
                 Machine::set_current(machine.clone());
+                machine.start_scheduler();
             })
             .build()
             .expect("failed to start new Runtime");
 
+        // dirty cpu schedulers default to one per core
+        let machine = vm.clone();
+        let dirty_cpu_pool = tokio_threadpool::Builder::new()
+            .name_prefix("dirty-cpu-")
+            .after_start(move || {
+                Machine::set_current(machine.clone());
+            })
+            .build();
+
+        let machine = vm.clone();
+        let dirty_io_pool = tokio_threadpool::Builder::new()
+            .name_prefix("dirty-io-")
+            .pool_size(DIRTY_IO_SCHEDULERS)
+            .after_start(move || {
+                Machine::set_current(machine.clone());
+            })
+            .build();
+
         unsafe {
             std::ptr::write(&vm.runtime as *const tokio::runtime::Runtime as *mut tokio::runtime::Runtime, runtime);
             std::ptr::write(&vm.process_pool as *const tokio::runtime::Runtime as *mut tokio::runtime::Runtime, process_pool);
+            std::ptr::write(&vm.dirty_cpu_pool as *const tokio_threadpool::ThreadPool as *mut tokio_threadpool::ThreadPool, dirty_cpu_pool);
+            std::ptr::write(&vm.dirty_io_pool as *const tokio_threadpool::ThreadPool as *mut tokio_threadpool::ThreadPool, dirty_io_pool);
         }
 
         vm
@@ -755,8 +1160,14 @@ impl Machine {
         // safe, so take care when capturing new variables.
         //let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
         let vm = Machine::current();
+        process.unpark();
+        vm.enqueue(process.local_data().priority());
         loop {
-            match vm.run(&mut process).await {
+            vm.begin_slice(process.context_mut());
+            let res = vm.run(&mut process).await;
+            vm.end_slice(process.context_mut());
+
+            match res {
                 Err(message) => {
                     if message.reason != Reason::TRAP {
                         // just a regular error
@@ -766,18 +1177,27 @@ impl Machine {
                             context.ip = new_pc;
                             // yield
                         } else {
+                            vm.dequeue(process.local_data().priority());
                             process.exit(&vm, message);
                             // println!("pid={} action=exited", process.pid);
                             break // crashed
                         }
+                    } else if process.local_data().flags.contains(process::Flag::HIBERNATE) {
+                        // ip already points at the function to continue with
+                        if let Err(reason) = hibernate(&vm, &process).await {
+                            process.set_waiting_for_message(false);
+                            process.exit(&vm, reason);
+                            break // killed while parked
+                        }
                     } else {
                         // we're trapping, ip was already set, now reschedule the process
                         eprintln!("TRAP!");
                         // yield
                     }
                 }
-                Ok(process::State::Yield) => (), // yield
+                Ok(process::State::Yield) => yield_to_scheduler(&vm, &process).await,
                 Ok(process::State::Done) => {
+                    vm.dequeue(process.local_data().priority());
                     process.exit(&vm, Exception::with_value(Reason::EXC_EXIT, atom!(NORMAL)));
                     break
                 }, // exited OK
@@ -804,6 +1224,90 @@ impl Machine {
         }*/
     }
 
+    /// A future that returns control to the executor once, letting other processes run.
+    struct YieldNow(bool);
+
+    impl std::future::Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context) -> std::task::Poll<()> {
+            if self.0 {
+                return std::task::Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    }
+
+    /// How many times a low priority process gives way to normal ones before it runs anyway.
+    const LOW_PRIORITY_DEFER: usize = 8;
+
+    /// Reschedules a process that exhausted its reductions. As long as processes of a higher
+    /// priority are runnable, the process sleeps until their run queues empty out. Low priority
+    /// processes only defer to normal ones a limited amount of turns so they don't get starved.
+    pub(crate) async fn yield_to_scheduler(vm: &Machine, process: &RcProcess) {
+        use process::StateFlag;
+        let priority = process.local_data().priority();
+        // normal processes don't make low ones sleep, only take turns before them
+        let above = match priority {
+            StateFlag::PRQ_LOW => StateFlag::PRQ_MEDIUM,
+            _ => priority,
+        };
+
+        process.park();
+        YieldNow(false).await;
+        while let Some(runnable) = vm.give_way(above) {
+            vm.dequeue(priority);
+            let _ = runnable.await;
+            vm.enqueue(priority);
+        }
+        if priority == StateFlag::PRQ_LOW {
+            for _ in 1..LOW_PRIORITY_DEFER {
+                if !vm.has_precedence(priority) {
+                    break;
+                }
+                YieldNow(false).await;
+            }
+        }
+        process.unpark();
+    }
+
+    /// Parks a process that called erlang:hibernate/3 until a message arrives for it. Other
+    /// signals get handled along the way without waking it up.
+    pub(crate) async fn hibernate(vm: &Machine, process: &RcProcess) -> Result<(), Exception> {
+        process.local_data_mut().flags.remove(process::Flag::HIBERNATE);
+        let priority = process.local_data().priority();
+        vm.dequeue(priority);
+
+        loop {
+            // The channel left over from the last receive might already be spent, so install a
+            // fresh one before looking at the queue: anything sent after that point wakes us up.
+            let (trigger, cancel) = futures::channel::oneshot::channel::<()>();
+            let context = process.context_mut();
+            context.timeout = Some(trigger);
+            context.recv_channel = None;
+            process.set_waiting_for_message(true);
+
+            process.process_incoming()?;
+            if process.needs_gc() {
+                // only the arguments of the function we continue with are live
+                let live = context.ip.lookup_func_info().map_or(0, |(mfa, _)| mfa.2);
+                process.garbage_collect(vm, live as usize, false)?;
+            }
+            if process.local_data().mailbox.has_messages() {
+                break;
+            }
+            process.park();
+            let _ = cancel.await;
+            process.unpark();
+        }
+
+        process.set_waiting_for_message(false);
+        vm.enqueue(priority);
+        Ok(())
+    }
+
     pub trait Captures<'a> {}
 
     impl<'a, T> Captures<'a> for T {}
@@ -816,7 +1320,6 @@ impl Machine {
     ) -> impl std::future::Future<Output = Result<process::State, Exception>> + Captures<'a> + Captures<'b> + 'c {
         async move {  // workaround for https://github.com/rust-lang/rust/issues/56238
         let context = process.context_mut();
-        context.reds = 1000; // self.config.reductions;
 
         // process the incoming signal queue
         process.process_incoming()?;
@@ -852,12 +1355,39 @@ impl Machine {
                     return Err(Exception::new(Reason::EXC_FUNCTION_CLAUSE));
                 }
                 Opcode::Return => {
-                    op_return!(process, context);
+                    op_return!(self, &process, context);
+                }
+                Opcode::MoveReturn => {
+                    debug_assert_eq!(ins.args.len(), 1);
+                    context.x[0] = context.expand_arg(&ins.args[0]);
+                    op_return!(self, &process, context);
+                }
+                Opcode::DeallocateReturn => {
+                    // literal nwords
+                    if let [LValue::Literal(nwords)] = &ins.args[..] {
+                        op_deallocate!(context, *nwords)
+                    } else {
+                        unreachable!()
+                    }
+                    op_return!(self, &process, context);
+                }
+                Opcode::MoveDeallocateReturn => {
+                    // src, literal nwords
+                    if let [src, LValue::Literal(nwords)] = &ins.args[..] {
+                        context.x[0] = context.expand_arg(src);
+                        op_deallocate!(context, *nwords)
+                    } else {
+                        unreachable!()
+                    }
+                    op_return!(self, &process, context);
                 }
                 Opcode::Send => {
                     // send x1 to x0, write result to x0
                     let pid = context.x[0];
                     let msg = context.x[1];
+                    if process.local_data().trace.contains(trace::Flag::SEND) {
+                        trace::send(self, &process, pid, msg);
+                    }
                     let res = match pid.into_variant() {
                         Variant::Port(id) => port::send_message(self, process.pid, id, msg),
                         _ => process::send_message(self, process.pid, pid, msg),
@@ -866,7 +1396,9 @@ impl Machine {
                 }
                 Opcode::RemoveMessage => {
                     // Unlink the current message from the message queue. Remove any timeout.
-                    process.local_data_mut().mailbox.remove();
+                    if let Some(heap) = process.local_data_mut().mailbox.remove() {
+                        context.heap.absorb(heap);
+                    }
                     // clear timeout
                     context.timeout.take();
                     // reset savepoint of the mailbox
@@ -904,6 +1436,10 @@ impl Machine {
                     // jump to label, set wait flag on process
                     debug_assert_eq!(ins.args.len(), 1);
 
+                    // nothing is live while we wait, so gc requests from others get answered
+                    // here (we come back through after handling new signals)
+                    gc_safepoint!(self, process, 0);
+
                     let label = ins.args[0].to_u32();
                     op_jump!(context, label);
 
@@ -911,11 +1447,19 @@ impl Machine {
                     // a message while we're in the process of suspending.
 
                     // set wait flag
-                    // process.set_waiting_for_message(true);
+                    process.set_waiting_for_message(true);
 
                     // LOCK mailbox on looprec, unlock on wait/waittimeout
                     let cancel = process.context_mut().recv_channel.take().unwrap();
+                    let priority = process.local_data().priority();
+                    self.end_slice(context);
+                    self.dequeue(priority);
+                    process.park();
                     cancel.await; // suspend process
+                    process.unpark();
+                    process.set_waiting_for_message(false);
+                    self.enqueue(priority);
+                    self.begin_slice(context);
 
                     // println!("pid={} resumption ", process.pid);
                     process.process_incoming()?;
@@ -933,6 +1477,7 @@ impl Machine {
                     }
 
                     let cancel = process.context_mut().recv_channel.take().unwrap();
+                    let priority = process.local_data().priority();
 
                     match context.expand_arg(&ins.args[1]).into_variant() {
                         Variant::Atom(atom::INFINITY) => {
@@ -941,14 +1486,32 @@ impl Machine {
                             let label = ins.args[0].to_u32();
                             op_jump!(context, label);
 
+                            self.end_slice(context);
+                            self.dequeue(priority);
+                            process.set_waiting_for_message(true);
+                            process.park();
                             cancel.await; // suspend process
+                            process.unpark();
+                            process.set_waiting_for_message(false);
+                            self.enqueue(priority);
+                            self.begin_slice(context);
                             // println!("select! resumption pid={}", process.pid);
                         },
                         Variant::Integer(ms) => {
                             let when = time::Duration::from_millis(ms as u64);
                             use tokio::prelude::FutureExt;
 
-                            match cancel.into_future().boxed().compat().timeout(when).compat().await {
+                            self.end_slice(context);
+                            self.dequeue(priority);
+                            process.set_waiting_for_message(true);
+                            process.park();
+                            let res = cancel.into_future().boxed().compat().timeout(when).compat().await;
+                            process.unpark();
+                            process.set_waiting_for_message(false);
+                            self.enqueue(priority);
+                            self.begin_slice(context);
+
+                            match res {
                                 Ok(()) =>  {
                                     // jump to success (start of recv loop)
                                     let label = ins.args[0].to_u32();
@@ -1003,6 +1566,7 @@ impl Machine {
                     // store arity as live
                     if let [LValue::Literal(_a), LValue::Label(i)] = &ins.args[..] {
                         context.cp = Some(context.ip);
+                        op_trace_local_call!(self, context, &process, *i);
                         op_jump!(context, *i);
 
                         // let (mfa, _) = context.ip.lookup_func_info().unwrap();
@@ -1019,6 +1583,7 @@ impl Machine {
                         &ins.args[..]
                     {
                         op_deallocate!(context, *nwords);
+                        op_trace_local_call!(self, context, &process, *i);
 
                         op_jump!(context, *i);
 
@@ -1034,6 +1599,7 @@ impl Machine {
                     // store arity as live
                     //
                     if let [LValue::Literal(_a), LValue::Label(l)] = &ins.args[..] {
+                        op_trace_local_call!(self, context, &process, *l);
                         op_jump!(context, *l);
 
                         // let (mfa, _) = context.ip.lookup_func_info().unwrap();
@@ -1043,6 +1609,46 @@ impl Machine {
                     }
                     safepoint_and_reduce!(self, process, context.reds);
                 }
+                Opcode::MoveCall => {
+                    // src, dest, literal arity, label jmp
+                    if let [src, dest, LValue::Literal(_a), LValue::Label(i)] = &ins.args[..] {
+                        let val = context.expand_arg(src);
+                        set_register!(context, dest, val);
+                        context.cp = Some(context.ip);
+                        op_trace_local_call!(self, context, &process, *i);
+                        op_jump!(context, *i);
+                    } else {
+                        unreachable!()
+                    }
+                    safepoint_and_reduce!(self, process, context.reds);
+                }
+                Opcode::MoveCallLast => {
+                    // src, dest, literal arity, label jmp, nwords
+                    if let [src, dest, LValue::Literal(_a), LValue::Label(i), LValue::Literal(nwords)] =
+                        &ins.args[..]
+                    {
+                        let val = context.expand_arg(src);
+                        set_register!(context, dest, val);
+                        op_deallocate!(context, *nwords);
+                        op_trace_local_call!(self, context, &process, *i);
+                        op_jump!(context, *i);
+                    } else {
+                        unreachable!()
+                    }
+                    safepoint_and_reduce!(self, process, context.reds);
+                }
+                Opcode::MoveCallOnly => {
+                    // src, dest, literal arity, label jmp
+                    if let [src, dest, LValue::Literal(_a), LValue::Label(i)] = &ins.args[..] {
+                        let val = context.expand_arg(src);
+                        set_register!(context, dest, val);
+                        op_trace_local_call!(self, context, &process, *i);
+                        op_jump!(context, *i);
+                    } else {
+                        unreachable!()
+                    }
+                    safepoint_and_reduce!(self, process, context.reds);
+                }
                 Opcode::CallExt => {
                     //literal arity, literal destination (module.imports index)
                     if let [LValue::Literal(arity), LValue::Literal(dest)] = &ins.args[..] {
@@ -1080,9 +1686,9 @@ impl Machine {
                 Opcode::Bif0 => {
                     // literal import, x reg
                     if let [LValue::Literal(dest), reg] = &ins.args[..] {
-                        // TODO: precompute these lookups
-                        let mfa = &module.imports[*dest as usize];
-                        let val = bif::apply(self, &process, mfa, &[]).unwrap(); // bif0 can't fail
+                        let bif = module.bif(*dest)?;
+                        context.bump_reductions(1);
+                        let val = op_bif!(self, &process, bif, &[]).unwrap(); // bif0 can't fail
                         set_register!(context, reg, val);
                     } else {
                         unreachable!()
@@ -1092,9 +1698,9 @@ impl Machine {
                     // literal import, arg, x reg
                     if let [fail, LValue::Literal(dest), arg1, reg] = &ins.args[..] {
                         let args = &[context.expand_arg(arg1)];
-                        // TODO: precompute these lookups
-                        let mfa = &module.imports[*dest as usize];
-                        match bif::apply(self, &process, mfa, args) {
+                        let bif = module.bif(*dest)?;
+                        context.bump_reductions(1);
+                        match op_bif!(self, &process, bif, args) {
                             Ok(val) => set_register!(context, reg, val),
                             Err(exc) => cond_fail!(context, fail, exc),
                         }
@@ -1106,9 +1712,9 @@ impl Machine {
                     // literal import, arg, arg, x reg
                     if let [fail, LValue::Literal(dest), arg1, arg2, reg] = &ins.args[..] {
                         let args = &[context.expand_arg(arg1), context.expand_arg(arg2)];
-                        // TODO: precompute these lookups
-                        let mfa = &module.imports[*dest as usize];
-                        match bif::apply(self, &process, mfa, args) {
+                        let bif = module.bif(*dest)?;
+                        context.bump_reductions(1);
+                        match op_bif!(self, &process, bif, args) {
                             Ok(val) => set_register!(context, reg, val),
                             Err(exc) => cond_fail!(context, fail, exc),
                         }
@@ -1118,7 +1724,8 @@ impl Machine {
                 }
                 Opcode::Allocate => {
                     // stackneed, live
-                    if let [LValue::Literal(stackneed), LValue::Literal(_live)] = &ins.args[..] {
+                    if let [LValue::Literal(stackneed), LValue::Literal(live)] = &ins.args[..] {
+                        gc_safepoint!(self, process, *live);
                         for _ in 0..*stackneed {
                             context.stack.push(Term::nil())
                         }
@@ -1135,13 +1742,13 @@ impl Machine {
                     // literal stackneed, literal heapneed, literal live
                     // allocate stackneed space on stack, ensure heapneed on heap, if gc, keep live
                     // num of X regs. save cp on stack.
-                    if let [LValue::Literal(stackneed), LValue::Literal(_heapneed), LValue::Literal(_live)] =
+                    if let [LValue::Literal(stackneed), LValue::Literal(_heapneed), LValue::Literal(live)] =
                         &ins.args[..]
                     {
+                        gc_safepoint!(self, process, *live);
                         context
                             .stack
                             .resize(context.stack.len() + *stackneed as usize, Term::nil());
-                        // TODO: check heap for heapneed space!
                         context.stack.push(Term::cp(&context.heap, context.cp));
                         context.cp = None;
                     } else {
@@ -1150,7 +1757,8 @@ impl Machine {
                 }
                 Opcode::AllocateZero => {
                     // literal stackneed, literal live
-                    if let [LValue::Literal(need), LValue::Literal(_live)] = &ins.args[..] {
+                    if let [LValue::Literal(need), LValue::Literal(live)] = &ins.args[..] {
+                        gc_safepoint!(self, process, *live);
                         context
                             .stack
                             .resize(context.stack.len() + *need as usize, Term::nil());
@@ -1164,13 +1772,13 @@ impl Machine {
                     // literal stackneed, literal heapneed, literal live
                     // allocate stackneed space on stack, ensure heapneed on heap, if gc, keep live
                     // num of X regs. save cp on stack.
-                    if let [LValue::Literal(stackneed), LValue::Literal(_heapneed), LValue::Literal(_live)] =
+                    if let [LValue::Literal(stackneed), LValue::Literal(_heapneed), LValue::Literal(live)] =
                         &ins.args[..]
                     {
+                        gc_safepoint!(self, process, *live);
                         context
                             .stack
                             .resize(context.stack.len() + *stackneed as usize, Term::nil());
-                        // TODO: check heap for heapneed space!
                         context.stack.push(Term::cp(&context.heap, context.cp));
                         context.cp = None;
                     } else {
@@ -1178,7 +1786,12 @@ impl Machine {
                     }
                 }
                 Opcode::TestHeap => {
-                    // println!("TODO: TestHeap unimplemented!");
+                    // heapneed, live
+                    if let [_heapneed, LValue::Literal(live)] = &ins.args[..] {
+                        gc_safepoint!(self, process, *live);
+                    } else {
+                        unreachable!()
+                    }
                 }
                 Opcode::Init => {
                     debug_assert_eq!(ins.args.len(), 1);
@@ -1198,7 +1811,7 @@ impl Machine {
                     let v1 = context.expand_arg(&ins.args[1]);
                     let v2 = context.expand_arg(&ins.args[2]);
 
-                    if let Some(std::cmp::Ordering::Less) = v1.partial_cmp(&v2) {
+                    if let Some(std::cmp::Ordering::Less) = v1.erl_partial_cmp(&v2) {
                         let fail = ins.args[0].to_u32();
                         op_jump!(context, fail);
                     } else {
@@ -1211,7 +1824,7 @@ impl Machine {
                     let v1 = context.expand_arg(&ins.args[1]);
                     let v2 = context.expand_arg(&ins.args[2]);
 
-                    if let Some(std::cmp::Ordering::Less) = v1.partial_cmp(&v2) {
+                    if let Some(std::cmp::Ordering::Less) = v1.erl_partial_cmp(&v2) {
                         // ok
                     } else {
                         let fail = ins.args[0].to_u32();
@@ -1315,6 +1928,17 @@ impl Machine {
                         unreachable!()
                     }
                 }
+                Opcode::IsTupleOfArity => {
+                    // fail, arg, arity
+                    if let [LValue::Label(fail), arg, LValue::Literal(arity)] = &ins.args[..] {
+                        match Tuple::try_from(&context.expand_arg(arg)) {
+                            Ok(t) if t.len == *arity => (),
+                            _ => op_jump!(context, *fail),
+                        }
+                    } else {
+                        unreachable!()
+                    }
+                }
                 Opcode::SelectVal => {
                     // arg, fail, dests
                     // loop over dests
@@ -1345,6 +1969,30 @@ impl Machine {
                         }
                     }
                 }
+                Opcode::SelectValJump => {
+                    // arg, fail, min, labels
+                    if let [arg, LValue::Label(fail), LValue::Integer(min), LValue::ExtendedList(table)] =
+                        &ins.args[..]
+                    {
+                        let label = match context.expand_arg(arg).into_variant() {
+                            Variant::Integer(i) => {
+                                let offset = i64::from(i) - i64::from(*min);
+                                if offset >= 0 {
+                                    table.get(offset as usize)
+                                } else {
+                                    None
+                                }
+                            }
+                            _ => None,
+                        };
+                        match label {
+                            Some(label) => op_jump!(context, label.to_u32()),
+                            None => op_jump!(context, *fail),
+                        }
+                    } else {
+                        unreachable!()
+                    }
+                }
                 Opcode::SelectTupleArity => {
                     // tuple fail dests
                     if let [arg, LValue::Label(fail), LValue::ExtendedList(vec)] = &ins.args[..] {
@@ -1610,8 +2258,9 @@ impl Machine {
                     if let LValue::Literal(i) = &ins.args[2] {
                         // TODO: GcBif needs to handle GC as necessary
                         let args = &[context.expand_arg(&ins.args[3])];
-                        let mfa = &module.imports[*i as usize];
-                        match bif::apply(self, &process, mfa, args) {
+                        let bif = module.bif(*i)?;
+                        context.bump_reductions(1);
+                        match op_bif!(self, &process, bif, args) {
                             Ok(val) => set_register!(context, &ins.args[4], val),
                             Err(exc) => cond_fail!(context, ins.args[0], exc),
                         }
@@ -1658,21 +2307,23 @@ impl Machine {
                     //   allocate binary + procbin
                     //   set as non writable initially??
 
-                    if let [LValue::Label(_fail), s1, LValue::Literal(_words), LValue::Literal(_live), _flags, dest] =
+                    if let [fail, s1, LValue::Literal(_words), LValue::Literal(_live), _flags, dest] =
                         &ins.args[..]
                     {
-                        // TODO: use a current_string ptr to be able to write to the Arc wrapped str
-                        // alternatively, loop through the instrs until we hit a non bs_ instr.
-                        // that way, no unsafe ptrs!
                         let size = match s1 {
-                            LValue::Literal(i) => *i as u32,
-                            _ => context.expand_arg(s1).to_int().unwrap()
+                            LValue::Literal(i) => Term::uint(&context.heap, *i),
+                            _ => context.expand_arg(s1),
+                        };
+                        let size = match bitstring::segment_size(size, 8) {
+                            Ok(bits) => bits / 8,
+                            Err(exc) => cond_fail!(context, fail, exc),
                         };
-                        let mut binary = bitstring::Binary::with_capacity(size as usize);
+                        let mut binary = bitstring::Binary::with_capacity(size);
                         binary.is_writable = false;
                         let term = Term::binary(&context.heap, binary);
                         // TODO ^ ensure this pointer stays valid after heap alloc
                         context.bs = &**term.get_boxed_value::<bitstring::RcBinary>().unwrap() as *const bitstring::Binary as *mut bitstring::Binary;
+                        context.bs_offset = 0;
                         set_register!(context, dest, term);
                     } else {
                         unreachable!()
@@ -1681,68 +2332,74 @@ impl Machine {
                 Opcode::BsPutInteger => {
                     // gen_put_integer(GenOpArg Fail,GenOpArg Size, GenOpArg Unit, GenOpArg Flags, GenOpArg Src)
                     // [Label(0), Integer(8), Literal(1), Literal(0), Y(0)]'
-                    // Size can be atom all
-                    if let [_fail, LValue::Integer(size), LValue::Literal(unit), _flags, src] = &ins.args[..] {
-                        // TODO: fail label
-                        let size = *size as u32 * *unit;
-
-                        if size != 8 {
-                            unimplemented!("bs_put_integer size * unit != 8");
-                            //fail!(context, fail);
-                        }
-                        println!("src is {}", context.expand_arg(src));
+                    if let [fail, size, LValue::Literal(unit), LValue::Literal(flags), src] = &ins.args[..] {
+                        let bits = match bitstring::segment_size(context.expand_arg(size), *unit) {
+                            Ok(bits) => bits,
+                            Err(exc) => cond_fail!(context, fail, exc),
+                        };
+                        let flags = bitstring::Flag::from_bits_truncate(*flags as u8);
 
-                        unsafe {
-                            (*context.bs).put_integer(size as usize, context.expand_arg(src));
+                        let ok = unsafe {
+                            (*context.bs).put_integer(context.bs_offset, bits, context.expand_arg(src), flags)
+                        };
+                        if !ok {
+                            cond_fail!(context, fail, Exception::new(Reason::EXC_BADARG));
                         }
+                        context.bs_offset += bits;
                     } else {
                         unreachable!()
                     }
                 }
                 Opcode::BsPutBinary => {
-                    if let [_fail, size, LValue::Literal(unit), _flags, src] = &ins.args[..] {
-                        // TODO: fail label
-                        if *unit != 8 {
-                            unimplemented!("bs_put_binary unit != 8");
-                            //fail!(context, fail);
+                    // Size can be atom all
+                    if let [fail, size, LValue::Literal(unit), _flags, src] = &ins.args[..] {
+                        let src = context.expand_arg(src);
+                        let (binary, offset, src_bits) = match bitstring::bits(&src) {
+                            Some(bits) => bits,
+                            None => cond_fail!(context, fail, Exception::new(Reason::EXC_BADARG)),
+                        };
+
+                        let bits = match size {
+                            LValue::Atom(atom::ALL) => src_bits,
+                            _ => match bitstring::segment_size(context.expand_arg(size), *unit) {
+                                Ok(bits) => bits,
+                                Err(exc) => cond_fail!(context, fail, exc),
+                            },
+                        };
+
+                        // all has to be a multiple of the unit, and we can't take more than there is
+                        if bits > src_bits || bits % (*unit as usize) != 0 {
+                            cond_fail!(context, fail, Exception::new(Reason::EXC_BADARG));
                         }
 
-                        if let Ok(value) = bitstring::RcBinary::try_from(&context.expand_arg(src)) {
-                            match size {
-                                LValue::Atom(atom::ALL) => unsafe {
-                                    (*context.bs).put_binary(&value);
-                                },
-                                _ => unimplemented!("bs_put_binary size {:?}", size),
-                            }
-                        } else {
-                            panic!("Bad argument to {:?}", ins.op)
+                        unsafe {
+                            (*context.bs).put_binary(context.bs_offset, binary, offset, bits);
                         }
+                        context.bs_offset += bits;
                     } else {
                         unreachable!()
                     }
                 }
                 Opcode::BsPutFloat => {
                     // gen_put_float(GenOpArg Fail,GenOpArg Size, GenOpArg Unit, GenOpArg Flags, GenOpArg Src)
-                    // Size can be atom all
-                    if let [_fail, size, LValue::Literal(unit), _flags, src] = &ins.args[..] {
-                        // TODO: fail label
-                        if *unit != 8 {
-                            unimplemented!("bs_put_float unit != 8");
-                            //fail!(context, fail);
-                        }
+                    if let [fail, size, LValue::Literal(unit), LValue::Literal(flags), src] = &ins.args[..] {
+                        let bits = match bitstring::segment_size(context.expand_arg(size), *unit) {
+                            Ok(bits) => bits,
+                            Err(exc) => cond_fail!(context, fail, exc),
+                        };
+                        let flags = bitstring::Flag::from_bits_truncate(*flags as u8);
 
-                        if let Variant::Float(value::Float(f)) =
-                            context.expand_arg(src).into_variant()
-                        {
-                            match size {
-                                LValue::Atom(atom::ALL) => unsafe {
-                                    (*context.bs).put_float(f);
-                                },
-                                _ => unimplemented!("bs_put_float size {:?}", size),
-                            }
-                        } else {
-                            panic!("Bad argument to {:?}", ins.op)
+                        let float = match context.expand_arg(src).into_variant() {
+                            Variant::Float(value::Float(f)) => f,
+                            Variant::Integer(i) => f64::from(i),
+                            _ => cond_fail!(context, fail, Exception::new(Reason::EXC_BADARG)),
+                        };
+
+                        let ok = unsafe { (*context.bs).put_float(context.bs_offset, bits, float, flags) };
+                        if !ok {
+                            cond_fail!(context, fail, Exception::new(Reason::EXC_BADARG));
                         }
+                        context.bs_offset += bits;
                     } else {
                         unreachable!()
                     }
@@ -1750,9 +2407,11 @@ impl Machine {
                 Opcode::BsPutString => {
                     // BsPutString uses the StrT strings table! needs to be patched in loader
                     if let LValue::Binary(str) = &ins.args[0] {
+                        let bits = str.data.len() * 8;
                         unsafe {
-                            (*context.bs).put_binary(&str);
+                            (*context.bs).put_bits(context.bs_offset, &str.data, 0, bits);
                         }
+                        context.bs_offset += bits;
                     } else {
                         unreachable!()
                     }
@@ -2258,17 +2917,12 @@ impl Machine {
 
                     let size = context.expand_arg(&ins.args[1]);
                     let extra_words = ins.args[2].to_u32() as usize;
-                    let unit = ins.args[2].to_u32() as usize;
-                    let src = context.expand_arg(&ins.args[4]);
-
-                    let res = bitstring::append(&process, src, size, extra_words, unit);
+                    let unit = ins.args[4].to_u32() as usize;
+                    let src = context.expand_arg(&ins.args[5]);
 
-                    if let Some(res) = res {
-                        set_register!(context, &ins.args[5], res)
-                    } else {
-                        // TODO: execute fail only if non zero, else raise
-                        /* TODO not yet: c_p->freason is already set (to BADARG or SYSTEM_LIMIT). */
-                        fail!(context, ins.args[0]);
+                    match bitstring::append(&process, src, size, extra_words, unit) {
+                        Ok(res) => set_register!(context, &ins.args[7], res),
+                        Err(exc) => cond_fail!(context, ins.args[0], exc),
                     }
                 }
                 Opcode::BsPrivateAppend => {
@@ -2279,20 +2933,48 @@ impl Machine {
                     let unit = ins.args[2].to_u32() as usize;
                     let src = context.expand_arg(&ins.args[3]);
 
-                    let res = bitstring::private_append(&process, src, size, unit);
-
-                    if let Some(res) = res {
-                        set_register!(context, &ins.args[5], res)
-                    } else {
-                        /* TODO not yet: c_p->freason is already set (to BADARG or SYSTEM_LIMIT). */
-                        fail!(context, ins.args[0]);
+                    match bitstring::private_append(&process, src, size, unit) {
+                        Ok(res) => set_register!(context, &ins.args[5], res),
+                        Err(exc) => cond_fail!(context, ins.args[0], exc),
                     }
-                    unimplemented!("bs_private_append") // TODO
                 }
                 Opcode::BsInitBits => {
                     debug_assert_eq!(ins.args.len(), 6);
-                    // TODO: RcBinary has to be set to is_writable = false
-                    unimplemented!("bs_init_bits") // TODO
+                    // bs_init_bits Fail Sz Words Regs Flags Dst, same as bs_init2 but Sz is in bits
+                    if let [fail, s1, LValue::Literal(_words), LValue::Literal(_live), _flags, dest] =
+                        &ins.args[..]
+                    {
+                        let size = match s1 {
+                            LValue::Literal(i) => Term::uint(&context.heap, *i),
+                            _ => context.expand_arg(s1),
+                        };
+                        let bits = match bitstring::segment_size(size, 1) {
+                            Ok(bits) => bits,
+                            Err(exc) => cond_fail!(context, fail, exc),
+                        };
+                        let size = (bits + 7) / 8;
+                        let mut binary = bitstring::Binary::with_capacity(size);
+                        binary.is_writable = false;
+                        let term = Term::binary(&context.heap, binary);
+                        // the data gets written later on, account for it now
+                        context.heap.add_binary(size);
+                        let binary = term.get_boxed_value::<bitstring::RcBinary>().unwrap();
+                        let ptr: *const bitstring::Binary = &**binary;
+                        context.bs = ptr as *mut bitstring::Binary;
+                        context.bs_offset = 0;
+
+                        // byte sized results are plain binaries, the rest need a sub binary to
+                        // carry the bit size
+                        let term = if bits % 8 == 0 {
+                            term
+                        } else {
+                            let sub = bitstring::SubBinary::new(binary.clone(), bits, 0, false);
+                            Term::subbinary(&context.heap, sub)
+                        };
+                        set_register!(context, dest, term);
+                    } else {
+                        unreachable!()
+                    }
                 }
                 Opcode::BsGetUtf8 => {
                     debug_assert_eq!(ins.args.len(), 5);
@@ -2406,10 +3088,11 @@ impl Machine {
                 Opcode::Fconv => {
                     // reg (x), dest (float reg)
                     let val: f64 = match context.expand_arg(&ins.args[0]).into_number() {
-                        Ok(value::Num::Float(f)) => f,
-                        Ok(value::Num::Integer(i)) => f64::from(i),
-                        Ok(_) => unimplemented!(),
-                        // TODO: bignum if it fits into float
+                        // bignums too large for a float are a badarith
+                        Ok(num) => match num.to_f64() {
+                            Some(f) => f,
+                            None => return Err(Exception::new(Reason::EXC_BADARITH)),
+                        },
                         Err(_) => return Err(Exception::new(Reason::EXC_BADARITH)),
                     };
 
@@ -2439,9 +3122,10 @@ impl Machine {
                             context.expand_arg(&ins.args[3]),
                             context.expand_arg(&ins.args[4]),
                         ];
-                        let mfa = &module.imports[*i as usize];
+                        let bif = module.bif(*i)?;
 
-                        match bif::apply(self, &process, mfa, args) {
+                        context.bump_reductions(1);
+                        match op_bif!(self, &process, bif, args) {
                             Ok(val) => set_register!(context, &ins.args[5], val),
                             Err(exc) => cond_fail!(context, ins.args[0], exc),
                         }
@@ -2458,9 +3142,10 @@ impl Machine {
                             context.expand_arg(&ins.args[4]),
                             context.expand_arg(&ins.args[5]),
                         ];
-                        let mfa = &module.imports[*i as usize];
+                        let bif = module.bif(*i)?;
 
-                        match bif::apply(self, &process, mfa, args) {
+                        context.bump_reductions(1);
+                        match op_bif!(self, &process, bif, args) {
                             Ok(val) => set_register!(context, &ins.args[6], val),
                             Err(exc) => cond_fail!(context, ins.args[0], exc),
                         }
@@ -2493,9 +3178,9 @@ impl Machine {
                     context.x[0] = Term::closure(
                         &context.heap,
                         value::Closure {
+                            module,
                             // arity is arity minus nfree (beam_emu.c)
                             mfa: module::MFA(module.name, lambda.name, lambda.arity - lambda.nfree),
-                            // TODO: use module id instead later
                             ptr: lambda.offset,
                             binding,
                         },
@@ -2508,7 +3193,7 @@ impl Machine {
                     context.cp = Some(context.ip);
                     // TODO: this clone is bad but the borrow checker complains (but doesn't on GetTl/GetHd)
                     if let Ok(closure) = value::Closure::try_from(&value) {
-                        op_call_fun!(self, context, closure, arity)
+                        op_call_fun!(self, context, value, closure, arity)
                     } else if let Ok(mfa) = module::MFA::try_from(&value) {
                         // TODO: deduplicate this part
                         let export = { self.exports.read().lookup(&mfa) }; // drop the exports lock
@@ -2518,6 +3203,9 @@ impl Machine {
                             Some(Export::Bif(bif)) => {
                                 op_call_bif!(self, context, &process, bif, mfa.2 as usize, true) // TODO is return true ok
                             }
+                            Some(Export::DirtyBif(bif, dirty)) => {
+                                op_call_dirty_bif!(self, context, &process, bif, dirty, mfa.2 as usize, true)
+                            }
                             None => {
                                 // println!("apply setup_error_handler");
                                 call_error_handler!(self, &process, &mfa);
@@ -2527,6 +3215,7 @@ impl Machine {
                     } else {
                         unreachable!()
                     }
+                    safepoint_and_reduce!(self, process, context.reds);
                 }
                 Opcode::GetHd => {
                     debug_assert_eq!(ins.args.len(), 2);